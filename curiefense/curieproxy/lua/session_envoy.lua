@@ -62,6 +62,12 @@ local function custom_response(handle, action_params)
     if action_params["headers"] and action_params["headers"] ~= cjson.null then
         response["headers"] = action_params["headers"]
     end
+    -- repeated headers, passed as tables of values
+    if type(action_params["multi_headers"]) == "table" then
+        for k, v in pairs(action_params["multi_headers"]) do
+            response["headers"][k] = v
+        end
+    end
     if action_params["reason" ] then response["reason" ] = action_params["reason" ] end
     if action_params["content"] then response["content"] = action_params["content"] end
 
@@ -30,6 +30,13 @@ local function custom_response(handle, action_params)
         end
     end
 
+    -- repeated headers, each value is sent as a separate header line
+    if action_params["multi_headers"] and action_params["multi_headers"] ~= cjson.null then
+        for k, v in pairs(action_params["multi_headers"]) do
+            handle.header[k] = v
+        end
+    end
+
     if action_params["status"] then
         local raw_status = action_params["status"]
         local status = tonumber(raw_status) or raw_status
@@ -161,11 +168,14 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
             end
             red:init_pipeline()
 
+            local now_ms = math.floor(ngx.now() * 1000)
             for _, limit in pairs(limits) do
                 local key = limit.key
                 if not limit.zero_limits then
                     local pw = limit.pairwith
-                    if pw then
+                    if limit.bucket_rate then
+                        red:eval(limit.bucket_script, 1, key, limit.bucket_rate, limit.bucket_burst, now_ms)
+                    elseif pw then
                         red:sadd(key, pw)
                         red:scard(key)
                         red:ttl(key)
@@ -187,14 +197,22 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
             for _, limit in pairs(limits) do
                 local key = limit.key
                 local curcount = 1
-                if not limit.zero_limits then
+                local expire = nil
+                if limit.bucket_rate then
+                    -- remaining tokens, -1 when the bucket is empty
+                    curcount = -1
+                    if not limit.zero_limits then
+                        curcount = results[result_idx] or -1
+                        result_idx = result_idx + 1
+                    end
+                elseif not limit.zero_limits then
                     local pw = limit.pairwith
                     if pw then
                         result_idx = result_idx + 1
                     end
                     curcount = results[result_idx]
                     result_idx = result_idx + 1
-                    local expire = results[result_idx]
+                    expire = results[result_idx]
                     result_idx = result_idx + 1
                     if curcount == nil then
                         curcount = 0
@@ -203,7 +221,7 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
                         red:expire(key, limit.timeframe)
                     end
                 end
-                table.insert(rlimits, limit:result(curcount))
+                table.insert(rlimits, limit:result(curcount, expire))
             end
             red:commit_pipeline()
 
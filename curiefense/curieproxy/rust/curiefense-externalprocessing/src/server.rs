@@ -3,7 +3,7 @@ use curiefense::{
     config::{flow::FlowMap, globalfilter::GlobalFilterSection, virtualtags::VirtualTags, with_config},
     grasshopper::DynGrasshopper,
     incremental::{add_body, add_headers, finalize, inspect_init, IData, IPInfo},
-    interface::{jsonlog, AnalyzeResult},
+    interface::{jsonlog, Action, AnalyzeResult},
     logs::{LogLevel, Logs},
     utils::RequestMeta,
 };
@@ -291,7 +291,7 @@ impl MyEP {
                                 status: Some(HttpStatus { code: a.status as i32 }),
                                 details: serde_json::to_string(&result.decision.reasons).unwrap(),
                                 body: a.content.clone(),
-                                headers: a.headers.clone().map(mutate_headers),
+                                headers: mutate_headers(a),
                                 grpc_status: None,
                             },
                         )),
@@ -334,18 +334,33 @@ impl MyEP {
     }
 }
 
-fn mutate_headers(headers: HashMap<String, String>) -> HeaderMutation {
-    HeaderMutation {
-        set_headers: headers
-            .into_iter()
-            .map(|(key, value)| HeaderValueOption {
-                header: Some(HeaderValue { key, value }),
-                append: None,
-                append_action: 0,
-            })
-            .collect(),
-        remove_headers: Vec::new(),
+fn mutate_headers(action: &Action) -> Option<HeaderMutation> {
+    if action.headers.is_none() && action.multi_headers.is_none() {
+        return None;
     }
+    let single = action.headers.iter().flatten().map(|(key, value)| HeaderValueOption {
+        header: Some(HeaderValue {
+            key: key.clone(),
+            value: value.clone(),
+        }),
+        append: None,
+        append_action: 0,
+    });
+    // repeated headers are appended so that every value is sent
+    let multi = action.multi_headers.iter().flatten().flat_map(|(key, values)| {
+        values.iter().map(move |value| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            append: Some(true),
+            append_action: 0,
+        })
+    });
+    Some(HeaderMutation {
+        set_headers: single.chain(multi).collect(),
+        remove_headers: Vec::new(),
+    })
 }
 
 async fn send_response(
@@ -240,7 +240,7 @@ pub async fn inspect_wrapper<GH: Grasshopper>(
     mgh: Option<&GH>,
 ) -> CFDecision {
     let mut mlogs = logs;
-    let result = inspect_generic_request_map_async(&configpath, mgh, raw, &mut mlogs, None, HashMap::new()).await;
+    let result = inspect_generic_request_map_async(&configpath, mgh, raw, &mut mlogs, None, HashMap::new(), &[]).await;
     CFDecision { result, logs: mlogs }
 }
 
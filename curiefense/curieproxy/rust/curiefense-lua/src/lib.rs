@@ -3,19 +3,29 @@ pub mod userdata;
 use curiefense::analyze::analyze_finish;
 use curiefense::analyze::analyze_flows;
 use curiefense::analyze::analyze_init;
+use curiefense::analyze::kill_switch;
 use curiefense::analyze::APhase1;
 use curiefense::analyze::APhase2I;
 use curiefense::analyze::APhase2O;
 use curiefense::analyze::APhase3;
 use curiefense::analyze::CfRulesArg;
 use curiefense::analyze::InitResult;
+use curiefense::analyze::KILL_SWITCH_TAG;
+use curiefense::config::{health_check, reload_config};
+use curiefense::geo::reload_geoip;
 use curiefense::grasshopper::DynGrasshopper;
 use curiefense::grasshopper::Grasshopper;
+use curiefense::grasshopper::MockGrasshopper;
+use curiefense::incremental::{extract_ip, XffResult};
 use curiefense::inspect_generic_request_map;
 use curiefense::inspect_generic_request_map_init;
 use curiefense::interface::aggregator::aggregated_values_block;
+use curiefense::learning::learning_mode;
 use curiefense::logs::LogLevel;
 use curiefense::logs::Logs;
+use curiefense::preview_generic_template;
+use curiefense::simulate_generic_request_map;
+use curiefense::tag_generic_request_map;
 use curiefense::utils::RequestMeta;
 use curiefense::utils::{InspectionResult, RawRequest};
 use mlua::prelude::*;
@@ -41,6 +51,8 @@ struct LuaArgs<'l> {
     humanity: Option<bool>,
     configpath: String,
     plugins: HashMap<String, String>,
+    xff: Option<XffResult>,
+    seed_tags: Vec<String>,
 }
 
 /// Lua function arguments:
@@ -55,6 +67,7 @@ struct LuaArgs<'l> {
 /// * secpolid, optional string. When set, bypass hostname matching for security policy selection
 /// * configpath, path to the lua configuration files, defaults to /cf-config/current/config
 /// * humanity, optional boolean, only used for the test functions
+/// * tags, optional list of strings, tags that are set before the global filters run, only used for the test functions
 fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>, String> {
     let vloglevel = args.get("loglevel").map_err(|_| "Missing log level".to_string())?;
     let vmeta = args.get("meta").map_err(|_| "Missing meta argument".to_string())?;
@@ -70,6 +83,7 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
         .map_err(|_| "Missing log level argument".to_string())?;
     let vhumanity = args.get("human").map_err(|_| "Missing human argument".to_string())?;
     let vconfigpath = args.get("configpath").map_err(|_| "Missing config path".to_string())?;
+    let vseed_tags = args.get("tags").map_err(|_| "Missing tags argument".to_string())?;
     let loglevel = match String::from_lua(vloglevel, lua) {
         Err(rr) => return Err(format!("Could not convert the loglevel argument: {}", rr)),
         Ok(m) => match m.as_str() {
@@ -104,9 +118,10 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
         Err(rr) => return Err(format!("Could not convert the hops argument: {}", rr)),
         Ok(i) => i,
     };
-    let ip = match hops {
+    let xff = hops.map(|hops| extract_ip(hops, &headers));
+    let ip = match &xff {
         None => str_ip,
-        Some(hops) => curiefense::incremental::extract_ip(hops, &headers).unwrap_or(str_ip),
+        Some(x) => x.clone().ip_or(str_ip),
     };
     let humanity = match FromLua::from_lua(vhumanity, lua) {
         Err(rr) => return Err(format!("Could not convert the humanity argument: {}", rr)),
@@ -116,6 +131,15 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
         Err(rr) => return Err(format!("Could not convert the config path argument: {}", rr)),
         Ok(p) => p,
     };
+    let seed_tags: Option<Vec<String>> = match FromLua::from_lua(vseed_tags, lua) {
+        Err(rr) => return Err(format!("Could not convert the tags argument: {}", rr)),
+        Ok(t) => t,
+    };
+    // a malformed x-forwarded-for is reported as a tag
+    let mut seed_tags = seed_tags.unwrap_or_default();
+    if let Some(x) = &xff {
+        seed_tags.extend(x.tags());
+    }
     let mplugins: Option<HashMap<String, HashMap<String, String>>> = match FromLua::from_lua(vplugins, lua) {
         Err(rr) => return Err(format!("Could not convert the plugins argument: {}", rr)),
         Ok(p) => p,
@@ -125,6 +149,7 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
         headers,
         lua_body,
         str_ip: ip,
+        xff,
         loglevel,
         secpolid,
         humanity,
@@ -138,6 +163,7 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
                     .map(move |(k, v)| (format!("{}.{}", &plugin_name, k), v))
             })
             .collect(),
+        seed_tags,
     })
 }
 
@@ -155,6 +181,8 @@ fn lua_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectionResu
                 Some(grasshopper),
                 lua_args.secpolid,
                 lua_args.plugins,
+                lua_args.xff,
+                &[],
             );
             Ok(LuaInspectionResult(res))
         }
@@ -179,6 +207,8 @@ fn lua_inspect_init(lua: &Lua, args: LuaTable) -> LuaResult<LInitResult<APhase1>
                 Some(grasshopper),
                 lua_args.secpolid,
                 lua_args.plugins,
+                lua_args.xff,
+                &lua_args.seed_tags,
             );
             Ok(match res {
                 Ok((r, logs)) => match r {
@@ -231,30 +261,63 @@ fn lua_inspect_process(lua: &Lua, args: (LuaValue, LuaValue)) -> LuaResult<LuaIn
     };
     let p3 = APhase3::from_phase2(*p2, limit_results);
     let grasshopper = &DynGrasshopper {};
-    let res = analyze_finish(&mut logs, Some(grasshopper), CfRulesArg::Global, p3);
+    let res = learning_mode(analyze_finish(&mut logs, Some(grasshopper), CfRulesArg::Global, p3));
+    let res = kill_switch(&mut logs, res, KILL_SWITCH_TAG.as_deref());
     Ok(LuaInspectionResult(Ok(InspectionResult::from_analyze(logs, res))))
 }
 
-struct DummyGrasshopper {
-    humanity: bool,
+/// Lua function that forces a configuration reload
+///
+/// Returns the new revision (or nil on failure), and the reload logs
+fn lua_reload_config(_lua: &Lua, configpath: Option<String>) -> LuaResult<(Option<String>, Vec<String>)> {
+    let mut logs = Logs::default();
+    let configpath = configpath.unwrap_or_else(|| "/cf-config/current/config".to_string());
+    let revision = reload_config(&configpath, &mut logs);
+    Ok((revision, logs.to_stringvec()))
 }
 
-impl Grasshopper for DummyGrasshopper {
-    fn js_app(&self) -> Option<std::string::String> {
-        None
-    }
-    fn js_bio(&self) -> Option<std::string::String> {
-        None
-    }
-    fn parse_rbzid(&self, _: &str, _: &str) -> Option<bool> {
-        Some(self.humanity)
-    }
-    fn gen_new_seed(&self, _: &str) -> Option<std::string::String> {
-        None
-    }
-    fn verify_workproof(&self, _: &str, _: &str) -> Option<std::string::String> {
-        Some("ok".into())
+/// Lua function that reloads the geoip databases
+///
+/// Returns nil on success, and the error message otherwise
+fn lua_reload_geoip(_lua: &Lua, _: ()) -> LuaResult<Option<String>> {
+    Ok(reload_geoip().err())
+}
+
+/// Lua function returning the tags of a request, without running the acl, flow, rate limit and content filter stages
+///
+/// Takes the same arguments as inspect_request. Returns the sorted list of tags, or nil and an error message.
+fn lua_tag_request(lua: &Lua, args: LuaTable) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    let lua_args = match lua_convert_args(lua, args) {
+        Ok(a) => a,
+        Err(rr) => return Ok((None, Some(rr))),
+    };
+    let grasshopper = &DynGrasshopper {};
+    let mut logs = Logs::new(lua_args.loglevel);
+    if let Some(x) = lua_args.xff {
+        x.log(&mut logs);
     }
+    let rmeta = match RequestMeta::from_map(lua_args.meta) {
+        Ok(m) => m,
+        Err(rr) => return Ok((None, Some(rr.to_string()))),
+    };
+    let raw = RawRequest {
+        ipstr: lua_args.str_ip,
+        meta: rmeta,
+        headers: lua_args.headers,
+        mbody: lua_args.lua_body.as_ref().map(|b| b.as_bytes()),
+    };
+    let tags = tag_generic_request_map(
+        &lua_args.configpath,
+        Some(grasshopper),
+        raw,
+        &mut logs,
+        lua_args.secpolid.as_deref(),
+        lua_args.plugins,
+        &lua_args.seed_tags,
+    );
+    let mut names: Vec<String> = tags.as_hash_ref().keys().cloned().collect();
+    names.sort();
+    Ok((Some(names), None))
 }
 
 /// Lua TEST interface to the inspection function
@@ -264,9 +327,9 @@ impl Grasshopper for DummyGrasshopper {
 fn lua_test_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectionResult> {
     match lua_convert_args(lua, args) {
         Ok(lua_args) => {
-            let gh = DummyGrasshopper {
-                humanity: lua_args.humanity.unwrap_or(false),
-            };
+            let gh = MockGrasshopper::default()
+                .with_rbzid(lua_args.humanity.unwrap_or(false))
+                .with_workproof("ok");
             let res = inspect_request(
                 &lua_args.configpath,
                 lua_args.meta,
@@ -276,6 +339,8 @@ fn lua_test_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectio
                 Some(&gh),
                 lua_args.secpolid,
                 lua_args.plugins,
+                lua_args.xff,
+                &lua_args.seed_tags,
             );
             Ok(LuaInspectionResult(res))
         }
@@ -283,6 +348,93 @@ fn lua_test_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectio
     }
 }
 
+/// Lua debugging interface, runs each inspection stage independently
+///
+/// Takes the same arguments as test_inspect_request. Returns a table mapping the stage names to the json encoded
+/// decisions of the stages, or nil and an error message.
+fn lua_simulate_request(lua: &Lua, args: LuaTable) -> LuaResult<(Option<HashMap<String, String>>, Option<String>)> {
+    let lua_args = match lua_convert_args(lua, args) {
+        Ok(a) => a,
+        Err(rr) => return Ok((None, Some(rr))),
+    };
+    let gh = MockGrasshopper::default()
+        .with_rbzid(lua_args.humanity.unwrap_or(false))
+        .with_workproof("ok");
+    let mut logs = Logs::new(lua_args.loglevel);
+    if let Some(x) = lua_args.xff {
+        x.log(&mut logs);
+    }
+    let rmeta = match RequestMeta::from_map(lua_args.meta) {
+        Ok(m) => m,
+        Err(rr) => return Ok((None, Some(rr.to_string()))),
+    };
+    let raw = RawRequest {
+        ipstr: lua_args.str_ip,
+        meta: rmeta,
+        headers: lua_args.headers,
+        mbody: lua_args.lua_body.as_ref().map(|b| b.as_bytes()),
+    };
+    let (stages, _) = simulate_generic_request_map(
+        &lua_args.configpath,
+        Some(&gh),
+        raw,
+        &mut logs,
+        lua_args.secpolid.as_deref(),
+        lua_args.plugins,
+        &lua_args.seed_tags,
+    );
+    let out = stages
+        .into_iter()
+        .map(|(stage, decision)| {
+            let encoded = serde_json::json!({
+                "action": decision.maction,
+                "reasons": decision.reasons,
+            });
+            (stage.to_string(), encoded.to_string())
+        })
+        .collect();
+    Ok((Some(out), None))
+}
+
+/// Lua template preview, for testing action templates
+///
+/// Takes a template string, and the same arguments as test_inspect_request. Returns the rendered template, or nil
+/// and an error message.
+fn lua_render_preview(lua: &Lua, args: (String, LuaTable)) -> LuaResult<(Option<String>, Option<String>)> {
+    let (template, args) = args;
+    let lua_args = match lua_convert_args(lua, args) {
+        Ok(a) => a,
+        Err(rr) => return Ok((None, Some(rr))),
+    };
+    let gh = MockGrasshopper::default()
+        .with_rbzid(lua_args.humanity.unwrap_or(false))
+        .with_workproof("ok");
+    let mut logs = Logs::new(lua_args.loglevel);
+    let rmeta = match RequestMeta::from_map(lua_args.meta) {
+        Ok(m) => m,
+        Err(rr) => return Ok((None, Some(rr.to_string()))),
+    };
+    let raw = RawRequest {
+        ipstr: lua_args.str_ip,
+        meta: rmeta,
+        headers: lua_args.headers,
+        mbody: lua_args.lua_body.as_ref().map(|b| b.as_bytes()),
+    };
+    match preview_generic_template(
+        &lua_args.configpath,
+        Some(&gh),
+        &template,
+        raw,
+        &mut logs,
+        lua_args.secpolid.as_deref(),
+        lua_args.plugins,
+        &lua_args.seed_tags,
+    ) {
+        Ok(rendered) => Ok((Some(rendered), None)),
+        Err(rr) => Ok((None, Some(rr.to_string()))),
+    }
+}
+
 /// Rust-native inspection top level function
 #[allow(clippy::too_many_arguments)]
 fn inspect_request<GH: Grasshopper>(
@@ -294,9 +446,14 @@ fn inspect_request<GH: Grasshopper>(
     grasshopper: Option<&GH>,
     selected_secpol: Option<String>,
     plugins: HashMap<String, String>,
+    xff: Option<XffResult>,
+    seed_tags: &[String],
 ) -> Result<InspectionResult, String> {
     let mut logs = Logs::default();
     logs.debug("Inspection init");
+    if let Some(x) = xff {
+        x.log(&mut logs);
+    }
     let rmeta: RequestMeta = RequestMeta::from_map(meta)?;
 
     let raw = RawRequest {
@@ -312,6 +469,7 @@ fn inspect_request<GH: Grasshopper>(
         &mut logs,
         selected_secpol.as_deref(),
         plugins,
+        seed_tags,
     );
 
     Ok(InspectionResult::from_analyze(logs, dec))
@@ -328,9 +486,14 @@ fn inspect_init<GH: Grasshopper>(
     grasshopper: Option<&GH>,
     selected_secpol: Option<String>,
     plugins: HashMap<String, String>,
+    xff: Option<XffResult>,
+    seed_tags: &[String],
 ) -> Result<(InitResult, Logs), String> {
     let mut logs = Logs::new(loglevel);
     logs.debug("Inspection init");
+    if let Some(x) = xff {
+        x.log(&mut logs);
+    }
     let rmeta: RequestMeta = RequestMeta::from_map(meta)?;
 
     let raw = RawRequest {
@@ -347,6 +510,7 @@ fn inspect_init<GH: Grasshopper>(
         &mut logs,
         selected_secpol.as_deref(),
         plugins,
+        seed_tags,
     ) {
         Err(res) => return Ok((InitResult::Res(res), logs)),
         Ok(p0) => p0,
@@ -367,12 +531,23 @@ fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
     exports.set("inspect_request_init", lua.create_function(lua_inspect_init)?)?;
     exports.set("inspect_request_flows", lua.create_function(lua_inspect_flows)?)?;
     exports.set("inspect_request_process", lua.create_function(lua_inspect_process)?)?;
+    exports.set("tag_request", lua.create_function(lua_tag_request)?)?;
     exports.set(
         "aggregated_values",
         lua.create_function(|_, ()| Ok(aggregated_values_block()))?,
     )?;
+    // configuration management
+    exports.set("reload_config", lua.create_function(lua_reload_config)?)?;
+    exports.set("reload_geoip", lua.create_function(lua_reload_geoip)?)?;
+    exports.set(
+        "health_check",
+        lua.create_function(|_, ()| Ok(health_check().to_string()))?,
+    )?;
     // end-to-end inspection (test)
     exports.set("test_inspect_request", lua.create_function(lua_test_inspect_request)?)?;
+    // per stage decisions (debugging)
+    exports.set("simulate_request", lua.create_function(lua_simulate_request)?)?;
+    exports.set("render_preview", lua.create_function(lua_render_preview)?)?;
 
     Ok(exports)
 }
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use curiefense::analyze::{APhase1, APhase2I};
 use curiefense::flow::{FlowCheck, FlowResult, FlowResultType};
 use curiefense::interface::Tags;
-use curiefense::limit::{LimitCheck, LimitResult};
+use curiefense::limit::{LimitCheck, LimitResult, TOKEN_BUCKET_SCRIPT};
 use curiefense::logs::Logs;
 use curiefense::utils::InspectionResult;
 use mlua::prelude::*;
@@ -222,13 +222,15 @@ impl mlua::UserData for LuaLimitCheck {
         fields.add_field_method_get("pairwith", |_, this| Ok(this.0.pairwith.clone()));
         fields.add_field_method_get("zero_limits", |_, this| Ok(this.0.zero_limits()));
         fields.add_field_method_get("timeframe", |_, this| Ok(this.0.limit.timeframe));
+        // token bucket parameters, nil for window limits
+        fields.add_field_method_get("bucket_rate", |_, this| Ok(this.0.limit.bucket.map(|b| b.rate)));
+        fields.add_field_method_get("bucket_burst", |_, this| Ok(this.0.limit.bucket.map(|b| b.burst)));
+        fields.add_field_method_get("bucket_script", |_, _| Ok(TOKEN_BUCKET_SCRIPT));
     }
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("result", |_, this, curcount| {
-            Ok(LuaLimitResult(LimitResult {
-                limit: this.0.limit.clone(),
-                curcount,
-            }))
+        // ttl is the time to live of the window counter, nil when unknown
+        methods.add_method("result", |_, this, (curcount, ttl): (i64, Option<i64>)| {
+            Ok(LuaLimitResult(this.0.result(curcount, ttl.unwrap_or(-1))))
         });
     }
 }
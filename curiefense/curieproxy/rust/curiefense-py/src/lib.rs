@@ -45,6 +45,7 @@ fn py_inspect_request(
         &mut logs,
         None,
         plugins.unwrap_or_default(),
+        &[],
     );
     let res = InspectionResult {
         decision: dec.decision,
@@ -2,31 +2,17 @@ use criterion::*;
 use curiefense::analyze::{analyze, APhase0, CfRulesArg};
 use curiefense::config::contentfilter::{ContentFilterProfile, ContentFilterRules};
 use curiefense::config::hostmap::{PolicyId, SecurityPolicy};
-use curiefense::config::raw::AclProfile;
+use curiefense::config::raw::{AclProfile, HeaderLogPolicy};
 use curiefense::config::virtualtags::VirtualTags;
 use curiefense::grasshopper::DummyGrasshopper;
-use curiefense::interface::{SecpolStats, SimpleDecision, StatsCollect};
+use curiefense::interface::{AnalyzeResult, SecpolStats, SimpleDecision, StatsCollect};
 use curiefense::logs::{LogLevel, Logs};
 use curiefense::tagging::tag_request;
 use curiefense::utils::{map_request, RawRequest, RequestMeta};
 use std::collections::HashMap;
 use std::sync::Arc;
 
-fn logging_empty(c: &mut Criterion) {
-    let mut headers = HashMap::new();
-    headers.insert("content-type".into(), "application/json".into());
-    let raw = RawRequest {
-        ipstr: "1.2.3.4".into(),
-        headers,
-        meta: RequestMeta {
-            authority: Some("x.com".into()),
-            method: "GET".into(),
-            path: "/some/path/to?x=1&y=2&z=ZHFzcXNkcXNk".into(),
-            requestid: None,
-            extra: HashMap::new(),
-        },
-        mbody: Some(b"{\"zzz\":45}"),
-    };
+fn analyzed(raw: &RawRequest, content_filter_profile: ContentFilterProfile) -> (AnalyzeResult, Logs) {
     let secpolicy = Arc::new(SecurityPolicy {
         policy: PolicyId {
             id: "__default__".into(),
@@ -40,16 +26,20 @@ fn logging_empty(c: &mut Criterion) {
         acl_active: true,
         acl_profile: AclProfile::default(),
         content_filter_active: true,
-        content_filter_profile: ContentFilterProfile::default_from_seed("seedqszqsdqsdd"),
+        content_filter_profile,
         limits: Vec::new(),
         session: Vec::new(),
         session_ids: Vec::new(),
+        globalfilter_active: true,
+        limit_active: true,
+        flow_active: true,
+        allowlist_first: false,
     });
     let mut logs = Logs::new(LogLevel::Debug);
     let stats =
         StatsCollect::new(std::time::Instant::now(), "QSDQSDQSD".into()).secpol(SecpolStats::build(&secpolicy, 0));
-    let reqinfo = map_request(&mut logs, secpolicy, None, &raw, None, HashMap::new());
-    let (itags, _, stats) = tag_request(stats, false, &[], &reqinfo, &VirtualTags::default(), &mut logs);
+    let reqinfo = map_request(&mut logs, secpolicy, None, raw, None, HashMap::new());
+    let (itags, _, stats) = tag_request(stats, false, &[], &[], &reqinfo, &VirtualTags::default(), &mut logs);
     let p0 = APhase0 {
         flows: HashMap::new(),
         globalfilter_dec: SimpleDecision::Pass,
@@ -65,10 +55,54 @@ fn logging_empty(c: &mut Criterion) {
         p0,
         CfRulesArg::Get(Some(&rules)),
     ));
+    (result, logs)
+}
+
+fn logging_empty(c: &mut Criterion) {
+    let mut headers = HashMap::new();
+    headers.insert("content-type".into(), "application/json".into());
+    let raw = RawRequest {
+        ipstr: "1.2.3.4".into(),
+        headers,
+        meta: RequestMeta {
+            authority: Some("x.com".into()),
+            method: "GET".into(),
+            path: "/some/path/to?x=1&y=2&z=ZHFzcXNkcXNk".into(),
+            requestid: None,
+            extra: HashMap::new(),
+        },
+        mbody: Some(b"{\"zzz\":45}"),
+    };
+    let (result, logs) = analyzed(&raw, ContentFilterProfile::default_from_seed("seedqszqsdqsdd"));
     c.bench_with_input(BenchmarkId::new("log_json", "empty_request"), &result, |b, r| {
         b.iter(|| async_std::task::block_on(r.decision.log_json(&r.rinfo, &r.tags, &r.stats, &logs, HashMap::new())))
     });
 }
 
-criterion_group!(logging, logging_empty);
+fn logging_large(c: &mut Criterion) {
+    let headers = (0..200).map(|i| (format!("x-header-{}", i), "v".repeat(200))).collect();
+    let query: Vec<String> = (0..200).map(|i| format!("arg{}={}", i, "a".repeat(200))).collect();
+    let raw = RawRequest {
+        ipstr: "1.2.3.4".into(),
+        headers,
+        meta: RequestMeta {
+            authority: Some("x.com".into()),
+            method: "GET".into(),
+            path: format!("/some/path/to?{}", query.join("&")),
+            requestid: None,
+            extra: HashMap::new(),
+        },
+        mbody: None,
+    };
+    let mut profile = ContentFilterProfile::default_from_seed("seedqszqsdqsdd");
+    profile
+        .header_log_policy
+        .insert("x-header-0".into(), HeaderLogPolicy::Hashed);
+    let (result, logs) = analyzed(&raw, profile);
+    c.bench_with_input(BenchmarkId::new("log_json", "large_request"), &result, |b, r| {
+        b.iter(|| async_std::task::block_on(r.decision.log_json(&r.rinfo, &r.tags, &r.stats, &logs, HashMap::new())))
+    });
+}
+
+criterion_group!(logging, logging_empty, logging_large);
 criterion_main!(logging);
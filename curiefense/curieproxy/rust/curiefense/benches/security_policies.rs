@@ -20,6 +20,7 @@ fn gen_bogus_config(sz: usize) -> Config {
                 HostMap {
                     name: format!("Dummy hostmap {}", i),
                     entries: Vec::new(),
+                    path_prefixes: Vec::new(),
                     default: None,
                 },
             )
@@ -61,6 +62,10 @@ fn gen_bogus_config(sz: usize) -> Config {
                     session: Vec::new(),
                     session_ids: Vec::new(),
                     limits: Vec::new(),
+                    globalfilter_active: true,
+                    limit_active: true,
+                    flow_active: true,
+                    allowlist_first: false,
                 }),
             )
             .unwrap()
@@ -70,6 +75,7 @@ fn gen_bogus_config(sz: usize) -> Config {
     def.default = Some(HostMap {
         name: "__default__".into(),
         entries: dummy_entries,
+        path_prefixes: Vec::new(),
         default: Some(Arc::new(SecurityPolicy {
             policy: PolicyId {
                 id: "__default__".into(),
@@ -87,6 +93,10 @@ fn gen_bogus_config(sz: usize) -> Config {
             session: Vec::new(),
             session_ids: Vec::new(),
             limits: Vec::new(),
+            globalfilter_active: true,
+            limit_active: true,
+            flow_active: true,
+            allowlist_first: false,
         })),
     });
 
@@ -1,22 +1,31 @@
+use lazy_static::lazy_static;
 use std::collections::HashSet;
 
 use crate::acl::check_acl;
 use crate::config::contentfilter::ContentFilterRules;
 use crate::config::flow::FlowMap;
+use crate::config::hostmap::SecurityPolicy;
 use crate::config::HSDB;
-use crate::contentfilter::{content_filter_check, masking};
+use crate::contentfilter::{content_filter_check, masking, CfBlock};
+use crate::decisioncache::{decision_cache_key, DECISION_CACHE};
 use crate::flow::{flow_build_query, flow_info, flow_process, flow_resolve_query, FlowCheck, FlowResult};
 use crate::grasshopper::{challenge_phase01, challenge_phase02, Grasshopper};
-use crate::interface::stats::{BStageMapped, StatsCollect};
+use crate::interface::stats::{BStageAcl, BStageContentFilter, BStageLimit, BStageMapped, StatsCollect};
 use crate::interface::{
-    merge_decisions, AclStage, AnalyzeResult, BDecision, BStageFlow, BlockReason, Decision, Location, SimpleDecision,
-    Tags,
+    merge_decisions, tagify, AclStage, AnalyzeResult, BDecision, BStageFlow, BlockReason, Decision, Location,
+    SimpleDecision, Tags,
 };
+use crate::learning::learning_mode;
 use crate::limit::{limit_build_query, limit_info, limit_process, limit_resolve_query, LimitCheck, LimitResult};
 use crate::logs::Logs;
-use crate::redis::redis_async_conn;
+use crate::redis::{redis_async_conn, QueryMode};
 use crate::utils::{eat_errors, BodyDecodingResult, RequestInfo};
 
+lazy_static! {
+    /// requests carrying this tag are never blocked, for incident response
+    pub static ref KILL_SWITCH_TAG: Option<String> = std::env::var("KILL_SWITCH_TAG").ok().map(|t| tagify(&t));
+}
+
 /*
 
   Scanning advances using the following steps:
@@ -90,15 +99,8 @@ pub enum InitResult {
     Phase1(APhase1),
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APhase0) -> InitResult {
-    let stats = p0.stats;
-    let mut tags = p0.itags;
-    let reqinfo = p0.reqinfo;
-    let securitypolicy = &reqinfo.rinfo.secpolicy;
-    let is_human = p0.is_human;
-    let globalfilter_dec = p0.globalfilter_dec;
-
+/// tags naming the security policy and the profiles applied to the request
+fn policy_tags(tags: &mut Tags, securitypolicy: &SecurityPolicy) {
     tags.insert_qualified("securitypolicy", &securitypolicy.policy.name, Location::Request);
     tags.insert_qualified("securitypolicy-entry", &securitypolicy.entry.name, Location::Request);
     tags.insert_qualified("aclid", &securitypolicy.acl_profile.id, Location::Request);
@@ -113,33 +115,62 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         &securitypolicy.content_filter_profile.name,
         Location::Request,
     );
+}
+
+/// the tags of a request once the global filters ran, without running the other stages
+///
+/// These are the tags the acl, flow, rate limit and content filter stages start from, they can add their own.
+pub fn analyze_tags(p0: APhase0) -> Tags {
+    let mut tags = p0.itags;
+    policy_tags(&mut tags, &p0.reqinfo.rinfo.secpolicy);
+    tags
+}
 
-    if !securitypolicy.content_filter_profile.content_type.is_empty() {
+/// checks that run before the global filters: malformed bodies, and challenge answers
+fn init_decision<GH: Grasshopper>(
+    mgh: Option<&GH>,
+    is_human: bool,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+) -> Option<Decision> {
+    let profile = &reqinfo.rinfo.secpolicy.content_filter_profile;
+    // a truncated body usually can't be decoded, it is inspected as is
+    if !profile.content_type.is_empty() && !reqinfo.body_too_large {
         // note that having no body is perfectly OK
         if let BodyDecodingResult::DecodingFailed(rr) = &reqinfo.rinfo.qinfo.body_decoding {
-            let reason = BlockReason::body_malformed(securitypolicy.content_filter_profile.id.clone(), rr);
+            let reason = BlockReason::body_malformed(profile.id.clone(), rr);
             // we expect the body to be properly decoded
-            let decision = securitypolicy.content_filter_profile.action.to_decision(
-                is_human,
-                mgh,
-                &reqinfo,
-                &mut tags,
-                vec![reason],
-            );
+            let decision = profile.action.to_decision(is_human, mgh, reqinfo, tags, vec![reason]);
             // add extra tags
-            for t in &securitypolicy.content_filter_profile.tags {
+            for t in &profile.tags {
                 tags.insert(t, Location::Body);
             }
-            return InitResult::Res(AnalyzeResult {
-                decision,
-                tags,
-                rinfo: masking(reqinfo),
-                stats: stats.mapped_stage_build(),
-            });
+            return Some(decision);
         }
     }
 
-    if let Some(decision) = mgh.and_then(|gh| challenge_phase02(gh, &reqinfo.rinfo.qinfo.uri, &reqinfo.headers)) {
+    mgh.and_then(|gh| {
+        challenge_phase02(
+            gh,
+            &reqinfo.rinfo.qinfo.uri,
+            &reqinfo.headers,
+            &reqinfo.rinfo.secpolicy.settings.challenge_cookie,
+        )
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APhase0) -> InitResult {
+    let stats = p0.stats;
+    let mut tags = p0.itags;
+    let reqinfo = p0.reqinfo;
+    let securitypolicy = &reqinfo.rinfo.secpolicy;
+    let is_human = p0.is_human;
+    let globalfilter_dec = p0.globalfilter_dec;
+
+    policy_tags(&mut tags, securitypolicy);
+
+    if let Some(decision) = init_decision(mgh, is_human, &reqinfo, &mut tags) {
         return InitResult::Res(AnalyzeResult {
             decision,
             tags,
@@ -167,7 +198,11 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         Decision::pass(Vec::new())
     };
 
-    let flow_checks = flow_info(logs, &p0.flows, &reqinfo, &tags);
+    let flow_checks = if securitypolicy.flow_active {
+        flow_info(logs, &p0.flows, &reqinfo, &tags)
+    } else {
+        Vec::new()
+    };
     let info = AnalysisInfo {
         is_human,
         p0_decision: decision,
@@ -204,7 +239,7 @@ impl APhase3 {
     }
 }
 
-pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
+pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1, mode: QueryMode) -> APhase2O {
     let empty = |info| APhase2O {
         flows: Vec::new(),
         limits: (),
@@ -235,7 +270,7 @@ pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
         }
     };
 
-    let flow_results = eat_errors(logs, flow_resolve_query(&mut redis, &mut lst, p1.flows).await);
+    let flow_results = eat_errors(logs, flow_resolve_query(&mut redis, &mut lst, p1.flows, mode).await);
     logs.debug("query - flow checks done");
 
     AnalysisPhase {
@@ -248,7 +283,12 @@ pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
 pub fn analyze_flows(logs: &mut Logs, p2: APhase2O) -> APhase2I {
     let mut info = p2.info;
     let stats = flow_process(info.stats.clone(), 0, &p2.flows, &mut info.tags);
-    let limit_checks = limit_info(logs, &info.reqinfo, &info.reqinfo.rinfo.secpolicy.limits, &info.tags);
+    let secpol = &info.reqinfo.rinfo.secpolicy;
+    let limit_checks = if secpol.limit_active {
+        limit_info(logs, &info.reqinfo, &secpol.limits, &info.tags)
+    } else {
+        Vec::new()
+    };
     APhase2I {
         flows: stats,
         limits: limit_checks,
@@ -256,7 +296,7 @@ pub fn analyze_flows(logs: &mut Logs, p2: APhase2O) -> APhase2I {
     }
 }
 
-pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3 {
+pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I, mode: QueryMode) -> APhase3 {
     let empty = |info, flows| APhase3 {
         flows,
         limits: Vec::new(),
@@ -279,7 +319,7 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
     };
 
     let mut pipe = redis::pipe();
-    limit_build_query(&mut pipe, &p2.limits);
+    limit_build_query(&mut pipe, &p2.limits, mode);
     let res: Result<Vec<Option<i64>>, _> = pipe.query_async(&mut redis).await;
     let mut lst = match res {
         Ok(l) => l.into_iter(),
@@ -289,7 +329,7 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
         }
     };
 
-    let limit_results_err = limit_resolve_query(logs, &mut redis, &mut lst, p2.limits).await;
+    let limit_results_err = limit_resolve_query(logs, &mut redis, &mut lst, p2.limits, mode).await;
     let limit_results = eat_errors(logs, limit_results_err);
     logs.debug("query - limit checks done");
 
@@ -313,12 +353,17 @@ pub fn analyze_finish<GH: Grasshopper>(
 
     let is_human = info.is_human;
     let reqinfo = info.reqinfo;
-    let secpol = &reqinfo.rinfo.secpolicy;
-
-    let (limit_check, stats) = limit_process(p3.flows, 0, &p3.limits, &mut tags);
 
-    if let SimpleDecision::Action(action, curbrs) = limit_check {
-        let limit_decision = action.to_decision(is_human, mgh, &reqinfo, &mut tags, curbrs);
+    let (limit_decision, stats) = limit_stage(
+        mgh,
+        is_human,
+        &reqinfo,
+        &mut tags,
+        p3.flows,
+        &cumulated_decision.reasons,
+        &p3.limits,
+    );
+    if let Some(limit_decision) = limit_decision {
         cumulated_decision = merge_decisions(cumulated_decision, limit_decision);
         if cumulated_decision.is_final() {
             return AnalyzeResult {
@@ -331,108 +376,170 @@ pub fn analyze_finish<GH: Grasshopper>(
     }
     logs.debug("limit checks done");
 
-    let acl_result = check_acl(&tags, &secpol.acl_profile);
-    logs.debug(|| format!("ACL result: {}", acl_result));
+    let (decision, acl_final, stats) = acl_stage(logs, mgh, is_human, &reqinfo, &mut tags, stats, cumulated_decision);
+    cumulated_decision = decision;
+    if acl_final {
+        return AnalyzeResult {
+            decision: cumulated_decision,
+            tags,
+            rinfo: masking(reqinfo),
+            stats: stats.acl_stage_build(),
+        };
+    }
 
-    let acl_decision = acl_result.decision(is_human);
-    let stats = stats.acl(if acl_decision.is_some() { 1 } else { 0 });
-    if let Some(decision) = acl_decision {
-        let bypass = decision.stage == AclStage::Bypass;
-        let mut br = BlockReason::acl(
-            reqinfo.rinfo.secpolicy.acl_profile.id.clone(),
-            decision.tags,
-            decision.stage,
-        );
-        if !secpol.acl_active {
-            br.decision.inactive();
-        }
-        let blocking = br.decision == BDecision::Blocking;
+    // otherwise, run content_filter_check
+    let (content_filter_result, stats) = run_content_filter(logs, stats, &mut tags, &reqinfo, cfrules);
+    logs.debug("Content Filter checks done");
 
-        let acl_decision = Decision::pass(vec![br]);
-        cumulated_decision = merge_decisions(cumulated_decision, acl_decision);
+    let content_filter_decision = content_filter_decision(
+        mgh,
+        is_human,
+        &reqinfo,
+        &mut tags,
+        &cumulated_decision.reasons,
+        content_filter_result,
+    );
 
-        // insert the extra tags
-        if !secpol.acl_profile.tags.is_empty() {
-            let locs = cumulated_decision
-                .reasons
-                .iter()
-                .flat_map(|r| std::iter::once(&r.location).chain(r.extra_locations.iter()))
-                .cloned()
-                .collect::<HashSet<_>>();
-            for t in &secpol.acl_profile.tags {
-                tags.insert_locs(t, locs.clone());
-            }
-        }
+    cumulated_decision = merge_decisions(cumulated_decision, content_filter_decision);
+    AnalyzeResult {
+        decision: cumulated_decision,
+        tags,
+        rinfo: masking(reqinfo),
+        stats: stats.cf_stage_build(),
+    }
+}
 
-        if secpol.acl_active && bypass {
-            return AnalyzeResult {
-                decision: cumulated_decision,
-                tags,
-                rinfo: masking(reqinfo),
-                stats: stats.acl_stage_build(),
-            };
+/// the decision of the rate limits, None when no limit was reached
+fn limit_stage<GH: Grasshopper>(
+    mgh: Option<&GH>,
+    is_human: bool,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    stats: StatsCollect<BStageFlow>,
+    previous: &[BlockReason],
+    results: &[LimitResult],
+) -> (Option<Decision>, StatsCollect<BStageLimit>) {
+    let (limit_check, stats) = limit_process(stats, 0, results, tags);
+    let decision = match limit_check {
+        SimpleDecision::Pass => None,
+        SimpleDecision::Action(action, curbrs) => {
+            Some(action.to_decision_after(is_human, mgh, reqinfo, tags, previous, curbrs))
         }
+    };
+    (decision, stats)
+}
 
-        let acl_block = |tags: &mut Tags| {
-            secpol
-                .acl_profile
-                .action
-                .to_decision(is_human, mgh, &reqinfo, tags, Vec::new())
-        };
-
-        // Send challenge, even if the acl is inactive in sec_pol.
-        if decision.challenge {
-            let decision = match (reqinfo.headers.get("user-agent"), mgh) {
-                (Some(ua), Some(gh)) => challenge_phase01(gh, ua, Vec::new()),
-                (gua, ggh) => {
-                    logs.debug(|| {
-                        format!(
-                            "ACL challenge detected: can't challenge, ua={} gh={}",
-                            gua.is_some(),
-                            ggh.is_some()
-                        )
-                    });
-                    acl_block(&mut tags)
-                }
-            };
+/// runs the acl checks, merging their decision with the cumulated one
+///
+/// The returned flag is set when the analysis stops at this stage.
+fn acl_stage<GH: Grasshopper>(
+    logs: &mut Logs,
+    mgh: Option<&GH>,
+    is_human: bool,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    stats: StatsCollect<BStageLimit>,
+    mut cumulated_decision: Decision,
+) -> (Decision, bool, StatsCollect<BStageAcl>) {
+    let secpol = &reqinfo.rinfo.secpolicy;
+    let acl_result = check_acl(tags, &secpol.acl_profile);
+    logs.debug(|| format!("ACL result: {}", acl_result));
 
-            cumulated_decision = merge_decisions(cumulated_decision, decision);
-            return AnalyzeResult {
-                decision: cumulated_decision,
-                tags,
-                rinfo: masking(reqinfo),
-                stats: stats.acl_stage_build(),
-            };
+    let acl_decision = acl_result.decision(is_human);
+    let stats = stats.acl(if acl_decision.is_some() { 1 } else { 0 });
+    let decision = match acl_decision {
+        None => return (cumulated_decision, false, stats),
+        Some(d) => d,
+    };
+    let bypass = decision.stage == AclStage::Bypass;
+    let mut br = BlockReason::acl(secpol.acl_profile.id.clone(), decision.tags, decision.stage);
+    if !secpol.acl_active {
+        br.decision.inactive();
+    }
+    let blocking = br.decision == BDecision::Blocking;
+
+    let acl_decision = Decision::pass(vec![br]);
+    cumulated_decision = merge_decisions(cumulated_decision, acl_decision);
+
+    // insert the extra tags
+    if !secpol.acl_profile.tags.is_empty() {
+        let locs = cumulated_decision
+            .reasons
+            .iter()
+            .flat_map(|r| std::iter::once(&r.location).chain(r.extra_locations.iter()))
+            .cloned()
+            .collect::<HashSet<_>>();
+        for t in &secpol.acl_profile.tags {
+            tags.insert_locs(t, locs.clone());
         }
+    }
 
-        if blocking {
-            let decision = acl_block(&mut tags);
-            cumulated_decision = merge_decisions(cumulated_decision, decision);
-            return AnalyzeResult {
-                decision: cumulated_decision,
-                tags,
-                rinfo: masking(reqinfo),
-                stats: stats.acl_stage_build(),
-            };
-        }
+    if secpol.acl_active && bypass {
+        return (cumulated_decision, true, stats);
+    }
+
+    let acl_block = |tags: &mut Tags, previous: &[BlockReason]| {
+        secpol
+            .acl_profile
+            .action
+            .to_decision_after(is_human, mgh, reqinfo, tags, previous, Vec::new())
     };
 
-    let mut cfcheck =
-        |stats, mrls| content_filter_check(logs, stats, &mut tags, &reqinfo, &secpol.content_filter_profile, mrls);
-    // otherwise, run content_filter_check
-    let (content_filter_result, stats) = match cfrules {
+    // Send challenge, even if the acl is inactive in sec_pol.
+    if decision.challenge {
+        let decision = match (reqinfo.headers.get("user-agent"), mgh) {
+            (Some(ua), Some(gh)) => challenge_phase01(gh, ua, Vec::new()),
+            (gua, ggh) => {
+                logs.debug(|| {
+                    format!(
+                        "ACL challenge detected: can't challenge, ua={} gh={}",
+                        gua.is_some(),
+                        ggh.is_some()
+                    )
+                });
+                acl_block(tags, &cumulated_decision.reasons)
+            }
+        };
+        return (merge_decisions(cumulated_decision, decision), true, stats);
+    }
+
+    if blocking {
+        let decision = acl_block(tags, &cumulated_decision.reasons);
+        return (merge_decisions(cumulated_decision, decision), true, stats);
+    }
+    (cumulated_decision, false, stats)
+}
+
+fn run_content_filter(
+    logs: &mut Logs,
+    stats: StatsCollect<BStageAcl>,
+    tags: &mut Tags,
+    reqinfo: &RequestInfo,
+    cfrules: CfRulesArg<'_>,
+) -> (Result<(), CfBlock>, StatsCollect<BStageContentFilter>) {
+    let profile = &reqinfo.rinfo.secpolicy.content_filter_profile;
+    match cfrules {
         CfRulesArg::Global => match HSDB.read() {
-            Ok(rd) => cfcheck(stats, rd.get(&secpol.content_filter_profile.id)),
+            Ok(rd) => content_filter_check(logs, stats, tags, reqinfo, profile, rd.get(&profile.id)),
             Err(rr) => {
                 logs.error(|| format!("Could not get lock on HSDB: {}", rr));
                 (Ok(()), stats.no_content_filter())
             }
         },
-        CfRulesArg::Get(r) => cfcheck(stats, r),
-    };
-    logs.debug("Content Filter checks done");
+        CfRulesArg::Get(r) => content_filter_check(logs, stats, tags, reqinfo, profile, r),
+    }
+}
 
-    let content_filter_decision = match content_filter_result {
+fn content_filter_decision<GH: Grasshopper>(
+    mgh: Option<&GH>,
+    is_human: bool,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    previous: &[BlockReason],
+    content_filter_result: Result<(), CfBlock>,
+) -> Decision {
+    let secpol = &reqinfo.rinfo.secpolicy;
+    match content_filter_result {
         Ok(()) => Decision::pass(Vec::new()),
         Err(cfblock) => {
             // insert extra tags
@@ -461,7 +568,7 @@ pub fn analyze_finish<GH: Grasshopper>(
                 let mut dec = secpol
                     .content_filter_profile
                     .action
-                    .to_decision(is_human, mgh, &reqinfo, &mut tags, br);
+                    .to_decision_after(is_human, mgh, reqinfo, tags, previous, br);
                 if let Some(mut action) = dec.maction.as_mut() {
                     action.block_mode &= secpol.content_filter_active;
                 }
@@ -470,15 +577,66 @@ pub fn analyze_finish<GH: Grasshopper>(
                 Decision::pass(br)
             }
         }
+    }
+}
+
+/// the decision of each stage, computed independently and without merging
+///
+/// Unlike `analyze`, a final decision does not stop the evaluation, so that the contribution of every stage can be
+/// inspected. This is meant for debugging, so the flow and rate limit states are only read, and not updated.
+pub async fn simulate<GH: Grasshopper>(
+    logs: &mut Logs,
+    mgh: Option<&GH>,
+    p0: APhase0,
+    cfrules: CfRulesArg<'_>,
+) -> (Vec<(&'static str, Decision)>, Tags) {
+    let mut out = Vec::new();
+    let mut tags = p0.itags;
+    let reqinfo = p0.reqinfo;
+    let is_human = p0.is_human;
+    let pass = || Decision::pass(Vec::new());
+
+    policy_tags(&mut tags, &reqinfo.rinfo.secpolicy);
+    let init_decision = init_decision(mgh, is_human, &reqinfo, &mut tags);
+    out.push(("init", init_decision.unwrap_or_else(pass)));
+
+    let globalfilter_decision = match p0.globalfilter_dec {
+        SimpleDecision::Pass => pass(),
+        SimpleDecision::Action(action, reasons) => action.to_decision(is_human, mgh, &reqinfo, &mut tags, reasons),
     };
+    out.push(("global_filters", globalfilter_decision));
 
-    cumulated_decision = merge_decisions(cumulated_decision, content_filter_decision);
-    AnalyzeResult {
-        decision: cumulated_decision,
+    let flow_checks = if reqinfo.rinfo.secpolicy.flow_active {
+        flow_info(logs, &p0.flows, &reqinfo, &tags)
+    } else {
+        Vec::new()
+    };
+    let info = AnalysisInfo {
+        is_human,
+        p0_decision: pass(),
+        reqinfo,
+        stats: p0.stats,
         tags,
-        rinfo: masking(reqinfo),
-        stats: stats.cf_stage_build(),
-    }
+    };
+    let p2 = analyze_query_flows(logs, APhase1::new(flow_checks, (), info), QueryMode::ReadOnly).await;
+    let p2 = analyze_flows(logs, p2);
+    let p3 = analyze_query_limits(logs, p2, QueryMode::ReadOnly).await;
+    let mut tags = p3.info.tags;
+    let reqinfo = p3.info.reqinfo;
+
+    let (limit_decision, stats) = limit_stage(mgh, is_human, &reqinfo, &mut tags, p3.flows, &[], &p3.limits);
+    out.push(("rate_limit", limit_decision.unwrap_or_else(pass)));
+
+    let (acl_decision, _, stats) = acl_stage(logs, mgh, is_human, &reqinfo, &mut tags, stats, pass());
+    out.push(("acl", acl_decision));
+
+    let (content_filter_result, _) = run_content_filter(logs, stats, &mut tags, &reqinfo, cfrules);
+    out.push((
+        "content_filter",
+        content_filter_decision(mgh, is_human, &reqinfo, &mut tags, &[], content_filter_result),
+    ));
+
+    (out, tags)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -488,14 +646,306 @@ pub async fn analyze<GH: Grasshopper>(
     p0: APhase0,
     cfrules: CfRulesArg<'_>,
 ) -> AnalyzeResult {
+    // the decision cache is only checked when enabled
+    let cache_key = DECISION_CACHE.as_ref().and_then(|_| decision_cache_key(&p0));
+    if let (Some(cache), Some(key)) = (DECISION_CACHE.as_ref(), cache_key.as_ref()) {
+        let cached = match cache.lock() {
+            Ok(mut c) => c.get(key),
+            Err(rr) => {
+                logs.error(|| format!("Could not get lock on the decision cache: {}", rr));
+                None
+            }
+        };
+        if let Some((decision, mut tags)) = cached {
+            logs.debug("decision cache hit");
+            tags.extend(p0.itags);
+            let result = learning_mode(AnalyzeResult {
+                decision,
+                tags,
+                rinfo: masking(p0.reqinfo),
+                stats: p0.stats.mapped_stage_build(),
+            });
+            return kill_switch(logs, result, KILL_SWITCH_TAG.as_deref());
+        }
+    }
+
     let init_result = analyze_init(logs, mgh, p0);
-    match init_result {
+    let result = match init_result {
         InitResult::Res(result) => result,
         InitResult::Phase1(p1) => {
-            let p2i = analyze_query_flows(logs, p1).await;
+            let p2i = analyze_query_flows(logs, p1, QueryMode::Update).await;
             let p2o = analyze_flows(logs, p2i);
-            let p3 = analyze_query_limits(logs, p2o).await;
+            let p3 = analyze_query_limits(logs, p2o, QueryMode::Update).await;
             analyze_finish(logs, mgh, cfrules, p3)
         }
+    };
+
+    if let (Some(cache), Some(key)) = (DECISION_CACHE.as_ref(), cache_key) {
+        if let Ok(mut c) = cache.lock() {
+            c.insert(key, &result.decision, &result.tags);
+        }
+    }
+    kill_switch(logs, learning_mode(result), KILL_SWITCH_TAG.as_deref())
+}
+
+/// downgrades a blocking decision to a monitoring one when the request carries the kill switch tag
+pub fn kill_switch(logs: &mut Logs, mut result: AnalyzeResult, tag: Option<&str>) -> AnalyzeResult {
+    let tag = match tag {
+        Some(t) if result.decision.is_blocking() && result.tags.contains(t) => t,
+        _ => return result,
+    };
+    logs.warning(|| {
+        format!(
+            "kill switch {} is set, the blocking decision is downgraded to monitor",
+            tag
+        )
+    });
+    result.decision = result.decision.monitor_only();
+    result.tags.insert("kill-switch-override", Location::Request);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::{ContentType, OversizedBodyPolicy};
+    use crate::config::virtualtags::VirtualTags;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::interface::stats::SecpolStats;
+    use crate::interface::{ActionType, Initiator, SimpleAction};
+    use crate::utils::RequestBuilder;
+    use std::sync::Arc;
+
+    fn p0(secpol: SecurityPolicy, globalfilter_dec: SimpleDecision) -> APhase0 {
+        let reqinfo = RequestBuilder::new("GET", "/")
+            .ip("1.2.3.4")
+            .authority("main.site")
+            .security_policy(Arc::new(secpol))
+            .build();
+        request_p0(reqinfo, globalfilter_dec)
+    }
+
+    fn request_p0(reqinfo: RequestInfo, globalfilter_dec: SimpleDecision) -> APhase0 {
+        let stats = StatsCollect::new(std::time::Instant::now(), "test".to_string())
+            .secpol(SecpolStats::build(&reqinfo.rinfo.secpolicy, 1))
+            .mapped(1, 1);
+        let mut itags = Tags::new(&VirtualTags::default());
+        itags.insert("all", Location::Request);
+        itags.insert("denied", Location::Request);
+        APhase0 {
+            flows: FlowMap::new(),
+            globalfilter_dec,
+            is_human: false,
+            itags,
+            reqinfo,
+            stats,
+        }
+    }
+
+    fn stage<'t>(stages: &'t [(&'static str, Decision)], name: &str) -> &'t Decision {
+        &stages.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn simulate_stages() {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.acl_active = true;
+        secpol.acl_profile.force_deny.insert("denied".to_string());
+        let gf_reason = BlockReason::global_filter(
+            "gf".to_string(),
+            "gf".to_string(),
+            BDecision::Blocking,
+            &std::iter::once(Location::Request).collect(),
+        );
+        let p0 = p0(secpol, SimpleDecision::Action(SimpleAction::default(), vec![gf_reason]));
+        let mut logs = Logs::default();
+        let (stages, _) = async_std::task::block_on(simulate(
+            &mut logs,
+            None::<&DummyGrasshopper>,
+            p0,
+            CfRulesArg::Get(None),
+        ));
+        let names: Vec<&str> = stages.iter().map(|(n, _)| *n).collect();
+        assert_eq!(
+            names,
+            vec!["init", "global_filters", "rate_limit", "acl", "content_filter"]
+        );
+
+        // both the global filter and the acl block, despite the global filter decision being final
+        let gf = stage(&stages, "global_filters");
+        assert!(gf.is_blocking());
+        assert!(matches!(gf.reasons[0].initiator, Initiator::GlobalFilter { .. }));
+        let acl = stage(&stages, "acl");
+        assert!(acl.is_blocking());
+        assert!(matches!(
+            acl.reasons[0].initiator,
+            Initiator::Acl {
+                stage: AclStage::EnforceDeny,
+                ..
+            }
+        ));
+        assert!(!stage(&stages, "rate_limit").is_blocking());
+        assert!(stage(&stages, "rate_limit").reasons.is_empty());
+        assert!(!stage(&stages, "content_filter").is_blocking());
+    }
+
+    #[test]
+    fn simulate_malformed_body() {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.content_filter_profile.content_type = vec![ContentType::Json];
+        let reqinfo = RequestBuilder::new("POST", "/")
+            .header("content-type", "application/json")
+            .body(br#"{"a": "#)
+            .security_policy(Arc::new(secpol))
+            .build();
+        let (stages, _) = async_std::task::block_on(simulate(
+            &mut Logs::default(),
+            None::<&DummyGrasshopper>,
+            request_p0(reqinfo, SimpleDecision::Pass),
+            CfRulesArg::Get(None),
+        ));
+        let init = stage(&stages, "init");
+        assert!(init.is_blocking());
+        assert!(matches!(
+            init.reasons[0].initiator,
+            Initiator::Restriction {
+                tpe: "malformed body",
+                ..
+            }
+        ));
+        // the other stages still run
+        assert_eq!(stages.len(), 5);
+        assert!(!stage(&stages, "global_filters").is_blocking());
+    }
+
+    #[test]
+    fn tags_only() {
+        let names = |tags: &Tags| {
+            let mut names: Vec<String> = tags.as_hash_ref().keys().cloned().collect();
+            names.sort();
+            names
+        };
+        let mut logs = Logs::default();
+        let full = async_std::task::block_on(analyze(
+            &mut logs,
+            None::<&DummyGrasshopper>,
+            p0(SecurityPolicy::empty(), SimpleDecision::Pass),
+            CfRulesArg::Get(None),
+        ));
+        let light = analyze_tags(p0(SecurityPolicy::empty(), SimpleDecision::Pass));
+        assert_eq!(names(&light), names(&full.tags));
+        assert!(light.contains("all"));
+        assert!(light
+            .as_hash_ref()
+            .keys()
+            .any(|t| t.starts_with("securitypolicy-entry:")));
+    }
+
+    #[test]
+    fn learning_mode_never_blocks() {
+        let gf_reason = || {
+            BlockReason::global_filter(
+                "gf".to_string(),
+                "gf".to_string(),
+                BDecision::Blocking,
+                &std::iter::once(Location::Request).collect(),
+            )
+        };
+        let run = |learning: bool| {
+            let mut secpol = SecurityPolicy::empty();
+            secpol.learning_mode = learning;
+            let p0 = p0(
+                secpol,
+                SimpleDecision::Action(SimpleAction::default(), vec![gf_reason()]),
+            );
+            let mut logs = Logs::default();
+            async_std::task::block_on(analyze(&mut logs, None::<&DummyGrasshopper>, p0, CfRulesArg::Get(None)))
+        };
+
+        let enforced = run(false);
+        assert!(enforced.decision.is_blocking());
+        assert!(enforced.rinfo.observations.is_none());
+        assert!(!enforced.tags.contains("learning-mode"));
+
+        let learning = run(true);
+        assert!(!learning.decision.is_blocking());
+        // downgraded as with the kill switch
+        assert_eq!(learning.decision.maction.as_ref().unwrap().atype, ActionType::Monitor);
+        assert_eq!(learning.decision.reasons.len(), 1);
+        assert_eq!(learning.decision.reasons[0].decision, BDecision::Monitor);
+        assert!(learning.tags.contains("learning-mode"));
+        let observations = learning.rinfo.observations.as_ref().unwrap();
+        assert_eq!(observations.endpoint, "/");
+        assert_eq!(observations.method, "GET");
+    }
+
+    #[test]
+    fn kill_switch_downgrades() {
+        let gf_reason = BlockReason::global_filter(
+            "gf".to_string(),
+            "gf".to_string(),
+            BDecision::Blocking,
+            &std::iter::once(Location::Request).collect(),
+        );
+        let p0 = p0(
+            SecurityPolicy::empty(),
+            SimpleDecision::Action(SimpleAction::default(), vec![gf_reason]),
+        );
+        let mut logs = Logs::default();
+        let result =
+            async_std::task::block_on(analyze(&mut logs, None::<&DummyGrasshopper>, p0, CfRulesArg::Get(None)));
+        assert!(result.decision.is_blocking());
+
+        // the tag is not set on this request
+        let result = kill_switch(&mut logs, result, Some("incident"));
+        assert!(result.decision.is_blocking());
+
+        let mut result = result;
+        result.tags.insert("incident", Location::Request);
+        let result = kill_switch(&mut logs, result, Some("incident"));
+        assert!(!result.decision.is_blocking());
+        let action = result.decision.maction.as_ref().unwrap();
+        assert_eq!(action.atype, ActionType::Monitor);
+        assert!(!action.block_mode);
+        assert_eq!(result.decision.reasons[0].decision, BDecision::Monitor);
+        assert!(result.tags.contains("kill-switch-override"));
+    }
+
+    #[test]
+    fn truncated_body_not_malformed() {
+        let request = |policy: OversizedBodyPolicy, body: &[u8]| {
+            let mut secpol = SecurityPolicy::empty();
+            secpol.content_filter_profile.content_type = vec![ContentType::Json];
+            secpol.content_filter_profile.max_body_size = 12;
+            secpol.content_filter_profile.oversized_body = policy;
+            let reqinfo = RequestBuilder::new("POST", "/")
+                .header("content-type", "application/json")
+                .body(body)
+                .security_policy(Arc::new(secpol))
+                .build();
+            analyze_init(
+                &mut Logs::default(),
+                None::<&DummyGrasshopper>,
+                request_p0(reqinfo, SimpleDecision::Pass),
+            )
+        };
+
+        // the truncated body can't be decoded, but it is not malformed
+        let truncated = request(OversizedBodyPolicy::InspectTruncated, br#"{"a": "1", "b": "2"}"#);
+        assert!(matches!(truncated, InitResult::Phase1(_)));
+
+        match request(OversizedBodyPolicy::InspectTruncated, br#"{"a": "1""#) {
+            InitResult::Phase1(_) => panic!("a malformed body should have been blocked"),
+            InitResult::Res(result) => {
+                assert!(result.decision.is_blocking());
+                assert!(matches!(
+                    result.decision.reasons[0].initiator,
+                    Initiator::Restriction {
+                        tpe: "malformed body",
+                        ..
+                    }
+                ));
+            }
+        }
     }
 }
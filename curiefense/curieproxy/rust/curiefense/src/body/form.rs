@@ -0,0 +1,151 @@
+use crate::config::contentfilter::Transformation;
+use crate::interface::Location;
+use crate::requestfields::RequestField;
+
+/// the fields of a multipart/form-data body
+#[derive(Debug, Clone)]
+pub struct FormFields {
+    /// text values of the fields, only for small and valid utf8 parts
+    pub values: RequestField,
+    /// names of the uploaded files, by field name
+    pub filenames: RequestField,
+    /// total amount of parts in the body
+    pub parts: usize,
+    /// parts whose value was not kept, because they were too large, not utf8, or over the field limit
+    pub skipped: usize,
+    max_fields: usize,
+    max_value_size: usize,
+}
+
+/// bounds of the recorded multipart/form-data fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormLimits {
+    /// maximum amount of recorded fields
+    pub max_fields: usize,
+    /// larger values are skipped
+    pub max_value_size: usize,
+}
+
+impl Default for FormLimits {
+    fn default() -> Self {
+        FormLimits {
+            max_fields: 64,
+            max_value_size: 4096,
+        }
+    }
+}
+
+/// extracts the boundary from a multipart/form-data content type
+pub fn multipart_boundary(content_type: &str) -> Option<&str> {
+    content_type.strip_prefix("multipart/form-data; boundary=")
+}
+
+impl FormFields {
+    /// an empty form, filled while the body is parsed
+    ///
+    /// At most `max_fields` fields are recorded, and only values up to `max_value_size` bytes are kept. The other
+    /// parts are counted, but not stored.
+    pub fn new(dec: &[Transformation], max_fields: usize, max_value_size: usize) -> Self {
+        FormFields {
+            values: RequestField::new(dec),
+            filenames: RequestField::new(dec),
+            parts: 0,
+            skipped: 0,
+            max_fields,
+            max_value_size,
+        }
+    }
+
+    /// records a part of the body, as it is parsed
+    pub fn add_part(&mut self, name: &str, filename: Option<&str>, content: &[u8]) {
+        self.parts += 1;
+        if self.parts > self.max_fields {
+            self.skipped += 1;
+            return;
+        }
+        if let Some(filename) = filename {
+            self.filenames.add(
+                name.to_string(),
+                Location::BodyArgument(name.to_string()),
+                filename.to_string(),
+            );
+        }
+        match std::str::from_utf8(content) {
+            Ok(value) if content.len() <= self.max_value_size => self.values.add(
+                name.to_string(),
+                Location::BodyArgumentValue(name.to_string(), value.to_string()),
+                value.to_string(),
+            ),
+            _ => self.skipped += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::multipart_form_encoded;
+
+    const BOUNDARY: &str = "------------------------28137e3917e320b3";
+
+    fn body(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, filename, content) in parts {
+            out.extend_from_slice(b"--------------------------28137e3917e320b3\r\n");
+            let disposition = match filename {
+                None => format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name),
+                Some(f) => format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    name, f
+                ),
+            };
+            out.extend_from_slice(disposition.as_bytes());
+            out.extend_from_slice(content);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"--------------------------28137e3917e320b3--\r\n");
+        out
+    }
+
+    fn form_fields(body: &[u8], max_fields: usize, max_value_size: usize) -> FormFields {
+        let mut args = RequestField::new(&[]);
+        let mut form = FormFields::new(&[], max_fields, max_value_size);
+        multipart_form_encoded(BOUNDARY, &mut args, &mut form, body).unwrap();
+        form
+    }
+
+    #[test]
+    fn simple_form() {
+        let body = body(&[
+            ("user", None, b"admin"),
+            ("avatar", Some("me.png"), &[0x89, 0x50, 0x4e, 0x47, 0xff, 0xfe]),
+            ("notes", Some("notes.txt"), b"hello"),
+        ]);
+        let fields = form_fields(&body, 16, 64);
+        assert_eq!(fields.parts, 3);
+        assert_eq!(fields.skipped, 1);
+        assert_eq!(fields.values.get_str("user"), Some("admin"));
+        assert_eq!(fields.values.get_str("notes"), Some("hello"));
+        assert_eq!(fields.values.get_str("avatar"), None);
+        assert_eq!(fields.filenames.get_str("avatar"), Some("me.png"));
+        assert_eq!(fields.filenames.get_str("user"), None);
+    }
+
+    #[test]
+    fn limits() {
+        let large = vec![b'a'; 100];
+        let body = body(&[
+            ("a", None, b"1"),
+            ("large", None, &large),
+            ("b", None, b"2"),
+            ("c", None, b"3"),
+        ]);
+        let fields = form_fields(&body, 3, 64);
+        assert_eq!(fields.parts, 4);
+        assert_eq!(fields.skipped, 2);
+        assert_eq!(fields.values.get_str("a"), Some("1"));
+        assert_eq!(fields.values.get_str("large"), None);
+        assert_eq!(fields.values.get_str("b"), Some("2"));
+        assert_eq!(fields.values.get_str("c"), None);
+    }
+}
@@ -14,12 +14,14 @@ use serde_json::Value;
 use std::io::Read;
 use xmlparser::{ElementEnd, EntityDefinition, ExternalId, Token};
 
+use crate::body::form::{FormFields, FormLimits};
 use crate::config::raw::ContentType;
 use crate::interface::{Action, ActionType, BlockReason, Location};
 use crate::logs::Logs;
 use crate::requestfields::RequestField;
 use crate::utils::decoders::parse_urlencoded_params_bytes;
 
+pub mod form;
 mod graphql;
 
 fn json_path(prefix: &[String]) -> String {
@@ -256,16 +258,22 @@ fn forms_body(args: &mut RequestField, body: &[u8]) -> Result<(), String> {
     }
 }
 
-/// reuses the multipart crate to parse these bodies
+/// reuses the multipart crate to parse these bodies, the fields are recorded in form at the same time
 ///
 /// will not work properly with binary data
-fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8]) -> Result<(), String> {
+fn multipart_form_encoded(
+    boundary: &str,
+    args: &mut RequestField,
+    form: &mut FormFields,
+    body: &[u8],
+) -> Result<(), String> {
     let mut multipart = Multipart::with_body(body, boundary);
     multipart
         .foreach_entry(|mut entry| {
             let mut content = Vec::new();
             let _ = entry.data.read_to_end(&mut content);
             let name = entry.headers.name.to_string();
+            form.add_part(&name, entry.headers.filename.as_deref(), &content);
             let scontent = String::from_utf8_lossy(&content);
             args.add(name, Location::Body, scontent.to_string());
         })
@@ -273,18 +281,21 @@ fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8])
 }
 
 /// body parsing function, returns an error when the body can't be decoded
+///
+/// The fields of multipart/form-data bodies are returned as well.
 pub fn parse_body(
     logs: &mut Logs,
     args: &mut RequestField,
     max_depth: usize,
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
+    form_limits: FormLimits,
     body: &[u8],
-) -> Result<(), String> {
+) -> Result<Option<FormFields>, String> {
     logs.debug("body parsing started");
     if max_depth == 0 {
         logs.warning("max_depth is 0, body parsing avoided");
-        return Ok(());
+        return Ok(None);
     }
 
     let active_accepted_types = if accepted_types.is_empty() {
@@ -298,27 +309,29 @@ pub fn parse_body(
             match t {
                 ContentType::Graphql => {
                     if content_type == "application/graphql" {
-                        return graphql::graphql_body(max_depth, args, body);
+                        return graphql::graphql_body(max_depth, args, body).map(|_| None);
                     }
                 }
                 ContentType::Json => {
                     if content_type.ends_with("/json") {
-                        return json_body(max_depth, args, body);
+                        return json_body(max_depth, args, body).map(|_| None);
                     }
                 }
                 ContentType::MultipartForm => {
-                    if let Some(boundary) = content_type.strip_prefix("multipart/form-data; boundary=") {
-                        return multipart_form_encoded(boundary, args, body);
+                    if let Some(boundary) = form::multipart_boundary(content_type) {
+                        let mut form =
+                            FormFields::new(&args.decoding, form_limits.max_fields, form_limits.max_value_size);
+                        return multipart_form_encoded(boundary, args, &mut form, body).map(|_| Some(form));
                     }
                 }
                 ContentType::Xml => {
                     if content_type.ends_with("/xml") {
-                        return xml_body(max_depth, args, body);
+                        return xml_body(max_depth, args, body).map(|_| None);
                     }
                 }
                 ContentType::UrlEncoded => {
                     if content_type == "application/x-www-form-urlencoded" {
-                        return forms_body(args, body);
+                        return forms_body(args, body).map(|_| None);
                     }
                 }
             }
@@ -330,7 +343,9 @@ pub fn parse_body(
     // content-type not found
     if accepted_types.is_empty() {
         // we had no particular expection, so blindly try json, and urlencoded
-        json_body(max_depth, args, body).or_else(|_| forms_body(args, body))
+        json_body(max_depth, args, body)
+            .or_else(|_| forms_body(args, body))
+            .map(|_| None)
     } else {
         // we expected a specific content type!
         Err(format!(
@@ -347,6 +362,11 @@ pub fn body_too_deep(id: String, expected: usize, actual: usize) -> (Action, Blo
             block_mode: true,
             status: 403,
             headers: None,
+            multi_headers: None,
+            challenge: None,
+            mirror: None,
+            route: None,
+            response_headers: None,
             content: "Access denied".to_string(),
             extra_tags: None,
         },
@@ -361,6 +381,11 @@ pub fn body_too_large(id: String, expected: usize, actual: usize) -> (Action, Bl
             block_mode: true,
             status: 403,
             headers: None,
+            multi_headers: None,
+            challenge: None,
+            mirror: None,
+            route: None,
+            response_headers: None,
             content: "Access denied".to_string(),
             extra_tags: None,
         },
@@ -383,7 +408,16 @@ mod tests {
     ) -> RequestField {
         let mut logs = Logs::default();
         let mut args = RequestField::new(dec);
-        parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, body).unwrap();
+        parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            mcontent_type,
+            accepted_types,
+            FormLimits::default(),
+            body,
+        )
+        .unwrap();
         for lg in logs.logs {
             if lg.level > LogLevel::Debug {
                 panic!("unexpected log: {:?}", lg);
@@ -395,7 +429,16 @@ mod tests {
     fn test_parse_bad(mcontent_type: Option<&str>, accepted_types: &[ContentType], body: &[u8], max_depth: usize) {
         let mut logs = Logs::default();
         let mut args = RequestField::new(&[]);
-        assert!(parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, body).is_err());
+        assert!(parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            mcontent_type,
+            accepted_types,
+            FormLimits::default(),
+            body
+        )
+        .is_err());
     }
 
     fn test_parse_dec(
@@ -496,6 +539,7 @@ mod tests {
             500,
             Some("application/json"),
             &[],
+            FormLimits::default(),
             br#"{"a": "body_arg"}"#,
         )
         .unwrap();
@@ -884,6 +928,7 @@ mod tests {
             0,
             Some("application/x-www-form-urlencoded"),
             &[],
+            FormLimits::default(),
             b"a=1&b=2&c=3",
         )
         .unwrap();
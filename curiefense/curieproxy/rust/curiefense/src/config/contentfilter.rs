@@ -1,6 +1,8 @@
+use crate::body::form::FormLimits;
 use crate::config::matchers::Matching;
 use crate::config::raw::{
-    ContentFilterRule, ContentType, RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterProperties,
+    ContentFilterRule, ContentType, HeaderLogPolicy, OversizedBodyPolicy, RawContentFilterEntryMatch,
+    RawContentFilterProfile, RawContentFilterProperties,
 };
 use crate::interface::{RawTags, SimpleAction};
 use crate::logs::Logs;
@@ -35,10 +37,24 @@ pub struct ContentFilterProfile {
     pub content_type: Vec<ContentType>,
     pub ignore_body: bool,
     pub max_body_size: usize,
+    pub oversized_body: OversizedBodyPolicy,
     pub max_body_depth: usize,
+    pub max_uri_length: usize,
+    pub block_long_uri: bool,
     pub referer_as_uri: bool,
+    /// maximum amount of arguments, headers and cookies that are parsed, unlimited when not set
+    pub max_parsed_args: Option<usize>,
+    pub max_parsed_headers: Option<usize>,
+    pub max_parsed_cookies: Option<usize>,
+    pub form_limits: FormLimits,
+    /// joins the values of the headers whose names only differ by case
+    pub header_join_separator: String,
     pub action: SimpleAction,
     pub tags: HashSet<String>,
+    /// header name (lowercase) to log policy, headers that are not listed are logged verbatim
+    pub header_log_policy: HashMap<String, HeaderLogPolicy>,
+    /// include the text matched by the rules in the block reasons
+    pub log_matched: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -95,8 +111,18 @@ impl ContentFilterProfile {
             content_type: Vec::new(),
             ignore_body: false,
             max_body_size: usize::MAX,
+            oversized_body: OversizedBodyPolicy::BlockUnverified,
             max_body_depth: usize::MAX,
+            max_uri_length: usize::MAX,
+            block_long_uri: false,
             referer_as_uri: false,
+            max_parsed_args: None,
+            max_parsed_headers: None,
+            max_parsed_cookies: None,
+            form_limits: FormLimits::default(),
+            header_join_separator: " ".to_string(),
+            header_log_policy: HashMap::new(),
+            log_matched: false,
             action: SimpleAction::default(),
             tags: HashSet::new(),
         }
@@ -288,6 +314,8 @@ fn convert_entry(
     }
     let max_body_size = nonzero(entry.max_body_size.unwrap_or(usize::MAX));
     let max_body_depth = nonzero(entry.max_body_depth.unwrap_or(usize::MAX));
+    let max_uri_length = nonzero(entry.max_uri_length.unwrap_or(usize::MAX));
+    let default_form_limits = FormLimits::default();
     let id = entry.id;
     let action = match entry.action {
         None => SimpleAction::default(),
@@ -322,10 +350,27 @@ fn convert_entry(
             content_type: entry.content_type,
             ignore_body: entry.ignore_body,
             max_body_size,
+            oversized_body: entry.oversized_body,
             max_body_depth,
+            max_uri_length,
+            block_long_uri: entry.block_long_uri,
             referer_as_uri: entry.referer_as_uri,
+            max_parsed_args: entry.max_parsed_args,
+            max_parsed_headers: entry.max_parsed_headers,
+            max_parsed_cookies: entry.max_parsed_cookies,
+            form_limits: FormLimits {
+                max_fields: entry.max_form_fields.unwrap_or(default_form_limits.max_fields),
+                max_value_size: entry.max_form_value_size.unwrap_or(default_form_limits.max_value_size),
+            },
+            header_join_separator: entry.header_join_separator.unwrap_or_else(|| " ".to_string()),
             action,
             tags: entry.tags.into_iter().collect(),
+            header_log_policy: entry
+                .header_log_policy
+                .into_iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v))
+                .collect(),
+            log_matched: entry.log_matched,
         },
     ))
 }
@@ -350,6 +395,19 @@ impl ContentFilterProfile {
     }
 }
 
+/// the text matched by a rule, found with a regex compiled with the flags of the hyperscan database
+///
+/// hyperscan only reports where the matches end. Rules that are not valid regexes report nothing.
+pub fn rule_matched_text(entry: &ContentFilterRule, value: &str) -> Option<String> {
+    let re = RegexBuilder::new(&entry.operand)
+        .multi_line(true)
+        .dot_matches_new_line(true)
+        .case_insensitive(true)
+        .build()
+        .ok()?;
+    re.find(value).map(|m| m.as_str().to_string())
+}
+
 fn convert_rule(entry: &ContentFilterRule) -> anyhow::Result<Pattern> {
     Pattern::with_flags(
         &entry.operand,
@@ -3,12 +3,16 @@ use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use regex::{Regex, RegexBuilder};
 use serde_json::{from_value, Value};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 
-use crate::config::raw::{GlobalFilterEntryType, RawGlobalFilterRule, RawGlobalFilterSection, Relation};
+use crate::config::matchers::RequestSelector;
+use crate::config::raw::{
+    GlobalFilterEntryType, RawGlobalFilterRule, RawGlobalFilterSection, RawRegexOptions, Relation,
+};
 use crate::interface::{RawTags, SimpleAction};
 use crate::logs::Logs;
+use crate::utils::normalize_header_name;
 
 #[derive(Debug, Clone)]
 pub struct GlobalFilterSection {
@@ -17,6 +21,13 @@ pub struct GlobalFilterSection {
     pub tags: RawTags,
     pub rule: GlobalFilterRule,
     pub action: Option<SimpleAction>,
+    pub log_matched: bool,
+    pub log_rule_tree: bool,
+    /// evaluation order, highest first
+    ///
+    /// When several matching sections have actions of the same strength, `stronger_decision` keeps the one that was
+    /// evaluated first, so the section with the highest priority wins the tie.
+    pub priority: i32,
 }
 
 #[derive(Debug, Clone)]
@@ -59,7 +70,10 @@ pub enum GlobalFilterEntryE {
     Args(PairEntry),
     Cookies(PairEntry),
     Header(PairEntry),
+    HeaderBase64(PairEntry),
     Plugins(PairEntry),
+    JwtClaim(PairEntry),
+    FormField(PairEntry),
 
     // ip/iprange
     Ip(IpAddr),
@@ -69,18 +83,308 @@ pub enum GlobalFilterEntryE {
 
     // single - the string has to be kept because exact matches are performed as well as regex matches
     Path(SingleEntry),
+    DecodedPath(SingleEntry),
     Query(SingleEntry),
     Uri(SingleEntry),
     Country(SingleEntry),
     Region(SingleEntry),
     SubRegion(SingleEntry),
+    RegionIso(SingleEntry),
+    SubRegionIso(SingleEntry),
     Method(SingleEntry),
     Asn(u32),
+    // inclusive bounds
+    AsnRange { from: u32, to: u32 },
     Company(SingleEntry),
     Authority(SingleEntry),
     Tag(SingleEntry),
     SecurityPolicyId(String),
     SecurityPolicyEntryId(String),
+    Session(SingleEntry),
+    ClientCertSubject(SingleEntry),
+    ClientCertSan(SingleEntry),
+    OriginHost(SingleEntry),
+    GrpcMethod(SingleEntry),
+    MethodOverride(SingleEntry),
+    // lowercased domain, with a leading dot when only subdomains match
+    AuthoritySuffix(String),
+    // minutes since midnight, end excluded, and the utc offset in minutes
+    TimeWindow { start: u32, end: u32, tz: i32 },
+
+    // amount of distinct cookies, above or below the threshold
+    CookiesCount { above: bool, threshold: usize },
+
+    // repeated query arguments
+    ArgsCount(String, usize),
+    ArgsAny(PairEntry),
+    // a query argument key, whatever its value
+    ArgsPresent(String),
+
+    // the body could not be parsed, or was parsed properly
+    BodyParseFailed(bool),
+
+    // the request is a CORS preflight, or is not
+    CorsPreflight(bool),
+
+    // the request is a websocket handshake, or is not
+    WebsocketUpgrade(bool),
+
+    // two request fields, left and right, missing fields never match
+    FieldsMatch(RequestSelector, RequestSelector, FieldsRelation),
+
+    // large sets of exact paths and prefixes
+    PathSet(PathSet),
+
+    // a request field whose metric is above the threshold, missing fields never match
+    Randomness(RequestSelector, RandomnessMetric, f64),
+
+    // a request field with more distinct values than the threshold
+    DistinctValues(RequestSelector, usize),
+}
+
+impl GlobalFilterEntryE {
+    /// the entry type, as written in the configuration
+    pub fn kind(&self) -> &'static str {
+        use GlobalFilterEntryE::*;
+        match self {
+            Always(_) => "always",
+            Args(_) => "args",
+            Cookies(_) => "cookies",
+            Header(_) => "headers",
+            HeaderBase64(_) => "headersbase64",
+            Plugins(_) => "plugins",
+            JwtClaim(_) => "jwtclaim",
+            FormField(_) => "formfield",
+            Ip(_) | Network(_) | Range4(_) | Range6(_) => "ip",
+            Path(_) => "path",
+            DecodedPath(_) => "decodedpath",
+            Query(_) => "query",
+            Uri(_) => "uri",
+            Country(_) => "country",
+            Region(_) => "region",
+            SubRegion(_) => "subregion",
+            RegionIso(_) => "regioniso",
+            SubRegionIso(_) => "subregioniso",
+            Method(_) => "method",
+            Asn(_) => "asn",
+            AsnRange { .. } => "asnrange",
+            Company(_) => "company",
+            Authority(_) => "authority",
+            Tag(_) => "tag",
+            SecurityPolicyId(_) => "securitypolicyid",
+            SecurityPolicyEntryId(_) => "securitypolicyentryid",
+            Session(_) => "session",
+            ClientCertSubject(_) => "clientcertsubject",
+            ClientCertSan(_) => "clientcertsan",
+            OriginHost(_) => "originhost",
+            GrpcMethod(_) => "grpcmethod",
+            MethodOverride(_) => "methodoverride",
+            AuthoritySuffix(_) => "authoritysuffix",
+            TimeWindow { .. } => "timewindow",
+            CookiesCount { .. } => "cookiescount",
+            ArgsCount(_, _) => "argscount",
+            ArgsAny(_) => "argsany",
+            ArgsPresent(_) => "argspresent",
+            BodyParseFailed(_) => "bodyparsefailed",
+            CorsPreflight(_) => "corspreflight",
+            WebsocketUpgrade(_) => "websocketupgrade",
+            FieldsMatch(_, _, _) => "fieldsmatch",
+            PathSet(_) => "pathset",
+            Randomness(_, _, _) => "randomness",
+            DistinctValues(_, _) => "distinctvalues",
+        }
+    }
+}
+
+/// the entry value, close to how it is written in the configuration, so that it can be found there
+impl std::fmt::Display for GlobalFilterEntryE {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use GlobalFilterEntryE::*;
+        match self {
+            Always(b) | BodyParseFailed(b) | CorsPreflight(b) | WebsocketUpgrade(b) => write!(f, "{}", b),
+            Args(p) | Cookies(p) | Header(p) | HeaderBase64(p) | Plugins(p) | JwtClaim(p) | FormField(p)
+            | ArgsAny(p) => write!(f, "{}:{}", p.key, p.exact),
+            Ip(a) => write!(f, "{}", a),
+            Network(n) => write!(f, "{}", n),
+            Range4(r) => write!(f, "{}", r.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(",")),
+            Range6(r) => write!(f, "{}", r.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(",")),
+            Path(e) | DecodedPath(e) | Query(e) | Uri(e) | Country(e) | Region(e) | SubRegion(e) | RegionIso(e)
+            | SubRegionIso(e) | Method(e) | Company(e) | Authority(e) | Tag(e) | Session(e) | ClientCertSubject(e)
+            | ClientCertSan(e) | OriginHost(e) | GrpcMethod(e) | MethodOverride(e) => {
+                write!(f, "{}", e.exact)
+            }
+            Asn(a) => write!(f, "{}", a),
+            AsnRange { from, to } => write!(f, "{}-{}", from, to),
+            SecurityPolicyId(s) | SecurityPolicyEntryId(s) | AuthoritySuffix(s) | ArgsPresent(s) => write!(f, "{}", s),
+            TimeWindow { start, end, tz } => write!(
+                f,
+                "{:02}:{:02},{:02}:{:02},{:+}",
+                start / 60,
+                start % 60,
+                end / 60,
+                end % 60,
+                tz
+            ),
+            CookiesCount { above, threshold } => write!(f, "{}{}", if *above { ">" } else { "<" }, threshold),
+            ArgsCount(k, c) => write!(f, "{},{}", k, c),
+            FieldsMatch(l, r, rel) => {
+                let rel = match rel {
+                    FieldsRelation::Equal => "equal",
+                    FieldsRelation::Contains => "contains",
+                };
+                write!(f, "{},{},{}", l, r, rel)
+            }
+            PathSet(p) => write!(f, "{} paths", p.len()),
+            Randomness(sel, metric, threshold) => {
+                let metric = match metric {
+                    RandomnessMetric::Entropy => "entropy",
+                    RandomnessMetric::Uppercase => "uppercase",
+                    RandomnessMetric::Special => "special",
+                };
+                write!(f, "{},{},{}", sel, metric, threshold)
+            }
+            DistinctValues(sel, threshold) => write!(f, "{},{}", sel, threshold),
+        }
+    }
+}
+
+/// a set of paths, looked up exactly or by prefix, for allowlists too large for regular expressions
+#[derive(Debug, Clone, Default)]
+pub struct PathSet {
+    exact: HashSet<String>,
+    prefixes: PrefixTrie,
+}
+
+impl PathSet {
+    /// builds the set from a list of paths, where a trailing `*` marks a prefix
+    pub fn new<'a, I: IntoIterator<Item = &'a str>>(paths: I) -> Self {
+        let mut out = PathSet::default();
+        for p in paths {
+            match p.strip_suffix('*') {
+                Some(prefix) => out.prefixes.insert(prefix),
+                None => {
+                    out.exact.insert(p.to_string());
+                }
+            }
+        }
+        out
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.exact.contains(path) || self.prefixes.has_prefix_of(path)
+    }
+
+    /// amount of exact paths and prefixes
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.prefixes.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// a byte level prefix tree
+#[derive(Debug, Clone, Default)]
+struct PrefixTrie {
+    terminal: bool,
+    children: HashMap<u8, PrefixTrie>,
+}
+
+impl PrefixTrie {
+    fn count(&self) -> usize {
+        usize::from(self.terminal) + self.children.values().map(PrefixTrie::count).sum::<usize>()
+    }
+
+    fn insert(&mut self, prefix: &str) {
+        let mut node = self;
+        for b in prefix.bytes() {
+            node = node.children.entry(b).or_default();
+        }
+        node.terminal = true;
+    }
+
+    /// true when one of the inserted prefixes is a prefix of the input
+    fn has_prefix_of(&self, input: &str) -> bool {
+        let mut node = self;
+        for b in input.bytes() {
+            if node.terminal {
+                return true;
+            }
+            match node.children.get(&b) {
+                None => return false,
+                Some(n) => node = n,
+            }
+        }
+        node.terminal
+    }
+}
+
+/// how the fields of a FieldsMatch entry are compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldsRelation {
+    Equal,
+    /// the left field contains the right one
+    Contains,
+}
+
+/// how the randomness of a field is measured in a Randomness entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomnessMetric {
+    /// shannon entropy, in bits per character
+    Entropy,
+    /// ratio of uppercase characters
+    Uppercase,
+    /// ratio of characters that are not ascii alphanumeric
+    Special,
+}
+
+/// compiles the regular expression of an entry, case insensitive unless configured otherwise
+fn build_re(pattern: &str, options: RawRegexOptions) -> Result<Regex, regex::Error> {
+    let anchored;
+    let pattern = if options.anchored {
+        anchored = format!("^(?:{})$", pattern);
+        &anchored
+    } else {
+        pattern
+    };
+    RegexBuilder::new(pattern)
+        .case_insensitive(!options.case_sensitive)
+        .build()
+}
+
+/// parses a `HH:MM` time of day into minutes since midnight
+fn parse_time_of_day(s: &str) -> anyhow::Result<u32> {
+    let (h, m) = s
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("time of day: {}", s))?;
+    let h: u32 = h.parse().with_context(|| format!("time of day: {}", s))?;
+    let m: u32 = m.parse().with_context(|| format!("time of day: {}", s))?;
+    if h > 23 || m > 59 {
+        anyhow::bail!("time of day out of range: {}", s);
+    }
+    Ok(h * 60 + m)
+}
+
+/// parses `UTC`, `Z` or a `+HH:MM` / `-HH:MM` offset into minutes
+fn parse_utc_offset(s: &str) -> anyhow::Result<i32> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("utc") || s.eq_ignore_ascii_case("z") {
+        return Ok(0);
+    }
+    let (sign, rest) = match (s.strip_prefix('+'), s.strip_prefix('-')) {
+        (Some(r), _) => (1, r),
+        (_, Some(r)) => (-1, r),
+        _ => anyhow::bail!("utc offset: {}", s),
+    };
+    let (h, m) = rest.split_once(':').unwrap_or((rest, "0"));
+    let h: i32 = h.parse().with_context(|| format!("utc offset: {}", s))?;
+    let m: i32 = m.parse().with_context(|| format!("utc offset: {}", s))?;
+    if h > 14 || m > 59 {
+        anyhow::bail!("utc offset out of range: {}", s);
+    }
+    Ok(sign * (h * 60 + m))
 }
 
 /// tries to aggregate ip ranges
@@ -229,7 +533,12 @@ impl GlobalFilterSection {
         }
 
         /// build a global filter entry for "single" conditions that match strings
-        fn single_re<F>(logs: &mut Logs, conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
+        fn single_re<F>(
+            logs: &mut Logs,
+            options: RawRegexOptions,
+            conv: F,
+            val: Value,
+        ) -> anyhow::Result<GlobalFilterEntry>
         where
             F: FnOnce(SingleEntry) -> GlobalFilterEntryE,
         {
@@ -237,7 +546,7 @@ impl GlobalFilterSection {
                 |s| {
                     Ok(conv(SingleEntry {
                         exact: s.to_string(),
-                        re: match RegexBuilder::new(s).case_insensitive(true).build() {
+                        re: match build_re(s, options) {
                             Ok(r) => Some(r),
                             Err(rr) => {
                                 logs.error(|| format!("Bad regex {}: {}", s, rr));
@@ -251,7 +560,13 @@ impl GlobalFilterSection {
         }
 
         /// build a global filter entry for "pair" conditions
-        fn pair<F>(logs: &mut Logs, conv: F, val: Value, lowercase_key: bool) -> anyhow::Result<GlobalFilterEntry>
+        fn pair<F>(
+            logs: &mut Logs,
+            options: RawRegexOptions,
+            conv: F,
+            val: Value,
+            lowercase_key: bool,
+        ) -> anyhow::Result<GlobalFilterEntry>
         where
             F: FnOnce(PairEntry) -> GlobalFilterEntryE,
         {
@@ -259,13 +574,13 @@ impl GlobalFilterSection {
                 Err(_) => from_value(val)?,
                 Ok((k, v, _)) => (k, v),
             };
-            let k = if lowercase_key { uk.to_ascii_lowercase() } else { uk };
+            let k = if lowercase_key { normalize_header_name(&uk) } else { uk };
             Ok(match &v.strip_prefix('!') {
                 None => GlobalFilterEntry {
                     negated: false,
                     entry: conv(PairEntry {
                         key: k,
-                        re: match RegexBuilder::new(&v).case_insensitive(true).build() {
+                        re: match build_re(&v, options) {
                             Ok(r) => Some(r),
                             Err(rr) => {
                                 logs.error(|| format!("Bad regex {}: {}", v, rr));
@@ -279,7 +594,7 @@ impl GlobalFilterSection {
                     negated: true,
                     entry: conv(PairEntry {
                         key: k,
-                        re: match RegexBuilder::new(nval).case_insensitive(true).build() {
+                        re: match build_re(nval, options) {
                             Ok(r) => Some(r),
                             Err(rr) => {
                                 logs.error(|| format!("Bad regex {}: {}", nval, rr));
@@ -293,7 +608,12 @@ impl GlobalFilterSection {
         }
 
         // convert a json value
-        fn convert_entry(logs: &mut Logs, tp: GlobalFilterEntryType, val: Value) -> anyhow::Result<GlobalFilterEntry> {
+        fn convert_entry(
+            logs: &mut Logs,
+            tp: GlobalFilterEntryType,
+            val: Value,
+            options: RawRegexOptions,
+        ) -> anyhow::Result<GlobalFilterEntry> {
             match tp {
                 GlobalFilterEntryType::Ip => single(
                     |rawip| {
@@ -305,20 +625,41 @@ impl GlobalFilterSection {
                     },
                     val,
                 ),
-                GlobalFilterEntryType::Args => pair(logs, GlobalFilterEntryE::Args, val, false),
-                GlobalFilterEntryType::Cookies => pair(logs, GlobalFilterEntryE::Cookies, val, false),
-                GlobalFilterEntryType::Headers => pair(logs, GlobalFilterEntryE::Header, val, true),
-                GlobalFilterEntryType::Plugins => pair(logs, GlobalFilterEntryE::Plugins, val, false),
-                GlobalFilterEntryType::Path => single_re(logs, GlobalFilterEntryE::Path, val),
-                GlobalFilterEntryType::Query => single_re(logs, GlobalFilterEntryE::Query, val),
-                GlobalFilterEntryType::Uri => single_re(logs, GlobalFilterEntryE::Uri, val),
-                GlobalFilterEntryType::Country => single_re(logs, GlobalFilterEntryE::Country, val),
-                GlobalFilterEntryType::Region => single_re(logs, GlobalFilterEntryE::Region, val),
-                GlobalFilterEntryType::SubRegion => single_re(logs, GlobalFilterEntryE::SubRegion, val),
-                GlobalFilterEntryType::Method => single_re(logs, GlobalFilterEntryE::Method, val),
+                GlobalFilterEntryType::Args => pair(logs, options, GlobalFilterEntryE::Args, val, false),
+                GlobalFilterEntryType::Cookies => pair(logs, options, GlobalFilterEntryE::Cookies, val, false),
+                GlobalFilterEntryType::Headers => pair(logs, options, GlobalFilterEntryE::Header, val, true),
+                GlobalFilterEntryType::HeadersBase64 => {
+                    pair(logs, options, GlobalFilterEntryE::HeaderBase64, val, true)
+                }
+                GlobalFilterEntryType::Plugins => pair(logs, options, GlobalFilterEntryE::Plugins, val, false),
+                GlobalFilterEntryType::JwtClaim => pair(logs, options, GlobalFilterEntryE::JwtClaim, val, false),
+                GlobalFilterEntryType::Path => single_re(logs, options, GlobalFilterEntryE::Path, val),
+                GlobalFilterEntryType::DecodedPath => single_re(logs, options, GlobalFilterEntryE::DecodedPath, val),
+                GlobalFilterEntryType::Query => single_re(logs, options, GlobalFilterEntryE::Query, val),
+                GlobalFilterEntryType::Uri => single_re(logs, options, GlobalFilterEntryE::Uri, val),
+                GlobalFilterEntryType::Country => single_re(logs, options, GlobalFilterEntryE::Country, val),
+                GlobalFilterEntryType::Region => single_re(logs, options, GlobalFilterEntryE::Region, val),
+                GlobalFilterEntryType::SubRegion => single_re(logs, options, GlobalFilterEntryE::SubRegion, val),
+                GlobalFilterEntryType::RegionIso => single_re(logs, options, GlobalFilterEntryE::RegionIso, val),
+                GlobalFilterEntryType::SubRegionIso => single_re(logs, options, GlobalFilterEntryE::SubRegionIso, val),
+                GlobalFilterEntryType::Method => single_re(logs, options, GlobalFilterEntryE::Method, val),
                 GlobalFilterEntryType::Asn => single(|rawasn| Ok(GlobalFilterEntryE::Asn(rawasn.parse()?)), val),
-                GlobalFilterEntryType::Company => single_re(logs, GlobalFilterEntryE::Company, val),
-                GlobalFilterEntryType::Authority => single_re(logs, GlobalFilterEntryE::Authority, val),
+                GlobalFilterEntryType::AsnRange => single(
+                    |rawrange| {
+                        let (rfrom, rto) = rawrange
+                            .split_once('-')
+                            .ok_or_else(|| anyhow::anyhow!("invalid asn range {}", rawrange))?;
+                        let from: u32 = rfrom.trim().parse().with_context(|| format!("asn: {}", rfrom))?;
+                        let to: u32 = rto.trim().parse().with_context(|| format!("asn: {}", rto))?;
+                        if from > to {
+                            anyhow::bail!("empty asn range {}", rawrange);
+                        }
+                        Ok(GlobalFilterEntryE::AsnRange { from, to })
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::Company => single_re(logs, options, GlobalFilterEntryE::Company, val),
+                GlobalFilterEntryType::Authority => single_re(logs, options, GlobalFilterEntryE::Authority, val),
                 GlobalFilterEntryType::Tag => single(
                     |s| {
                         Ok(GlobalFilterEntryE::Tag(SingleEntry {
@@ -334,6 +675,201 @@ impl GlobalFilterSection {
                 GlobalFilterEntryType::SecurityPolicyEntryId => {
                     single(|id| Ok(GlobalFilterEntryE::SecurityPolicyEntryId(id.to_string())), val)
                 }
+                GlobalFilterEntryType::Session => single_re(logs, options, GlobalFilterEntryE::Session, val),
+                GlobalFilterEntryType::ClientCertSubject => {
+                    single_re(logs, options, GlobalFilterEntryE::ClientCertSubject, val)
+                }
+                GlobalFilterEntryType::ClientCertSan => {
+                    single_re(logs, options, GlobalFilterEntryE::ClientCertSan, val)
+                }
+                GlobalFilterEntryType::ArgsCount => {
+                    let (k, v): (String, String) = match from_value::<(String, String, Value)>(val.clone()) {
+                        Err(_) => from_value(val)?,
+                        Ok((k, v, _)) => (k, v),
+                    };
+                    let (negated, count) = match v.strip_prefix('!') {
+                        None => (false, v.as_str()),
+                        Some(n) => (true, n),
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::ArgsCount(
+                            k,
+                            count.parse().with_context(|| format!("args count: {}", count))?,
+                        ),
+                    })
+                }
+                GlobalFilterEntryType::ArgsAny => pair(logs, options, GlobalFilterEntryE::ArgsAny, val, false),
+                GlobalFilterEntryType::ArgsPresent => {
+                    single(|k| Ok(GlobalFilterEntryE::ArgsPresent(k.to_string())), val)
+                }
+                GlobalFilterEntryType::OriginHost => single_re(logs, options, GlobalFilterEntryE::OriginHost, val),
+                GlobalFilterEntryType::GrpcMethod => single_re(logs, options, GlobalFilterEntryE::GrpcMethod, val),
+                GlobalFilterEntryType::MethodOverride => {
+                    single_re(logs, options, GlobalFilterEntryE::MethodOverride, val)
+                }
+                GlobalFilterEntryType::FormField => pair(logs, options, GlobalFilterEntryE::FormField, val, false),
+                GlobalFilterEntryType::BodyParseFailed => single(
+                    |b| {
+                        Ok(GlobalFilterEntryE::BodyParseFailed(
+                            b.trim()
+                                .to_lowercase()
+                                .parse()
+                                .with_context(|| format!("body parse failed: {}", b))?,
+                        ))
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::CorsPreflight => single(
+                    |b| {
+                        Ok(GlobalFilterEntryE::CorsPreflight(
+                            b.trim()
+                                .to_lowercase()
+                                .parse()
+                                .with_context(|| format!("cors preflight: {}", b))?,
+                        ))
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::WebsocketUpgrade => single(
+                    |b| {
+                        Ok(GlobalFilterEntryE::WebsocketUpgrade(
+                            b.trim()
+                                .to_lowercase()
+                                .parse()
+                                .with_context(|| format!("websocket upgrade: {}", b))?,
+                        ))
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::AuthoritySuffix => single(
+                    |dom| {
+                        let dom = dom.trim().trim_end_matches('.').to_lowercase();
+                        let suffix = match dom.strip_prefix("*.") {
+                            Some(d) => format!(".{}", d),
+                            None => dom,
+                        };
+                        if suffix.trim_start_matches('.').is_empty() {
+                            anyhow::bail!("empty authority suffix");
+                        }
+                        Ok(GlobalFilterEntryE::AuthoritySuffix(suffix))
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::CookiesCount => single(
+                    |raw| {
+                        let raw = raw.trim();
+                        let (above, count) = match (raw.strip_prefix('>'), raw.strip_prefix('<')) {
+                            (Some(c), _) => (true, c),
+                            (_, Some(c)) => (false, c),
+                            _ => (true, raw),
+                        };
+                        Ok(GlobalFilterEntryE::CookiesCount {
+                            above,
+                            threshold: count
+                                .trim()
+                                .parse()
+                                .with_context(|| format!("cookies count: {}", raw))?,
+                        })
+                    },
+                    val,
+                ),
+                GlobalFilterEntryType::PathSet => {
+                    let mut paths: Vec<String> = from_value(val)?;
+                    let negated = match paths.first().and_then(|p| p.strip_prefix('!')) {
+                        None => false,
+                        Some(first) => {
+                            paths[0] = first.to_string();
+                            true
+                        }
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::PathSet(PathSet::new(paths.iter().map(|p| p.as_str()))),
+                    })
+                }
+                GlobalFilterEntryType::FieldsMatch => {
+                    let (left, right, rrelation): (String, String, Option<String>) =
+                        match from_value::<(String, String, String)>(val.clone()) {
+                            Err(_) => {
+                                let (l, r): (String, String) = from_value(val)?;
+                                (l, r, None)
+                            }
+                            Ok((l, r, rel)) => (l, r, Some(rel)),
+                        };
+                    let (negated, left) = match left.strip_prefix('!') {
+                        None => (false, left.as_str()),
+                        Some(n) => (true, n),
+                    };
+                    let relation = match rrelation.as_deref().map(|r| r.trim().to_lowercase()).as_deref() {
+                        None | Some("equal") => FieldsRelation::Equal,
+                        Some("contains") => FieldsRelation::Contains,
+                        Some(other) => anyhow::bail!("unknown fields relation {}", other),
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::FieldsMatch(
+                            RequestSelector::decode_dotted(left)?,
+                            RequestSelector::decode_dotted(&right)?,
+                            relation,
+                        ),
+                    })
+                }
+                GlobalFilterEntryType::Randomness => {
+                    let (sel, rmetric, threshold): (String, String, f64) = from_value(val)?;
+                    let (negated, sel) = match sel.strip_prefix('!') {
+                        None => (false, sel.as_str()),
+                        Some(n) => (true, n),
+                    };
+                    let metric = match rmetric.trim().to_lowercase().as_str() {
+                        "entropy" => RandomnessMetric::Entropy,
+                        "uppercase" => RandomnessMetric::Uppercase,
+                        "special" => RandomnessMetric::Special,
+                        other => anyhow::bail!("unknown randomness metric {}", other),
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::Randomness(RequestSelector::decode_dotted(sel)?, metric, threshold),
+                    })
+                }
+                GlobalFilterEntryType::DistinctValues => {
+                    let (sel, threshold): (String, usize) = from_value(val)?;
+                    let (negated, sel) = match sel.strip_prefix('!') {
+                        None => (false, sel.as_str()),
+                        Some(n) => (true, n),
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::DistinctValues(RequestSelector::decode_dotted(sel)?, threshold),
+                    })
+                }
+                GlobalFilterEntryType::TimeWindow => {
+                    let (rstart, rend, rtz): (String, String, Option<String>) =
+                        match from_value::<(String, String, String)>(val.clone()) {
+                            Err(_) => {
+                                let (s, e): (String, String) = from_value(val)?;
+                                (s, e, None)
+                            }
+                            Ok((s, e, tz)) => (s, e, Some(tz)),
+                        };
+                    let (negated, rstart) = match rstart.strip_prefix('!') {
+                        None => (false, rstart.as_str()),
+                        Some(n) => (true, n),
+                    };
+                    let start = parse_time_of_day(rstart)?;
+                    let end = parse_time_of_day(&rend)?;
+                    if start == end {
+                        anyhow::bail!("empty time window {}-{}", rstart, rend);
+                    }
+                    let tz = match rtz {
+                        None => 0,
+                        Some(tz) => parse_utc_offset(&tz)?,
+                    };
+                    Ok(GlobalFilterEntry {
+                        negated,
+                        entry: GlobalFilterEntryE::TimeWindow { start, end, tz },
+                    })
+                }
             }
         }
 
@@ -350,7 +886,16 @@ impl GlobalFilterSection {
                         entries: optimize_ipranges(rl.relation, entries),
                     }))
                 }
-                RawGlobalFilterRule::Entry(e) => convert_entry(logs, e.tp, e.vl).map(GlobalFilterRule::Entry),
+                RawGlobalFilterRule::Entry(e) => {
+                    let options = match e.options {
+                        None => RawRegexOptions::default(),
+                        Some(o) => from_value(o.clone()).unwrap_or_else(|rr| {
+                            logs.error(|| format!("Bad regex options {}: {}", o, rr));
+                            RawRegexOptions::default()
+                        }),
+                    };
+                    convert_entry(logs, e.tp, e.vl, options).map(GlobalFilterRule::Entry)
+                }
             }
         }
 
@@ -362,13 +907,25 @@ impl GlobalFilterSection {
             let sname = &s.name;
             let sid = &s.id;
             let rule = convert_rule(logs, s.rule).with_context(|| format!("in section {}, sid={}", sname, sid))?;
-            let action = s.action.as_ref().and_then(|r| actions.get(r)).cloned();
+            let action = match &s.action {
+                None => None,
+                Some(aid) => {
+                    let action = actions.get(aid).cloned();
+                    if action.is_none() {
+                        logs.warning(|| format!("Could not resolve action {} in global filter {}", aid, sid));
+                    }
+                    action
+                }
+            };
             Ok(GlobalFilterSection {
                 id: s.id,
                 tags: s.tags.iter().cloned().collect(),
                 rule,
                 action,
                 name: s.name,
+                log_matched: s.log_matched,
+                log_rule_tree: s.log_rule_tree,
+                priority: s.priority,
             })
         }
 
@@ -381,6 +938,8 @@ impl GlobalFilterSection {
             }
         }
 
+        // stable sort, so that sections with the same priority keep their configuration order
+        out.sort_by_key(|s| std::cmp::Reverse(s.priority));
         out
     }
 }
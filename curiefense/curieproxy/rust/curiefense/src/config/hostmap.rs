@@ -1,9 +1,13 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use crate::config::contentfilter::ContentFilterProfile;
 use crate::config::limit::Limit;
 use crate::config::matchers::Matching;
-use crate::config::raw::AclProfile;
+use crate::config::raw::{AclProfile, RawHostMap};
+use crate::grasshopper::{ChallengeCookie, SameSite};
+use crate::logs::Logs;
 
 use super::matchers::RequestSelector;
 
@@ -12,6 +16,8 @@ use super::matchers::RequestSelector;
 pub struct HostMap {
     pub name: String,
     pub entries: Vec<Matching<Arc<SecurityPolicy>>>,
+    /// ordered by decreasing prefix length, so that the longest prefix is matched first
+    pub path_prefixes: Vec<(String, Arc<SecurityPolicy>)>,
     pub default: Option<Arc<SecurityPolicy>>,
 }
 
@@ -34,6 +40,120 @@ pub struct SecurityPolicy {
     pub limits: Vec<Limit>,
     pub session: Vec<RequestSelector>,
     pub session_ids: Vec<RequestSelector>,
+    /// stage toggles, mostly useful for troubleshooting
+    pub globalfilter_active: bool,
+    pub limit_active: bool,
+    pub flow_active: bool,
+    /// global filters with a skip action are evaluated first, a match bypassing all the other stages
+    pub allowlist_first: bool,
+    /// blocking decisions are turned into monitoring ones, and observations are logged
+    pub learning_mode: bool,
+    pub settings: HostMapSettings,
+}
+
+/// settings of the host map, shared by all its entries
+#[derive(Debug, Clone)]
+pub struct HostMapSettings {
+    /// networks of the requests that are tagged as internal
+    pub internal_networks: Vec<IpNet>,
+    /// global filters are not evaluated for internal requests
+    pub internal_skip_globalfilters: bool,
+    /// when set, only the bearer tokens with a valid HS256 signature are decoded
+    pub jwt_hs256_secret: Option<Vec<u8>>,
+    /// cookie set once the challenge is solved
+    pub challenge_cookie: ChallengeCookie,
+    /// lowercased names of the headers that override the request method, checked in order
+    pub method_override_headers: Vec<String>,
+    /// the overriding method replaces the request method for matching
+    pub apply_method_override: bool,
+    /// request field, such as `headers.x-verified-human`, that overrides the challenge verification
+    ///
+    /// this must only be set when the field is set by a trusted upstream, as clients could otherwise claim to be human
+    pub human_override: Option<RequestSelector>,
+}
+
+impl Default for HostMapSettings {
+    fn default() -> Self {
+        HostMapSettings {
+            internal_networks: Vec::new(),
+            internal_skip_globalfilters: false,
+            jwt_hs256_secret: None,
+            challenge_cookie: ChallengeCookie::default(),
+            method_override_headers: default_method_override_headers(),
+            apply_method_override: false,
+            human_override: None,
+        }
+    }
+}
+
+fn default_method_override_headers() -> Vec<String> {
+    vec![
+        "x-http-method-override".to_string(),
+        "x-http-method".to_string(),
+        "x-method-override".to_string(),
+    ]
+}
+
+impl HostMapSettings {
+    pub fn resolve(logs: &mut Logs, rawmap: &RawHostMap) -> Self {
+        let internal_networks = rawmap
+            .internal_networks
+            .iter()
+            .filter_map(|n| {
+                let net = parse_network(n);
+                if net.is_none() {
+                    logs.error(|| format!("Invalid internal network {} in {}", n, rawmap.name));
+                }
+                net
+            })
+            .collect();
+        let default_cookie = ChallengeCookie::default();
+        let same_site = rawmap.challenge_cookie_samesite.as_ref().and_then(|s| {
+            let same_site = SameSite::parse(s);
+            if same_site.is_none() {
+                logs.error(|| format!("Invalid challenge cookie SameSite attribute {} in {}", s, rawmap.name));
+            }
+            same_site
+        });
+        let challenge_cookie = ChallengeCookie {
+            name: rawmap.challenge_cookie_name.clone().unwrap_or(default_cookie.name),
+            path: rawmap.challenge_cookie_path.clone().unwrap_or(default_cookie.path),
+            same_site,
+        };
+        let human_override = rawmap.human_override.as_ref().and_then(|s| {
+            RequestSelector::decode_dotted(s)
+                .map_err(|rr| logs.error(|| format!("Invalid human override {} in {}: {}", s, rawmap.name, rr)))
+                .ok()
+        });
+        HostMapSettings {
+            internal_networks,
+            internal_skip_globalfilters: rawmap.internal_skip_globalfilters,
+            jwt_hs256_secret: rawmap.jwt_hs256_secret.as_ref().map(|s| s.as_bytes().to_vec()),
+            challenge_cookie,
+            method_override_headers: rawmap
+                .method_override_headers
+                .as_ref()
+                .map(|hs| {
+                    hs.iter()
+                        .map(|h| h.trim().to_ascii_lowercase())
+                        .filter(|h| !h.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(default_method_override_headers),
+            apply_method_override: rawmap.apply_method_override,
+            human_override,
+        }
+    }
+}
+
+/// parses a network, plain addresses being single host networks
+pub fn parse_network(s: &str) -> Option<IpNet> {
+    let s = s.trim();
+    s.parse().ok().or_else(|| {
+        let ip: IpAddr = s.parse().ok()?;
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        IpNet::new(ip, prefix).ok()
+    })
 }
 
 impl Default for SecurityPolicy {
@@ -55,6 +175,12 @@ impl Default for SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            globalfilter_active: true,
+            limit_active: true,
+            flow_active: true,
+            allowlist_first: false,
+            learning_mode: false,
+            settings: HostMapSettings::default(),
         }
     }
 }
@@ -78,6 +204,12 @@ impl SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            globalfilter_active: true,
+            limit_active: true,
+            flow_active: true,
+            allowlist_first: false,
+            learning_mode: false,
+            settings: HostMapSettings::default(),
         };
         out.content_filter_profile.content_type = Vec::new();
         out.content_filter_profile.decoding = Vec::new();
@@ -2,6 +2,7 @@ use anyhow::Context;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use crate::config::matchers::{
     decode_request_selector_condition, RequestSelector, RequestSelectorCondition, SelectorType,
@@ -9,6 +10,7 @@ use crate::config::matchers::{
 use crate::config::raw::{RawLimit, RawLimitSelector};
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
+use crate::utils::templating::{parse_request_template_strict, RequestTemplate};
 
 #[derive(Debug, Clone)]
 pub struct Limit {
@@ -20,7 +22,24 @@ pub struct Limit {
     pub include: HashSet<String>,
     pub pairwith: Option<RequestSelector>,
     pub key: Vec<RequestSelector>,
+    /// extra part of the counter key, rendered for each request
+    pub key_template: Option<RequestTemplate>,
     pub tags: Vec<String>,
+    pub bucket: Option<TokenBucket>,
+    /// blocking actions carry a Retry-After header, with the time until the window resets
+    pub retry_after: bool,
+    /// thresholds that replace the default ones, by security policy entry id, shared by the clones of the limit
+    pub entry_overrides: Arc<HashMap<String, Vec<LimitThreshold>>>,
+}
+
+/// token bucket parameters
+///
+/// The bucket holds at most `burst` tokens, and is refilled with `rate` tokens per second. Each request consumes a
+/// token, and the limit triggers when the bucket is empty.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub rate: f64,
+    pub burst: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +74,12 @@ impl Limit {
             .collect();
         let key = mkey.with_context(|| "when converting the key entry")?;
         let pairwith = RequestSelector::resolve_selector_map(rawlimit.pairwith).ok();
+        let key_template = rawlimit
+            .key_template
+            .as_deref()
+            .map(parse_request_template_strict)
+            .transpose()
+            .with_context(|| "when converting the key template")?;
         let mut thresholds: Vec<LimitThreshold> = Vec::new();
         let id = rawlimit.id;
         for thr in rawlimit.thresholds {
@@ -68,7 +93,44 @@ impl Limit {
                 action,
             })
         }
+        if rawlimit.bucket.is_some() && !rawlimit.entry_overrides.is_empty() {
+            anyhow::bail!("threshold overrides do not apply to token buckets");
+        }
+        let mut entry_overrides = HashMap::new();
+        for (entry_id, limits) in rawlimit.entry_overrides {
+            if limits.len() != thresholds.len() {
+                anyhow::bail!(
+                    "override for entry {} has {} thresholds, expected {}",
+                    entry_id,
+                    limits.len(),
+                    thresholds.len()
+                );
+            }
+            // overrides are written in the same order as the thresholds, so they are sorted afterwards
+            let mut overridden: Vec<LimitThreshold> = thresholds
+                .iter()
+                .zip(limits)
+                .map(|(thr, l)| LimitThreshold {
+                    limit: l.inner,
+                    action: thr.action.clone(),
+                })
+                .collect();
+            overridden.sort_unstable_by(limit_order);
+            entry_overrides.insert(entry_id, overridden);
+        }
         thresholds.sort_unstable_by(limit_order);
+        let bucket = match rawlimit.bucket {
+            None => None,
+            Some(b) => {
+                if !(b.rate > 0.0 && b.rate.is_finite()) {
+                    anyhow::bail!("invalid token bucket rate {}", b.rate);
+                }
+                Some(TokenBucket {
+                    rate: b.rate,
+                    burst: b.burst.inner,
+                })
+            }
+        };
         Ok((
             Limit {
                 id,
@@ -79,12 +141,21 @@ impl Limit {
                 thresholds,
                 pairwith,
                 key,
+                key_template,
                 tags: rawlimit.tags,
+                bucket,
+                retry_after: rawlimit.retry_after,
+                entry_overrides: Arc::new(entry_overrides),
             },
             rawlimit.active,
         ))
     }
 
+    /// the thresholds evaluated for a security policy entry, the overridden ones when set for this entry
+    pub fn thresholds_for(&self, entry_id: &str) -> &[LimitThreshold] {
+        self.entry_overrides.get(entry_id).unwrap_or(&self.thresholds)
+    }
+
     /// returns the limit table, list of global limits, set of inactive limits
     pub fn resolve(
         logs: &mut Logs,
@@ -122,6 +193,7 @@ pub fn limit_order(a: &LimitThreshold, b: &LimitThreshold) -> Ordering {
 
 #[cfg(test)]
 mod tests {
+    use crate::config::raw::ContentFormat;
     use crate::interface::SimpleActionT;
 
     use super::*;
@@ -134,8 +206,11 @@ mod tests {
                 action: SimpleAction {
                     atype: SimpleActionT::Custom {
                         content: "test".to_string(),
+                        json_content: None,
+                        default_format: ContentFormat::Html,
                     },
                     headers: None,
+                    multi_headers: None,
                     status: v as u32,
                     extra_tags: None,
                 },
@@ -151,4 +226,67 @@ mod tests {
         let expected: Vec<u64> = vec![8, 4, 1, 0];
         assert_eq!(status, expected);
     }
+
+    #[test]
+    fn key_template_validation() {
+        let raw = |template: &str| -> RawLimit {
+            serde_json::from_value(serde_json::json!({
+                "id": "lid",
+                "name": "limit",
+                "timeframe": 60,
+                "pairwith": {},
+                "active": true,
+                "key_template": template
+            }))
+            .unwrap()
+        };
+        let mut logs = Logs::default();
+        let (limit, _) = Limit::convert(&mut logs, &HashMap::new(), raw("${ip}-${headers.x-api-key}")).unwrap();
+        assert_eq!(limit.key_template.map(|t| t.len()), Some(3));
+        assert!(Limit::convert(&mut logs, &HashMap::new(), raw("${headers}")).is_err());
+        assert!(Limit::convert(&mut logs, &HashMap::new(), raw("${unknown}")).is_err());
+    }
+    #[test]
+    fn entry_overrides() {
+        let raw = |overrides: serde_json::Value, bucket: serde_json::Value| -> RawLimit {
+            serde_json::from_value(serde_json::json!({
+                "id": "lid",
+                "name": "limit",
+                "timeframe": 60,
+                "pairwith": {},
+                "active": true,
+                "thresholds": [{"limit": 10, "action": "a1"}, {"limit": "20", "action": "a2"}],
+                "entry_overrides": overrides,
+                "bucket": bucket
+            }))
+            .unwrap()
+        };
+        let mut logs = Logs::default();
+        let (limit, _) = Limit::convert(
+            &mut logs,
+            &HashMap::new(),
+            raw(serde_json::json!({"checkout": [2, "4"]}), serde_json::Value::Null),
+        )
+        .unwrap();
+        let limits = |entry: &str| -> Vec<u64> { limit.thresholds_for(entry).iter().map(|t| t.limit).collect() };
+        assert_eq!(limits("checkout"), vec![4, 2]);
+        assert_eq!(limits("other"), vec![20, 10]);
+
+        // one value per threshold
+        assert!(Limit::convert(
+            &mut logs,
+            &HashMap::new(),
+            raw(serde_json::json!({"checkout": [2]}), serde_json::Value::Null)
+        )
+        .is_err());
+        assert!(Limit::convert(
+            &mut logs,
+            &HashMap::new(),
+            raw(
+                serde_json::json!({"checkout": [2, 4]}),
+                serde_json::json!({"rate": 1.0, "burst": 5})
+            )
+        )
+        .is_err());
+    }
 }
@@ -1,3 +1,4 @@
+use crate::utils::normalize_header_name;
 use regex::{Regex, RegexBuilder};
 use std::{collections::HashMap, str::FromStr};
 
@@ -17,12 +18,19 @@ pub enum RequestSelector {
     Cookie(String),
     Header(String),
     Plugins(String),
+    Jwt(String),
+    FormField(String),
+    FormFilename(String),
     Company,
     Authority,
     Tags,
     Session,
     SecpolId,
     SecpolEntryId,
+    ContentLength,
+    GrpcService,
+    GrpcMethod,
+    Nonce,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +46,9 @@ pub enum SelectorType {
     Args,
     Attrs,
     Plugins,
+    Jwt,
+    Form,
+    FormFilenames,
 }
 
 fn resolve_selector_type(k: &str) -> anyhow::Result<SelectorType> {
@@ -45,6 +56,9 @@ fn resolve_selector_type(k: &str) -> anyhow::Result<SelectorType> {
         "headers" => Ok(SelectorType::Headers),
         "cookies" => Ok(SelectorType::Cookies),
         "plugins" => Ok(SelectorType::Plugins),
+        "jwt" => Ok(SelectorType::Jwt),
+        "form" => Ok(SelectorType::Form),
+        "form_filenames" => Ok(SelectorType::FormFilenames),
         "args" => Ok(SelectorType::Args),
         "arguments" => Ok(SelectorType::Args),
         "attrs" => Ok(SelectorType::Attrs),
@@ -71,6 +85,10 @@ impl RequestSelector {
             "authority" => Some(RequestSelector::Authority),
             "tags" => Some(RequestSelector::Tags),
             "session" => Some(RequestSelector::Session),
+            "content_length" | "contentlength" => Some(RequestSelector::ContentLength),
+            "grpc_service" => Some(RequestSelector::GrpcService),
+            "grpc_method" => Some(RequestSelector::GrpcMethod),
+            "nonce" => Some(RequestSelector::Nonce),
             "secpolid" | "securitypolicyid" | "securitypolicy" => Some(RequestSelector::SecpolId),
             "secpolentryid" | "securitypolicyentryid" | "securitypolicyentry" => Some(RequestSelector::SecpolEntryId),
             _ => None,
@@ -84,14 +102,25 @@ impl RequestSelector {
 
     pub fn resolve_selector(tp: SelectorType, v: &str) -> anyhow::Result<Self> {
         match tp {
-            SelectorType::Headers => Ok(RequestSelector::Header(v.to_ascii_lowercase())),
+            SelectorType::Headers => Ok(RequestSelector::Header(normalize_header_name(v))),
             SelectorType::Cookies => Ok(RequestSelector::Cookie(v.to_string())),
             SelectorType::Args => Ok(RequestSelector::Args(v.to_string())),
             SelectorType::Plugins => Ok(RequestSelector::Plugins(v.to_string())),
+            SelectorType::Jwt => Ok(RequestSelector::Jwt(v.to_string())),
+            SelectorType::Form => Ok(RequestSelector::FormField(v.to_string())),
+            SelectorType::FormFilenames => Ok(RequestSelector::FormFilename(v.to_string())),
             SelectorType::Attrs => Self::decode_attribute(v).ok_or_else(|| anyhow::anyhow!("Unknown attribute {}", v)),
         }
     }
 
+    /// decodes a selector written as in templates, such as `ip` or `headers.user-agent`
+    pub fn decode_dotted(s: &str) -> anyhow::Result<Self> {
+        match s.split_once('.') {
+            None => Self::decode_attribute(s).ok_or_else(|| anyhow::anyhow!("Unknown attribute {}", s)),
+            Some((k, v)) => Self::resolve_selector_raw(k, v),
+        }
+    }
+
     pub fn resolve_selector_map(sel: HashMap<String, String>) -> anyhow::Result<Self> {
         if sel.len() != 1 {
             return Err(anyhow::anyhow!("invalid selector {:?}", sel));
@@ -123,7 +152,14 @@ impl std::fmt::Display for RequestSelector {
             RequestSelector::Region => write!(f, "region"),
             RequestSelector::SubRegion => write!(f, "subregion"),
             RequestSelector::Session => write!(f, "session"),
+            RequestSelector::ContentLength => write!(f, "content_length"),
+            RequestSelector::GrpcService => write!(f, "grpc_service"),
+            RequestSelector::GrpcMethod => write!(f, "grpc_method"),
+            RequestSelector::Nonce => write!(f, "nonce"),
             RequestSelector::Plugins(n) => write!(f, "plugins_{}", n),
+            RequestSelector::Jwt(n) => write!(f, "jwt_{}", n),
+            RequestSelector::FormField(n) => write!(f, "form_{}", n),
+            RequestSelector::FormFilename(n) => write!(f, "form_filename_{}", n),
         }
     }
 }
@@ -8,21 +8,23 @@ pub mod raw;
 pub mod virtualtags;
 
 use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::config::limit::Limit;
 use crate::interface::SimpleAction;
-use crate::logs::Logs;
+use crate::logs::{LogLevel, Logs};
 use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
 use flow::flow_resolve;
 use globalfilter::GlobalFilterSection;
-use hostmap::{HostMap, PolicyId, SecurityPolicy};
+use hostmap::{HostMap, HostMapSettings, PolicyId, SecurityPolicy};
 use matchers::Matching;
 use raw::{AclProfile, RawFlowEntry, RawGlobalFilterSection, RawHostMap, RawLimit, RawSecurityPolicy, RawVirtualTag};
 use virtualtags::{vtags_resolve, VirtualTags};
@@ -49,7 +51,51 @@ fn container_name() -> Option<String> {
         .map(|s| s.trim().to_string())
 }
 
+/// why `try_with_config` could not run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// the configuration path does not exist, or has no json subdirectory, nothing was loaded
+    MissingDirectory(String),
+    /// the configuration lock is poisoned
+    Lock(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::MissingDirectory(path) => write!(f, "{} is not a configuration directory", path),
+            ConfigError::Lock(rr) => write!(f, "could not access the configuration: {}", rr),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 pub fn with_config<R, F>(basepath: &str, logs: &mut Logs, f: F) -> Option<R>
+where
+    F: FnOnce(&mut Logs, &Config) -> R,
+{
+    apply_config(basepath, logs, f).ok()
+}
+
+/// like `with_config`, but fails when the configuration directory is missing, instead of installing an empty
+/// configuration
+///
+/// Files that can not be parsed do not make it fail, they are logged and counted in the `LoadStats` of the
+/// installed configuration. All errors are logged, as with `with_config`.
+pub fn try_with_config<R, F>(basepath: &str, logs: &mut Logs, f: F) -> Result<R, ConfigError>
+where
+    F: FnOnce(&mut Logs, &Config) -> R,
+{
+    if !Path::new(basepath).join("json").is_dir() {
+        let rr = ConfigError::MissingDirectory(basepath.to_string());
+        logs.error(|| rr.to_string());
+        return Err(rr);
+    }
+    apply_config(basepath, logs, f)
+}
+
+fn apply_config<R, F>(basepath: &str, logs: &mut Logs, f: F) -> Result<R, ConfigError>
 where
     F: FnOnce(&mut Logs, &Config) -> R,
 {
@@ -57,7 +103,7 @@ where
         Ok(cfg) => match cfg.reload(basepath) {
             None => {
                 config_logs(logs, &cfg);
-                return Some(f(logs, &cfg));
+                return Ok(f(logs, &cfg));
             }
             Some(cfginfo) => cfginfo,
         },
@@ -65,7 +111,7 @@ where
         // read failed :(
         {
             logs.error(|| rr.to_string());
-            return None;
+            return Err(ConfigError::Lock(rr.to_string()));
         }
     };
     config_logs(logs, &newconfig);
@@ -78,7 +124,144 @@ where
         Ok(mut dbw) => *dbw = newhsdb,
         Err(rr) => logs.error(|| rr.to_string()),
     };
-    Some(r)
+    Ok(r)
+}
+
+/// forces a configuration reload, regardless of the last modification time, and returns the new revision
+pub fn reload_config(basepath: &str, logs: &mut Logs) -> Option<String> {
+    let last_mod = std::fs::metadata(basepath)
+        .and_then(|x| x.modified())
+        .unwrap_or_else(|rr| {
+            logs.error(|| format!("Could not get last modified time for {}: {}", basepath, rr));
+            SystemTime::now()
+        });
+    let (newconfig, newhsdb) = Config::load(Logs::default(), basepath, last_mod);
+    config_logs(logs, &newconfig);
+    let revision = newconfig.revision.clone();
+    match CONFIG.write() {
+        Ok(mut w) => *w = newconfig,
+        Err(rr) => {
+            logs.error(|| rr.to_string());
+            return None;
+        }
+    };
+    match HSDB.write() {
+        Ok(mut dbw) => *dbw = newhsdb,
+        Err(rr) => {
+            logs.error(|| rr.to_string());
+            return None;
+        }
+    };
+    Some(revision)
+}
+
+/// loads and resolves a configuration directory, without installing it
+///
+/// returns the warnings and errors that were logged while resolving it, so that configuration changes can be
+/// validated before being deployed
+pub fn validate_config(basepath: &str) -> Result<Vec<String>, String> {
+    let mut bjson = PathBuf::from(basepath);
+    bjson.push("json");
+    if !bjson.is_dir() {
+        return Err(ConfigError::MissingDirectory(basepath.to_string()).to_string());
+    }
+    let (config, _) = Config::load(Logs::default(), basepath, SystemTime::now());
+    Ok(config
+        .logs
+        .logs
+        .iter()
+        .filter(|l| l.level >= LogLevel::Warning)
+        .map(|l| l.message.clone())
+        .collect())
+}
+
+/// ids of the configuration entries that differ between two revisions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EntriesDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl EntriesDiff {
+    fn new(old: Vec<serde_json::Value>, new: Vec<serde_json::Value>) -> Self {
+        fn by_id(entries: Vec<serde_json::Value>) -> BTreeMap<String, serde_json::Value> {
+            entries
+                .into_iter()
+                .filter_map(|e| Some((e.get("id")?.as_str()?.to_string(), e)))
+                .collect()
+        }
+        let old = by_id(old);
+        let new = by_id(new);
+        let mut out = EntriesDiff::default();
+        for (id, nentry) in &new {
+            match old.get(id) {
+                None => out.added.push(id.clone()),
+                Some(oentry) if oentry != nentry => out.changed.push(id.clone()),
+                Some(_) => (),
+            }
+        }
+        out.removed = old.keys().filter(|id| !new.contains_key(*id)).cloned().collect();
+        out
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// rule level differences between two configuration directories
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConfigDiff {
+    pub old_revision: String,
+    pub new_revision: String,
+    pub globalfilters: EntriesDiff,
+    pub actions: EntriesDiff,
+    pub securitypolicies: EntriesDiff,
+}
+
+/// compares the raw entries of two configuration directories, by id
+///
+/// entries are compared before being resolved, so that changes are reported even for entries that fail to resolve
+pub fn diff_configs(old_path: &str, new_path: &str) -> Result<ConfigDiff, String> {
+    let mut logs = Logs::default();
+    let mut load = |basepath: &str, fname: &str| -> Result<Vec<serde_json::Value>, String> {
+        let mut bjson = PathBuf::from(basepath);
+        bjson.push("json");
+        if !bjson.is_dir() {
+            return Err(ConfigError::MissingDirectory(basepath.to_string()).to_string());
+        }
+        Ok(Config::load_config_file(&mut logs, &bjson, fname))
+    };
+    let mut entries = |fname: &str| -> Result<EntriesDiff, String> {
+        Ok(EntriesDiff::new(load(old_path, fname)?, load(new_path, fname)?))
+    };
+    let globalfilters = entries("globalfilter-lists.json")?;
+    let actions = entries("actions.json")?;
+    let securitypolicies = entries("securitypolicy.json")?;
+    Ok(ConfigDiff {
+        old_revision: Config::load_revision(&mut logs, old_path),
+        new_revision: Config::load_revision(&mut logs, new_path),
+        globalfilters,
+        actions,
+        securitypolicies,
+    })
+}
+
+/// statistics of the last configuration load
+pub fn config_load_stats() -> Option<LoadStats> {
+    CONFIG.read().ok().map(|cfg| cfg.load_stats.clone())
+}
+
+/// health of the engine, the configuration is degraded when errors were logged while loading it
+pub fn health_check() -> serde_json::Value {
+    match config_load_stats() {
+        None => serde_json::json!({ "status": "error" }),
+        Some(stats) => serde_json::json!({
+            "status": if stats.errors == 0 { "ok" } else { "degraded" },
+            "config": stats,
+        }),
+    }
 }
 
 pub fn with_config_default_path<R, F>(logs: &mut Logs, f: F) -> Option<R>
@@ -101,6 +284,34 @@ pub struct Config {
     pub content_filter_profiles: HashMap<String, ContentFilterProfile>,
     pub virtual_tags: VirtualTags,
     pub logs: Logs,
+    pub load_stats: LoadStats,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LoadStats {
+    pub revision: String,
+    pub duration_us: u64,
+    /// amount of errors logged while loading, including the entries that could not be parsed
+    pub errors: usize,
+    pub warnings: usize,
+    pub security_policies: usize,
+    pub content_filter_profiles: usize,
+    pub globalfilters: usize,
+}
+
+impl LoadStats {
+    fn new(cfg: &Config, duration: Duration) -> Self {
+        let count = |level: LogLevel| cfg.logs.logs.iter().filter(|l| l.level == level).count();
+        LoadStats {
+            revision: cfg.revision.clone(),
+            duration_us: duration.as_micros() as u64,
+            errors: count(LogLevel::Error),
+            warnings: count(LogLevel::Warning),
+            security_policies: cfg.securitypolicies.len() + cfg.default.iter().count(),
+            content_filter_profiles: cfg.content_filter_profiles.len(),
+            globalfilters: cfg.globalfilters.len(),
+        }
+    }
 }
 
 fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
@@ -125,6 +336,7 @@ impl Config {
         contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
         session: Vec<RequestSelector>,
         session_ids: Vec<RequestSelector>,
+        settings: HostMapSettings,
     ) -> (Vec<Matching<Arc<SecurityPolicy>>>, Option<Arc<SecurityPolicy>>) {
         let mut default: Option<Arc<SecurityPolicy>> = None;
         let mut entries: Vec<Matching<Arc<SecurityPolicy>>> = Vec::new();
@@ -178,6 +390,12 @@ impl Config {
                 content_filter_active: rawmap.content_filter_active,
                 content_filter_profile,
                 limits: olimits,
+                globalfilter_active: rawmap.globalfilter_active,
+                limit_active: rawmap.limit_active,
+                flow_active: rawmap.flow_active,
+                allowlist_first: rawmap.allowlist_first,
+                learning_mode: rawmap.learning_mode,
+                settings: settings.clone(),
             };
             if rawmap.match_ == "__default__"
                 || securitypolicy.entry.id == "__default__"
@@ -231,6 +449,7 @@ impl Config {
         // build the entries while looking for the default entry
         for rawmap in rawmaps {
             let mapname = rawmap.name.clone();
+            let settings = HostMapSettings::resolve(&mut logs, &rawmap);
             let msession: anyhow::Result<Vec<RequestSelector>> = if rawmap.session.is_empty() {
                 Ok(Vec::new())
             } else {
@@ -267,13 +486,33 @@ impl Config {
                 &content_filter_profiles,
                 session,
                 session_ids,
+                settings,
             );
             if default_entry.is_none() {
                 logs.warning(format!("HostMap entry '{}' does not have a default entry", &rawmap.name).as_str());
             }
+            let mut path_prefixes: Vec<(String, Arc<SecurityPolicy>)> = Vec::new();
+            for (prefix, entryid) in rawmap.path_prefixes {
+                match entries
+                    .iter()
+                    .map(|m| &m.inner)
+                    .chain(default_entry.iter())
+                    .find(|e| e.entry.id == entryid)
+                {
+                    Some(e) => path_prefixes.push((prefix, e.clone())),
+                    None => logs.error(|| {
+                        format!(
+                            "Unknown security policy entry {} for path prefix {} in {}",
+                            entryid, prefix, mapname
+                        )
+                    }),
+                }
+            }
+            path_prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
             let hostmap = HostMap {
                 name: rawmap.name,
                 entries,
+                path_prefixes,
                 default: default_entry,
             };
             securitypolicies_map.insert(rawmap.id, hostmap.clone());
@@ -313,6 +552,28 @@ impl Config {
             content_filter_profiles,
             logs,
             virtual_tags,
+            load_stats: LoadStats::default(),
+        }
+    }
+
+    /// reads the revision from the manifest.json file of the parent directory
+    fn load_revision(logs: &mut Logs, basepath: &str) -> String {
+        let mmanifest: Result<RawManifest, String> = PathBuf::from(basepath)
+            .parent()
+            .ok_or_else(|| "could not get parent directory?".to_string())
+            .and_then(|x| {
+                let mut pth = x.to_owned();
+                pth.push("manifest.json");
+                std::fs::File::open(pth).map_err(|rr| rr.to_string())
+            })
+            .and_then(|file| serde_json::from_reader(file).map_err(|rr| rr.to_string()));
+
+        match mmanifest {
+            Err(rr) => {
+                logs.error(move || format!("When loading manifest.json: {}", rr));
+                "unknown".to_string()
+            }
+            Ok(manifest) => manifest.meta.version,
         }
     }
 
@@ -347,29 +608,14 @@ impl Config {
     }
 
     pub fn load(logs: Logs, basepath: &str, last_mod: SystemTime) -> (Config, HashMap<String, ContentFilterRules>) {
+        let start = Instant::now();
         let mut logs = logs;
         let mut bjson = PathBuf::from(basepath);
         bjson.push("json");
 
         logs.debug(|| format!("Loading configuration from {}", basepath));
 
-        let mmanifest: Result<RawManifest, String> = PathBuf::from(basepath)
-            .parent()
-            .ok_or_else(|| "could not get parent directory?".to_string())
-            .and_then(|x| {
-                let mut pth = x.to_owned();
-                pth.push("manifest.json");
-                std::fs::File::open(pth).map_err(|rr| rr.to_string())
-            })
-            .and_then(|file| serde_json::from_reader(file).map_err(|rr| rr.to_string()));
-
-        let revision = match mmanifest {
-            Err(rr) => {
-                logs.error(move || format!("When loading manifest.json: {}", rr));
-                "unknown".to_string()
-            }
-            Ok(manifest) => manifest.meta.version,
-        };
+        let revision = Config::load_revision(&mut logs, basepath);
 
         let rawactions = Config::load_config_file(&mut logs, &bjson, "actions.json");
         let securitypolicy = Config::load_config_file(&mut logs, &bjson, "securitypolicy.json");
@@ -388,7 +634,7 @@ impl Config {
 
         let hsdb = resolve_rules(&mut logs, &content_filter_profiles, contentfilterrules);
 
-        let config = Config::resolve(
+        let mut config = Config::resolve(
             logs,
             revision,
             last_mod,
@@ -402,6 +648,7 @@ impl Config {
             flows,
             virtualtags,
         );
+        config.load_stats = LoadStats::new(&config, start.elapsed());
 
         (config, hsdb)
     }
@@ -434,6 +681,7 @@ impl Config {
             content_filter_profiles: HashMap::new(),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
+            load_stats: LoadStats::default(),
         }
     }
 }
@@ -444,3 +692,207 @@ pub fn init_config() -> (bool, Vec<String>) {
     let is_ok = logs.logs.is_empty();
     (is_ok, logs.to_stringvec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        /// serializes the tests that read or replace the global configuration
+        static ref GLOBAL_CONFIG: Mutex<()> = Mutex::new(());
+    }
+
+    fn lock_global_config() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_CONFIG.lock().unwrap_or_else(|rr| rr.into_inner())
+    }
+
+    /// an empty directory, only used by the calling test
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("curiefense-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    fn write_manifest(root: &Path, version: &str) {
+        let manifest = format!(r#"{{"meta": {{"id": "test", "version": "{}"}}}}"#, version);
+        std::fs::write(root.join("manifest.json"), manifest).unwrap();
+    }
+
+    #[test]
+    fn reload_picks_up_new_revision() {
+        let _global = lock_global_config();
+        let root = test_root("reload");
+        let basepath = root.join("config");
+        std::fs::create_dir_all(basepath.join("json")).unwrap();
+        let basepath_str = basepath.to_str().unwrap();
+
+        write_manifest(&root, "rev1");
+        let mut logs = Logs::default();
+        assert_eq!(reload_config(basepath_str, &mut logs), Some("rev1".to_string()));
+        assert_eq!(CONFIG.read().unwrap().revision, "rev1");
+
+        write_manifest(&root, "rev2");
+        let mut logs = Logs::default();
+        assert_eq!(reload_config(basepath_str, &mut logs), Some("rev2".to_string()));
+        assert_eq!(CONFIG.read().unwrap().revision, "rev2");
+
+        *CONFIG.write().unwrap() = Config::empty();
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn missing_directory() {
+        let _global = lock_global_config();
+        let root = test_root("missing");
+        let basepath = root.join("config");
+        let basepath_str = basepath.to_str().unwrap();
+        let mut logs = Logs::default();
+        let called = try_with_config(basepath_str, &mut logs, |_, _| ());
+        assert_eq!(called, Err(ConfigError::MissingDirectory(basepath_str.to_string())));
+        assert!(logs.logs[0].message.contains("is not a configuration directory"));
+
+        // a directory without the json subdirectory is not a configuration either
+        std::fs::create_dir_all(&basepath).unwrap();
+        let mut logs = Logs::default();
+        assert!(matches!(
+            try_with_config(basepath_str, &mut logs, |_, _| ()),
+            Err(ConfigError::MissingDirectory(_))
+        ));
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn validate_broken_action() {
+        let _global = lock_global_config();
+        let root = test_root("validate");
+        let basepath = root.join("config");
+        let json = basepath.join("json");
+        std::fs::create_dir_all(&json).unwrap();
+        write_manifest(&root, "validated");
+        std::fs::write(
+            json.join("actions.json"),
+            r#"[{"id": "monitor", "type": "monitor"}, {"id": "broken", "type": "nonexistent"}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            json.join("globalfilter-lists.json"),
+            r#"[{"id": "gf1", "name": "gf1", "active": true, "tags": ["t"], "action": "broken",
+                 "rule": {"relation": "OR", "entries": [["ip", "1.2.3.4"]]}}]"#,
+        )
+        .unwrap();
+
+        let warnings = validate_config(basepath.to_str().unwrap()).unwrap();
+        assert!(warnings.iter().any(|w| w.contains("actions.json")));
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("Could not resolve action broken in global filter gf1")));
+        // the configuration is not installed
+        assert_ne!(CONFIG.read().unwrap().revision, "validated");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn load_stats() {
+        let root = test_root("loadstats");
+        let basepath = root.join("config");
+        let json = basepath.join("json");
+        std::fs::create_dir_all(&json).unwrap();
+        write_manifest(&root, "stats");
+        for fname in [
+            "securitypolicy.json",
+            "limits.json",
+            "acl-profiles.json",
+            "contentfilter-profiles.json",
+            "contentfilter-rules.json",
+            "flow-control.json",
+            "virtual-tags.json",
+        ] {
+            std::fs::write(json.join(fname), "[]").unwrap();
+        }
+        std::fs::write(
+            json.join("actions.json"),
+            r#"[{"id": "monitor", "type": "monitor"}, {"id": "broken", "type": "nonexistent"}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            json.join("globalfilter-lists.json"),
+            r#"[{"id": "gf1", "name": "gf1", "active": true, "tags": ["t"], "action": "monitor",
+                 "rule": {"relation": "OR", "entries": [["ip", "1.2.3.4"]]}},
+                {"id": "gf2", "name": "gf2", "active": true, "tags": ["t"],
+                 "rule": {"relation": "OR", "entries": [["path", "/admin"]]}}]"#,
+        )
+        .unwrap();
+
+        let (config, _) = Config::load(Logs::default(), basepath.to_str().unwrap(), SystemTime::now());
+        let stats = &config.load_stats;
+        assert_eq!(stats.revision, "stats");
+        // the broken action
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.security_policies, 0);
+        assert_eq!(stats.content_filter_profiles, 0);
+        assert_eq!(stats.globalfilters, 2);
+        let serialized = serde_json::to_value(stats).unwrap();
+        assert_eq!(serialized["revision"], "stats");
+        assert_eq!(serialized["errors"], 1);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn diff_one_filter() {
+        let root = test_root("diff");
+        let write = |name: &str, version: &str, globalfilters: &str| {
+            let dir = root.join(name);
+            let json = dir.join("config").join("json");
+            std::fs::create_dir_all(&json).unwrap();
+            write_manifest(&dir, version);
+            std::fs::write(json.join("actions.json"), r#"[{"id": "monitor", "type": "monitor"}]"#).unwrap();
+            std::fs::write(json.join("globalfilter-lists.json"), globalfilters).unwrap();
+            dir.join("config").to_str().unwrap().to_string()
+        };
+        let old = write(
+            "old",
+            "rev1",
+            r#"[{"id": "gf1", "name": "gf1", "active": true, "tags": ["t"],
+                 "rule": {"relation": "OR", "entries": [["ip", "1.2.3.4"]]}},
+                {"id": "gf2", "name": "gf2", "active": true, "tags": ["t"],
+                 "rule": {"relation": "OR", "entries": [["path", "/admin"]]}}]"#,
+        );
+        let new = write(
+            "new",
+            "rev2",
+            r#"[{"id": "gf1", "name": "gf1", "active": true, "tags": ["t"],
+                 "rule": {"relation": "OR", "entries": [["ip", "1.2.3.4"]]}},
+                {"id": "gf2", "name": "gf2", "active": true, "tags": ["t"],
+                 "rule": {"relation": "OR", "entries": [["path", "/private"]]}}]"#,
+        );
+
+        let diff = diff_configs(&old, &new).unwrap();
+        assert_eq!(diff.old_revision, "rev1");
+        assert_eq!(diff.new_revision, "rev2");
+        assert_eq!(
+            diff.globalfilters,
+            EntriesDiff {
+                added: Vec::new(),
+                removed: Vec::new(),
+                changed: vec!["gf2".to_string()],
+            }
+        );
+        assert!(diff.actions.is_empty());
+        assert!(diff.securitypolicies.is_empty());
+        let serialized = serde_json::to_value(&diff).unwrap();
+        assert_eq!(serialized["globalfilters"]["changed"], serde_json::json!(["gf2"]));
+
+        assert!(diff_configs(&old, "/nonexistent/curiefense/config").is_err());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn validate_missing_directory() {
+        assert!(validate_config("/nonexistent/curiefense/config").is_err());
+    }
+}
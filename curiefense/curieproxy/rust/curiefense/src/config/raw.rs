@@ -71,6 +71,29 @@ pub struct RawHostMap {
     pub session: Vec<HashMap<String, String>>,
     #[serde(default)]
     pub session_ids: Vec<HashMap<String, String>>,
+    /// path prefix to security policy entry id, used when no entry matches, prefixes match whole path segments
+    #[serde(default)]
+    pub path_prefixes: HashMap<String, String>,
+    /// networks, or single addresses, of the requests that are tagged as internal
+    #[serde(default)]
+    pub internal_networks: Vec<String>,
+    /// global filters are not evaluated for internal requests
+    #[serde(default)]
+    pub internal_skip_globalfilters: bool,
+    /// secret used to check the signature of the bearer tokens, their claims are decoded without checking when absent
+    pub jwt_hs256_secret: Option<String>,
+    /// attributes of the cookie set once the challenge is solved, `rbzid` on `/` by default
+    pub challenge_cookie_name: Option<String>,
+    pub challenge_cookie_path: Option<String>,
+    /// `strict`, `lax` or `none`, the attribute is omitted by default
+    pub challenge_cookie_samesite: Option<String>,
+    /// names of the headers that override the request method, checked in order
+    pub method_override_headers: Option<Vec<String>>,
+    /// when set, the overriding method replaces the request method for matching
+    #[serde(default)]
+    pub apply_method_override: bool,
+    /// request field, such as `headers.x-verified-human`, set by a trusted upstream to override the challenge verification
+    pub human_override: Option<String>,
 }
 
 /// a mapping of the configuration file for security policies
@@ -86,6 +109,22 @@ pub struct RawSecurityPolicy {
     pub acl_active: bool,
     pub content_filter_active: bool,
     pub limit_ids: Vec<String>,
+    #[serde(default = "default_true")]
+    pub globalfilter_active: bool,
+    #[serde(default = "default_true")]
+    pub limit_active: bool,
+    #[serde(default = "default_true")]
+    pub flow_active: bool,
+    /// evaluate the skip global filters before anything else, and stop at the first match
+    #[serde(default)]
+    pub allowlist_first: bool,
+    /// never block, and log the shapes of the request values instead
+    #[serde(default)]
+    pub learning_mode: bool,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -104,6 +143,15 @@ pub struct RawGlobalFilterSection {
     pub tags: Vec<String>,
     pub rule: RawGlobalFilterRule,
     pub action: Option<String>,
+    /// include the text matched by regexes in the block reasons, disabled by default as it might be sensitive
+    #[serde(default)]
+    pub log_matched: bool,
+    /// include the tree of the entries that contributed to the match in the block reasons, for rule debugging
+    #[serde(default)]
+    pub log_rule_tree: bool,
+    /// sections with a higher priority are evaluated first, ties keep the order of the configuration file
+    #[serde(default)]
+    pub priority: i32,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -125,7 +173,10 @@ pub enum GlobalFilterEntryType {
     Args,
     Cookies,
     Headers,
+    /// the raw path, as sent by the client, without URL decoding
     Path,
+    /// the URL decoded path, where `%2e%2e` is seen as `..`
+    DecodedPath,
     Plugins,
     Query,
     Uri,
@@ -140,6 +191,57 @@ pub enum GlobalFilterEntryType {
     Tag,
     SecurityPolicyId,
     SecurityPolicyEntryId,
+    Session,
+    ClientCertSubject,
+    ClientCertSan,
+    /// an argument key that is repeated more than N times
+    ArgsCount,
+    /// any of the values of a (possibly repeated) argument
+    ArgsAny,
+    /// the ISO 3166-2 code of the first level geo subdivision, without the country prefix
+    RegionIso,
+    /// the ISO 3166-2 code of the second level geo subdivision, without the country prefix
+    SubRegionIso,
+    /// a query argument key, matched whatever its value, including valueless arguments such as `?debug`
+    ArgsPresent,
+    /// a claim of the bearer JWT
+    JwtClaim,
+    OriginHost,
+    /// a domain and its subdomains, or only its subdomains when written as `*.example.com`
+    AuthoritySuffix,
+    /// a time of day window, as `[start, end]` or `[start, end, utc_offset]`
+    TimeWindow,
+    /// the amount of distinct cookies, as `>N` or `<N`
+    CookiesCount,
+    /// the `package.Service/Method` name of gRPC requests
+    GrpcMethod,
+    /// requests with a body that could not be parsed, as `true` or `false`
+    BodyParseFailed,
+    /// CORS preflight requests, OPTIONS requests with an access-control-request-method header, as `true` or `false`
+    CorsPreflight,
+    /// websocket handshakes, GET requests without a body, with the upgrade connection token and the websocket upgrade
+    /// header, as `true` or `false`
+    WebsocketUpgrade,
+    /// a field of a multipart/form-data body
+    FormField,
+    /// a header, matched against its base64 decoded value when it is valid base64 and utf8, and its raw value otherwise
+    HeadersBase64,
+    /// two request fields compared with each other, as `[selector, selector]` or `[selector, selector, relation]`,
+    /// where selectors are written as in templates and the relation is `equal` (default) or `contains`
+    FieldsMatch,
+    /// a list of paths, matched exactly, or as prefixes when they end with `*`
+    /// the entry is negated when the first path starts with `!`
+    PathSet,
+    /// the method announced in a method override header, such as `X-HTTP-Method-Override`
+    MethodOverride,
+    /// an inclusive range of ASNs, as `from-to`
+    AsnRange,
+    /// a request field that looks random, as `[selector, metric, threshold]`, where the metric is `entropy`
+    /// (shannon entropy, in bits per character), `uppercase` or `special` (ratios of characters)
+    Randomness,
+    /// a repeatable request field with more distinct values than the threshold, as `[selector, threshold]`, where
+    /// query arguments are counted per occurrence and headers per comma separated value
+    DistinctValues,
 }
 
 /// a special datatype for deserializing tuples with 2 elements, and optional extra elements
@@ -148,6 +250,19 @@ pub struct RawGlobalFilterEntry {
     pub tp: GlobalFilterEntryType,
     pub vl: serde_json::Value,
     pub comment: Option<String>,
+    /// the raw regular expression options, decoded as `RawRegexOptions` when the entry is resolved
+    pub options: Option<serde_json::Value>,
+}
+
+/// options of the regular expressions of an entry, passed as an optional fourth element
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RawRegexOptions {
+    /// regular expressions are case insensitive by default
+    #[serde(default)]
+    pub case_sensitive: bool,
+    /// the regular expression must match the whole value
+    #[serde(default)]
+    pub anchored: bool,
 }
 
 impl<'de> Deserialize<'de> for RawGlobalFilterEntry {
@@ -172,8 +287,18 @@ impl<'de> Deserialize<'de> for RawGlobalFilterEntry {
                 let vl = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
                 // comment might not be present
                 let comment = seq.next_element().ok().flatten();
-
-                Ok(RawGlobalFilterEntry { tp, vl, comment })
+                let options = seq
+                    .next_element::<serde_json::Value>()
+                    .ok()
+                    .flatten()
+                    .filter(|o| !o.is_null());
+
+                Ok(RawGlobalFilterEntry {
+                    tp,
+                    vl,
+                    comment,
+                    options,
+                })
             }
         }
 
@@ -201,6 +326,25 @@ pub struct RawLimit {
     pub active: bool,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// when set, the limit is a token bucket instead of a fixed window counter
+    #[serde(default)]
+    pub bucket: Option<RawTokenBucket>,
+    /// template added to the counter key, such as `${ip}${path}${headers.x-api-key}`
+    #[serde(default)]
+    pub key_template: Option<String>,
+    /// add a Retry-After header to the blocking actions of this limit
+    #[serde(default)]
+    pub retry_after: bool,
+    /// threshold limits for specific security policy entries, by entry id, with one value per threshold
+    #[serde(default)]
+    pub entry_overrides: HashMap<String, Vec<Repru64>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawTokenBucket {
+    /// tokens added per second
+    pub rate: f64,
+    pub burst: Repru64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -240,6 +384,20 @@ pub enum RawActionType {
     Custom,
     Challenge,
     Identity,
+    /// a monitor action that never adds response headers
+    Count,
+    /// a blocking action that clears the cookies listed in cookie_names
+    ClearCookies,
+    /// a 401 response with the www_authenticate challenge
+    Authenticate,
+    /// a monitor action asking the proxy to copy a sample of the requests to mirror_target
+    Mirror,
+    /// a monitor action telling the proxy to route the request to the route_cluster upstream
+    Route,
+    /// a monitor action asking the proxy to add headers preventing the response from being cached
+    NoStore,
+    /// combines the actions listed in actions, see `SimpleAction::resolve_composite` for the precedence rules
+    Composite,
 }
 
 impl std::default::Default for RawActionType {
@@ -248,12 +406,66 @@ impl std::default::Default for RawActionType {
     }
 }
 
+/// what to do when a challenge can't be issued, because there is no user-agent or no challenge engine
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeFallback {
+    /// issue the challenge anyway, when there is a challenge engine
+    Challenge,
+    Block,
+    Pass,
+}
+
+impl std::default::Default for ChallengeFallback {
+    fn default() -> Self {
+        ChallengeFallback::Block
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RawActionParams {
     pub status: Option<u32>,
     #[serde(default)]
     pub headers: Option<HashMap<String, String>>,
+    /// repeatable headers, such as set-cookie, each value is a template and is sent as a separate header
+    #[serde(default)]
+    pub multi_headers: Option<HashMap<String, Vec<String>>>,
     pub content: Option<String>,
+    /// names of the cookies cleared by the clear_cookies action, can be templates
+    #[serde(default)]
+    pub cookie_names: Vec<String>,
+    #[serde(default)]
+    pub challenge_fallback: ChallengeFallback,
+    /// body of the custom action for clients that prefer json
+    pub json_content: Option<String>,
+    /// value of the WWW-Authenticate header of the authenticate action, can be a template
+    pub www_authenticate: Option<String>,
+    /// body served to custom actions when the accept header has no preference
+    #[serde(default)]
+    pub default_format: ContentFormat,
+    /// url the mirror action copies the requests to, can be a template
+    pub mirror_target: Option<String>,
+    /// ratio of the requests that are mirrored, between 0 and 1, defaults to 1
+    pub sample_rate: Option<f32>,
+    /// upstream cluster selected by the route action, can be a template
+    pub route_cluster: Option<String>,
+    /// ids of the actions combined by the composite action, composite actions can not be nested
+    #[serde(default)]
+    pub actions: Vec<String>,
+}
+
+/// format of the body of a custom action
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentFormat {
+    Html,
+    Json,
+}
+
+impl std::default::Default for ContentFormat {
+    fn default() -> Self {
+        ContentFormat::Html
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -376,12 +588,63 @@ pub struct RawContentFilterProfile {
     #[serde(default)]
     pub ignore_body: bool,
     pub max_body_size: Option<usize>,
+    /// what to do with bodies larger than max_body_size
+    #[serde(default)]
+    pub oversized_body: OversizedBodyPolicy,
     pub max_body_depth: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    /// block requests whose uri is longer than max_uri_length, instead of only tagging them
+    #[serde(default)]
+    pub block_long_uri: bool,
     #[serde(default)]
     pub referer_as_uri: bool,
+    /// beyond these amounts, arguments, headers and cookies are not parsed and the request is tagged as truncated
+    pub max_parsed_args: Option<usize>,
+    pub max_parsed_headers: Option<usize>,
+    pub max_parsed_cookies: Option<usize>,
+    /// maximum amount of recorded multipart/form-data fields, 64 by default
+    pub max_form_fields: Option<usize>,
+    /// larger multipart/form-data values are skipped, 4096 bytes by default
+    pub max_form_value_size: Option<usize>,
+    /// joins the values of the headers whose names only differ by case, a space by default
+    pub header_join_separator: Option<String>,
     pub action: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub header_log_policy: HashMap<String, HeaderLogPolicy>,
+    /// include the text matched by the content filter rules in the block reasons, disabled by default as it might be
+    /// sensitive
+    #[serde(default)]
+    pub log_matched: bool,
+}
+
+/// how a body larger than the inspection limit is handled
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OversizedBodyPolicy {
+    /// the request is blocked, as its body can not be verified
+    BlockUnverified,
+    /// only the beginning of the body, up to the limit, is inspected
+    InspectTruncated,
+    /// the body is not inspected
+    Pass,
+}
+
+impl Default for OversizedBodyPolicy {
+    fn default() -> Self {
+        OversizedBodyPolicy::BlockUnverified
+    }
+}
+
+/// how a header value is written in the logs
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderLogPolicy {
+    Plain,
+    /// keyed hash, see HEADER_LOG_HMAC_SECRET
+    Hashed,
+    Redacted,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -1,13 +1,13 @@
 use hyperscan::Matching;
 use lazy_static::lazy_static;
 use libinjection::{sqli, xss};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::config::contentfilter::{
-    rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules, ContentFilterSection, Section,
-    SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
+    rule_matched_text, rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules,
+    ContentFilterSection, Section, SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
 };
-use crate::interface::stats::{BStageAcl, BStageContentFilter, StatsCollect};
+use crate::interface::stats::{BStageAcl, BStageContentFilter, CfCategoryStats, StatsCollect};
 use crate::interface::{BDecision, BlockReason, Initiator, Location, Tags};
 use crate::requestfields::RequestField;
 use crate::utils::{masker, RequestInfo};
@@ -124,12 +124,23 @@ pub fn content_filter_check(
         injection_check(tags, &hca_keys, &omit, test_xss, test_sqli)
     };
     if is_blocking(&iblock) {
+        let nactive = iblock.iter().filter(|r| r.decision == BDecision::Blocking).count();
+        let categories = std::iter::once((
+            "libinjection".to_string(),
+            CfCategoryStats {
+                triggered: iblock.len(),
+                active: nactive,
+            },
+        ))
+        .collect();
+        let total = mhsdb.map(|hsdb| hsdb.ids.len()).unwrap_or_default();
+        let stats = stats.cf_matches(total, iblock.len(), nactive, categories);
         return (
             Err(CfBlock {
                 blocking: true,
                 reasons: iblock,
             }),
-            stats.no_content_filter(),
+            stats,
         );
     }
 
@@ -150,6 +161,7 @@ pub fn content_filter_check(
                 &profile.report,
                 &profile.ignore,
                 &omit.exclusions,
+                profile.log_matched,
             );
             match scanresult {
                 Err(rr) => {
@@ -331,6 +343,7 @@ fn hyperscan(
     report: &HashSet<String>,
     global_ignore: &HashSet<String>,
     exclusions: &Section<HashMap<String, HashSet<String>>>,
+    log_matched: bool,
 ) -> (anyhow::Result<Vec<BlockReason>>, StatsCollect<BStageContentFilter>) {
     let scratch = match sigs.db.alloc_scratch() {
         Err(rr) => return (Err(rr), stats.no_content_filter()),
@@ -351,10 +364,11 @@ fn hyperscan(
         return (Ok(Vec::new()), stats.cf_no_match(sigs.ids.len()));
     }
 
-    let mut founds: HashSet<(&str, Location, BDecision, u8)> = HashSet::new();
+    let mut founds: HashSet<(&str, Location, BDecision, u8, Option<String>)> = HashSet::new();
 
     let mut matches = 0;
     let mut nactive = 0;
+    let mut categories: BTreeMap<String, CfCategoryStats> = BTreeMap::new();
     // something matched! but what?
     for (k, (sid, name)) in hca_keys {
         // for some reason, from is always set to 0 in my tests, so we can't accurately capture substrings
@@ -391,20 +405,26 @@ fn hyperscan(
                         } else {
                             BDecision::Monitor
                         };
-                        founds.insert((&sig.id, location, decision, sig.risk));
+                        let category = categories.entry(sig.category.clone()).or_default();
+                        category.triggered += 1;
+                        if decision == BDecision::Blocking {
+                            category.active += 1;
+                        }
+                        let matched = if log_matched { rule_matched_text(sig, &k) } else { None };
+                        founds.insert((&sig.id, location, decision, sig.risk, matched));
                     }
                 }
             }
             Matching::Continue
         });
         if let Err(rr) = scanr {
-            return (Err(rr), stats.cf_matches(sigs.ids.len(), matches, nactive));
+            return (Err(rr), stats.cf_matches(sigs.ids.len(), matches, nactive, categories));
         }
     }
     (
         Ok(founds
             .into_iter()
-            .map(|(sigid, location, decision, risk_level)| BlockReason {
+            .map(|(sigid, location, decision, risk_level, matched)| BlockReason {
                 initiator: Initiator::ContentFilter {
                     id: sigid.to_string(),
                     risk_level,
@@ -412,10 +432,13 @@ fn hyperscan(
                 location,
                 decision,
                 extra_locations: Vec::new(),
-                extra: serde_json::Value::Null,
+                extra: match matched {
+                    Some(m) => serde_json::json!({ "matched": [m] }),
+                    None => serde_json::Value::Null,
+                },
             })
             .collect()),
-        stats.cf_matches(sigs.ids.len(), matches, nactive),
+        stats.cf_matches(sigs.ids.len(), matches, nactive, categories),
     )
 }
 
@@ -490,18 +513,24 @@ mod test {
     use std::sync::Arc;
 
     use super::*;
+    use crate::config::contentfilter::resolve_rules;
     use crate::config::hostmap::SecurityPolicy;
+    use crate::config::raw::ContentFilterRule;
     use crate::config::virtualtags::VirtualTags;
-    use crate::interface::stats::Stats;
+    use crate::interface::stats::{SecpolStats, Stats};
     use crate::interface::{jsonlog, Decision};
     use crate::utils::{map_request, RequestMeta};
     use crate::{Logs, RawRequest};
 
     fn test_request_info(profile: ContentFilterProfile) -> RequestInfo {
+        test_request_info_path(profile, "/foo?arg1=avalue1&arg2=a%20value2")
+    }
+
+    fn test_request_info_path(profile: ContentFilterProfile, path: &str) -> RequestInfo {
         let meta = RequestMeta {
             authority: Some("myhost".to_string()),
             method: "GET".to_string(),
-            path: "/foo?arg1=avalue1&arg2=a%20value2".to_string(),
+            path: path.to_string(),
             extra: HashMap::default(),
             requestid: None,
         };
@@ -744,4 +773,139 @@ mod test {
             panic!("U0VDU found in {}", log_string);
         }
     }
+
+    fn cf_rule(id: &str, operand: &str, category: &str) -> ContentFilterRule {
+        ContentFilterRule {
+            id: id.to_string(),
+            operand: operand.to_string(),
+            risk: 5,
+            category: category.to_string(),
+            subcategory: format!("{}-test", category),
+            tags: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn category_stats() {
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.active.insert("cf-rule-category:sqli".to_string());
+        profile.active.insert("cf-rule-category:xss".to_string());
+        let mut logs = Logs::default();
+        let profiles = std::iter::once((profile.id.clone(), profile.clone())).collect();
+        let hsdbs = resolve_rules(
+            &mut logs,
+            &profiles,
+            vec![
+                cf_rule("100", "union\\s+select", "sqli"),
+                cf_rule("101", "drop\\s+table", "sqli"),
+                cf_rule("200", "<script", "xss"),
+            ],
+        );
+        let rinfo = test_request_info_path(profile.clone(), "/foo?q=1%20union%20select%202&c=%3Cscript%3E");
+        let mut tags = Tags::new(&VirtualTags::default());
+        let stats = StatsCollect::new(std::time::Instant::now(), "test".to_string())
+            .secpol(SecpolStats::default())
+            .mapped(0, 0)
+            .no_flow()
+            .no_limit()
+            .acl(0);
+        let (result, stats) =
+            content_filter_check(&mut logs, stats, &mut tags, &rinfo, &profile, hsdbs.get(&profile.id));
+        assert!(result.is_err());
+        let stats = stats.cf_stage_build();
+        let expected: BTreeMap<String, CfCategoryStats> = vec![
+            (
+                "sqli".to_string(),
+                CfCategoryStats {
+                    triggered: 1,
+                    active: 1,
+                },
+            ),
+            (
+                "xss".to_string(),
+                CfCategoryStats {
+                    triggered: 1,
+                    active: 1,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stats.content_filter_categories, expected);
+
+        let (logged, _) = async_std::task::block_on(jsonlog(
+            &Decision::pass(Vec::new()),
+            Some(&rinfo),
+            None,
+            &tags,
+            &stats,
+            &Logs::default(),
+            HashMap::new(),
+        ));
+        let log: serde_json::Value = serde_json::from_slice(&logged).unwrap();
+        assert_eq!(
+            log["cf_categories"],
+            serde_json::json!({"sqli": {"triggered": 1, "active": 1}, "xss": {"triggered": 1, "active": 1}})
+        );
+    }
+
+    #[test]
+    fn libinjection_category_stats() {
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.active.insert("cf-rule-category:libinjection".to_string());
+        let rinfo = test_request_info_path(profile.clone(), "/foo?q=1%27%20OR%20%271%27%3D%271");
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let stats = StatsCollect::new(std::time::Instant::now(), "test".to_string())
+            .secpol(SecpolStats::default())
+            .mapped(0, 0)
+            .no_flow()
+            .no_limit()
+            .acl(0);
+        let (result, stats) = content_filter_check(&mut logs, stats, &mut tags, &rinfo, &profile, None);
+        assert!(result.unwrap_err().blocking);
+        let stats = stats.cf_stage_build();
+        assert_eq!(
+            stats.content_filter_categories.get("libinjection"),
+            Some(&CfCategoryStats {
+                triggered: 1,
+                active: 1,
+            })
+        );
+    }
+
+    fn matched_reasons_extra(log_matched: bool) -> Vec<serde_json::Value> {
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.active.insert("cf-rule-category:sqli".to_string());
+        profile.log_matched = log_matched;
+        let mut logs = Logs::default();
+        let profiles = std::iter::once((profile.id.clone(), profile.clone())).collect();
+        let hsdbs = resolve_rules(&mut logs, &profiles, vec![cf_rule("100", "union\\s+select", "sqli")]);
+        let rinfo = test_request_info_path(profile.clone(), "/foo?q=1%20UNION%20%20select%202");
+        let mut tags = Tags::new(&VirtualTags::default());
+        let stats = StatsCollect::new(std::time::Instant::now(), "test".to_string())
+            .secpol(SecpolStats::default())
+            .mapped(0, 0)
+            .no_flow()
+            .no_limit()
+            .acl(0);
+        let (result, _) = content_filter_check(&mut logs, stats, &mut tags, &rinfo, &profile, hsdbs.get(&profile.id));
+        result
+            .unwrap_err()
+            .reasons
+            .into_iter()
+            .filter(|r| matches!(&r.initiator, Initiator::ContentFilter { id, .. } if id == "100"))
+            .map(|r| r.extra)
+            .collect()
+    }
+
+    #[test]
+    fn matched_text_in_reasons() {
+        assert_eq!(
+            matched_reasons_extra(true),
+            vec![serde_json::json!({"matched": ["UNION  select"]})]
+        );
+        // not logged by default
+        assert_eq!(matched_reasons_extra(false), vec![serde_json::Value::Null]);
+    }
 }
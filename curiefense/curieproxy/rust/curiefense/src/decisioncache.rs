@@ -0,0 +1,252 @@
+/// short lived cache of decisions, for identical requests
+///
+/// The cache is disabled by default, and is enabled by setting the DECISION_CACHE_TTL environment
+/// variable to a non zero amount of seconds.
+///
+/// Requests that are subject to limits or flow controls are never cached, as their decision depends
+/// on the redis state. Neither are requests that could get an action rendered for them (templates,
+/// nonces, challenges), as the rendered action would be replayed to other requests. The tags and the
+/// decision of the global filters are part of the signature, so that time dependent filters are
+/// honored.
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::analyze::APhase0;
+use crate::flow::session_sequence_key;
+use crate::interface::{Decision, Location, SimpleDecision, Tags};
+use crate::requestfields::RequestField;
+
+/// maximum amount of entries, the cache is flushed when it is reached
+const DECISION_CACHE_MAX_SIZE: usize = 10000;
+
+lazy_static! {
+    pub static ref DECISION_CACHE: Option<Mutex<DecisionCache>> = std::env::var("DECISION_CACHE_TTL")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .filter(|ttl| *ttl > 0)
+        .map(|ttl| Mutex::new(DecisionCache::new(Duration::from_secs(ttl))));
+}
+
+pub struct DecisionCache {
+    ttl: Duration,
+    entries: HashMap<String, (Instant, Decision, Tags)>,
+}
+
+impl DecisionCache {
+    pub fn new(ttl: Duration) -> Self {
+        DecisionCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// returns the cached decision, with the decision-cached tag added
+    pub fn get(&mut self, key: &str) -> Option<(Decision, Tags)> {
+        let expired = match self.entries.get(key) {
+            None => return None,
+            Some((inserted, _, _)) => inserted.elapsed() > self.ttl,
+        };
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        let (_, decision, tags) = self.entries.get(key)?;
+        let mut tags = tags.clone();
+        tags.insert("decision-cached", Location::Request);
+        Some((decision.clone(), tags))
+    }
+
+    pub fn insert(&mut self, key: String, decision: &Decision, tags: &Tags) {
+        if self.entries.len() >= DECISION_CACHE_MAX_SIZE {
+            let ttl = self.ttl;
+            self.entries.retain(|_, (inserted, _, _)| inserted.elapsed() <= ttl);
+            if self.entries.len() >= DECISION_CACHE_MAX_SIZE {
+                self.entries.clear();
+            }
+        }
+        self.entries
+            .insert(key, (Instant::now(), decision.clone(), tags.clone()));
+    }
+}
+
+fn hash_field(hasher: &mut Sha256, name: &str, field: &RequestField, excluded: &[&str]) {
+    let mut entries: Vec<(&str, &str)> = field.iter().filter(|(k, _)| !excluded.contains(k)).collect();
+    entries.sort_unstable();
+    for (k, v) in entries {
+        hasher.update(name);
+        hasher.update([0u8]);
+        hasher.update(k);
+        hasher.update([0u8]);
+        hasher.update(v);
+        hasher.update([0u8]);
+    }
+}
+
+/// true when all the actions the request could get render the same way for every request
+fn static_actions(p0: &APhase0) -> bool {
+    let secpol = &p0.reqinfo.rinfo.secpolicy;
+    let globalfilter_static = match &p0.globalfilter_dec {
+        SimpleDecision::Pass => true,
+        SimpleDecision::Action(action, _) => action.is_static(),
+    };
+    globalfilter_static && secpol.acl_profile.action.is_static() && secpol.content_filter_profile.action.is_static()
+}
+
+/// computes the normalized signature of a request
+///
+/// returns None when the request can't be cached, because its decision depends on limits or flow controls, or
+/// because its action would be rendered for this request only
+pub fn decision_cache_key(p0: &APhase0) -> Option<String> {
+    let reqinfo = &p0.reqinfo;
+    let secpol = &reqinfo.rinfo.secpolicy;
+    if !secpol.limits.is_empty() || p0.flows.contains_key(&session_sequence_key(reqinfo)) || !static_actions(p0) {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    for s in [
+        secpol.policy.id.as_str(),
+        secpol.entry.id.as_str(),
+        if p0.is_human { "human" } else { "bot" },
+        reqinfo.rinfo.meta.method.as_str(),
+        reqinfo.rinfo.host.as_str(),
+        reqinfo.rinfo.qinfo.uri.as_str(),
+        reqinfo.rinfo.geoip.ipstr.as_str(),
+    ] {
+        hasher.update(s);
+        hasher.update([0u8]);
+    }
+    // the request id is unique per request, and must not be part of the signature
+    hash_field(&mut hasher, "h", &reqinfo.headers, &["x-request-id"]);
+    hash_field(&mut hasher, "c", &reqinfo.cookies, &[]);
+    hash_field(&mut hasher, "a", &reqinfo.rinfo.qinfo.args, &[]);
+    hash_field(&mut hasher, "p", &reqinfo.plugins, &[]);
+    // the global filters ran on this request, their result can depend on more than the hashed fields (the time)
+    let mut tags: Vec<&String> = p0.itags.as_hash_ref().keys().collect();
+    tags.sort_unstable();
+    for tag in tags {
+        hasher.update("t");
+        hasher.update([0u8]);
+        hasher.update(tag);
+        hasher.update([0u8]);
+    }
+    match &p0.globalfilter_dec {
+        SimpleDecision::Pass => hasher.update("pass"),
+        SimpleDecision::Action(_, reasons) => {
+            for reason in reasons {
+                // the matched locations are not part of it, their order is not stable
+                hasher.update(format!("{} {}", reason.decision, reason.initiator));
+                hasher.update([0u8]);
+            }
+        }
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::limit::Limit;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::stats::{SecpolStats, StatsCollect};
+    use crate::interface::{BDecision, BlockReason, SimpleAction, SimpleActionT};
+    use crate::utils::RequestBuilder;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn mk_p0(secpol: SecurityPolicy, requestid: &str) -> APhase0 {
+        let secpol = Arc::new(secpol);
+        let stats = StatsCollect::new(Instant::now(), "test".to_string()).secpol(SecpolStats::build(&secpol, 0));
+        let reqinfo = RequestBuilder::new("GET", "/a/b?c=d")
+            .ip("1.2.3.4")
+            .authority("main.site")
+            .header("user-agent", "curl")
+            .header("x-request-id", requestid)
+            .requestid(requestid)
+            .security_policy(secpol)
+            .build();
+        APhase0 {
+            flows: HashMap::new(),
+            globalfilter_dec: SimpleDecision::Pass,
+            is_human: false,
+            itags: Tags::new(&VirtualTags::default()),
+            reqinfo,
+            stats: stats.mapped(0, 0),
+        }
+    }
+
+    #[test]
+    fn identical_requests_hit() {
+        let mut cache = DecisionCache::new(Duration::from_secs(60));
+        let key1 = decision_cache_key(&mk_p0(SecurityPolicy::empty(), "id1")).unwrap();
+        let key2 = decision_cache_key(&mk_p0(SecurityPolicy::empty(), "id2")).unwrap();
+        assert_eq!(key1, key2);
+        assert!(cache.get(&key1).is_none());
+        cache.insert(key1, &Decision::pass(Vec::new()), &Tags::new(&VirtualTags::default()));
+        let (decision, tags) = cache.get(&key2).unwrap();
+        assert!(!decision.is_blocking());
+        assert!(tags.contains("decision-cached"));
+    }
+
+    #[test]
+    fn limits_are_not_cached() {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.limits.push(Limit {
+            id: "lid".to_string(),
+            name: "limit".to_string(),
+            timeframe: 60,
+            thresholds: Vec::new(),
+            exclude: Default::default(),
+            include: Default::default(),
+            pairwith: None,
+            key: Vec::new(),
+            key_template: None,
+            tags: Vec::new(),
+            bucket: None,
+            retry_after: false,
+            entry_overrides: Arc::default(),
+        });
+        assert!(decision_cache_key(&mk_p0(secpol, "id1")).is_none());
+    }
+
+    #[test]
+    fn global_filter_results_are_hashed() {
+        let key = decision_cache_key(&mk_p0(SecurityPolicy::empty(), "id1")).unwrap();
+
+        let mut tagged = mk_p0(SecurityPolicy::empty(), "id1");
+        tagged.itags.insert("business-hours", Location::Request);
+        assert_ne!(decision_cache_key(&tagged).unwrap(), key);
+
+        let mut monitored = mk_p0(SecurityPolicy::empty(), "id1");
+        monitored.globalfilter_dec = SimpleDecision::Action(
+            SimpleAction {
+                atype: SimpleActionT::Monitor,
+                ..SimpleAction::default()
+            },
+            vec![BlockReason::global_filter(
+                "gf".to_string(),
+                "gf".to_string(),
+                BDecision::Monitor,
+                &HashSet::new(),
+            )],
+        );
+        assert_ne!(decision_cache_key(&monitored).unwrap(), key);
+    }
+
+    #[test]
+    fn rendered_actions_are_not_cached() {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.content_filter_profile.action.headers = Some(
+            std::iter::once((
+                "x-request".to_string(),
+                crate::utils::templating::parse_request_template("${headers.x-request-id}"),
+            ))
+            .collect(),
+        );
+        assert!(decision_cache_key(&mk_p0(secpol, "id1")).is_none());
+    }
+}
@@ -6,10 +6,10 @@ use crate::Logs;
 use crate::config::flow::{FlowElement, FlowMap, SequenceKey};
 use crate::config::matchers::RequestSelector;
 use crate::interface::{Location, Tags};
-use crate::redis::REDIS_KEY_PREFIX;
+use crate::redis::{QueryMode, REDIS_KEY_PREFIX};
 use crate::utils::{check_selector_cond, select_string, RequestInfo};
 
-fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
+pub fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
     SequenceKey(ri.rinfo.meta.method.to_string() + &ri.rinfo.host + &ri.rinfo.qinfo.qpath)
 }
 
@@ -98,6 +98,7 @@ pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
     redis: &mut ConnectionManager,
     iter: &mut I,
     checks: Vec<FlowCheck>,
+    mode: QueryMode,
 ) -> anyhow::Result<Vec<FlowResult>> {
     let mut out = Vec::new();
     for check in checks {
@@ -112,7 +113,7 @@ pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
                 FlowResultType::LastBlock
             }
         } else {
-            if check.step as usize == listlen {
+            if check.step as usize == listlen && mode == QueryMode::Update {
                 let (_, mexpire): ((), Option<i64>) = redis::pipe()
                     .cmd("LPUSH")
                     .arg(&check.redis_key)
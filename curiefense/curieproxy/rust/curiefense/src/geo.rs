@@ -13,9 +13,12 @@ use maxminddb::{
 };
 use serde::Deserialize;
 
-#[cfg(not(test))]
-use std::ops::Deref;
-use std::{collections::HashMap, net::IpAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
 use crate::ipinfo::{AsnDetails, CarrierDetails, CompanyDetails, LocationDetails, PrivacyDetails};
 
@@ -27,14 +30,14 @@ const IPINFO_COUNTRY_IN_EU_RAW: &str = include_str!("../assets/ipinfo/eu.json");
 const IPINFO_CONTINENT_RAW: &str = include_str!("../assets/ipinfo/continent.json");
 
 #[allow(dead_code)]
-struct MaxmindGeo {
+pub struct MaxmindGeo {
     asn: Reader<Vec<u8>>,
     country: Reader<Vec<u8>>,
     city: Reader<Vec<u8>>,
 }
 
 #[allow(dead_code)]
-struct IpinfoGeo {
+pub struct IpinfoGeo {
     location: Reader<Vec<u8>>,
     company: Reader<Vec<u8>>,
     asn: Reader<Vec<u8>>,
@@ -42,6 +45,38 @@ struct IpinfoGeo {
     carrier: Reader<Vec<u8>>,
 }
 
+/// a database that can be replaced while lookups are running
+///
+/// Lookups clone the current handle and release the lock right away, so a reload never stalls them, and they keep
+/// using the database they started with.
+pub struct Reloadable<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Reloadable {
+            current: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(cur) => cur.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// atomically replaces the database, returning the previous one
+    pub fn swap(&self, value: T) -> Arc<T> {
+        let new = Arc::new(value);
+        match self.current.write() {
+            Ok(mut cur) => std::mem::replace(&mut *cur, new),
+            Err(poisoned) => std::mem::replace(&mut *poisoned.into_inner(), new),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct IpInfoContinent<'a> {
     pub code: &'a str,
@@ -52,63 +87,112 @@ lazy_static! {
     // as they are lazy, these loads will not be triggered in test mode
     pub static ref USE_IPINFO: bool = std::env::var("USE_IPINFO").map(|s| s.parse().unwrap_or(false)).unwrap_or(false);
 
-    static ref MAXMIND: anyhow::Result<MaxmindGeo> = {
-        let maxmind_root = std::env::var("MAXMIND_ROOT").unwrap_or_else(|_| "/cf-config/current/config/maxmind".to_string());
-        let maxmind_asn = std::env::var("MAXMIND_ASN").unwrap_or_else(|_| "GeoLite2-ASN.mmdb".to_string());
-        let maxmind_country = std::env::var("MAXMIND_COUNTRY").unwrap_or_else(|_| "GeoLite2-Country.mmdb".to_string());
-        let maxmind_city = std::env::var("MAXMIND_CITY").unwrap_or_else(|_| "GeoLite2-City.mmdb".to_string());
-
-        let root_path = PathBuf::from(maxmind_root);
-        let mut asn_path = root_path.clone();
-        asn_path.push(maxmind_asn);
-        let mut country_path = root_path.clone();
-        country_path.push(maxmind_country);
-        let mut city_path = root_path;
-        city_path.push(maxmind_city);
-        Reader::open_readfile(asn_path)
-            .and_then(|asn| Reader::open_readfile(country_path)
-            .and_then(|country| Reader::open_readfile(city_path)
-            .map(|city| MaxmindGeo { asn, country, city } ))).map_err(|rr| anyhow!("{}", rr))
-    };
-
-
-    static ref IPINFO: anyhow::Result<IpinfoGeo> = {
-        let ipinfo_root = std::env::var("IPINFO_ROOT");
-        let ipinfo_location = std::env::var("IPINFO_LOCATION");
-        let ipinfo_company = std::env::var("IPINFO_COMPANY");
-        let ipinfo_asn = std::env::var("IPINFO_ASN");
-        let ipinfo_privacy = std::env::var("IPINFO_PRIVACY");
-        let ipinfo_carrier = std::env::var("IPINFO_CARRIER");
-
-        match (ipinfo_root, ipinfo_location, ipinfo_company, ipinfo_asn, ipinfo_privacy, ipinfo_carrier) {
-            (Ok(root), Ok(location), Ok(company), Ok(asn), Ok(privacy), Ok(carrier)) => {
-                    let root_path = PathBuf::from(root);
-                    let mut location_path = root_path.clone();
-                    location_path.push(location);
-                    let mut company_path = root_path.clone();
-                    company_path.push(company);
-                    let mut asn_path = root_path.clone();
-                    asn_path.push(asn);
-                    let mut privacy_path = root_path.clone();
-                    privacy_path.push(privacy);
-                    let mut carrier_path = root_path;
-                    carrier_path.push(carrier);
-                    Reader::open_readfile(location_path)
-                        .and_then(|location| Reader::open_readfile(company_path)
-                        .and_then(|company| Reader::open_readfile(privacy_path)
-                        .and_then(|asn| Reader::open_readfile(asn_path)
-                        .and_then(|privacy| Reader::open_readfile(carrier_path)
-                        .map(|carrier| IpinfoGeo { location, company, asn, privacy, carrier } ))))).map_err(|rr| anyhow!("{}", rr))
-            }
-            _ => Err(anyhow!("Could not read ipinfo")) // TODO: add actual error in Err
-        }
-    };
+    static ref MAXMIND: Reloadable<anyhow::Result<MaxmindGeo>> = Reloadable::new(load_maxmind());
+    static ref IPINFO: Reloadable<anyhow::Result<IpinfoGeo>> = Reloadable::new(load_ipinfo());
     static ref IPINFO_COUNTRY_NAME: HashMap<&'static str, &'static str> = serde_json::from_str(IPINFO_COUNTRY_NAME_RAW).unwrap();
     static ref IPINFO_COUNTRY_IN_EU: Vec<&'static str> = serde_json::from_str(IPINFO_COUNTRY_IN_EU_RAW).unwrap();
     static ref IPINFO_CONTINENT: HashMap<&'static str, IpInfoContinent<'static>> = serde_json::from_str(IPINFO_CONTINENT_RAW).unwrap();
 
 }
 
+fn load_maxmind() -> anyhow::Result<MaxmindGeo> {
+    let maxmind_root =
+        std::env::var("MAXMIND_ROOT").unwrap_or_else(|_| "/cf-config/current/config/maxmind".to_string());
+    let maxmind_asn = std::env::var("MAXMIND_ASN").unwrap_or_else(|_| "GeoLite2-ASN.mmdb".to_string());
+    let maxmind_country = std::env::var("MAXMIND_COUNTRY").unwrap_or_else(|_| "GeoLite2-Country.mmdb".to_string());
+    let maxmind_city = std::env::var("MAXMIND_CITY").unwrap_or_else(|_| "GeoLite2-City.mmdb".to_string());
+
+    let root_path = PathBuf::from(maxmind_root);
+    let mut asn_path = root_path.clone();
+    asn_path.push(maxmind_asn);
+    let mut country_path = root_path.clone();
+    country_path.push(maxmind_country);
+    let mut city_path = root_path;
+    city_path.push(maxmind_city);
+    Reader::open_readfile(asn_path)
+        .and_then(|asn| {
+            Reader::open_readfile(country_path)
+                .and_then(|country| Reader::open_readfile(city_path).map(|city| MaxmindGeo { asn, country, city }))
+        })
+        .map_err(|rr| anyhow!("{}", rr))
+}
+
+fn load_ipinfo() -> anyhow::Result<IpinfoGeo> {
+    let ipinfo_root = std::env::var("IPINFO_ROOT");
+    let ipinfo_location = std::env::var("IPINFO_LOCATION");
+    let ipinfo_company = std::env::var("IPINFO_COMPANY");
+    let ipinfo_asn = std::env::var("IPINFO_ASN");
+    let ipinfo_privacy = std::env::var("IPINFO_PRIVACY");
+    let ipinfo_carrier = std::env::var("IPINFO_CARRIER");
+
+    match (
+        ipinfo_root,
+        ipinfo_location,
+        ipinfo_company,
+        ipinfo_asn,
+        ipinfo_privacy,
+        ipinfo_carrier,
+    ) {
+        (Ok(root), Ok(location), Ok(company), Ok(asn), Ok(privacy), Ok(carrier)) => {
+            let root_path = PathBuf::from(root);
+            let mut location_path = root_path.clone();
+            location_path.push(location);
+            let mut company_path = root_path.clone();
+            company_path.push(company);
+            let mut asn_path = root_path.clone();
+            asn_path.push(asn);
+            let mut privacy_path = root_path.clone();
+            privacy_path.push(privacy);
+            let mut carrier_path = root_path;
+            carrier_path.push(carrier);
+            Reader::open_readfile(location_path)
+                .and_then(|location| {
+                    Reader::open_readfile(company_path).and_then(|company| {
+                        Reader::open_readfile(privacy_path).and_then(|asn| {
+                            Reader::open_readfile(asn_path).and_then(|privacy| {
+                                Reader::open_readfile(carrier_path).map(|carrier| IpinfoGeo {
+                                    location,
+                                    company,
+                                    asn,
+                                    privacy,
+                                    carrier,
+                                })
+                            })
+                        })
+                    })
+                })
+                .map_err(|rr| anyhow!("{}", rr))
+        }
+        _ => Err(anyhow!("Could not read ipinfo")), // TODO: add actual error in Err
+    }
+}
+
+/// the current MaxMind databases, the maxmind lookups borrow from this handle
+#[cfg(not(test))]
+pub fn maxmind_db() -> Arc<anyhow::Result<MaxmindGeo>> {
+    MAXMIND.get()
+}
+
+/// the current ipinfo databases, to be shared by all the ipinfo lookups of a request
+#[cfg(not(test))]
+pub fn ipinfo_db() -> Arc<anyhow::Result<IpinfoGeo>> {
+    IPINFO.get()
+}
+
+/// reloads the geoip databases from disk, without stalling the lookups in progress
+///
+/// The current databases are kept when the new ones can't be read.
+pub fn reload_geoip() -> Result<(), String> {
+    if *USE_IPINFO {
+        let db = load_ipinfo().map_err(|rr| format!("could not reload the ipinfo databases: {}", rr))?;
+        IPINFO.swap(Ok(db));
+    } else {
+        let db = load_maxmind().map_err(|rr| format!("could not reload the maxmind databases: {}", rr))?;
+        MAXMIND.swap(Ok(db));
+    }
+    Ok(())
+}
+
 pub fn ipinfo_resolve_country_name(country_iso: &str) -> Option<String> {
     IPINFO_COUNTRY_NAME.get(country_iso).map(|c| c.to_string())
 }
@@ -129,12 +213,15 @@ fn compute_network<T>(data: T, addr: IpAddr, prefix_len: usize) -> (T, Option<Ip
 
 /// Retrieves the english name of the country associated with this IP
 #[cfg(not(test))]
-pub fn get_maxmind_country(addr: IpAddr) -> Result<(Country<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_country(
+    db: &anyhow::Result<MaxmindGeo>,
+    addr: IpAddr,
+) -> Result<(Country<'_>, Option<IpNet>), String> {
     if *USE_IPINFO {
         return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
     }
 
-    match MAXMIND.deref() {
+    match db {
         Err(rr) => Err(format!("could not read country db: {}", rr)),
         Ok(maxmind) => match maxmind.country.lookup_prefix(addr) {
             Ok((country, prefix_len)) => Ok(compute_network::<Country>(country, addr, prefix_len)),
@@ -144,12 +231,12 @@ pub fn get_maxmind_country(addr: IpAddr) -> Result<(Country<'static>, Option<IpN
 }
 
 #[cfg(not(test))]
-pub fn get_maxmind_asn(addr: IpAddr) -> Result<(Asn<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_asn(db: &anyhow::Result<MaxmindGeo>, addr: IpAddr) -> Result<(Asn<'_>, Option<IpNet>), String> {
     if *USE_IPINFO {
         return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
     }
 
-    match MAXMIND.deref() {
+    match db {
         Err(rr) => Err(format!("could not read ASN db: {}", rr)),
         Ok(maxmind) => match maxmind.asn.lookup_prefix(addr) {
             Ok((asn, prefix_len)) => Ok(compute_network::<Asn>(asn, addr, prefix_len)),
@@ -159,12 +246,12 @@ pub fn get_maxmind_asn(addr: IpAddr) -> Result<(Asn<'static>, Option<IpNet>), St
 }
 
 #[cfg(not(test))]
-pub fn get_maxmind_city(addr: IpAddr) -> Result<(City<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_city(db: &anyhow::Result<MaxmindGeo>, addr: IpAddr) -> Result<(City<'_>, Option<IpNet>), String> {
     if *USE_IPINFO {
         return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
     }
 
-    match MAXMIND.deref() {
+    match db {
         Err(rr) => Err(format!("could not read city db: {}", rr)),
         Ok(maxmind) => match maxmind.city.lookup_prefix(addr) {
             Ok((city, prefix_len)) => Ok(compute_network::<City>(city, addr, prefix_len)),
@@ -174,12 +261,15 @@ pub fn get_maxmind_city(addr: IpAddr) -> Result<(City<'static>, Option<IpNet>),
 }
 
 #[cfg(not(test))]
-pub fn get_ipinfo_location(addr: IpAddr) -> Result<(LocationDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_location(
+    db: &anyhow::Result<IpinfoGeo>,
+    addr: IpAddr,
+) -> Result<(LocationDetails, Option<IpNet>), String> {
     if !(*USE_IPINFO) {
         return Err("Ipinfo is not enabled. You can enable it by setting USE_IPINFO=true".to_string());
     }
 
-    match IPINFO.deref() {
+    match db {
         Err(rr) => Err(format!("could not read city db: {}", rr)),
         Ok(ipinfo) => match ipinfo.location.lookup_prefix(addr) {
             Ok((loc, prefix_len)) => Ok(compute_network::<LocationDetails>(loc, addr, prefix_len)),
@@ -189,12 +279,15 @@ pub fn get_ipinfo_location(addr: IpAddr) -> Result<(LocationDetails, Option<IpNe
 }
 
 #[cfg(not(test))]
-pub fn get_ipinfo_privacy(addr: IpAddr) -> Result<(PrivacyDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_privacy(
+    db: &anyhow::Result<IpinfoGeo>,
+    addr: IpAddr,
+) -> Result<(PrivacyDetails, Option<IpNet>), String> {
     if !(*USE_IPINFO) {
         return Err("Ipinfo is not enabled. You can enable it by setting USE_IPINFO=true".to_string());
     }
 
-    match IPINFO.deref() {
+    match db {
         Err(rr) => Err(format!("could not read city db: {}", rr)),
         Ok(ipinfo) => match ipinfo.privacy.lookup_prefix(addr) {
             Ok((privacy, prefix_len)) => Ok(compute_network::<PrivacyDetails>(privacy, addr, prefix_len)),
@@ -204,12 +297,15 @@ pub fn get_ipinfo_privacy(addr: IpAddr) -> Result<(PrivacyDetails, Option<IpNet>
 }
 
 #[cfg(not(test))]
-pub fn get_ipinfo_company(addr: IpAddr) -> Result<(CompanyDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_company(
+    db: &anyhow::Result<IpinfoGeo>,
+    addr: IpAddr,
+) -> Result<(CompanyDetails, Option<IpNet>), String> {
     if !(*USE_IPINFO) {
         return Err("Ipinfo is not enabled. You can enable it by setting USE_IPINFO=true".to_string());
     }
 
-    match IPINFO.deref() {
+    match db {
         Err(rr) => Err(format!("could not read city db: {}", rr)),
         Ok(ipinfo) => match ipinfo.company.lookup_prefix(addr) {
             Ok((comp, prefix_len)) => Ok(compute_network::<CompanyDetails>(comp, addr, prefix_len)),
@@ -219,12 +315,12 @@ pub fn get_ipinfo_company(addr: IpAddr) -> Result<(CompanyDetails, Option<IpNet>
 }
 
 #[cfg(not(test))]
-pub fn get_ipinfo_asn(addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_asn(db: &anyhow::Result<IpinfoGeo>, addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), String> {
     if !(*USE_IPINFO) {
         return Err("Ipinfo is not enabled. You can enable it by setting USE_IPINFO=true".to_string());
     }
 
-    match IPINFO.deref() {
+    match db {
         Err(rr) => Err(format!("could not read asn db: {}", rr)),
         Ok(ipinfo) => match ipinfo.asn.lookup_prefix(addr) {
             Ok((asn, prefix_len)) => Ok(compute_network::<AsnDetails>(asn, addr, prefix_len)),
@@ -234,12 +330,15 @@ pub fn get_ipinfo_asn(addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), Strin
 }
 
 #[cfg(not(test))]
-pub fn get_ipinfo_carrier(addr: IpAddr) -> Result<(CarrierDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_carrier(
+    db: &anyhow::Result<IpinfoGeo>,
+    addr: IpAddr,
+) -> Result<(CarrierDetails, Option<IpNet>), String> {
     if !(*USE_IPINFO) {
         return Err("Ipinfo is not enabled. You can enable it by setting USE_IPINFO=true".to_string());
     }
 
-    match IPINFO.deref() {
+    match db {
         Err(rr) => Err(format!("could not read city db: {}", rr)),
         Ok(ipinfo) => match ipinfo.carrier.lookup_prefix(addr) {
             Ok((car, prefix_len)) => Ok(compute_network::<CarrierDetails>(car, addr, prefix_len)),
@@ -249,41 +348,91 @@ pub fn get_ipinfo_carrier(addr: IpAddr) -> Result<(CarrierDetails, Option<IpNet>
 }
 
 #[cfg(test)]
-pub fn get_maxmind_country(_addr: IpAddr) -> Result<(Country<'static>, Option<IpNet>), String> {
+pub fn maxmind_db() -> Arc<anyhow::Result<MaxmindGeo>> {
+    Arc::new(Err(anyhow!("TEST")))
+}
+
+#[cfg(test)]
+pub fn ipinfo_db() -> Arc<anyhow::Result<IpinfoGeo>> {
+    Arc::new(Err(anyhow!("TEST")))
+}
+
+#[cfg(test)]
+pub fn get_maxmind_country(
+    _db: &anyhow::Result<MaxmindGeo>,
+    _addr: IpAddr,
+) -> Result<(Country<'static>, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_maxmind_asn(_addr: IpAddr) -> Result<(Asn<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_asn(
+    _db: &anyhow::Result<MaxmindGeo>,
+    _addr: IpAddr,
+) -> Result<(Asn<'static>, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_maxmind_city(_addr: IpAddr) -> Result<(City<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_city(
+    _db: &anyhow::Result<MaxmindGeo>,
+    _addr: IpAddr,
+) -> Result<(City<'static>, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_ipinfo_location(_addr: IpAddr) -> Result<(LocationDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_location(
+    _db: &anyhow::Result<IpinfoGeo>,
+    _addr: IpAddr,
+) -> Result<(LocationDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_ipinfo_privacy(_addr: IpAddr) -> Result<(PrivacyDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_privacy(
+    _db: &anyhow::Result<IpinfoGeo>,
+    _addr: IpAddr,
+) -> Result<(PrivacyDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_ipinfo_company(_addr: IpAddr) -> Result<(CompanyDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_company(
+    _db: &anyhow::Result<IpinfoGeo>,
+    _addr: IpAddr,
+) -> Result<(CompanyDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_ipinfo_asn(_addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_asn(_db: &anyhow::Result<IpinfoGeo>, _addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_ipinfo_carrier(_addr: IpAddr) -> Result<(CarrierDetails, Option<IpNet>), String> {
+pub fn get_ipinfo_carrier(
+    _db: &anyhow::Result<IpinfoGeo>,
+    _addr: IpAddr,
+) -> Result<(CarrierDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_after_swap() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let db = Reloadable::new(vec![(ip, "old")].into_iter().collect::<HashMap<IpAddr, &str>>());
+        let inflight = db.get();
+        assert_eq!(inflight.get(&ip), Some(&"old"));
+
+        let previous = db.swap(vec![(ip, "new")].into_iter().collect());
+        assert_eq!(previous.get(&ip), Some(&"old"));
+        assert_eq!(db.get().get(&ip), Some(&"new"));
+        // a lookup started before the swap keeps its database
+        assert_eq!(inflight.get(&ip), Some(&"old"));
+    }
+}
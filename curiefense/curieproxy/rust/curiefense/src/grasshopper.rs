@@ -1,9 +1,72 @@
-use crate::interface::BlockReason;
+use crate::config::matchers::RequestSelector;
+use crate::interface::{BlockReason, ChallengeMarker};
 use crate::requestfields::RequestField;
+use crate::tagging::identity_hash;
+use crate::utils::{select_string, RequestInfo};
 use crate::{Action, ActionType, Decision};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 
+/// the humanity announced in the override field, values other than `true`/`human` and `false`/`bot` are ignored
+pub fn human_override(sel: &RequestSelector, reqinfo: &RequestInfo) -> Option<bool> {
+    match select_string(reqinfo, sel, None)?.trim().to_ascii_lowercase().as_str() {
+        "true" | "human" => Some(true),
+        "false" | "bot" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+}
+
+/// attributes of the challenge cookie, deployments sharing a domain can use distinct names and paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeCookie {
+    pub name: String,
+    pub path: String,
+    pub same_site: Option<SameSite>,
+}
+
+impl Default for ChallengeCookie {
+    fn default() -> Self {
+        ChallengeCookie {
+            name: "rbzid".to_string(),
+            path: "/".to_string(),
+            same_site: None,
+        }
+    }
+}
+
+impl ChallengeCookie {
+    /// value of the Set-Cookie header
+    pub fn set_cookie(&self, value: &str) -> String {
+        let mut cookie = format!("{}={}; Path={}; HttpOnly", self.name, value, self.path);
+        match self.same_site {
+            None => (),
+            Some(SameSite::Strict) => cookie += "; SameSite=Strict",
+            Some(SameSite::Lax) => cookie += "; SameSite=Lax",
+            // browsers reject SameSite=None cookies that are not secure
+            Some(SameSite::None) => cookie += "; SameSite=None; Secure",
+        }
+        cookie
+    }
+}
+
 pub trait Grasshopper {
     fn js_app(&self) -> Option<String>;
     fn js_bio(&self) -> Option<String>;
@@ -45,6 +108,101 @@ impl Grasshopper for DummyGrasshopper {
     }
 }
 
+/// a configurable grasshopper, for tests and examples
+///
+/// All the methods return None unless configured with the corresponding builder method.
+///
+/// ```
+/// use curiefense::config::hostmap::SecurityPolicy;
+/// use curiefense::config::raw::ChallengeFallback;
+/// use curiefense::config::virtualtags::VirtualTags;
+/// use curiefense::grasshopper::MockGrasshopper;
+/// use curiefense::interface::{SimpleAction, SimpleActionT, Tags};
+/// use curiefense::utils::RequestBuilder;
+/// use std::sync::Arc;
+///
+/// let rinfo = RequestBuilder::new("GET", "/")
+///     .ip("1.2.3.4")
+///     .authority("main.site")
+///     .header("user-agent", "curl/7.58.0")
+///     .security_policy(Arc::new(SecurityPolicy::empty()))
+///     .build();
+/// let action = SimpleAction {
+///     atype: SimpleActionT::Challenge {
+///         fallback: ChallengeFallback::Block,
+///     },
+///     ..SimpleAction::default()
+/// };
+///
+/// // the challenge page is served when the engine works
+/// let gh = MockGrasshopper::default().with_js_app("chall_lib").with_seed("seed");
+/// let mut tags = Tags::new(&VirtualTags::default());
+/// let decision = action.to_decision(false, Some(&gh), &rinfo, &mut tags, Vec::new());
+/// let challenge = decision.maction.unwrap();
+/// assert_eq!(challenge.status, 247);
+/// assert!(challenge.content.contains("chall_lib"));
+///
+/// // and an internal error is returned when it can't generate a seed
+/// let gh = MockGrasshopper::default().with_js_app("chall_lib");
+/// let decision = action.to_decision(false, Some(&gh), &rinfo, &mut tags, Vec::new());
+/// assert_eq!(decision.maction.unwrap().status, 500);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockGrasshopper {
+    js_app: Option<String>,
+    js_bio: Option<String>,
+    rbzid: Option<bool>,
+    seed: Option<String>,
+    workproof: Option<String>,
+}
+
+impl MockGrasshopper {
+    pub fn with_js_app(mut self, js_app: &str) -> Self {
+        self.js_app = Some(js_app.to_string());
+        self
+    }
+
+    pub fn with_js_bio(mut self, js_bio: &str) -> Self {
+        self.js_bio = Some(js_bio.to_string());
+        self
+    }
+
+    /// result of parse_rbzid, true when the client is a human
+    pub fn with_rbzid(mut self, is_human: bool) -> Self {
+        self.rbzid = Some(is_human);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: &str) -> Self {
+        self.seed = Some(seed.to_string());
+        self
+    }
+
+    /// result of verify_workproof, the cookie that is set on success
+    pub fn with_workproof(mut self, workproof: &str) -> Self {
+        self.workproof = Some(workproof.to_string());
+        self
+    }
+}
+
+impl Grasshopper for MockGrasshopper {
+    fn js_app(&self) -> Option<String> {
+        self.js_app.clone()
+    }
+    fn js_bio(&self) -> Option<String> {
+        self.js_bio.clone()
+    }
+    fn parse_rbzid(&self, _rbzid: &str, _seed: &str) -> Option<bool> {
+        self.rbzid
+    }
+    fn gen_new_seed(&self, _seed: &str) -> Option<String> {
+        self.seed.clone()
+    }
+    fn verify_workproof(&self, _workproof: &str, _seed: &str) -> Option<String> {
+        self.workproof.clone()
+    }
+}
+
 #[derive(Clone)]
 pub struct DynGrasshopper {}
 
@@ -109,6 +267,11 @@ pub fn gh_fail_decision(reason: &str) -> Decision {
             atype: ActionType::Block,
             block_mode: true,
             headers: None,
+            multi_headers: None,
+            challenge: None,
+            mirror: None,
+            route: None,
+            response_headers: None,
             status: 500,
             content: "internal_error".to_string(),
             extra_tags: None,
@@ -154,6 +317,14 @@ pub fn challenge_phase01<GH: Grasshopper>(gh: &GH, ua: &str, reasons: Vec<BlockR
             atype: ActionType::Block,
             block_mode: true,
             headers: Some(hdrs),
+            multi_headers: None,
+            challenge: Some(ChallengeMarker {
+                seed,
+                js_app: identity_hash(&chall_lib),
+            }),
+            mirror: None,
+            route: None,
+            response_headers: None,
             status: 247,
             content,
             extra_tags: Some(["challenge_phase01"].iter().map(|s| s.to_string()).collect()),
@@ -171,7 +342,13 @@ fn extract_zebra(headers: &RequestField) -> Option<String> {
     None
 }
 
-pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestField) -> Option<Decision> {
+/// the Set-Cookie header of the response uses the challenge cookie of the host map
+pub fn challenge_phase02<GH: Grasshopper>(
+    gh: &GH,
+    uri: &str,
+    headers: &RequestField,
+    cookie: &ChallengeCookie,
+) -> Option<Decision> {
     if !uri.starts_with("/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/") {
         return None;
     }
@@ -179,17 +356,18 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestF
     let workproof = extract_zebra(headers)?;
     let verified = gh.verify_workproof(&workproof, ua)?;
     let mut nheaders = HashMap::<String, String>::new();
-    let mut cookie = "rbzid=".to_string();
-    cookie += &verified.replace('=', "-");
-    cookie += "; Path=/; HttpOnly";
-
-    nheaders.insert("Set-Cookie".to_string(), cookie);
+    nheaders.insert("Set-Cookie".to_string(), cookie.set_cookie(&verified.replace('=', "-")));
 
     Some(Decision::action(
         Action {
             atype: ActionType::Block,
             block_mode: true,
             headers: Some(nheaders),
+            multi_headers: None,
+            challenge: None,
+            mirror: None,
+            route: None,
+            response_headers: None,
             status: 248,
             content: "{}".to_string(),
             extra_tags: Some(["challenge_phase02"].iter().map(|s| s.to_string()).collect()),
@@ -197,3 +375,153 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestF
         vec![BlockReason::phase02()],
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Location;
+    use crate::logs::Logs;
+
+    fn phase02_cookie(cookie: &ChallengeCookie) -> String {
+        let mut headers = RequestField::new(&[]);
+        for (k, v) in [("user-agent", "curl/7.58.0"), ("x-zebra-abc", "proof-")] {
+            headers.add(k.to_string(), Location::Header(k.to_string()), v.to_string());
+        }
+        let gh = MockGrasshopper::default().with_workproof("verified=");
+        let uri = "/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/verify";
+        let decision = challenge_phase02(&gh, uri, &headers, cookie).unwrap();
+        decision.maction.unwrap().headers.unwrap().remove("Set-Cookie").unwrap()
+    }
+
+    fn human_rinfo(headers: &[(&str, &str)]) -> RequestInfo {
+        use crate::config::hostmap::SecurityPolicy;
+        use crate::utils::RequestBuilder;
+        use std::sync::Arc;
+
+        headers
+            .iter()
+            .fold(RequestBuilder::new("GET", "/"), |builder, (k, v)| builder.header(k, v))
+            .ip("1.2.3.4")
+            .authority("main.site")
+            .security_policy(Arc::new(SecurityPolicy::empty()))
+            .build()
+    }
+
+    #[test]
+    fn human_override_values() {
+        let sel = RequestSelector::decode_dotted("headers.x-verified-human").unwrap();
+        let check = |v: &str| human_override(&sel, &human_rinfo(&[("x-verified-human", v)]));
+        assert_eq!(check("true"), Some(true));
+        assert_eq!(check(" Human "), Some(true));
+        assert_eq!(check("bot"), Some(false));
+        assert_eq!(check("false"), Some(false));
+        assert_eq!(check("maybe"), None);
+        assert_eq!(human_override(&sel, &human_rinfo(&[])), None);
+    }
+
+    #[test]
+    fn human_override_flips() {
+        let sel = RequestSelector::decode_dotted("headers.x-verified-human").unwrap();
+        let verified = MockGrasshopper::default().with_rbzid(true);
+        let mut logs = Logs::default();
+
+        let rinfo = human_rinfo(&[
+            ("user-agent", "curl"),
+            ("cookie", "rbzid=abc"),
+            ("x-verified-human", "bot"),
+        ]);
+        assert!(crate::is_human(Some(&verified), None, &rinfo, &mut logs));
+        assert!(!crate::is_human(Some(&verified), Some(&sel), &rinfo, &mut logs));
+
+        let rinfo = human_rinfo(&[("user-agent", "curl"), ("x-verified-human", "human")]);
+        assert!(!crate::is_human(Some(&verified), None, &rinfo, &mut logs));
+        assert!(crate::is_human(Some(&verified), Some(&sel), &rinfo, &mut logs));
+        assert!(crate::is_human::<MockGrasshopper>(None, Some(&sel), &rinfo, &mut logs));
+    }
+
+    #[test]
+    fn host_map_human_override() {
+        use crate::config::hostmap::HostMapSettings;
+        use crate::config::raw::RawHostMap;
+
+        let raw = |human_override: &str| -> RawHostMap {
+            serde_json::from_value(serde_json::json!({
+                "match": "__default__", "id": "hm", "name": "host map", "tags": [], "map": [],
+                "human_override": human_override
+            }))
+            .unwrap()
+        };
+        let mut logs = Logs::default();
+        let settings = HostMapSettings::resolve(&mut logs, &raw("headers.x-verified-human"));
+        assert_eq!(
+            settings.human_override,
+            Some(RequestSelector::decode_dotted("headers.x-verified-human").unwrap())
+        );
+        assert!(logs.logs.is_empty());
+
+        let settings = HostMapSettings::resolve(&mut logs, &raw("nonexistent.x"));
+        assert_eq!(settings.human_override, None);
+        assert!(logs.logs[0].message.contains("Invalid human override nonexistent.x"));
+    }
+
+    #[test]
+    fn challenge_marker() {
+        let gh = MockGrasshopper::default().with_js_app("chall_lib").with_seed("seed");
+        let decision = challenge_phase01(&gh, "curl", Vec::new());
+        let response: serde_json::Value = serde_json::from_str(&decision.response_json()).unwrap();
+        assert_eq!(response["action"], "custom_response");
+        assert_eq!(response["response"]["challenge"]["seed"], "seed");
+        assert_eq!(
+            response["response"]["challenge"]["js_app"],
+            identity_hash("chall_lib").as_str()
+        );
+
+        // other responses are not marked
+        let failed = challenge_phase01(&MockGrasshopper::default(), "curl", Vec::new());
+        let response: serde_json::Value = serde_json::from_str(&failed.response_json()).unwrap();
+        assert!(response["response"].get("challenge").is_none());
+    }
+
+    #[test]
+    fn default_challenge_cookie() {
+        assert_eq!(
+            phase02_cookie(&ChallengeCookie::default()),
+            "rbzid=verified-; Path=/; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn host_map_challenge_cookie() {
+        use crate::config::hostmap::SecurityPolicy;
+        use crate::utils::RequestBuilder;
+        use std::sync::Arc;
+
+        let verified = MockGrasshopper::default().with_rbzid(true);
+        let mut logs = Logs::default();
+        let mut secpol = SecurityPolicy::empty();
+        secpol.settings.challenge_cookie.name = "cf_app1".to_string();
+        let secpol = Arc::new(secpol);
+        let rinfo = |cookie: &str| {
+            RequestBuilder::new("GET", "/")
+                .header("user-agent", "curl")
+                .header("cookie", cookie)
+                .security_policy(secpol.clone())
+                .build()
+        };
+        assert!(crate::is_human(Some(&verified), None, &rinfo("cf_app1=abc"), &mut logs));
+        assert!(!crate::is_human(Some(&verified), None, &rinfo("rbzid=abc"), &mut logs));
+    }
+
+    #[test]
+    fn configured_challenge_cookie() {
+        let cookie = ChallengeCookie {
+            name: "cf_app1".to_string(),
+            path: "/app1".to_string(),
+            same_site: SameSite::parse("None"),
+        };
+        assert_eq!(
+            phase02_cookie(&cookie),
+            "cf_app1=verified-; Path=/app1; HttpOnly; SameSite=None; Secure"
+        );
+    }
+}
@@ -13,20 +13,20 @@ use chrono::{DateTime, Utc};
 use crate::{
     analyze::{analyze, APhase0, CfRulesArg},
     body::body_too_large,
-    challenge_verified,
     config::{
         contentfilter::ContentFilterRules, contentfilter::SectionIdx, flow::FlowMap, globalfilter::GlobalFilterSection,
-        hostmap::SecurityPolicy, virtualtags::VirtualTags, Config,
+        hostmap::SecurityPolicy, raw::OversizedBodyPolicy, virtualtags::VirtualTags, Config,
     },
     grasshopper::Grasshopper,
     interface::{
         stats::{BStageSecpol, SecpolStats, StatsCollect},
         Action, ActionType, AnalyzeResult, BlockReason, Decision, Location, Tags,
     },
+    is_human,
     logs::{LogLevel, Logs},
     securitypolicy::match_securitypolicy,
     tagging::tag_request,
-    utils::{map_request, RawRequest, RequestMeta},
+    utils::{map_request, parse_xff_entry, RawRequest, RequestMeta},
 };
 
 pub enum IPInfo {
@@ -41,6 +41,8 @@ pub struct IData {
     headers: HashMap<String, String>,
     secpol: Arc<SecurityPolicy>,
     body: Option<Vec<u8>>,
+    /// the body went over the max body size, and was truncated or dropped according to the profile policy
+    body_too_large: bool,
     ipinfo: IPInfo,
     stats: StatsCollect<BStageSecpol>,
     container_name: Option<String>,
@@ -48,26 +50,104 @@ pub struct IData {
 }
 
 impl IData {
-    fn ip(&self) -> String {
+    /// the client IP, and the tags describing its extraction
+    fn ip(&mut self) -> (String, Vec<String>) {
         match &self.ipinfo {
-            IPInfo::Ip(s) => s.clone(),
-            IPInfo::Hops(hops) => extract_ip(*hops, &self.headers).unwrap_or_else(|| "1.1.1.1".to_string()),
+            IPInfo::Ip(s) => (s.clone(), Vec::new()),
+            IPInfo::Hops(hops) => {
+                let xff = extract_ip(*hops, &self.headers);
+                xff.log(&mut self.logs);
+                let tags = xff.tags();
+                (xff.ip_or("1.1.1.1".to_string()), tags)
+            }
         }
     }
 }
 
-/// reproduces the original IP extraction algorithm, for envoy
-pub fn extract_ip(trusted_hops: usize, headers: &HashMap<String, String>) -> Option<String> {
-    let detect_ip = |xff: &str| -> String {
-        let splitted = xff.split(',').collect::<Vec<_>>();
-        if trusted_hops < splitted.len() {
-            splitted[splitted.len() - trusted_hops]
-        } else {
-            splitted[0]
+/// result of the IP extraction from the x-forwarded-for header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XffResult {
+    Ip(String),
+    /// no x-forwarded-for header
+    NoXff,
+    /// no hop is trusted, the x-forwarded-for header is ignored
+    Untrusted,
+    /// the selected entry is not a valid IP address
+    ParseError(String),
+    /// there are not enough entries for the amount of trusted hops, the first entry is used
+    OutOfRange {
+        hops: usize,
+        entries: usize,
+        ip: String,
+    },
+}
+
+impl XffResult {
+    /// the extracted IP, or the fallback when it could not be extracted
+    pub fn ip_or(self, fallback: String) -> String {
+        match self {
+            XffResult::Ip(ip) => ip,
+            XffResult::OutOfRange { ip, .. } => ip,
+            XffResult::NoXff | XffResult::Untrusted | XffResult::ParseError(_) => fallback,
         }
-        .to_string()
+    }
+
+    /// the tags describing the extraction, that are seeded in the request tags
+    pub fn tags(&self) -> Vec<String> {
+        match self {
+            XffResult::ParseError(_) => vec!["xff-malformed".to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn log(&self, logs: &mut Logs) {
+        match self {
+            XffResult::Ip(_) => (),
+            XffResult::NoXff => logs.debug("no x-forwarded-for header, using the fallback IP"),
+            XffResult::Untrusted => logs.debug("no trusted hops, x-forwarded-for is ignored, using the fallback IP"),
+            XffResult::ParseError(entry) => {
+                logs.warning(|| format!("invalid x-forwarded-for entry {:?}, using the fallback IP", entry))
+            }
+            XffResult::OutOfRange { hops, entries, ip } => logs.warning(|| {
+                format!(
+                    "x-forwarded-for has {} entries, less than the {} trusted hops, using the first entry {}",
+                    entries, hops, ip
+                )
+            }),
+        }
+    }
+}
+
+/// reproduces the original IP extraction algorithm, for envoy
+pub fn extract_ip(trusted_hops: usize, headers: &HashMap<String, String>) -> XffResult {
+    let xff = match headers.get("x-forwarded-for") {
+        None => return XffResult::NoXff,
+        Some(x) => x,
+    };
+    if trusted_hops == 0 {
+        return XffResult::Untrusted;
+    }
+    let splitted = xff.split(',').map(|s| s.trim()).collect::<Vec<_>>();
+    let in_range = trusted_hops < splitted.len();
+    let entry = if in_range {
+        splitted[splitted.len() - trusted_hops]
+    } else {
+        splitted[0]
+    };
+    // ports and brackets are dropped
+    let ip = match parse_xff_entry(entry) {
+        None => return XffResult::ParseError(entry.to_string()),
+        Some(ip) => ip.to_string(),
     };
-    headers.get("x-forwarded-for").map(|s| detect_ip(s.as_str()))
+    if in_range {
+        XffResult::Ip(ip)
+    } else {
+        XffResult::OutOfRange {
+            hops: trusted_hops,
+            entries: splitted.len(),
+            ip,
+        }
+    }
 }
 
 pub fn inspect_init(
@@ -99,6 +179,7 @@ pub fn inspect_init(
                 headers: HashMap::new(),
                 secpol,
                 body: None,
+                body_too_large: false,
                 ipinfo,
                 stats,
                 container_name: config.container_name.clone(),
@@ -110,8 +191,8 @@ pub fn inspect_init(
 
 /// called when the content filter policy is violated
 /// no tags are returned though!
-fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeResult) {
-    let ipstr = idata.ip();
+fn early_block(mut idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeResult) {
+    let (ipstr, _) = idata.ip();
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let rawrequest = RawRequest {
@@ -160,6 +241,11 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
         block_mode: true,
         status: 403,
         headers: None,
+        multi_headers: None,
+        challenge: None,
+        mirror: None,
+        route: None,
+        response_headers: None,
         content: "Access denied".to_string(),
         extra_tags: None,
     };
@@ -175,7 +261,9 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
         if kl == "content-length" {
             if let Ok(content_length) = value.parse::<usize>() {
                 let max_size = dt.secpol.content_filter_profile.max_body_size;
-                if content_length > max_size {
+                if content_length > max_size
+                    && dt.secpol.content_filter_profile.oversized_body == OversizedBodyPolicy::BlockUnverified
+                {
                     let (a, br) = body_too_large(cfid.clone(), max_size, content_length);
                     return Err(early_block(dt, a, br));
                 }
@@ -199,14 +287,33 @@ pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeRe
     if dt.secpol.content_filter_profile.ignore_body {
         return Ok(dt);
     }
+    // the body already went over the limit, the rest of it is not inspected
+    if dt.body_too_large {
+        return Ok(dt);
+    }
 
     let cur_body_size = dt.body.as_ref().map(|v| v.len()).unwrap_or(0);
     let new_size = cur_body_size + new_body.len();
     let max_size = dt.secpol.content_filter_profile.max_body_size;
-    if dt.secpol.content_filter_active && new_size > max_size {
-        let (a, br) = body_too_large(dt.secpol.content_filter_profile.id.clone(), max_size, new_size);
-        return Err(early_block(dt, a, br));
-    }
+    let new_body = if dt.secpol.content_filter_active && new_size > max_size {
+        match dt.secpol.content_filter_profile.oversized_body {
+            OversizedBodyPolicy::BlockUnverified => {
+                let (a, br) = body_too_large(dt.secpol.content_filter_profile.id.clone(), max_size, new_size);
+                return Err(early_block(dt, a, br));
+            }
+            OversizedBodyPolicy::InspectTruncated => {
+                dt.body_too_large = true;
+                &new_body[..max_size.saturating_sub(cur_body_size)]
+            }
+            OversizedBodyPolicy::Pass => {
+                dt.body_too_large = true;
+                dt.body = None;
+                return Ok(dt);
+            }
+        }
+    } else {
+        new_body
+    };
 
     match dt.body.as_mut() {
         None => dt.body = Some(new_body.to_vec()),
@@ -216,14 +323,14 @@ pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeRe
 }
 
 pub async fn finalize<GH: Grasshopper>(
-    idata: IData,
+    mut idata: IData,
     mgh: Option<&GH>,
     globalfilters: &[GlobalFilterSection],
     flows: &FlowMap,
     mcfrules: Option<&HashMap<String, ContentFilterRules>>,
     vtags: VirtualTags,
 ) -> (AnalyzeResult, Logs) {
-    let ipstr = idata.ip();
+    let (ipstr, seed_tags) = idata.ip();
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let rawrequest = RawRequest {
@@ -243,17 +350,25 @@ pub async fn finalize<GH: Grasshopper>(
         Some(idata.start),
         idata.plugins,
     );
+    reqinfo.body_too_large |= idata.body_too_large;
 
-    // without grasshopper, default to being human
-    let is_human = if let Some(gh) = mgh {
-        challenge_verified(gh, &reqinfo, &mut logs)
-    } else {
-        false
-    };
+    let is_human = is_human(
+        mgh,
+        reqinfo.rinfo.secpolicy.settings.human_override.as_ref(),
+        &reqinfo,
+        &mut logs,
+    );
 
     logs.debug(|| format!("rinfo {:?}", reqinfo));
-    let (mut tags, globalfilter_dec, stats) =
-        tag_request(idata.stats, is_human, globalfilters, &mut reqinfo, &vtags, &mut logs);
+    let (mut tags, globalfilter_dec, stats) = tag_request(
+        idata.stats,
+        is_human,
+        &seed_tags,
+        globalfilters,
+        &mut reqinfo,
+        &vtags,
+        &mut logs,
+    );
     tags.insert("all", Location::Request);
 
     let dec = analyze(
@@ -277,8 +392,9 @@ pub async fn finalize<GH: Grasshopper>(
 mod test {
     use crate::config::{
         contentfilter::ContentFilterProfile,
-        hostmap::{HostMap, PolicyId},
+        hostmap::{HostMap, HostMapSettings, PolicyId},
         raw::AclProfile,
+        LoadStats,
     };
     use std::time::SystemTime;
 
@@ -293,6 +409,7 @@ mod test {
             default: Some(HostMap {
                 name: "default".to_string(),
                 entries: Vec::new(),
+                path_prefixes: Vec::new(),
                 default: Some(Arc::new(SecurityPolicy {
                     policy: PolicyId {
                         id: "__default__".to_string(),
@@ -310,6 +427,12 @@ mod test {
                     session: Vec::new(),
                     session_ids: Vec::new(),
                     limits: Vec::new(),
+                    globalfilter_active: true,
+                    limit_active: true,
+                    flow_active: true,
+                    allowlist_first: false,
+                    learning_mode: false,
+                    settings: HostMapSettings::default(),
                 })),
             }),
             last_mod: SystemTime::now(),
@@ -318,6 +441,7 @@ mod test {
             content_filter_profiles: HashMap::new(),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
+            load_stats: LoadStats::default(),
         }
     }
 
@@ -344,6 +468,77 @@ mod test {
         .unwrap()
     }
 
+    #[test]
+    fn xff_ok() {
+        let headers = hashmap(&[("x-forwarded-for", "1.2.3.4, 10.0.0.1, 10.0.0.2")]);
+        assert_eq!(extract_ip(2, &headers), XffResult::Ip("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn xff_missing() {
+        assert_eq!(extract_ip(1, &HashMap::new()), XffResult::NoXff);
+        assert_eq!(extract_ip(1, &HashMap::new()).ip_or("5.6.7.8".to_string()), "5.6.7.8");
+    }
+
+    #[test]
+    fn xff_parse_error() {
+        let headers = hashmap(&[("x-forwarded-for", "1.2.3.4, garbage")]);
+        let res = extract_ip(1, &headers);
+        assert_eq!(res, XffResult::ParseError("garbage".to_string()));
+        assert_eq!(res.tags(), vec!["xff-malformed".to_string()]);
+        assert_eq!(res.ip_or("5.6.7.8".to_string()), "5.6.7.8");
+        assert!(extract_ip(2, &headers).tags().is_empty());
+    }
+
+    #[test]
+    fn xff_no_trusted_hops() {
+        let headers = hashmap(&[("x-forwarded-for", "1.2.3.4, 10.0.0.1")]);
+        let res = extract_ip(0, &headers);
+        assert_eq!(res, XffResult::Untrusted);
+        assert_eq!(res.ip_or("5.6.7.8".to_string()), "5.6.7.8");
+    }
+
+    #[test]
+    fn xff_out_of_range() {
+        let headers = hashmap(&[("x-forwarded-for", "1.2.3.4, 10.0.0.1")]);
+        let res = extract_ip(3, &headers);
+        assert_eq!(
+            res,
+            XffResult::OutOfRange {
+                hops: 3,
+                entries: 2,
+                ip: "1.2.3.4".to_string()
+            }
+        );
+        let mut logs = Logs::default();
+        res.log(&mut logs);
+        assert_eq!(logs.logs.len(), 1);
+    }
+
+    #[test]
+    fn xff_ipv6() {
+        let xff = |value: &str, hops: usize| extract_ip(hops, &hashmap(&[("x-forwarded-for", value)]));
+        let mixed = "2001:db8::1, [2001:db8::2]:8443, 10.0.0.1:1234, [2001:db8::3], 10.0.0.2";
+        assert_eq!(xff(mixed, 1), XffResult::Ip("10.0.0.2".to_string()));
+        assert_eq!(xff(mixed, 2), XffResult::Ip("2001:db8::3".to_string()));
+        assert_eq!(xff(mixed, 3), XffResult::Ip("10.0.0.1".to_string()));
+        assert_eq!(xff(mixed, 4), XffResult::Ip("2001:db8::2".to_string()));
+        assert_eq!(
+            xff(mixed, 5),
+            XffResult::OutOfRange {
+                hops: 5,
+                entries: 5,
+                ip: "2001:db8::1".to_string()
+            }
+        );
+        // unbracketed IPv6 addresses can't have a port, and brackets must be closed
+        for garbage in &["2001:db8::1:8443x", "[2001:db8::1", "[2001:db8::1]:port", "[10.0.0.1]"] {
+            let res = xff(&format!("1.2.3.4, {}", garbage), 1);
+            assert_eq!(res, XffResult::ParseError(garbage.to_string()));
+            assert_eq!(res.ip_or("5.6.7.8".to_string()), "5.6.7.8");
+        }
+    }
+
     #[test]
     fn too_many_headers_1() {
         let mut cf = ContentFilterProfile::default_from_seed("seed");
@@ -437,4 +632,34 @@ mod test {
             ),
         }
     }
+
+    #[test]
+    fn body_too_large_truncated() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 10;
+        cf.oversized_body = OversizedBodyPolicy::InspectTruncated;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let idata = add_headers(idata, hashmap(&[("content-length", "150")])).unwrap();
+        let idata = add_body(idata, b"a=1&b=2").unwrap();
+        let idata = add_body(idata, b"&c=3&d=4").unwrap();
+        let idata = add_body(idata, b"&e=5").unwrap();
+        assert!(idata.body_too_large);
+        assert_eq!(idata.body.as_deref(), Some(&b"a=1&b=2&c="[..]));
+    }
+
+    #[test]
+    fn body_too_large_pass() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 10;
+        cf.oversized_body = OversizedBodyPolicy::Pass;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let idata = add_headers(idata, hashmap(&[("content-length", "150")])).unwrap();
+        let idata = add_body(idata, b"a=1&b=2").unwrap();
+        let idata = add_body(idata, b"&c=3&d=4").unwrap();
+        let idata = add_body(idata, b"&e=5").unwrap();
+        assert!(idata.body_too_large);
+        assert!(idata.body.is_none());
+    }
 }
@@ -494,6 +494,8 @@ fn is_autotag_prefix(s: &str) -> bool {
             | "geo-region"
             | "network"
             | "geo-subregion"
+            | "geo-region-iso"
+            | "geo-subregion-iso"
             | "geo-asn"
     )
 }
@@ -551,6 +553,7 @@ impl AggregatedCounters {
                     }
                 }
                 Phase01Fail(_) => (),
+                ChallengeFallback(_) => (),
                 Phase02 => {
                     if this_blocked {
                         self.requests_triggered_acl_active += 1;
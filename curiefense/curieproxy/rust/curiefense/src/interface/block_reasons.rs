@@ -5,7 +5,7 @@ use serde::Serialize;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 
-use super::tagging::{Location, Tags};
+use super::tagging::{CappedLocations, Location, Tags, FLATTEN_REASON_LOCATIONS, MAX_LOGGED_LOCATIONS};
 
 #[derive(Debug, Clone, Copy, Serialize, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -48,6 +48,8 @@ pub enum Initiator {
     // TODO, these two are not serialized for now
     Phase01Fail(String),
     Phase02,
+    /// a challenge action could not be issued, the fallback is described
+    ChallengeFallback(String),
 }
 
 impl std::fmt::Display for Initiator {
@@ -55,11 +57,16 @@ impl std::fmt::Display for Initiator {
         use Initiator::*;
         match self {
             GlobalFilter { id, name } => write!(f, "global filter {}[{}]", name, id),
-            Acl { id, tags, stage } => write!(f, "acl[{}] {:?} {:?}", id, stage, tags),
+            Acl { id, tags, stage } => {
+                let mut stags: Vec<&String> = tags.iter().collect();
+                stags.sort();
+                write!(f, "acl[{}] {:?} {:?}", id, stage, stags)
+            }
             ContentFilter { id, risk_level } => write!(f, "content filter {}[lvl{}]", id, risk_level),
             Limit { id, name, threshold } => write!(f, "rate limit {}[{}] threshold={}", name, id, threshold),
             Phase01Fail(r) => write!(f, "grasshopper phase 1 error: {}", r),
             Phase02 => write!(f, "grasshopper phase 2"),
+            ChallengeFallback(r) => write!(f, "challenge fallback: {}", r),
             Restriction {
                 id,
                 tpe,
@@ -80,6 +87,21 @@ pub enum InitiatorKind {
     Restriction,
 }
 
+impl InitiatorKind {
+    /// decodes the serialized name of the kind
+    pub fn decode(name: &str) -> Option<Self> {
+        use InitiatorKind::*;
+        match name {
+            "acl" => Some(Acl),
+            "rate_limit" => Some(RateLimit),
+            "global_filter" => Some(GlobalFilter),
+            "content_filter" => Some(ContentFilter),
+            "restriction" => Some(Restriction),
+            _ => None,
+        }
+    }
+}
+
 impl Initiator {
     pub fn to_kind(&self) -> Option<InitiatorKind> {
         use InitiatorKind::*;
@@ -90,6 +112,7 @@ impl Initiator {
             Initiator::Limit { .. } => Some(RateLimit),
             Initiator::Phase01Fail(_) => None,
             Initiator::Phase02 => None,
+            Initiator::ChallengeFallback(_) => None,
             Initiator::Restriction { .. } => Some(Restriction),
         }
     }
@@ -104,8 +127,10 @@ impl Initiator {
                 map.serialize_entry("name", name)?;
             }
             Initiator::Acl { id, tags, stage } => {
+                let mut stags: Vec<&String> = tags.iter().collect();
+                stags.sort();
                 map.serialize_entry("id", id)?;
-                map.serialize_entry("tags", tags)?;
+                map.serialize_entry("tags", &stags)?;
                 map.serialize_entry("stage", stage)?;
             }
             Initiator::ContentFilter { id, risk_level } => {
@@ -137,6 +162,10 @@ impl Initiator {
             Initiator::Phase02 => {
                 map.serialize_entry("type", "phase2")?;
             }
+            Initiator::ChallengeFallback(r) => {
+                map.serialize_entry("type", "challenge_fallback")?;
+                map.serialize_entry("details", r)?;
+            }
         }
         Ok(())
     }
@@ -247,6 +276,10 @@ impl BlockReason {
         BlockReason::nodetails(Initiator::Phase01Fail(reason.to_string()), BDecision::Blocking)
     }
 
+    pub fn challenge_fallback(reason: String, decision: BDecision) -> Self {
+        BlockReason::nodetails(Initiator::ChallengeFallback(reason), decision)
+    }
+
     pub fn phase02() -> Self {
         BlockReason::nodetails(Initiator::Phase02, BDecision::Blocking)
     }
@@ -289,6 +322,20 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn uri_too_long(id: String, actual: usize, expected: usize) -> Self {
+        BlockReason {
+            initiator: Initiator::Restriction {
+                id,
+                tpe: "too long",
+                actual: actual.to_string(),
+                expected: expected.to_string(),
+            },
+            location: Location::Uri,
+            decision: BDecision::Blocking,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn body_missing(id: String) -> Self {
         BlockReason {
             initiator: Initiator::Restriction {
@@ -418,12 +465,46 @@ impl BlockReason {
         out
     }
 
+    /// all the locations of the reason, deduplicated and sorted by their string representation
+    pub fn flat_locations(&self) -> CappedLocations<'_> {
+        let mut locations: Vec<&Location> = std::iter::once(&self.location)
+            .chain(self.extra_locations.iter())
+            .collect();
+        locations.sort_by_cached_key(|l| l.to_string());
+        CappedLocations::new(locations, *MAX_LOGGED_LOCATIONS)
+    }
+
     pub fn serialize_in_map<S: serde::Serializer>(
         &self,
         map: &mut <S as serde::Serializer>::SerializeMap,
+    ) -> Result<(), S::Error> {
+        self.serialize_in_map_with::<S>(map, *FLATTEN_REASON_LOCATIONS)
+    }
+
+    fn serialize_in_map_with<S: serde::Serializer>(
+        &self,
+        map: &mut <S as serde::Serializer>::SerializeMap,
+        flatten: bool,
     ) -> Result<(), S::Error> {
         self.initiator.serialize_in_map::<S>(map)?;
         self.location.serialize_with_parent::<S>(map)?;
+        if flatten {
+            let locations = self.flat_locations();
+            map.serialize_entry("locations", &locations)?;
+            if locations.truncated > 0 {
+                map.serialize_entry("locations_truncated", &locations.truncated)?;
+            }
+        } else if !self.extra_locations.is_empty() {
+            // the main location is not repeated
+            let extra = CappedLocations::new(
+                self.extra_locations.iter().filter(|l| **l != self.location),
+                *MAX_LOGGED_LOCATIONS,
+            );
+            map.serialize_entry("extra_locations", &extra)?;
+            if extra.truncated > 0 {
+                map.serialize_entry("extra_locations_truncated", &extra.truncated)?;
+            }
+        }
         map.serialize_entry("active", &Value::Bool(self.decision != BDecision::Monitor))?;
         Ok(())
     }
@@ -442,3 +523,17 @@ impl<'t> Serialize for LegacyBlockReason<'t> {
         map.end()
     }
 }
+
+/// serializes a reason with all its locations in a single list, whatever the FLATTEN_REASON_LOCATIONS setting
+pub struct FlatBlockReason<'t>(pub &'t BlockReason);
+
+impl<'t> Serialize for FlatBlockReason<'t> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map: <S as serde::Serializer>::SerializeMap = serializer.serialize_map(None)?;
+        self.0.serialize_in_map_with::<S>(&mut map, true)?;
+        map.end()
+    }
+}
@@ -0,0 +1,218 @@
+/// decisions as Envoy ext_authz check responses
+///
+/// These structures follow the json mapping of `envoy.service.auth.v3.CheckResponse`, using the proto field
+/// names, so that a gRPC shim can forward them without depending on this crate's types.
+use serde::Serialize;
+
+use super::Decision;
+
+/// `google.rpc.Code::OK`
+pub const GRPC_OK: i32 = 0;
+/// `google.rpc.Code::PERMISSION_DENIED`
+pub const GRPC_PERMISSION_DENIED: i32 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResponse {
+    pub status: RpcStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ok_response: Option<OkHttpResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denied_response: Option<DeniedHttpResponse>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcStatus {
+    pub code: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HttpStatus {
+    pub code: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderValue {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderValueOption {
+    pub header: HeaderValue,
+    /// set for repeated headers, so that all their values are kept
+    pub append: bool,
+}
+
+/// headers added to the request forwarded upstream, and to the response sent back to the client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OkHttpResponse {
+    pub headers: Vec<HeaderValueOption>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub response_headers_to_add: Vec<HeaderValueOption>,
+}
+
+/// the response sent to the client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeniedHttpResponse {
+    pub status: HttpStatus,
+    pub headers: Vec<HeaderValueOption>,
+    pub body: String,
+}
+
+fn header_option(key: &str, value: &str, append: bool) -> HeaderValueOption {
+    HeaderValueOption {
+        header: HeaderValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        },
+        append,
+    }
+}
+
+/// response headers of a passing action, sorted by name
+fn response_header_options(decision: &Decision) -> Vec<HeaderValueOption> {
+    let mut headers: Vec<(&String, &String)> = decision
+        .maction
+        .iter()
+        .flat_map(|a| a.response_headers.iter().flatten())
+        .collect();
+    headers.sort();
+    headers.into_iter().map(|(k, v)| header_option(k, v, false)).collect()
+}
+
+/// headers of the action, sorted by name, with the repeated headers last
+fn header_options(decision: &Decision) -> Vec<HeaderValueOption> {
+    let action = match &decision.maction {
+        None => return Vec::new(),
+        Some(a) => a,
+    };
+    let mut single: Vec<(&String, &String)> = action.headers.iter().flatten().collect();
+    single.sort();
+    let mut multi: Vec<(&String, &Vec<String>)> = action.multi_headers.iter().flatten().collect();
+    multi.sort();
+    single
+        .into_iter()
+        .map(|(k, v)| header_option(k, v, false))
+        .chain(
+            multi
+                .into_iter()
+                .flat_map(|(k, vs)| vs.iter().map(move |v| header_option(k, v, true))),
+        )
+        .collect()
+}
+
+impl From<&Decision> for CheckResponse {
+    fn from(decision: &Decision) -> Self {
+        let headers = header_options(decision);
+        match &decision.maction {
+            Some(action) if decision.is_blocking() => CheckResponse {
+                status: RpcStatus {
+                    code: GRPC_PERMISSION_DENIED,
+                },
+                ok_response: None,
+                denied_response: Some(DeniedHttpResponse {
+                    status: HttpStatus { code: action.status },
+                    headers,
+                    body: action.content.clone(),
+                }),
+            },
+            _ => CheckResponse {
+                status: RpcStatus { code: GRPC_OK },
+                ok_response: Some(OkHttpResponse {
+                    headers,
+                    response_headers_to_add: response_header_options(decision),
+                }),
+                denied_response: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{Action, ActionType};
+    use std::collections::HashMap;
+
+    #[test]
+    fn allowed() {
+        let response = CheckResponse::from(&Decision::pass(Vec::new()));
+        assert_eq!(response.status.code, GRPC_OK);
+        assert!(response.denied_response.is_none());
+        assert!(response.ok_response.unwrap().headers.is_empty());
+
+        let monitor = Decision::action(
+            Action {
+                atype: ActionType::Monitor,
+                headers: Some(std::iter::once(("x-flagged".to_string(), "1".to_string())).collect()),
+                ..Action::default()
+            },
+            Vec::new(),
+        );
+        let serialized = serde_json::to_value(CheckResponse::from(&monitor)).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::json!({
+                "status": {"code": 0},
+                "ok_response": {
+                    "headers": [{"header": {"key": "x-flagged", "value": "1"}, "append": false}]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn response_headers() {
+        let monitor = Decision::action(
+            Action {
+                atype: ActionType::Monitor,
+                response_headers: Some(std::iter::once(("pragma".to_string(), "no-cache".to_string())).collect()),
+                ..Action::default()
+            },
+            Vec::new(),
+        );
+        let serialized = serde_json::to_value(CheckResponse::from(&monitor)).unwrap();
+        assert_eq!(
+            serialized["ok_response"],
+            serde_json::json!({
+                "headers": [],
+                "response_headers_to_add": [{"header": {"key": "pragma", "value": "no-cache"}, "append": false}]
+            })
+        );
+    }
+
+    #[test]
+    fn denied() {
+        let mut multi_headers = HashMap::new();
+        multi_headers.insert("set-cookie".to_string(), vec!["a=1".to_string(), "b=2".to_string()]);
+        let block = Decision::action(
+            Action {
+                status: 403,
+                headers: Some(std::iter::once(("content-type".to_string(), "text/html".to_string())).collect()),
+                multi_headers: Some(multi_headers),
+                content: "blocked".to_string(),
+                ..Action::default()
+            },
+            Vec::new(),
+        );
+        let response = CheckResponse::from(&block);
+        assert_eq!(response.status.code, GRPC_PERMISSION_DENIED);
+        assert!(response.ok_response.is_none());
+        let denied = response.denied_response.unwrap();
+        assert_eq!(denied.status.code, 403);
+        assert_eq!(denied.body, "blocked");
+        let headers: Vec<(&str, &str, bool)> = denied
+            .headers
+            .iter()
+            .map(|h| (h.header.key.as_str(), h.header.value.as_str(), h.append))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type", "text/html", false),
+                ("set-cookie", "a=1", true),
+                ("set-cookie", "b=2", true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+/// conversion of the inspection results to OpenTelemetry span attributes
+///
+/// This does not depend on the OpenTelemetry crates, the attributes are returned as a flat list of key/values that
+/// can be converted to the attribute type of the SDK in use.
+use crate::interface::{ActionType, BDecision, BlockReason, Decision, InitiatorKind, Stats, Tags};
+use crate::utils::RequestInfo;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Bool(bool),
+    I64(i64),
+    StringArray(Vec<String>),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(s: &str) -> Self {
+        AttributeValue::String(s.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(s: String) -> Self {
+        AttributeValue::String(s)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(b: bool) -> Self {
+        AttributeValue::Bool(b)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(i: i64) -> Self {
+        AttributeValue::I64(i)
+    }
+}
+
+pub fn otel_attributes(
+    dec: &Decision,
+    tags: &Tags,
+    stats: &Stats,
+    rinfo: &RequestInfo,
+) -> Vec<(String, AttributeValue)> {
+    let mut out: Vec<(String, AttributeValue)> = Vec::new();
+    let mut push = |k: &str, v: AttributeValue| out.push((k.to_string(), v));
+
+    let decision = match dec.maction.as_ref().map(|a| a.atype) {
+        None => "pass",
+        Some(ActionType::Skip) => "skip",
+        Some(ActionType::Monitor) => "monitor",
+        Some(ActionType::Block) => "block",
+    };
+    push("curiefense.decision", decision.into());
+    push("curiefense.blocked", dec.is_blocking().into());
+    if let Some(action) = &dec.maction {
+        push("curiefense.response.status_code", (action.status as i64).into());
+    }
+    if let Some(reason) = BlockReason::block_reason_desc(&dec.reasons) {
+        push("curiefense.reason", reason.into());
+    }
+    let mut tagnames: Vec<String> = tags.tags.keys().cloned().collect();
+    tagnames.sort();
+    push("curiefense.tags", AttributeValue::StringArray(tagnames));
+
+    let greasons = BlockReason::regroup(&dec.reasons);
+    for (kind, name) in [
+        (InitiatorKind::Acl, "acl"),
+        (InitiatorKind::GlobalFilter, "global_filter"),
+        (InitiatorKind::RateLimit, "rate_limit"),
+        (InitiatorKind::ContentFilter, "content_filter"),
+        (InitiatorKind::Restriction, "restriction"),
+    ] {
+        let reasons = greasons.get(&kind).map(|v| v.as_slice()).unwrap_or(&[]);
+        let active = reasons.iter().filter(|r| r.decision == BDecision::Blocking).count();
+        push(&format!("curiefense.triggers.{}", name), (reasons.len() as i64).into());
+        push(&format!("curiefense.triggers.{}_active", name), (active as i64).into());
+    }
+
+    push("curiefense.revision", stats.revision.clone().into());
+    push("curiefense.processing_stage", (stats.processing_stage as i64).into());
+    push(
+        "curiefense.security_policy.id",
+        rinfo.rinfo.secpolicy.policy.id.clone().into(),
+    );
+    push(
+        "curiefense.security_policy.entry_id",
+        rinfo.rinfo.secpolicy.entry.id.clone().into(),
+    );
+    push("curiefense.session", rinfo.session.clone().into());
+
+    push("http.request.method", rinfo.rinfo.meta.method.clone().into());
+    push("url.path", rinfo.rinfo.qinfo.qpath.clone().into());
+    push("server.address", rinfo.rinfo.host.clone().into());
+    push("client.address", rinfo.rinfo.geoip.ipstr.clone().into());
+
+    let geo = &rinfo.rinfo.geoip;
+    for (k, v) in [
+        ("client.geo.country_iso_code", &geo.country_iso),
+        ("client.geo.region", &geo.region),
+        ("client.geo.city", &geo.city_name),
+        ("client.geo.company", &geo.company),
+    ] {
+        if let Some(v) = v {
+            push(k, v.clone().into());
+        }
+    }
+    if let Some(asn) = geo.asn {
+        push("client.geo.asn", (asn as i64).into());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::{Action, Location};
+    use crate::utils::RequestBuilder;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    #[test]
+    fn blocked_request() {
+        let mut rinfo = RequestBuilder::new("GET", "/admin?x=1")
+            .ip("1.2.3.4")
+            .authority("main.site")
+            .security_policy(Arc::new(SecurityPolicy::empty()))
+            .build();
+        rinfo.rinfo.geoip.country_iso = Some("fr".to_string());
+        rinfo.rinfo.geoip.asn = Some(1234);
+
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("gf-admin", Location::Request);
+        tags.insert("bot", Location::Request);
+        let locs: HashSet<Location> = std::iter::once(Location::Path).collect();
+        let dec = Decision::action(
+            Action {
+                status: 403,
+                ..Action::default()
+            },
+            vec![
+                BlockReason::global_filter("gf1".to_string(), "admin".to_string(), BDecision::Blocking, &locs),
+                BlockReason::global_filter("gf2".to_string(), "monitored".to_string(), BDecision::Monitor, &locs),
+            ],
+        );
+        let mut stats = Stats::new(std::time::Instant::now(), "rev1".to_string());
+        stats.processing_stage = 2;
+
+        let attrs: HashMap<String, AttributeValue> = otel_attributes(&dec, &tags, &stats, &rinfo).into_iter().collect();
+        let get = |k: &str| attrs.get(k).cloned();
+        assert_eq!(get("curiefense.decision"), Some("block".into()));
+        assert_eq!(get("curiefense.blocked"), Some(true.into()));
+        assert_eq!(get("curiefense.response.status_code"), Some(403i64.into()));
+        assert!(matches!(get("curiefense.reason"), Some(AttributeValue::String(r)) if r.contains("gf1")));
+        assert_eq!(
+            get("curiefense.tags"),
+            Some(AttributeValue::StringArray(vec![
+                "bot".to_string(),
+                "gf-admin".to_string()
+            ]))
+        );
+        assert_eq!(get("curiefense.triggers.global_filter"), Some(2i64.into()));
+        assert_eq!(get("curiefense.triggers.global_filter_active"), Some(1i64.into()));
+        assert_eq!(get("curiefense.triggers.acl"), Some(0i64.into()));
+        assert_eq!(get("curiefense.revision"), Some("rev1".into()));
+        assert_eq!(get("curiefense.processing_stage"), Some(2i64.into()));
+        assert_eq!(get("http.request.method"), Some("GET".into()));
+        assert_eq!(get("url.path"), Some("/admin".into()));
+        assert_eq!(get("server.address"), Some("main.site".into()));
+        assert_eq!(get("client.address"), Some("1.2.3.4".into()));
+        assert_eq!(get("client.geo.country_iso_code"), Some("fr".into()));
+        assert_eq!(get("client.geo.asn"), Some(1234i64.into()));
+        assert_eq!(get("client.geo.city"), None);
+    }
+}
@@ -0,0 +1,129 @@
+/// log time filters, that tag requests based on the response metrics reported by the proxy
+///
+/// These tags only appear in the logs and the aggregated statistics, they play no part in the decision.
+use crate::interface::{Location, Tags};
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+lazy_static! {
+    /// filters built from the environment, a response slower than `SLOW_RESPONSE_MS` milliseconds is tagged
+    /// `slow-response`, and a response larger than `LARGE_RESPONSE_BYTES` bytes is tagged `large-response`
+    pub static ref RESPONSE_FILTERS: Vec<ResponseFilter> = {
+        let threshold = |name: &str| std::env::var(name).ok().and_then(|s| s.parse().ok());
+        let mut out = Vec::new();
+        if let Some(t) = threshold("SLOW_RESPONSE_MS") {
+            out.push(ResponseFilter::new(ResponseMetric::Duration, t, "slow-response"));
+        }
+        if let Some(t) = threshold("LARGE_RESPONSE_BYTES") {
+            out.push(ResponseFilter::new(ResponseMetric::BytesSent, t, "large-response"));
+        }
+        out
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMetric {
+    /// the `request_time` proxy value, in seconds as reported by nginx, compared in milliseconds
+    Duration,
+    /// the `bytes_sent` proxy value
+    BytesSent,
+}
+
+impl ResponseMetric {
+    fn key(&self) -> &'static str {
+        match self {
+            ResponseMetric::Duration => "request_time",
+            ResponseMetric::BytesSent => "bytes_sent",
+        }
+    }
+
+    /// converts the proxy value to the unit of the thresholds
+    fn scale(&self, v: f64) -> f64 {
+        match self {
+            ResponseMetric::Duration => v * 1000.0,
+            ResponseMetric::BytesSent => v,
+        }
+    }
+}
+
+/// tags the request when the metric is at or above the threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseFilter {
+    pub metric: ResponseMetric,
+    pub threshold: f64,
+    pub tag: String,
+}
+
+impl ResponseFilter {
+    pub fn new(metric: ResponseMetric, threshold: f64, tag: &str) -> Self {
+        ResponseFilter {
+            metric,
+            threshold,
+            tag: tag.to_string(),
+        }
+    }
+
+    fn matches(&self, proxy: &HashMap<String, String>) -> bool {
+        proxy
+            .get(self.metric.key())
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .map(|v| self.metric.scale(v) >= self.threshold)
+            .unwrap_or(false)
+    }
+}
+
+/// the request tags, with the tags of the matching response filters added
+///
+/// the tags are only copied when a filter matches
+pub fn response_tags<'t>(filters: &[ResponseFilter], proxy: &HashMap<String, String>, tags: &'t Tags) -> Cow<'t, Tags> {
+    let mut matching = filters.iter().filter(|f| f.matches(proxy)).peekable();
+    if matching.peek().is_none() {
+        return Cow::Borrowed(tags);
+    }
+    let mut out = tags.clone();
+    for f in matching {
+        out.insert(&f.tag, Location::Request);
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+
+    fn proxy(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn slow_response() {
+        let filters = vec![
+            ResponseFilter::new(ResponseMetric::Duration, 1000.0, "slow-response"),
+            ResponseFilter::new(ResponseMetric::BytesSent, 1e6, "large-response"),
+        ];
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("all", Location::Request);
+
+        let slow = response_tags(
+            &filters,
+            &proxy(&[("request_time", "1.501"), ("bytes_sent", "20")]),
+            &tags,
+        );
+        assert!(slow.contains("slow-response"));
+        assert!(!slow.contains("large-response"));
+        assert!(slow.contains("all"));
+
+        let fast = response_tags(
+            &filters,
+            &proxy(&[("request_time", "0.012"), ("bytes_sent", "abc")]),
+            &tags,
+        );
+        assert!(matches!(fast, Cow::Borrowed(_)));
+        assert!(!fast.contains("slow-response"));
+
+        let missing = response_tags(&filters, &HashMap::new(), &tags);
+        assert!(!missing.contains("slow-response"));
+    }
+}
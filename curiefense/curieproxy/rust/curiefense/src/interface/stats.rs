@@ -1,5 +1,5 @@
 use serde::{ser::SerializeSeq, Serialize};
-use std::{marker::PhantomData, time::Instant};
+use std::{collections::BTreeMap, marker::PhantomData, time::Instant};
 
 use crate::{config::hostmap::SecurityPolicy, utils::json::BigTableKV};
 
@@ -11,6 +11,19 @@ pub struct TimingInfo {
     limit: Option<u64>,
     acl: Option<u64>,
     content_filter: Option<u64>,
+    // durations of the steps, not cumulated
+    body_parse: Option<u64>,
+    geo: Option<u64>,
+    globalfilters: Option<u64>,
+    body_bytes: Option<u64>,
+}
+
+/// durations of the request mapping steps, in microseconds, and the amount of body bytes that were parsed
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingDurations {
+    pub body_parse: Option<u64>,
+    pub geo: u64,
+    pub body_bytes: usize,
 }
 
 impl Serialize for TimingInfo {
@@ -43,6 +56,22 @@ impl Serialize for TimingInfo {
             name: "content_filter",
             value: &self.content_filter,
         })?;
+        mp.serialize_element(&BigTableKV {
+            name: "body_parse",
+            value: &self.body_parse,
+        })?;
+        mp.serialize_element(&BigTableKV {
+            name: "geo",
+            value: &self.geo,
+        })?;
+        mp.serialize_element(&BigTableKV {
+            name: "globalfilters",
+            value: &self.globalfilters,
+        })?;
+        mp.serialize_element(&BigTableKV {
+            name: "body_bytes",
+            value: &self.body_bytes,
+        })?;
         mp.end()
     }
 }
@@ -65,6 +94,9 @@ pub struct SecpolStats {
     pub content_filter_enabled: bool,
     pub limit_amount: usize,
     pub globalfilters_amount: usize,
+    pub globalfilter_enabled: bool,
+    pub limit_enabled: bool,
+    pub flow_enabled: bool,
 }
 
 impl SecpolStats {
@@ -74,10 +106,35 @@ impl SecpolStats {
             content_filter_enabled: policy.content_filter_active,
             limit_amount: policy.limits.len(),
             globalfilters_amount,
+            globalfilter_enabled: policy.globalfilter_active,
+            limit_enabled: policy.limit_active,
+            flow_enabled: policy.flow_active,
+        }
+    }
+
+    /// stages that were skipped because they are disabled in the security policy
+    pub fn skipped_stages(&self) -> Vec<&'static str> {
+        let mut out = Vec::new();
+        if !self.globalfilter_enabled {
+            out.push("globalfilter");
+        }
+        if !self.flow_enabled {
+            out.push("flow");
+        }
+        if !self.limit_enabled {
+            out.push("limit");
         }
+        out
     }
 }
 
+/// content filter matches for a rule category
+#[derive(Debug, Default, Clone, Serialize, PartialEq, Eq)]
+pub struct CfCategoryStats {
+    pub triggered: usize,
+    pub active: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Stats {
     start: Instant,
@@ -104,6 +161,7 @@ pub struct Stats {
     pub content_filter_total: usize,
     content_filter_triggered: usize,
     content_filter_active: usize,
+    pub content_filter_categories: BTreeMap<String, CfCategoryStats>,
 
     pub timing: TimingInfo,
 }
@@ -130,6 +188,7 @@ impl Stats {
             content_filter_total: 0,
             content_filter_triggered: 0,
             content_filter_active: 0,
+            content_filter_categories: BTreeMap::new(),
             timing: TimingInfo::default(),
         }
     }
@@ -173,6 +232,19 @@ impl StatsCollect<BStageInit> {
 }
 
 impl StatsCollect<BStageSecpol> {
+    /// records the durations of the request mapping steps and of the global filters evaluation, in microseconds
+    pub fn durations(self, mapping: MappingDurations, globalfilters: u64) -> Self {
+        let mut stats = self.stats;
+        stats.timing.body_parse = mapping.body_parse;
+        stats.timing.geo = Some(mapping.geo);
+        stats.timing.globalfilters = Some(globalfilters);
+        stats.timing.body_bytes = Some(mapping.body_bytes as u64);
+        StatsCollect {
+            stats,
+            phantom: PhantomData,
+        }
+    }
+
     pub fn mapped(self, globalfilters_total: usize, globalfilters_active: usize) -> StatsCollect<BStageMapped> {
         let mut stats = self.stats;
         stats.processing_stage = 2;
@@ -286,12 +358,19 @@ impl StatsCollect<BStageAcl> {
         }
     }
 
-    pub fn cf_matches(self, total: usize, triggered: usize, active: usize) -> StatsCollect<BStageContentFilter> {
+    pub fn cf_matches(
+        self,
+        total: usize,
+        triggered: usize,
+        active: usize,
+        categories: BTreeMap<String, CfCategoryStats>,
+    ) -> StatsCollect<BStageContentFilter> {
         let mut stats = self.stats;
         stats.processing_stage = 6;
         stats.content_filter_total = total;
         stats.content_filter_active = active;
         stats.content_filter_triggered = triggered;
+        stats.content_filter_categories = categories;
         stats.timing.content_filter = Some(stats.start.elapsed().as_micros() as u64);
         StatsCollect {
             stats,
@@ -1,9 +1,23 @@
 use crate::config::contentfilter::SectionIdx;
 use crate::config::virtualtags::VirtualTags;
+use lazy_static::lazy_static;
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 
+lazy_static! {
+    /// maximum amount of distinct locations that are logged for a tag or a reason
+    pub static ref MAX_LOGGED_LOCATIONS: usize = std::env::var("MAX_LOGGED_LOCATIONS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10);
+    /// when set, the locations of a reason are logged as a single sorted list, instead of the main location and
+    /// the extra ones
+    pub static ref FLATTEN_REASON_LOCATIONS: bool = std::env::var("FLATTEN_REASON_LOCATIONS")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Location {
     Request,
@@ -258,6 +272,43 @@ pub fn all_parents(locs: HashSet<Location>) -> HashSet<Location> {
     out
 }
 
+/// the first distinct locations of a list, and the amount of distinct locations that were left out
+///
+/// serialized as a list of location descriptions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CappedLocations<'t> {
+    pub kept: Vec<&'t Location>,
+    pub truncated: usize,
+}
+
+impl<'t> CappedLocations<'t> {
+    pub fn new<I: IntoIterator<Item = &'t Location>>(locations: I, max: usize) -> Self {
+        let mut seen = HashSet::new();
+        let mut kept = Vec::new();
+        let mut truncated = 0;
+        for l in locations {
+            if !seen.insert(l) {
+                continue;
+            }
+            if kept.len() < max {
+                kept.push(l);
+            } else {
+                truncated += 1;
+            }
+        }
+        CappedLocations { kept, truncated }
+    }
+}
+
+impl<'t> Serialize for CappedLocations<'t> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.kept.iter().map(|l| l.to_string()))
+    }
+}
+
 /// a newtype representing tags, to make sure they are tagified when inserted
 #[derive(Debug, Clone)]
 pub struct Tags {
@@ -289,7 +340,9 @@ impl Serialize for Tags {
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.tags.keys())
+        let mut keys: Vec<&String> = self.tags.keys().collect();
+        keys.sort();
+        serializer.collect_seq(keys)
     }
 }
 
@@ -443,16 +496,15 @@ impl Tags {
         I: Iterator<Item = &'t str>,
         Q: Iterator<Item = (&'t str, String)>,
     {
-        let mut sq = serializer.serialize_seq(None)?;
-        for t in self.tags.keys() {
+        // tags are sorted so that the logs do not depend on the hashmap ordering
+        let mut all: Vec<String> = self.tags.keys().cloned().collect();
+        all.extend(extra.map(tagify));
+        all.extend(extra_qualified.map(|(k, v)| Self::qualified(k, &v)));
+        all.sort();
+        let mut sq = serializer.serialize_seq(Some(all.len()))?;
+        for t in &all {
             sq.serialize_element(t)?;
         }
-        for t in extra {
-            sq.serialize_element(&tagify(t))?;
-        }
-        for (k, v) in extra_qualified {
-            sq.serialize_element(&Self::qualified(k, &v))?;
-        }
         sq.end()
     }
 }
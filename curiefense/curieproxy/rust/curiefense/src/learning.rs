@@ -0,0 +1,167 @@
+/// learning mode, where requests are never blocked, and the shapes of the request values are logged
+///
+/// The observations are meant to be aggregated offline, to build baseline content filter profiles. Only the
+/// names of the fields and the shapes of their values are recorded, never the values themselves. Paths are
+/// normalized into endpoints, so that identifiers do not create a new endpoint per request.
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::interface::{AnalyzeResult, Location};
+use crate::requestfields::RequestField;
+
+lazy_static! {
+    /// maximum amount of fields that are recorded per request
+    pub static ref LEARNING_MAX_FIELDS: usize =
+        std::env::var("LEARNING_MAX_FIELDS").ok().and_then(|s| s.parse().ok()).unwrap_or(64);
+}
+
+/// longer field names are truncated
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueShape {
+    Empty,
+    Numeric,
+    Hex,
+    Uuid,
+    Alpha,
+    Alphanumeric,
+    Text,
+}
+
+pub fn value_shape(value: &str) -> ValueShape {
+    let is_hex = |s: &str| s.chars().all(|c| c.is_ascii_hexdigit());
+    if value.is_empty() {
+        ValueShape::Empty
+    } else if value.chars().all(|c| c.is_ascii_digit()) {
+        ValueShape::Numeric
+    } else if value.len() == 36
+        && value.split('-').map(|p| p.len()).eq([8, 4, 4, 4, 12].iter().copied())
+        && value.split('-').all(is_hex)
+    {
+        ValueShape::Uuid
+    } else if value.len() >= 16 && is_hex(value) {
+        ValueShape::Hex
+    } else if value.chars().all(|c| c.is_ascii_alphabetic()) {
+        ValueShape::Alpha
+    } else if value.chars().all(|c| c.is_ascii_alphanumeric()) {
+        ValueShape::Alphanumeric
+    } else {
+        ValueShape::Text
+    }
+}
+
+/// replaces the path segments that look like identifiers with their shape
+pub fn endpoint(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match value_shape(segment) {
+            ValueShape::Numeric => "{numeric}",
+            ValueShape::Hex => "{hex}",
+            ValueShape::Uuid => "{uuid}",
+            _ => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Observation {
+    pub section: &'static str,
+    pub name: String,
+    pub shape: ValueShape,
+    pub length: usize,
+}
+
+/// what was observed in a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Observations {
+    pub endpoint: String,
+    pub method: String,
+    pub fields: Vec<Observation>,
+    /// amount of fields that were not recorded, because of LEARNING_MAX_FIELDS
+    pub truncated: usize,
+}
+
+impl Observations {
+    pub fn new(path: &str, method: &str, sections: &[(&'static str, &RequestField)], max_fields: usize) -> Self {
+        let mut fields: Vec<Observation> = sections
+            .iter()
+            .flat_map(|&(section, field)| {
+                field.iter().map(move |(name, value)| Observation {
+                    section,
+                    name: name.chars().take(MAX_NAME_LENGTH).collect(),
+                    shape: value_shape(value),
+                    length: value.len(),
+                })
+            })
+            .collect();
+        // sorted so that the kept fields do not depend on the hashmaps ordering
+        fields.sort_by(|a, b| (a.section, &a.name).cmp(&(b.section, &b.name)));
+        let truncated = fields.len().saturating_sub(max_fields);
+        fields.truncate(max_fields);
+        Observations {
+            endpoint: endpoint(path),
+            method: method.to_string(),
+            fields,
+            truncated,
+        }
+    }
+}
+
+/// in learning mode, blocking decisions are turned into monitoring ones, and the request is tagged
+pub fn learning_mode(mut result: AnalyzeResult) -> AnalyzeResult {
+    if !result.rinfo.rinfo.secpolicy.learning_mode {
+        return result;
+    }
+    result.decision = result.decision.monitor_only();
+    result.tags.insert("learning-mode", Location::Request);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes() {
+        assert_eq!(value_shape(""), ValueShape::Empty);
+        assert_eq!(value_shape("1234"), ValueShape::Numeric);
+        assert_eq!(value_shape("0123456789abcdef0123"), ValueShape::Hex);
+        assert_eq!(value_shape("beef"), ValueShape::Alpha);
+        assert_eq!(value_shape("af36dcec-524d-4d21-b90e-22d5798a6300"), ValueShape::Uuid);
+        assert_eq!(value_shape("admin"), ValueShape::Alpha);
+        assert_eq!(value_shape("user42"), ValueShape::Alphanumeric);
+        assert_eq!(value_shape("a b"), ValueShape::Text);
+        assert_eq!(
+            endpoint("/users/1234/files/af36dcec-524d-4d21-b90e-22d5798a6300"),
+            "/users/{numeric}/files/{uuid}"
+        );
+    }
+
+    #[test]
+    fn bounded_observations() {
+        let mut args = RequestField::new(&[]);
+        for i in 0..10 {
+            let name = format!("arg{}", i);
+            args.add(name.clone(), Location::UriArgument(name), i.to_string());
+        }
+        let mut headers = RequestField::new(&[]);
+        headers.add(
+            "user-agent".to_string(),
+            Location::Header("user-agent".to_string()),
+            "curl/7.58.0".to_string(),
+        );
+        let observations = Observations::new("/api/42", "POST", &[("args", &args), ("headers", &headers)], 4);
+        assert_eq!(observations.endpoint, "/api/{numeric}");
+        assert_eq!(observations.truncated, 7);
+        let names: Vec<&str> = observations.fields.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, vec!["arg0", "arg1", "arg2", "arg3"]);
+        assert!(observations
+            .fields
+            .iter()
+            .all(|o| o.section == "args" && o.shape == ValueShape::Numeric && o.length == 1));
+        let serialized = serde_json::to_value(&observations).unwrap();
+        assert_eq!(serialized["fields"][0]["shape"], "numeric");
+    }
+}
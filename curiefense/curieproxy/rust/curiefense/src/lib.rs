@@ -3,14 +3,17 @@ pub mod analyze;
 pub mod body;
 pub mod config;
 pub mod contentfilter;
+pub mod decisioncache;
 pub mod flow;
 pub mod geo;
 pub mod grasshopper;
 pub mod incremental;
 pub mod interface;
 pub mod ipinfo;
+pub mod learning;
 pub mod limit;
 pub mod logs;
+pub mod logsink;
 pub mod redis;
 pub mod requestfields;
 pub mod securitypolicy;
@@ -35,9 +38,12 @@ use tagging::tag_request;
 use utils::{map_request, RawRequest, RequestInfo};
 
 use crate::config::hostmap::SecurityPolicy;
+use crate::config::matchers::RequestSelector;
+use crate::config::raw::OversizedBodyPolicy;
 
 fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mut Logs) -> bool {
-    if let Some(rbzid) = reqinfo.cookies.get("rbzid") {
+    let cookie_name = &reqinfo.rinfo.secpolicy.settings.challenge_cookie.name;
+    if let Some(rbzid) = reqinfo.cookies.get(cookie_name) {
         if let Some(ua) = reqinfo.headers.get("user-agent") {
             logs.debug(|| format!("Checking rbzid cookie {} with user-agent {}", rbzid, ua));
             return match gh.parse_rbzid(&rbzid.replace('-', "="), ua) {
@@ -54,6 +60,52 @@ fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mu
     false
 }
 
+/// whether the request comes from a human, the override field taking precedence over the challenge cookie
+pub fn is_human<GH: Grasshopper>(
+    mgh: Option<&GH>,
+    human_override: Option<&RequestSelector>,
+    reqinfo: &RequestInfo,
+    logs: &mut Logs,
+) -> bool {
+    if let Some(sel) = human_override {
+        if let Some(overridden) = grasshopper::human_override(sel, reqinfo) {
+            logs.info(|| {
+                format!(
+                    "humanity overridden by {}: {}",
+                    sel,
+                    if overridden { "human" } else { "bot" }
+                )
+            });
+            return overridden;
+        }
+    }
+    // without grasshopper, default to being a bot
+    match mgh {
+        Some(gh) => challenge_verified(gh, reqinfo, logs),
+        None => false,
+    }
+}
+
+/// blocks requests whose uri is longer than the max_uri_length of the content filter profile
+fn uri_too_long(id: String, expected: usize, actual: usize) -> (Action, BlockReason) {
+    (
+        Action {
+            atype: ActionType::Block,
+            block_mode: true,
+            status: 414,
+            headers: None,
+            multi_headers: None,
+            challenge: None,
+            mirror: None,
+            route: None,
+            response_headers: None,
+            content: "URI too long".to_string(),
+            extra_tags: None,
+        },
+        BlockReason::uri_too_long(id, actual, expected),
+    )
+}
+
 /// # Safety
 ///
 /// Steps a valid executor
@@ -81,6 +133,7 @@ pub fn inspect_generic_request_map<GH: Grasshopper>(
     logs: &mut Logs,
     selected_secpol: Option<&str>,
     plugins: HashMap<String, String>,
+    seed_tags: &[String],
 ) -> AnalyzeResult {
     async_std::task::block_on(inspect_generic_request_map_async(
         configpath,
@@ -89,6 +142,7 @@ pub fn inspect_generic_request_map<GH: Grasshopper>(
         logs,
         selected_secpol,
         plugins,
+        seed_tags,
     ))
 }
 
@@ -100,6 +154,7 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     logs: &mut Logs,
     selected_secpol: Option<&str>,
     plugins: HashMap<String, String>,
+    seed_tags: &[String],
 ) -> Result<APhase0, AnalyzeResult> {
     let start = chrono::Utc::now();
 
@@ -111,7 +166,7 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     #[allow(clippy::large_enum_variant)]
     enum RequestMappingResult<A> {
         NoSecurityPolicy,
-        BodyTooLarge((Action, BlockReason), RequestInfo),
+        TooLarge((Action, BlockReason), RequestInfo),
         Res(A),
     }
 
@@ -126,24 +181,6 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                 Some(secpolicy) => {
                     // this part is where we use the configuration as much as possible, while we have a lock on it
 
-                    // check if the body is too large
-                    // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
-                    let body_too_large = if let Some(body) = raw.mbody {
-                        if body.len() > secpolicy.content_filter_profile.max_body_size
-                            && !secpolicy.content_filter_profile.ignore_body
-                        {
-                            Some(body_too_large(
-                                secpolicy.content_filter_profile.id.clone(),
-                                secpolicy.content_filter_profile.max_body_size,
-                                body.len(),
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    };
-
                     let stats = StatsCollect::new(slogs.start, cfg.revision.clone())
                         .secpol(SecpolStats::build(&secpolicy, cfg.globalfilters.len()));
                     // if the max depth is equal to 0, the body will not be parsed
@@ -156,23 +193,39 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                         plugins.clone(),
                     );
 
-                    if let Some(action) = body_too_large {
-                        return RequestMappingResult::BodyTooLarge(action, reqinfo);
+                    let cf_profile = &reqinfo.rinfo.secpolicy.content_filter_profile;
+                    // with the other policies, the body is truncated or skipped when mapping the request
+                    if reqinfo.body_too_large && cf_profile.oversized_body == OversizedBodyPolicy::BlockUnverified {
+                        let action = body_too_large(
+                            cf_profile.id.clone(),
+                            cf_profile.max_body_size,
+                            raw.mbody.map(|b| b.len()).unwrap_or(0),
+                        );
+                        return RequestMappingResult::TooLarge(action, reqinfo);
+                    }
+                    if reqinfo.uri_too_long && cf_profile.block_long_uri {
+                        let action = uri_too_long(
+                            cf_profile.id.clone(),
+                            cf_profile.max_uri_length,
+                            reqinfo.rinfo.qinfo.uri.len(),
+                        );
+                        return RequestMappingResult::TooLarge(action, reqinfo);
                     }
 
                     let nflows = cfg.flows.clone();
 
-                    // without grasshopper, default to being human
-                    let is_human = if let Some(gh) = mgh {
-                        challenge_verified(gh, &reqinfo, slogs)
-                    } else {
-                        false
-                    };
+                    let is_human = is_human(
+                        mgh,
+                        reqinfo.rinfo.secpolicy.settings.human_override.as_ref(),
+                        &reqinfo,
+                        slogs,
+                    );
 
                     // slogs.debug(|| format!("rinfo {:?}", reqinfo));
                     let ntags = tag_request(
                         stats,
                         is_human,
+                        seed_tags,
                         &cfg.globalfilters,
                         &mut reqinfo,
                         &cfg.virtual_tags,
@@ -185,7 +238,14 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
             }
         }) {
             Some(RequestMappingResult::Res(x)) => x,
-            Some(RequestMappingResult::BodyTooLarge((action, br), rinfo)) => {
+            Some(RequestMappingResult::TooLarge((action, br), rinfo)) => {
+                let mut tags = tags;
+                if rinfo.uri_too_long {
+                    tags.insert("uri-too-long", Location::Uri);
+                }
+                if rinfo.body_too_large {
+                    tags.insert("body-too-large", Location::Body);
+                }
                 return Err(AnalyzeResult {
                     decision: Decision::action(action, vec![br]),
                     tags,
@@ -230,6 +290,56 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     })
 }
 
+/// runs all the inspection stages independently, returning the decision of each stage
+pub fn simulate_generic_request_map<GH: Grasshopper>(
+    configpath: &str,
+    mgh: Option<&GH>,
+    raw: RawRequest,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    plugins: HashMap<String, String>,
+    seed_tags: &[String],
+) -> (Vec<(&'static str, Decision)>, Tags) {
+    match inspect_generic_request_map_init(configpath, mgh, raw, logs, selected_secpol, plugins, seed_tags) {
+        Err(res) => (vec![("init", res.decision)], res.tags),
+        Ok(p0) => async_std::task::block_on(analyze::simulate(logs, mgh, p0, CfRulesArg::Global)),
+    }
+}
+
+/// runs the inspection up to the global filters, returning the tags without running the other stages
+pub fn tag_generic_request_map<GH: Grasshopper>(
+    configpath: &str,
+    mgh: Option<&GH>,
+    raw: RawRequest,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    plugins: HashMap<String, String>,
+    seed_tags: &[String],
+) -> Tags {
+    match inspect_generic_request_map_init(configpath, mgh, raw, logs, selected_secpol, plugins, seed_tags) {
+        Err(res) => res.tags,
+        Ok(p0) => analyze::analyze_tags(p0),
+    }
+}
+
+/// renders a template against a sample request, as it would be seen by the actions of the selected security policy
+#[allow(clippy::too_many_arguments)]
+pub fn preview_generic_template<GH: Grasshopper>(
+    configpath: &str,
+    mgh: Option<&GH>,
+    template: &str,
+    raw: RawRequest,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    plugins: HashMap<String, String>,
+    seed_tags: &[String],
+) -> anyhow::Result<String> {
+    match inspect_generic_request_map_init(configpath, mgh, raw, logs, selected_secpol, plugins, seed_tags) {
+        Err(res) => interface::render_preview(template, &res.rinfo, &res.tags),
+        Ok(p0) => interface::render_preview(template, &p0.reqinfo, &p0.itags),
+    }
+}
+
 // generic entry point when the request map has already been parsed
 pub async fn inspect_generic_request_map_async<GH: Grasshopper>(
     configpath: &str,
@@ -238,8 +348,9 @@ pub async fn inspect_generic_request_map_async<GH: Grasshopper>(
     logs: &mut Logs,
     selected_secpol: Option<&str>,
     plugins: HashMap<String, String>,
+    seed_tags: &[String],
 ) -> AnalyzeResult {
-    match inspect_generic_request_map_init(configpath, mgh, raw, logs, selected_secpol, plugins) {
+    match inspect_generic_request_map_init(configpath, mgh, raw, logs, selected_secpol, plugins, seed_tags) {
         Err(res) => res,
         Ok(p0) => analyze::analyze(logs, mgh, p0, CfRulesArg::Global).await,
     }
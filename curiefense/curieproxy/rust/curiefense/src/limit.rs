@@ -1,39 +1,95 @@
 use crate::interface::stats::{BStageFlow, BStageLimit, StatsCollect};
 use crate::logs::Logs;
-use crate::redis::REDIS_KEY_PREFIX;
+use crate::redis::{QueryMode, REDIS_KEY_PREFIX};
 use redis::aio::ConnectionManager;
 
 use crate::config::limit::Limit;
 use crate::config::limit::LimitThreshold;
-use crate::interface::{stronger_decision, BlockReason, Location, SimpleDecision, Tags};
+use crate::interface::{stronger_decision, BDecision, BlockReason, Location, SimpleDecision, Tags};
+use crate::utils::templating::{TVar, TemplatePart};
 use crate::utils::{select_string, RequestInfo};
+use serde_json::json;
+use std::collections::HashMap;
 
 fn build_key(reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> Option<String> {
     let mut key = limit.id.clone();
     for kpart in limit.key.iter().map(|r| select_string(reqinfo, r, Some(tags))) {
         key += &kpart?;
     }
-    Some(format!("{}{:X}", *REDIS_KEY_PREFIX, md5::compute(key)))
+    // as for the key selectors, the limit is skipped when a selected value is missing
+    for part in limit.key_template.iter().flatten() {
+        match part {
+            TemplatePart::Raw(s) => key += s,
+            TemplatePart::Var(TVar::Selector(sel)) => key += &select_string(reqinfo, sel, Some(tags))?,
+            TemplatePart::Var(TVar::Capture(name)) => key += reqinfo.captures.get(name)?,
+            TemplatePart::Var(tv) => key += if tv.tags_match(tags) { "true" } else { "false" },
+        }
+    }
+    // bucket states are hashes, they must not share their keys with the window counters
+    let kind = if limit.bucket.is_some() { "bucket_" } else { "" };
+    Some(format!("{}{}{:X}", *REDIS_KEY_PREFIX, kind, md5::compute(key)))
 }
 
-#[allow(clippy::too_many_arguments)]
-fn limit_pure_react(tags: &mut Tags, limit: &Limit, threshold: &LimitThreshold) -> SimpleDecision {
+/// atomically refills the bucket stored in KEYS[1], and takes a token
+///
+/// ARGV holds the rate (tokens per second), burst and current time in milliseconds. It returns the amount of
+/// remaining tokens, or -1 if the bucket was empty. It is exercised against redis by the lua tests.
+///
+/// When ARGV[4] is "1", the bucket is left untouched.
+pub const TOKEN_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local read_only = ARGV[4] == '1'
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1])
+local ts = tonumber(state[2])
+if tokens == nil or ts == nil then
+    tokens = burst
+    ts = now
+end
+if now > ts then
+    tokens = math.min(burst, tokens + (now - ts) * rate / 1000)
+    ts = now
+end
+local allowed = tokens >= 1
+if allowed then
+    tokens = tokens - 1
+end
+if not read_only then
+    redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(ts))
+    redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+end
+if allowed then
+    return math.floor(tokens)
+end
+return -1
+"#;
+
+fn limit_pure_react(tags: &mut Tags, limit: &Limit, threshold: &LimitThreshold, retry_after: u64) -> SimpleDecision {
     tags.insert_qualified("limit-id", &limit.id, Location::Request);
     tags.insert_qualified("limit-name", &limit.name, Location::Request);
-    let action = threshold.action.clone();
+    let mut action = threshold.action.clone();
     let decision = action.atype.to_bdecision();
+    if limit.retry_after && decision == BDecision::Blocking {
+        action.headers.get_or_insert_with(HashMap::new).insert(
+            "retry-after".to_string(),
+            vec![TemplatePart::Raw(retry_after.to_string())],
+        );
+    }
     for t in &limit.tags {
         tags.insert(t, Location::Request);
     }
-    SimpleDecision::Action(
-        action,
-        vec![BlockReason::limit(
-            limit.id.clone(),
-            limit.name.clone(),
-            threshold.limit,
-            decision,
-        )],
-    )
+    let mut reason = BlockReason::limit(
+        limit.id.clone(),
+        limit.name.clone(),
+        limit.bucket.map(|b| b.burst).unwrap_or(threshold.limit),
+        decision,
+    );
+    if limit.bucket.is_some() {
+        reason.extra = json!({ "next_token_in": retry_after });
+    }
+    SimpleDecision::Action(action, vec![reason])
 }
 
 fn limit_match(tags: &Tags, elem: &Limit) -> bool {
@@ -56,7 +112,30 @@ pub struct LimitCheck {
 
 impl LimitCheck {
     pub fn zero_limits(&self) -> bool {
-        self.limit.thresholds.iter().all(|t| t.limit == 0)
+        match &self.limit.bucket {
+            None => self.limit.thresholds.iter().all(|t| t.limit == 0),
+            Some(b) => b.burst == 0,
+        }
+    }
+
+    /// builds the result from the redis answer, which is the counter for window limits, and the remaining
+    /// tokens (or -1) for token buckets
+    ///
+    /// ttl is the time to live of the window counter, in seconds, as returned by redis
+    pub fn result(&self, value: i64, ttl: i64) -> LimitResult {
+        let retry_after = match &self.limit.bucket {
+            // the time it takes to refill a single token
+            Some(b) => (1.0 / b.rate).ceil() as u64,
+            // the counter has no expiration yet, it will be set to the full window
+            None if ttl <= 0 => self.limit.timeframe,
+            None => ttl as u64,
+        };
+        LimitResult {
+            limit: self.limit.clone(),
+            curcount: value,
+            remaining: self.limit.bucket.map(|_| value),
+            retry_after,
+        }
     }
 }
 
@@ -84,7 +163,10 @@ pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags
         out.push(LimitCheck {
             key,
             pairwith,
-            limit: limit.clone(),
+            limit: Limit {
+                thresholds: limit.thresholds_for(&reqinfo.rinfo.secpolicy.entry.id).to_vec(),
+                ..limit.clone()
+            },
         })
     }
     out
@@ -94,17 +176,45 @@ pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags
 pub struct LimitResult {
     pub limit: Limit,
     pub curcount: i64,
+    /// remaining tokens for token buckets, -1 when the bucket is empty
+    pub remaining: Option<i64>,
+    /// seconds until the window resets, or until a token is available
+    pub retry_after: u64,
 }
 
-pub fn limit_build_query(pipe: &mut redis::Pipeline, checks: &[LimitCheck]) {
+pub fn limit_build_query(pipe: &mut redis::Pipeline, checks: &[LimitCheck], mode: QueryMode) {
+    let now = chrono::Utc::now().timestamp_millis();
+    let read_only = mode == QueryMode::ReadOnly;
     for check in checks {
         let key = &check.key;
         if !check.zero_limits() {
-            match &check.pairwith {
-                None => {
+            match (&check.limit.bucket, &check.pairwith) {
+                (Some(bucket), _) => {
+                    pipe.cmd("EVAL")
+                        .arg(TOKEN_BUCKET_SCRIPT)
+                        .arg(1)
+                        .arg(key)
+                        .arg(bucket.rate)
+                        .arg(bucket.burst)
+                        .arg(now)
+                        .arg(if read_only { 1 } else { 0 });
+                }
+                (None, None) if read_only => {
+                    pipe.cmd("GET").arg(key).cmd("TTL").arg(key);
+                }
+                (None, None) => {
                     pipe.cmd("INCR").arg(key).cmd("TTL").arg(key);
                 }
-                Some(pv) => {
+                (None, Some(pv)) if read_only => {
+                    pipe.cmd("SISMEMBER")
+                        .arg(key)
+                        .arg(pv)
+                        .cmd("SCARD")
+                        .arg(key)
+                        .cmd("TTL")
+                        .arg(key);
+                }
+                (None, Some(pv)) => {
                     pipe.cmd("SADD")
                         .arg(key)
                         .arg(pv)
@@ -124,17 +234,40 @@ pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
     redis: &mut ConnectionManager,
     iter: &mut I,
     checks: Vec<LimitCheck>,
+    mode: QueryMode,
 ) -> anyhow::Result<Vec<LimitResult>> {
     let mut out = Vec::new();
     let mut pipe = redis::pipe();
 
     for check in checks {
+        if check.limit.bucket.is_some() {
+            let remaining = if check.zero_limits() {
+                -1
+            } else {
+                match iter.next() {
+                    None => anyhow::bail!("Empty iterator when getting tokens for {:?}", check.limit),
+                    Some(r) => r.unwrap_or(-1),
+                }
+            };
+            logs.debug(|| format!("limit {} remaining tokens={}", check.limit.id, remaining));
+            out.push(check.result(remaining, -1));
+            continue;
+        }
         let (curcount, expire) = if check.zero_limits() {
             (1, 0)
         } else {
+            // in read only mode, the counter is not updated, and the request is added to the count
+            let added = match (mode, &check.pairwith) {
+                (QueryMode::Update, _) => 0,
+                (QueryMode::ReadOnly, None) => 1,
+                (QueryMode::ReadOnly, Some(_)) => match iter.next() {
+                    None => anyhow::bail!("Empty iterator when getting membership for {:?}", check.limit),
+                    Some(r) => 1 - r.unwrap_or(0),
+                },
+            };
             let curcount = match iter.next() {
                 None => anyhow::bail!("Empty iterator when getting curcount for {:?}", check.limit),
-                Some(r) => r.unwrap_or(0),
+                Some(r) => r.unwrap_or(0) + added,
             };
             let expire = match iter.next() {
                 None => anyhow::bail!("Empty iterator when getting expire for {:?}", check.limit),
@@ -143,14 +276,11 @@ pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
             (curcount, expire)
         };
         logs.debug(|| format!("limit {} curcount={} expire={}", check.limit.id, curcount, expire));
-        if expire < 0 {
+        if expire < 0 && mode == QueryMode::Update {
             pipe.cmd("EXPIRE").arg(&check.key).arg(&check.limit.timeframe);
         }
         pipe.query_async(redis).await?;
-        out.push(LimitResult {
-            limit: check.limit,
-            curcount,
-        })
+        out.push(check.result(curcount, expire))
     }
     Ok(out)
 }
@@ -164,16 +294,284 @@ pub fn limit_process(
 ) -> (SimpleDecision, StatsCollect<BStageLimit>) {
     let mut out = SimpleDecision::Pass;
     for result in results {
-        if result.curcount > 0 {
-            for threshold in &result.limit.thresholds {
-                // Only one action with highest limit larger than current
-                // counter will be applied, all the rest will be skipped.
-                if result.curcount > threshold.limit as i64 {
-                    out = stronger_decision(out, limit_pure_react(tags, &result.limit, threshold));
+        out = stronger_decision(out, limit_result_react(tags, result));
+    }
+
+    (out, stats.limit(nlimits, results.len()))
+}
+
+fn limit_result_react(tags: &mut Tags, result: &LimitResult) -> SimpleDecision {
+    let mut out = SimpleDecision::Pass;
+    match result.remaining {
+        // token buckets ignore the threshold limits, their actions apply when the bucket is empty
+        Some(remaining) => {
+            if remaining < 0 {
+                for threshold in &result.limit.thresholds {
+                    out = stronger_decision(
+                        out,
+                        limit_pure_react(tags, &result.limit, threshold, result.retry_after),
+                    );
+                }
+            }
+        }
+        None => {
+            if result.curcount > 0 {
+                for threshold in &result.limit.thresholds {
+                    // Only one action with highest limit larger than current
+                    // counter will be applied, all the rest will be skipped.
+                    if result.curcount > threshold.limit as i64 {
+                        out = stronger_decision(
+                            out,
+                            limit_pure_react(tags, &result.limit, threshold, result.retry_after),
+                        );
+                    }
                 }
             }
         }
     }
+    out
+}
 
-    (out, stats.limit(nlimits, results.len()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::limit::TokenBucket;
+    use crate::config::matchers::RequestSelector;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::{BDecision, Initiator, SimpleAction};
+    use crate::utils::templating::parse_request_template_strict;
+    use crate::utils::RequestBuilder;
+    use std::sync::Arc;
+
+    const BUCKET: TokenBucket = TokenBucket { rate: 2.0, burst: 5 };
+
+    fn keyed_limit(template: &str) -> Limit {
+        Limit {
+            id: "lid".to_string(),
+            name: "keyed".to_string(),
+            timeframe: 60,
+            thresholds: Vec::new(),
+            exclude: Default::default(),
+            include: Default::default(),
+            pairwith: None,
+            key: vec![RequestSelector::Ip],
+            key_template: Some(parse_request_template_strict(template).unwrap()),
+            tags: Vec::new(),
+            bucket: None,
+            retry_after: false,
+            entry_overrides: Arc::default(),
+        }
+    }
+
+    fn keyed_request(api_key: Option<&str>) -> RequestInfo {
+        let mut builder = RequestBuilder::new("GET", "/api")
+            .ip("1.2.3.4")
+            .authority("localhost")
+            .security_policy(std::sync::Arc::new(SecurityPolicy::empty()));
+        if let Some(k) = api_key {
+            builder = builder.header("x-api-key", k);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn key_template_separates_header_values() {
+        let limit = keyed_limit("${path}/${headers.x-api-key}");
+        let tags = Tags::new(&VirtualTags::default());
+        let key = |api_key| build_key(&keyed_request(api_key), &tags, &limit);
+        let k1 = key(Some("k1"));
+        assert!(k1.is_some());
+        assert_eq!(k1, key(Some("k1")));
+        assert_ne!(k1, key(Some("k2")));
+        // the header is missing, the limit does not apply
+        assert_eq!(key(None), None);
+    }
+
+    #[test]
+    fn key_template_tags() {
+        let limit = keyed_limit("${tags.premium}");
+        let mut tags = Tags::new(&VirtualTags::default());
+        let rinfo = keyed_request(None);
+        let regular = build_key(&rinfo, &tags, &limit);
+        tags.insert("premium", Location::Request);
+        assert_ne!(regular, build_key(&rinfo, &tags, &limit));
+    }
+
+    #[test]
+    fn bucket_key_namespace() {
+        let mut limit = keyed_limit("");
+        let tags = Tags::new(&VirtualTags::default());
+        let rinfo = keyed_request(None);
+        let window = build_key(&rinfo, &tags, &limit);
+        limit.bucket = Some(BUCKET);
+        // switching a limit to a token bucket must not reuse the window counter
+        assert_ne!(window, build_key(&rinfo, &tags, &limit));
+    }
+
+    fn bucket_result(remaining: i64) -> LimitResult {
+        LimitResult {
+            limit: Limit {
+                id: "lid".to_string(),
+                name: "bucket".to_string(),
+                timeframe: 60,
+                thresholds: vec![LimitThreshold {
+                    limit: 0,
+                    action: SimpleAction::default(),
+                }],
+                exclude: Default::default(),
+                include: Default::default(),
+                pairwith: None,
+                key: Vec::new(),
+                key_template: None,
+                tags: Vec::new(),
+                bucket: Some(BUCKET),
+                retry_after: false,
+                entry_overrides: Arc::default(),
+            },
+            curcount: remaining,
+            remaining: Some(remaining),
+            retry_after: 1,
+        }
+    }
+
+    #[test]
+    fn bucket_tokens_left() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        let decision = limit_result_react(&mut tags, &bucket_result(3));
+        assert!(matches!(decision, SimpleDecision::Pass));
+        assert!(!tags.contains("limit-id:lid"));
+    }
+
+    #[test]
+    fn bucket_empty() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        match limit_result_react(&mut tags, &bucket_result(-1)) {
+            SimpleDecision::Pass => panic!("should have been blocked"),
+            SimpleDecision::Action(action, reasons) => {
+                assert_eq!(reasons[0].decision, BDecision::Blocking);
+                assert_eq!(reasons.len(), 1);
+                assert_eq!(reasons[0].extra, json!({"next_token_in": 1}));
+                assert_eq!(
+                    reasons[0].initiator,
+                    Initiator::Limit {
+                        id: "lid".to_string(),
+                        name: "bucket".to_string(),
+                        threshold: 5
+                    }
+                );
+            }
+        }
+        assert!(tags.contains("limit-id:lid"));
+    }
+
+    #[test]
+    fn entry_override_threshold() {
+        let decision = |override_entry: &str| {
+            let mut limit = keyed_limit("");
+            limit.thresholds = vec![LimitThreshold {
+                limit: 10,
+                action: SimpleAction::default(),
+            }];
+            Arc::make_mut(&mut limit.entry_overrides).insert(
+                override_entry.to_string(),
+                vec![LimitThreshold {
+                    limit: 2,
+                    action: SimpleAction::default(),
+                }],
+            );
+            let mut tags = Tags::new(&VirtualTags::default());
+            let checks = limit_info(&mut Logs::default(), &keyed_request(None), &[limit], &tags);
+            assert_eq!(checks.len(), 1);
+            limit_result_react(&mut tags, &checks[0].result(5, 30))
+        };
+        // the request matches the "entryid" security policy entry
+        assert!(matches!(decision("entryid"), SimpleDecision::Action(_, _)));
+        assert!(matches!(decision("other"), SimpleDecision::Pass));
+    }
+
+    fn window_check(retry_after: bool, action: SimpleAction) -> LimitCheck {
+        let mut limit = keyed_limit("");
+        limit.retry_after = retry_after;
+        limit.thresholds = vec![LimitThreshold { limit: 5, action }];
+        LimitCheck {
+            key: "key".to_string(),
+            pairwith: None,
+            limit,
+        }
+    }
+
+    fn retry_after_header(decision: SimpleDecision) -> Option<String> {
+        match decision {
+            SimpleDecision::Pass => panic!("should have been blocked"),
+            SimpleDecision::Action(action, _) => action.headers.and_then(|h| h.get("retry-after").cloned()).map(|t| {
+                t.iter()
+                    .map(|p| match p {
+                        TemplatePart::Raw(s) => s.clone(),
+                        TemplatePart::Var(_) => panic!("retry-after should not be templated"),
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    #[test]
+    fn read_only_query() {
+        let packed = |check: &LimitCheck, mode: QueryMode| {
+            let mut pipe = redis::pipe();
+            limit_build_query(&mut pipe, &[check.clone()], mode);
+            String::from_utf8(pipe.get_packed_pipeline()).unwrap()
+        };
+        let mut check = window_check(false, SimpleAction::default());
+        assert!(packed(&check, QueryMode::Update).contains("INCR"));
+        let query = packed(&check, QueryMode::ReadOnly);
+        assert!(!query.contains("INCR"));
+        assert!(query.contains("GET"));
+
+        check.pairwith = Some("value".to_string());
+        assert!(packed(&check, QueryMode::Update).contains("SADD"));
+        let query = packed(&check, QueryMode::ReadOnly);
+        assert!(!query.contains("SADD"));
+        assert!(query.contains("SISMEMBER"));
+    }
+
+    #[test]
+    fn retry_after_remaining_window() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        let check = window_check(true, SimpleAction::default());
+        let decision = limit_result_react(&mut tags, &check.result(6, 42));
+        assert_eq!(retry_after_header(decision), Some("42".to_string()));
+        // first hit of the window, the counter expires after the whole timeframe
+        let decision = limit_result_react(&mut tags, &check.result(6, -1));
+        assert_eq!(retry_after_header(decision), Some("60".to_string()));
+    }
+
+    #[test]
+    fn retry_after_opt_in() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        let check = window_check(false, SimpleAction::default());
+        assert_eq!(
+            retry_after_header(limit_result_react(&mut tags, &check.result(6, 42))),
+            None
+        );
+        // monitoring actions are not delayed
+        let monitor = SimpleAction {
+            atype: crate::interface::SimpleActionT::Monitor,
+            ..SimpleAction::default()
+        };
+        let check = window_check(true, monitor);
+        assert_eq!(
+            retry_after_header(limit_result_react(&mut tags, &check.result(6, 42))),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_after_bucket() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut check = window_check(true, SimpleAction::default());
+        check.limit.bucket = Some(TokenBucket { rate: 0.25, burst: 5 });
+        let decision = limit_result_react(&mut tags, &check.result(-1, -1));
+        assert_eq!(retry_after_header(decision), Some("4".to_string()));
+    }
 }
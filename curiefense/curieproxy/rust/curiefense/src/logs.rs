@@ -1,6 +1,15 @@
+use lazy_static::lazy_static;
 use serde::Serialize;
 use std::time::Instant;
 
+lazy_static! {
+    /// minimum level of the log entries written in the JSON logs, independently of the capture level
+    pub static ref LOG_OUTPUT_LEVEL: LogLevel = std::env::var("LOG_OUTPUT_LEVEL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(LogLevel::Debug);
+}
+
 #[derive(Debug, Clone)]
 pub struct Logs {
     pub level: LogLevel,
@@ -129,6 +138,11 @@ impl Logs {
         self.logs.extend(other.logs);
     }
 
+    /// a view of the logs that only serializes the entries at or above the given level
+    pub fn filtered(&self, level: LogLevel) -> FilteredLogs<'_> {
+        FilteredLogs { logs: self, level }
+    }
+
     pub fn to_json(&self) -> serde_json::Value {
         serde_json::to_value(&self.logs).unwrap_or_else(|rr| serde_json::Value::String(rr.to_string()))
     }
@@ -142,3 +156,47 @@ impl Serialize for Logs {
         serializer.collect_seq(self.logs.iter().map(|l| l.to_string()))
     }
 }
+
+pub struct FilteredLogs<'t> {
+    logs: &'t Logs,
+    level: LogLevel,
+}
+
+impl<'t> Serialize for FilteredLogs<'t> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(
+            self.logs
+                .logs
+                .iter()
+                .filter(|l| l.level >= self.level)
+                .map(|l| l.to_string()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filtered_serialization() {
+        let mut logs = Logs::new(LogLevel::Debug);
+        logs.debug("dbg");
+        logs.info("inf");
+        logs.error("err");
+
+        let all: Vec<String> = serde_json::from_value(serde_json::to_value(&logs).unwrap()).unwrap();
+        assert_eq!(all.len(), 3);
+
+        let filtered: Vec<String> =
+            serde_json::from_value(serde_json::to_value(&logs.filtered(LogLevel::Info)).unwrap()).unwrap();
+        assert_eq!(filtered.len(), 2);
+        assert!(filtered[0].starts_with('I') && filtered[0].ends_with("inf"));
+        assert!(filtered[1].starts_with('E') && filtered[1].ends_with("err"));
+        // the captured logs are untouched
+        assert_eq!(logs.logs.len(), 3);
+    }
+}
@@ -0,0 +1,182 @@
+/// optional sink the json logs are directly written to
+///
+/// The sink is configured with the LOG_SINK environment variable, either as `unix:/path/to/socket` or
+/// `tcp:host:port`. Log lines are queued in a bounded queue (LOG_SINK_QUEUE entries, 1024 by default) and
+/// written by a background thread, one json object per line. When the queue is full, log lines are dropped
+/// so that request processing is never slowed down by the sink. For the same reason, all log lines are dropped when
+/// the background thread can't be started.
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+lazy_static! {
+    pub static ref LOG_SINK: Option<LogSink> =
+        std::env::var("LOG_SINK")
+            .ok()
+            .and_then(|s| LogSinkAddr::parse(&s))
+            .map(|addr| {
+                let queue_size = std::env::var("LOG_SINK_QUEUE")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(DEFAULT_QUEUE_SIZE);
+                LogSink::new(addr, queue_size)
+            });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogSinkAddr {
+    Unix(String),
+    Tcp(String),
+}
+
+impl LogSinkAddr {
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Some(LogSinkAddr::Unix(path.to_string()))
+        } else {
+            s.strip_prefix("tcp:").map(|addr| LogSinkAddr::Tcp(addr.to_string()))
+        }
+    }
+
+    fn connect(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        match self {
+            LogSinkAddr::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            LogSinkAddr::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            LogSinkAddr::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+type Connection = Option<Box<dyn Write + Send>>;
+
+pub struct LogSink {
+    /// None when the background thread could not be started
+    sender: Option<SyncSender<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogSink {
+    pub fn new(addr: LogSinkAddr, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel(queue_size);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let wdropped = dropped.clone();
+        let sender = match std::thread::Builder::new()
+            .name("curiefense-logsink".to_string())
+            .spawn(move || sink_writer(addr, receiver, wdropped))
+        {
+            Ok(_) => Some(sender),
+            Err(rr) => {
+                eprintln!("could not spawn the log sink thread, logs will be dropped: {}", rr);
+                None
+            }
+        };
+        LogSink { sender, dropped }
+    }
+
+    /// queues a log line, returns false if it was dropped
+    pub fn send(&self, line: &[u8]) -> bool {
+        let sent = match &self.sender {
+            Some(sender) => sender.try_send(line.to_vec()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// amount of log lines that could not be written to the sink
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// writes a log line, returns false if it could not be written
+fn write_line(addr: &LogSinkAddr, conn: &mut Connection, mut line: Vec<u8>) -> bool {
+    line.push(b'\n');
+    // a broken connection is reopened once, the line is dropped if that fails too
+    for _ in 0..2 {
+        if conn.is_none() {
+            *conn = addr.connect().ok();
+        }
+        match conn.as_mut() {
+            None => return false,
+            Some(c) => {
+                if c.write_all(&line).and_then(|_| c.flush()).is_ok() {
+                    return true;
+                }
+                *conn = None;
+            }
+        }
+    }
+    false
+}
+
+fn sink_writer(addr: LogSinkAddr, receiver: Receiver<Vec<u8>>, dropped: Arc<AtomicU64>) {
+    let mut conn = None;
+    for line in receiver {
+        if !write_line(&addr, &mut conn, line) {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_addr() {
+        assert_eq!(
+            LogSinkAddr::parse("unix:/tmp/log.sock"),
+            Some(LogSinkAddr::Unix("/tmp/log.sock".to_string()))
+        );
+        assert_eq!(
+            LogSinkAddr::parse("tcp:127.0.0.1:5000"),
+            Some(LogSinkAddr::Tcp("127.0.0.1:5000".to_string()))
+        );
+        assert_eq!(LogSinkAddr::parse("udp:127.0.0.1:5000"), None);
+    }
+
+    #[test]
+    fn tcp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sink = LogSink::new(LogSinkAddr::Tcp(addr.to_string()), 4);
+        assert!(sink.send(br#"{"a":1}"#));
+        assert!(sink.send(br#"{"b":2}"#));
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"a\":1}\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"b\":2}\n");
+        assert_eq!(sink.dropped(), 0);
+    }
+
+    #[test]
+    fn no_writer() {
+        let sink = LogSink {
+            sender: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        assert!(!sink.send(b"{}"));
+        assert_eq!(sink.dropped(), 1);
+    }
+}
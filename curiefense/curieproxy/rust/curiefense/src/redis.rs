@@ -11,6 +11,15 @@ lazy_static! {
         .unwrap_or_default();
 }
 
+/// how the flow and limit states stored in redis are queried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryMode {
+    /// the states are updated with the request, as when it is analyzed
+    Update,
+    /// the states are only read, the results are those the request would get if it was analyzed
+    ReadOnly,
+}
+
 /// creates an async connection to a redis server
 pub async fn build_pool() -> anyhow::Result<redis::aio::ConnectionManager> {
     let server = std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis".to_string());
@@ -12,6 +12,12 @@ use std::collections::{hash_map, HashMap};
 pub struct RequestField {
     pub decoding: Vec<Transformation>,
     pub fields: HashMap<String, (String, HashSet<Location>)>,
+    /// maximum amount of entries, repeated keys included, new entries are dropped beyond that
+    pub max_entries: Option<usize>,
+    /// amount of added entries
+    pub entries: usize,
+    /// set when an entry was dropped because of max_entries
+    pub truncated: bool,
 }
 
 impl RequestField {
@@ -31,6 +37,13 @@ impl RequestField {
     }
 
     pub fn add(&mut self, key: String, ds: Location, value: String) {
+        if let Some(max) = self.max_entries {
+            if self.entries >= max {
+                self.truncated = true;
+                return;
+            }
+        }
+        self.entries += 1;
         let mut v = value.clone();
         let mut replace_parameter = true;
         // try to insert each value as its decoded base64 version, if it makes sense
@@ -125,9 +138,16 @@ impl RequestField {
     }
 
     pub fn new(decoding: &[Transformation]) -> Self {
+        RequestField::with_max_entries(decoding, None)
+    }
+
+    pub fn with_max_entries(decoding: &[Transformation], max_entries: Option<usize>) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
             fields: HashMap::default(),
+            max_entries,
+            entries: 0,
+            truncated: false,
         }
     }
 
@@ -162,6 +182,9 @@ impl RequestField {
                     (k.to_string(), (v.to_string(), hs))
                 })
                 .collect(),
+            max_entries: None,
+            entries: content.len(),
+            truncated: false,
         }
     }
 }
@@ -172,7 +195,7 @@ impl serde::Serialize for RequestField {
         S: serde::Serializer,
     {
         serializer.collect_seq(self.fields.iter().map(|(k, (v, _))| BigTableKV {
-            name: k.to_string(),
+            name: k.as_str(),
             value: v,
         }))
     }
@@ -36,12 +36,19 @@ pub fn match_securitypolicy<'a>(
         },
     };
     logs.debug(|| format!("Selected hostmap {}", hostmap.name));
-    // find the first matching securitypolicy, or use the default, if it exists
+    // find the first matching securitypolicy, then the longest matching path prefix, or use the default, if it exists
     let securitypolicy: Arc<SecurityPolicy> = match hostmap
         .entries
         .iter()
         .find(|e| e.matches(path))
         .map(|m| &m.inner)
+        .or_else(|| {
+            hostmap
+                .path_prefixes
+                .iter()
+                .find(|(prefix, _)| prefix_matches(path, prefix))
+                .map(|(_, e)| e)
+        })
         .or(hostmap.default.as_ref())
     {
         None => {
@@ -53,3 +60,80 @@ pub fn match_securitypolicy<'a>(
     logs.debug(|| format!("Selected hostmap entry {}", securitypolicy.entry.id));
     Some(securitypolicy)
 }
+
+/// the prefix matches whole path segments, so that `/api` matches `/api` and `/api/users`, but not `/apix`
+fn prefix_matches(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        None => false,
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::PolicyId;
+    use crate::config::matchers::Matching;
+
+    fn mk_entry(id: &str) -> Arc<SecurityPolicy> {
+        Arc::new(SecurityPolicy {
+            entry: PolicyId {
+                id: id.to_string(),
+                name: id.to_string(),
+            },
+            ..SecurityPolicy::default()
+        })
+    }
+
+    fn prefix_config() -> Config {
+        let mut cfg = Config::empty();
+        let mut path_prefixes = vec![
+            ("/api".to_string(), mk_entry("api")),
+            ("/admin".to_string(), mk_entry("admin")),
+            ("/api/v2".to_string(), mk_entry("apiv2")),
+        ];
+        path_prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        cfg.default = Some(HostMap {
+            name: "default".to_string(),
+            entries: vec![Matching::from_str("^/static/", mk_entry("static")).unwrap()],
+            path_prefixes,
+            default: Some(mk_entry("default")),
+        });
+        cfg
+    }
+
+    fn selected_entry(cfg: &Config, path: &str) -> String {
+        let mut logs = Logs::default();
+        match_securitypolicy("example.com", path, cfg, &mut logs, None)
+            .unwrap()
+            .entry
+            .id
+            .clone()
+    }
+
+    #[test]
+    fn path_prefix_selection() {
+        let cfg = prefix_config();
+        assert_eq!(selected_entry(&cfg, "/api/users"), "api");
+        assert_eq!(selected_entry(&cfg, "/admin/login"), "admin");
+        assert_eq!(selected_entry(&cfg, "/api/v2/users"), "apiv2");
+        assert_eq!(selected_entry(&cfg, "/other"), "default");
+    }
+
+    #[test]
+    fn path_prefix_segments() {
+        let cfg = prefix_config();
+        assert_eq!(selected_entry(&cfg, "/api"), "api");
+        assert_eq!(selected_entry(&cfg, "/apix"), "default");
+        assert_eq!(selected_entry(&cfg, "/api/v2"), "apiv2");
+        assert_eq!(selected_entry(&cfg, "/api/v2x/users"), "api");
+        assert!(prefix_matches("/api/users", "/api/"));
+        assert!(!prefix_matches("/api", "/api/"));
+    }
+
+    #[test]
+    fn path_prefix_after_entries() {
+        let cfg = prefix_config();
+        assert_eq!(selected_entry(&cfg, "/static/api"), "static");
+    }
+}
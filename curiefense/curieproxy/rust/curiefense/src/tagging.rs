@@ -1,26 +1,110 @@
 use crate::config::globalfilter::{
-    GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection, PairEntry, SingleEntry,
+    FieldsRelation, GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection, PairEntry, PathSet,
+    RandomnessMetric, SingleEntry,
 };
 use crate::config::matchers::RequestSelector;
-use crate::config::raw::Relation;
+use crate::config::raw::{OversizedBodyPolicy, Relation};
 use crate::config::virtualtags::VirtualTags;
 use crate::interface::stats::{BStageMapped, BStageSecpol, StatsCollect};
-use crate::interface::{stronger_decision, BlockReason, Location, SimpleActionT, SimpleDecision, Tags};
+use crate::interface::{
+    append_multi_headers, stronger_decision, BlockReason, Location, SimpleActionT, SimpleDecision, Tags,
+};
 use crate::logs::Logs;
 use crate::requestfields::RequestField;
+use crate::utils::decoders::base64dec_all_str;
+use crate::utils::grpc::is_grpc;
+use crate::utils::jwt::hmac_sha256;
 use crate::utils::templating::parse_request_template;
 use crate::utils::templating::TVar;
 use crate::utils::templating::TemplatePart;
-use crate::utils::RequestInfo;
-use crate::utils::{selector, Selected};
+use crate::utils::url::strip_port;
+use crate::utils::{content_length_bucket, select_string, select_values, selector, Selected, CLIENT_CERT_HEADER};
+use crate::utils::{GeoIp, RequestInfo};
+use chrono::{DateTime, Timelike, Utc};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
 use regex::Regex;
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::time::Instant;
+
+lazy_static! {
+    /// when set, requests are also tagged with the ISO codes of their geo subdivisions
+    static ref GEO_ISO_TAGS: bool = std::env::var("GEO_ISO_TAGS")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// when set, identity headers are signed with HMAC-SHA256 using this secret, instead of being plain hashes
+    static ref IDENTITY_HMAC_SECRET: Option<Vec<u8>> =
+        std::env::var("IDENTITY_HMAC_SECRET").ok().map(|s| s.into_bytes());
+    /// how the segments of the identity hash input are joined
+    static ref IDENTITY_ENCODING: IdentityEncoding = IdentityEncoding {
+        separator: std::env::var("IDENTITY_SEPARATOR").unwrap_or_else(|_| ".".to_string()),
+        length_prefixed: std::env::var("IDENTITY_LENGTH_PREFIX")
+            .map(|s| s.parse().unwrap_or(false))
+            .unwrap_or(false),
+    };
+}
+
+/// encoding of the segments that make up the input of the identity hash
+///
+/// With the default settings, segments are joined with a dot, so that segments containing dots can collide. When
+/// length prefixed, each segment is written as `<length>:<segment>`, which removes the ambiguity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityEncoding {
+    pub separator: String,
+    pub length_prefixed: bool,
+}
+
+impl Default for IdentityEncoding {
+    fn default() -> Self {
+        IdentityEncoding {
+            separator: ".".to_string(),
+            length_prefixed: false,
+        }
+    }
+}
+
+impl IdentityEncoding {
+    pub fn push_segment(&self, out: &mut String, segment: &str) {
+        out.push_str(&self.separator);
+        if self.length_prefixed {
+            out.push_str(&segment.len().to_string());
+            out.push(':');
+        }
+        out.push_str(segment);
+    }
+}
+
+pub fn is_internal(networks: &[IpNet], ip: Option<IpAddr>) -> bool {
+    match ip {
+        None => false,
+        Some(ip) => networks.iter().any(|net| net.contains(&ip)),
+    }
+}
+
+/// hash used for identity headers
+pub fn identity_hash(value: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(value);
+    format!("{:X}", hasher.finalize())
+}
+
+/// value of the identity headers, signed when a secret is provided so that the backend can check it
+pub fn identity_value(value: &str, secret: Option<&[u8]>) -> String {
+    match secret {
+        None => identity_hash(value),
+        Some(key) => hmac_sha256(key, value.as_bytes())
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect(),
+    }
+}
 
-struct MatchResult {
-    matched: HashSet<Location>,
-    matching: bool,
+/// result of a global filter rule or entry, with the locations that matched
+pub struct MatchResult {
+    pub matched: HashSet<Location>,
+    pub matching: bool,
 }
 
 fn check_rule(rinfo: &RequestInfo, tags: &Tags, rel: &GlobalFilterRule) -> MatchResult {
@@ -60,6 +144,137 @@ fn check_rule(rinfo: &RequestInfo, tags: &Tags, rel: &GlobalFilterRule) -> Match
     }
 }
 
+/// the entries of a matching rule that contributed to the match, with their type, value and locations
+///
+/// all the matching entries of OR relations are recorded, not only the first one
+fn matched_tree(rinfo: &RequestInfo, tags: &Tags, rule: &GlobalFilterRule) -> Option<serde_json::Value> {
+    match rule {
+        GlobalFilterRule::Rel(rl) => {
+            let entries: Vec<serde_json::Value> =
+                rl.entries.iter().filter_map(|e| matched_tree(rinfo, tags, e)).collect();
+            let (relation, matching) = match rl.relation {
+                Relation::And => ("and", entries.len() == rl.entries.len()),
+                Relation::Or => ("or", !entries.is_empty()),
+            };
+            if matching {
+                Some(serde_json::json!({ "relation": relation, "entries": entries }))
+            } else {
+                None
+            }
+        }
+        GlobalFilterRule::Entry(e) => {
+            let res = check_entry(rinfo, tags, e);
+            if !res.matching {
+                return None;
+            }
+            let mut locations: Vec<Location> = if e.negated {
+                vec![inspected_location(&e.entry)]
+            } else {
+                res.matched.into_iter().collect()
+            };
+            locations.sort_by_key(|l| l.to_string());
+            Some(serde_json::json!({
+                "type": e.entry.kind(),
+                "id": e.entry.to_string(),
+                "negated": e.negated,
+                "locations": locations
+            }))
+        }
+    }
+}
+
+/// the part of the request an entry looks at, reported for negated entries, that match when nothing is found there
+fn inspected_location(entry: &GlobalFilterEntryE) -> Location {
+    use GlobalFilterEntryE::*;
+    match entry {
+        Ip(_)
+        | Network(_)
+        | Range4(_)
+        | Range6(_)
+        | Country(_)
+        | Region(_)
+        | SubRegion(_)
+        | RegionIso(_)
+        | SubRegionIso(_)
+        | Asn(_)
+        | AsnRange { .. }
+        | Company(_) => Location::Ip,
+        Path(_) | DecodedPath(_) | Query(_) | PathSet(_) | GrpcMethod(_) => Location::Path,
+        Uri(_) => Location::Uri,
+        Header(p) | HeaderBase64(p) => Location::Header(p.key.clone()),
+        JwtClaim(_) => Location::Header("authorization".to_string()),
+        ClientCertSubject(_) | ClientCertSan(_) => Location::Header(CLIENT_CERT_HEADER.to_string()),
+        MethodOverride(_) => Location::Headers,
+        Cookies(p) => Location::Cookie(p.key.clone()),
+        CookiesCount { .. } => Location::Cookies,
+        Args(p) | ArgsAny(p) => Location::UriArgument(p.key.clone()),
+        ArgsCount(k, _) | ArgsPresent(k) => Location::UriArgument(k.clone()),
+        FormField(p) => Location::BodyArgument(p.key.clone()),
+        BodyParseFailed(_) => Location::Body,
+        Plugins(p) => Location::Plugin(p.key.clone()),
+        Always(_)
+        | Method(_)
+        | Authority(_)
+        | Tag(_)
+        | SecurityPolicyId(_)
+        | SecurityPolicyEntryId(_)
+        | Session(_)
+        | OriginHost(_)
+        | AuthoritySuffix(_)
+        | TimeWindow { .. }
+        | CorsPreflight(_)
+        | WebsocketUpgrade(_)
+        | FieldsMatch(_, _, _)
+        | Randomness(_, _, _)
+        | DistinctValues(_, _) => Location::Request,
+    }
+}
+
+/// the base64 decoded value, when it is valid base64 and utf8, the raw value otherwise
+fn base64_or_raw(v: &str) -> std::borrow::Cow<'_, str> {
+    match base64dec_all_str(v) {
+        Ok(decoded) => std::borrow::Cow::Owned(decoded),
+        Err(_) => std::borrow::Cow::Borrowed(v),
+    }
+}
+
+/// shorter values never look random, their entropy is bounded by their length and their ratios are too noisy
+const MIN_RANDOMNESS_LENGTH: usize = 8;
+
+/// measures how random a value looks, None when it is too short to tell
+pub fn randomness(value: &str, metric: RandomnessMetric) -> Option<f64> {
+    let len = value.chars().count();
+    if len < MIN_RANDOMNESS_LENGTH {
+        return None;
+    }
+    let ratio = |f: fn(&char) -> bool| value.chars().filter(f).count() as f64 / len as f64;
+    Some(match metric {
+        RandomnessMetric::Entropy => {
+            let mut counts: HashMap<char, usize> = HashMap::new();
+            for c in value.chars() {
+                *counts.entry(c).or_default() += 1;
+            }
+            counts
+                .values()
+                .map(|&n| {
+                    let p = n as f64 / len as f64;
+                    -p * p.log2()
+                })
+                .sum()
+        }
+        RandomnessMetric::Uppercase => ratio(|c| c.is_uppercase()),
+        RandomnessMetric::Special => ratio(|c| !c.is_ascii_alphanumeric()),
+    })
+}
+
+/// compares two request fields, as in GlobalFilterEntryE::FieldsMatch
+fn fields_match(left: &str, right: &str, relation: FieldsRelation) -> bool {
+    match relation {
+        FieldsRelation::Equal => left == right,
+        FieldsRelation::Contains => !right.is_empty() && left.contains(right),
+    }
+}
+
 fn check_pair<F>(pr: &PairEntry, s: &RequestField, locf: F) -> Option<HashSet<Location>>
 where
     F: Fn(&str) -> Location,
@@ -81,7 +296,108 @@ fn check_single(pr: &SingleEntry, s: &str, loc: Location) -> Option<HashSet<Loca
     }
 }
 
-fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> MatchResult {
+/// matches a host against a domain suffix, as stored in GlobalFilterEntryE::AuthoritySuffix
+pub fn authority_suffix_match(host: &str, suffix: &str) -> bool {
+    let host = match strip_port(host) {
+        Some(h) => h.trim_end_matches('.'),
+        None => return false,
+    };
+    let (subdomains_only, domain) = match suffix.strip_prefix('.') {
+        Some(d) => (true, d),
+        None => (false, suffix),
+    };
+    if host.len() == domain.len() {
+        return !subdomains_only && host.eq_ignore_ascii_case(domain);
+    }
+    let start = match host.len().checked_sub(domain.len() + 1) {
+        Some(s) => s,
+        None => return false,
+    };
+    host.as_bytes()[start] == b'.' && host[start + 1..].eq_ignore_ascii_case(domain)
+}
+
+/// checks if a timestamp falls in a time of day window, which wraps around midnight when start > end
+pub fn time_window_match(ts: &DateTime<Utc>, start: u32, end: u32, tz: i32) -> bool {
+    let local = (ts.hour() * 60 + ts.minute()) as i32 + tz;
+    let local = local.rem_euclid(24 * 60) as u32;
+    if start <= end {
+        local >= start && local < end
+    } else {
+        local >= start || local < end
+    }
+}
+
+/// the regexes of the positive entries of a rule, with the values they are checked against
+fn regex_values<'r>(rinfo: &RequestInfo, rule: &'r GlobalFilterRule) -> Vec<(&'r Regex, String)> {
+    fn span<'r>(re: &'r Option<Regex>, s: &str) -> Option<(&'r Regex, String)> {
+        re.as_ref().map(|r| (r, s.to_string()))
+    }
+    fn pair_span<'r>(pr: &'r PairEntry, s: &RequestField) -> Option<(&'r Regex, String)> {
+        s.get(&pr.key).and_then(|v| span(&pr.re, v))
+    }
+    match rule {
+        GlobalFilterRule::Rel(rl) => rl.entries.iter().flat_map(|e| regex_values(rinfo, e)).collect(),
+        GlobalFilterRule::Entry(e) if e.negated => Vec::new(),
+        GlobalFilterRule::Entry(e) => match &e.entry {
+            GlobalFilterEntryE::Path(pth) => span(&pth.re, &rinfo.rinfo.qinfo.qpath),
+            GlobalFilterEntryE::DecodedPath(pth) => span(&pth.re, &rinfo.rinfo.qinfo.decoded_path),
+            GlobalFilterEntryE::Query(qry) => span(&qry.re, &rinfo.rinfo.qinfo.query),
+            GlobalFilterEntryE::Uri(uri) => span(&uri.re, &rinfo.rinfo.qinfo.uri),
+            GlobalFilterEntryE::Method(mtd) => span(&mtd.re, &rinfo.rinfo.meta.method),
+            GlobalFilterEntryE::Authority(at) => span(&at.re, &rinfo.rinfo.host),
+            GlobalFilterEntryE::Session(ses) => span(&ses.re, &rinfo.session_source),
+            GlobalFilterEntryE::OriginHost(oh) => rinfo.origin_host.as_ref().and_then(|h| span(&oh.re, h)),
+            GlobalFilterEntryE::GrpcMethod(gm) => rinfo.grpc.as_ref().and_then(|g| span(&gm.re, &g.full_method())),
+            GlobalFilterEntryE::MethodOverride(mo) => {
+                rinfo.method_override.as_ref().and_then(|o| span(&mo.re, &o.method))
+            }
+            GlobalFilterEntryE::Header(hdr) => pair_span(hdr, &rinfo.headers),
+            GlobalFilterEntryE::HeaderBase64(hdr) => rinfo
+                .headers
+                .get(&hdr.key)
+                .and_then(|v| span(&hdr.re, &base64_or_raw(v))),
+            GlobalFilterEntryE::Args(arg) => pair_span(arg, &rinfo.rinfo.qinfo.args),
+            GlobalFilterEntryE::Cookies(arg) => pair_span(arg, &rinfo.cookies),
+            GlobalFilterEntryE::Plugins(arg) => pair_span(arg, &rinfo.plugins),
+            GlobalFilterEntryE::JwtClaim(clm) => pair_span(clm, &rinfo.jwt_claims),
+            GlobalFilterEntryE::FormField(fld) => {
+                rinfo.rinfo.qinfo.form.as_ref().and_then(|f| pair_span(fld, &f.values))
+            }
+            _ => None,
+        }
+        .into_iter()
+        .collect(),
+    }
+}
+
+/// the text matched by the regexes of the positive entries of a rule
+fn matched_spans(rinfo: &RequestInfo, rule: &GlobalFilterRule) -> Vec<String> {
+    regex_values(rinfo, rule)
+        .into_iter()
+        .filter_map(|(re, s)| re.find(&s).map(|m| m.as_str().to_string()))
+        .collect()
+}
+
+/// the named groups captured by the regexes of the positive entries of a rule
+fn matched_captures(rinfo: &RequestInfo, rule: &GlobalFilterRule) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    for (re, s) in regex_values(rinfo, rule) {
+        // most regexes have no named groups, and do not need to run again
+        if re.capture_names().flatten().next().is_none() {
+            continue;
+        }
+        if let Some(caps) = re.captures(&s) {
+            for name in re.capture_names().flatten() {
+                if let Some(m) = caps.name(name) {
+                    out.push((name.to_string(), m.as_str().to_string()));
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> MatchResult {
     fn bool(loc: Location, b: bool) -> Option<HashSet<Location>> {
         if b {
             Some(std::iter::once(loc).collect())
@@ -112,6 +428,7 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
             },
         ),
         GlobalFilterEntryE::Path(pth) => check_single(pth, &rinfo.rinfo.qinfo.qpath, Location::Path),
+        GlobalFilterEntryE::DecodedPath(pth) => check_single(pth, &rinfo.rinfo.qinfo.decoded_path, Location::Path),
         GlobalFilterEntryE::Query(qry) => check_single(qry, &rinfo.rinfo.qinfo.query, Location::Path),
         GlobalFilterEntryE::Uri(uri) => check_single(uri, &rinfo.rinfo.qinfo.uri, Location::Uri),
         GlobalFilterEntryE::Country(cty) => rinfo
@@ -132,13 +449,41 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
             .subregion
             .as_ref()
             .and_then(|ccty| check_single(cty, ccty.to_lowercase().as_ref(), Location::Ip)),
+        GlobalFilterEntryE::RegionIso(iso) => rinfo
+            .rinfo
+            .geoip
+            .region_iso
+            .as_ref()
+            .and_then(|ciso| check_single(iso, ciso.to_lowercase().as_ref(), Location::Ip)),
+        GlobalFilterEntryE::SubRegionIso(iso) => rinfo
+            .rinfo
+            .geoip
+            .subregion_iso
+            .as_ref()
+            .and_then(|ciso| check_single(iso, ciso.to_lowercase().as_ref(), Location::Ip)),
         GlobalFilterEntryE::Method(mtd) => check_single(mtd, &rinfo.rinfo.meta.method, Location::Request),
         GlobalFilterEntryE::Header(hdr) => check_pair(hdr, &rinfo.headers, |h| {
             Location::HeaderValue(hdr.key.clone(), h.to_string())
         }),
+        GlobalFilterEntryE::HeaderBase64(hdr) => rinfo.headers.get(&hdr.key).and_then(|v| {
+            let decoded = base64_or_raw(v);
+            if hdr.exact == decoded || hdr.re.as_ref().map(|re| re.is_match(&decoded)).unwrap_or(false) {
+                Some(std::iter::once(Location::HeaderValue(hdr.key.clone(), v.to_string())).collect())
+            } else {
+                None
+            }
+        }),
         GlobalFilterEntryE::Plugins(arg) => check_pair(arg, &rinfo.plugins, |a| {
             Location::PluginValue(arg.key.clone(), a.to_string())
         }),
+        GlobalFilterEntryE::JwtClaim(clm) => check_pair(clm, &rinfo.jwt_claims, |_| {
+            Location::Header("authorization".to_string())
+        }),
+        GlobalFilterEntryE::FormField(fld) => rinfo.rinfo.qinfo.form.as_ref().and_then(|f| {
+            check_pair(fld, &f.values, |v| {
+                Location::BodyArgumentValue(fld.key.clone(), v.to_string())
+            })
+        }),
         GlobalFilterEntryE::Args(arg) => check_pair(arg, &rinfo.rinfo.qinfo.args, |a| {
             Location::UriArgumentValue(arg.key.clone(), a.to_string())
         }),
@@ -146,6 +491,10 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
             Location::CookieValue(arg.key.clone(), c.to_string())
         }),
         GlobalFilterEntryE::Asn(asn) => mbool(Location::Ip, rinfo.rinfo.geoip.asn.map(|casn| casn == *asn)),
+        GlobalFilterEntryE::AsnRange { from, to } => mbool(
+            Location::Ip,
+            rinfo.rinfo.geoip.asn.map(|casn| (*from..=*to).contains(&casn)),
+        ),
         GlobalFilterEntryE::Company(cmp) => rinfo
             .rinfo
             .geoip
@@ -168,6 +517,78 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
                 None
             }
         }
+        GlobalFilterEntryE::Session(ses) => check_single(ses, &rinfo.session_source, Location::Request),
+        GlobalFilterEntryE::ClientCertSubject(sbj) => rinfo
+            .client_cert
+            .as_ref()
+            .and_then(|cert| cert.subject.as_ref())
+            .and_then(|subject| check_single(sbj, subject, Location::Header(CLIENT_CERT_HEADER.to_string()))),
+        GlobalFilterEntryE::ClientCertSan(san) => rinfo.client_cert.as_ref().and_then(|cert| {
+            cert.san
+                .iter()
+                .find_map(|s| check_single(san, s, Location::Header(CLIENT_CERT_HEADER.to_string())))
+        }),
+        GlobalFilterEntryE::OriginHost(oh) => rinfo
+            .origin_host
+            .as_ref()
+            .and_then(|host| check_single(oh, host, Location::Request)),
+        GlobalFilterEntryE::GrpcMethod(gm) => rinfo
+            .grpc
+            .as_ref()
+            .and_then(|g| check_single(gm, &g.full_method(), Location::Path)),
+        GlobalFilterEntryE::MethodOverride(mo) => rinfo
+            .method_override
+            .as_ref()
+            .and_then(|o| check_single(mo, &o.method, Location::Header(o.header.clone()))),
+        GlobalFilterEntryE::AuthoritySuffix(suffix) => {
+            bool(Location::Request, authority_suffix_match(&rinfo.rinfo.host, suffix))
+        }
+        GlobalFilterEntryE::TimeWindow { start, end, tz } => bool(
+            Location::Request,
+            time_window_match(&rinfo.timestamp, *start, *end, *tz),
+        ),
+        GlobalFilterEntryE::CookiesCount { above, threshold } => {
+            let count = rinfo.cookies.len();
+            bool(
+                Location::Cookies,
+                if *above { count > *threshold } else { count < *threshold },
+            )
+        }
+        GlobalFilterEntryE::BodyParseFailed(failed) => bool(Location::Body, rinfo.body_parse_failed() == *failed),
+        GlobalFilterEntryE::CorsPreflight(preflight) => bool(Location::Request, rinfo.cors_preflight == *preflight),
+        GlobalFilterEntryE::WebsocketUpgrade(upgrade) => bool(Location::Request, rinfo.websocket_upgrade == *upgrade),
+        GlobalFilterEntryE::PathSet(paths) => bool(Location::Path, paths.contains(&rinfo.rinfo.qinfo.qpath)),
+        GlobalFilterEntryE::Randomness(sel, metric, threshold) => select_string(rinfo, sel, Some(tags))
+            .and_then(|v| randomness(&v, *metric))
+            .and_then(|r| bool(Location::Request, r > *threshold)),
+        GlobalFilterEntryE::DistinctValues(sel, threshold) => {
+            let distinct: HashSet<String> = select_values(rinfo, sel, Some(tags)).into_iter().collect();
+            bool(Location::Request, distinct.len() > *threshold)
+        }
+        GlobalFilterEntryE::FieldsMatch(left, right, relation) => {
+            match (
+                select_string(rinfo, left, Some(tags)),
+                select_string(rinfo, right, Some(tags)),
+            ) {
+                (Some(l), Some(r)) => bool(Location::Request, fields_match(&l, &r, *relation)),
+                _ => None,
+            }
+        }
+        GlobalFilterEntryE::ArgsCount(key, count) => bool(
+            Location::UriArgument(key.clone()),
+            rinfo.rinfo.qinfo.arg_values(key).len() > *count,
+        ),
+        GlobalFilterEntryE::ArgsAny(arg) => rinfo
+            .rinfo
+            .qinfo
+            .arg_values(&arg.key)
+            .into_iter()
+            .find(|v| &arg.exact == v || arg.re.as_ref().map(|re| re.is_match(v)).unwrap_or(false))
+            .map(|v| std::iter::once(Location::UriArgumentValue(arg.key.clone(), v)).collect()),
+        GlobalFilterEntryE::ArgsPresent(key) => bool(
+            Location::UriArgument(key.clone()),
+            !rinfo.rinfo.qinfo.arg_values(key).is_empty(),
+        ),
     };
     match r {
         Some(matched) => MatchResult {
@@ -181,15 +602,34 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
     }
 }
 
+/// subdivision ISO codes are less ambiguous than their names, missing codes are tagged as `nil`
+fn tag_geo_iso(tags: &mut Tags, geoip: &GeoIp) {
+    tags.insert_qualified(
+        "geo-region-iso",
+        geoip.region_iso.as_deref().unwrap_or("nil"),
+        Location::Ip,
+    );
+    tags.insert_qualified(
+        "geo-subregion-iso",
+        geoip.subregion_iso.as_deref().unwrap_or("nil"),
+        Location::Ip,
+    );
+}
+
 pub fn tag_request(
     stats: StatsCollect<BStageSecpol>,
     is_human: bool,
+    seed_tags: &[String],
     globalfilters: &[GlobalFilterSection],
     rinfo: &mut RequestInfo,
     vtags: &VirtualTags,
     logs: &mut Logs,
 ) -> (Tags, SimpleDecision, StatsCollect<BStageMapped>) {
     let mut tags = Tags::new(vtags);
+    // seeded tags are inserted first, so that the global filters can match them
+    for t in seed_tags {
+        tags.insert(t, Location::Request);
+    }
     if is_human {
         tags.insert("human", Location::Request);
     } else {
@@ -198,6 +638,11 @@ pub fn tag_request(
     tags.insert_qualified("headers", &rinfo.headers.len().to_string(), Location::Headers);
     tags.insert_qualified("cookies", &rinfo.cookies.len().to_string(), Location::Cookies);
     tags.insert_qualified("args", &rinfo.rinfo.qinfo.args.len().to_string(), Location::Request);
+    tags.insert_qualified(
+        "content-length",
+        rinfo.content_length.map(content_length_bucket).unwrap_or("nil"),
+        Location::Request,
+    );
     tags.insert_qualified("host", &rinfo.rinfo.host, Location::Request);
     tags.insert_qualified("ip", &rinfo.rinfo.geoip.ipstr, Location::Ip);
     tags.insert_qualified(
@@ -235,6 +680,9 @@ pub fn tag_request(
         rinfo.rinfo.geoip.subregion.as_deref().unwrap_or("nil"),
         Location::Ip,
     );
+    if *GEO_ISO_TAGS {
+        tag_geo_iso(&mut tags, &rinfo.rinfo.geoip);
+    }
     match rinfo.rinfo.geoip.asn {
         None => {
             tags.insert_qualified("geo-asn", "nil", Location::Ip);
@@ -275,6 +723,72 @@ pub fn tag_request(
         tags.insert("geo-mobile", Location::Ip);
     }
 
+    if is_grpc(&rinfo.headers) {
+        tags.insert_qualified("proto", "grpc", Location::Header("content-type".to_string()));
+    }
+    if rinfo.cors_preflight {
+        tags.insert(
+            "cors-preflight",
+            Location::Header("access-control-request-method".to_string()),
+        );
+    }
+    if rinfo.websocket_upgrade {
+        tags.insert("websocket-upgrade", Location::Header("upgrade".to_string()));
+    }
+    if let Some(mo) = &rinfo.method_override {
+        tags.insert_qualified("method-override", &mo.method, Location::Header(mo.header.clone()));
+    }
+
+    if let Some(subject) = rinfo.client_cert.as_ref().and_then(|cert| cert.subject.as_ref()) {
+        tags.insert_qualified(
+            "client-cert-subject",
+            subject,
+            Location::Header(CLIENT_CERT_HEADER.to_string()),
+        );
+    }
+
+    if !rinfo.encoding_invalid.is_empty() {
+        tags.insert_locs("encoding-invalid", rinfo.encoding_invalid.clone());
+    }
+    if rinfo.rinfo.qinfo.form.is_some() {
+        tags.insert_qualified("body", "multipart", Location::Body);
+    }
+    if rinfo.body_parse_failed() {
+        tags.insert("body-parse-failed", Location::Body);
+    }
+    if rinfo.uri_too_long {
+        tags.insert("uri-too-long", Location::Uri);
+    }
+    if rinfo.body_too_large {
+        tags.insert("body-too-large", Location::Body);
+        match rinfo.rinfo.secpolicy.content_filter_profile.oversized_body {
+            OversizedBodyPolicy::InspectTruncated => tags.insert("body-truncated", Location::Body),
+            OversizedBodyPolicy::Pass => tags.insert("body-uninspected", Location::Body),
+            OversizedBodyPolicy::BlockUnverified => (),
+        }
+    }
+    if rinfo.rinfo.qinfo.args.truncated {
+        tags.insert("args-truncated", Location::Request);
+    }
+    let valueless = rinfo.rinfo.qinfo.valueless_args();
+    if !valueless.is_empty() {
+        tags.insert_locs(
+            "arg-valueless",
+            valueless.into_iter().map(Location::UriArgument).collect(),
+        );
+    }
+    if rinfo.headers.truncated {
+        tags.insert("headers-truncated", Location::Headers);
+    }
+    if rinfo.cookies.truncated {
+        tags.insert("cookies-truncated", Location::Cookies);
+    }
+
+    let internal = is_internal(&rinfo.rinfo.secpolicy.settings.internal_networks, rinfo.rinfo.geoip.ip);
+    if internal {
+        tags.insert("internal", Location::Ip);
+    }
+
     for tag in rinfo.rinfo.secpolicy.tags.iter() {
         tags.insert(tag, Location::Request)
     }
@@ -282,7 +796,28 @@ pub fn tag_request(
     let mut matched = 0;
     let mut decision = SimpleDecision::Pass;
     let mut monitor_headers = HashMap::new();
-    for psection in globalfilters {
+    let mut monitor_multi_headers = HashMap::new();
+    let globalfilters: &[GlobalFilterSection] = if !rinfo.rinfo.secpolicy.globalfilter_active {
+        logs.debug("global filters are disabled in this security policy");
+        &[]
+    } else if internal && rinfo.rinfo.secpolicy.settings.internal_skip_globalfilters {
+        logs.debug("global filters are skipped for internal requests");
+        &[]
+    } else {
+        globalfilters
+    };
+    let allowlist_first = rinfo.rinfo.secpolicy.allowlist_first;
+    let is_skip = |s: &GlobalFilterSection| s.action.as_ref().map(|a| a.atype == SimpleActionT::Skip) == Some(true);
+    let ordered: Vec<&GlobalFilterSection> = if allowlist_first {
+        // the partition is stable, so the priority order is kept in both groups
+        let (skips, others): (Vec<&GlobalFilterSection>, Vec<&GlobalFilterSection>) =
+            globalfilters.iter().partition(|s| is_skip(*s));
+        skips.into_iter().chain(others).collect()
+    } else {
+        globalfilters.iter().collect()
+    };
+    let globalfilters_start = Instant::now();
+    for psection in ordered {
         let mtch = check_rule(rinfo, &tags, &psection.rule);
         if mtch.matching {
             matched += 1;
@@ -290,10 +825,14 @@ pub fn tag_request(
                 .new_with_vtags()
                 .with_raw_tags_locs(psection.tags.clone(), &mtch.matched);
             tags.extend(rtags);
+            for (name, value) in matched_captures(rinfo, &psection.rule) {
+                rinfo.captures.entry(name).or_insert(value);
+            }
             if let Some(a) = &psection.action {
                 // merge headers from Monitor decision
                 if a.atype == SimpleActionT::Monitor {
                     monitor_headers.extend(a.headers.clone().unwrap_or_default());
+                    append_multi_headers(&mut monitor_multi_headers, a.multi_headers.clone().unwrap_or_default());
                 } else if a.atype == SimpleActionT::Identity {
                     for (custom_headers, header_rules) in a.headers.clone().unwrap().into_iter() {
                         // logs.info(|| format!("custom_header = {:?}, header_rule = {:?}", custom_headers, header_rules));
@@ -332,20 +871,20 @@ pub fn tag_request(
                                         // logs.info(|| format!("{:?} Selected::U32(s) = {:?}", sel, v));
                                     }
                                 },
-                                TemplatePart::Var(TVar::Tag(tagname)) => {
-                                    hash_item.push_str(if tags.contains(&tagname) { "true" } else { "false" });
+                                TemplatePart::Var(tv) => {
+                                    hash_item.push_str(if tv.tags_match(&tags) { "true" } else { "false" });
                                 }
                             }
 
                             if pre_rule != cur_rule {
-                                hash_item.push_str(".");
                                 if regex_rule.is_empty() {
-                                    hash_item.push_str(&pre_rule);
+                                    IDENTITY_ENCODING.push_segment(&mut hash_item, &pre_rule);
                                 } else {
                                     let re = Regex::new(&regex_rule.as_str()).unwrap();
                                     match re.find(pre_rule.as_str()) {
-                                        Some(m) => hash_item.push_str(&pre_rule[m.start()..m.end()]),
-                                        _ => hash_item.push_str("none"),
+                                        Some(m) => IDENTITY_ENCODING
+                                            .push_segment(&mut hash_item, &pre_rule[m.start()..m.end()]),
+                                        _ => IDENTITY_ENCODING.push_segment(&mut hash_item, "none"),
                                     }
                                     regex_rule.clear();
                                 }
@@ -353,22 +892,21 @@ pub fn tag_request(
                         }
 
                         // the last one
-                        hash_item.push('.');
                         if regex_rule.is_empty() {
-                            hash_item.push_str(&cur_rule);
+                            IDENTITY_ENCODING.push_segment(&mut hash_item, &cur_rule);
                         } else {
                             let re = Regex::new(&regex_rule.as_str()).unwrap();
                             match re.find(cur_rule.as_str()) {
-                                Some(m) => hash_item.push_str(&cur_rule[m.start()..m.end()]),
-                                _ => hash_item.push_str("none"),
+                                Some(m) => {
+                                    IDENTITY_ENCODING.push_segment(&mut hash_item, &cur_rule[m.start()..m.end()])
+                                }
+                                _ => IDENTITY_ENCODING.push_segment(&mut hash_item, "none"),
                             }
                         }
 
                         // SHA256 all item
                         logs.info(|| format!("hash_item = {:?}", hash_item));
-                        let mut hasher = Sha256::new();
-                        hasher.update(hash_item);
-                        let hash_value = format!("{:X}", hasher.finalize());
+                        let hash_value = identity_value(&hash_item, IDENTITY_HMAC_SECRET.as_deref());
                         let mut identity_hash = HashMap::new();
                         identity_hash.insert(custom_headers.clone(), parse_request_template(&hash_value));
 
@@ -379,17 +917,39 @@ pub fn tag_request(
                         rinfo.identity.insert(custom_headers, hash_value);
                     }
                 }
-                let curdec = SimpleDecision::Action(
-                    a.clone(),
-                    vec![BlockReason::global_filter(
-                        psection.id.clone(),
-                        psection.name.clone(),
-                        a.atype.to_bdecision(),
-                        &mtch.matched,
-                    )],
+                let mut reason = BlockReason::global_filter(
+                    psection.id.clone(),
+                    psection.name.clone(),
+                    a.atype.to_bdecision(),
+                    &mtch.matched,
                 );
+                let mut extra = serde_json::Map::new();
+                if psection.log_matched {
+                    let spans = matched_spans(rinfo, &psection.rule);
+                    if !spans.is_empty() {
+                        extra.insert("matched".to_string(), serde_json::json!(spans));
+                    }
+                }
+                if psection.log_rule_tree {
+                    if let Some(tree) = matched_tree(rinfo, &tags, &psection.rule) {
+                        extra.insert("rule_tree".to_string(), tree);
+                    }
+                }
+                let early_skip = allowlist_first && a.atype == SimpleActionT::Skip;
+                if early_skip {
+                    extra.insert("early_skip".to_string(), serde_json::Value::Bool(true));
+                }
+                if !extra.is_empty() {
+                    reason.extra = serde_json::Value::Object(extra);
+                }
+                let curdec = SimpleDecision::Action(a.clone(), vec![reason]);
 
                 decision = stronger_decision(decision, curdec);
+                // the skip decision is final, so that the other global filters and stages are not evaluated
+                if early_skip {
+                    logs.debug(|| format!("early skip from global filter {}", psection.id));
+                    break;
+                }
             }
         }
     }
@@ -398,6 +958,13 @@ pub fn tag_request(
     decision = if let SimpleDecision::Action(mut action, block_reasons) = decision {
         if action.atype == SimpleActionT::Monitor || action.atype == SimpleActionT::Identity {
             action.headers = Some(monitor_headers);
+            action.multi_headers = if monitor_multi_headers.is_empty() {
+                None
+            } else {
+                Some(monitor_multi_headers)
+            };
+        } else if action.atype == SimpleActionT::Count {
+            action.headers = None;
         }
         SimpleDecision::Action(action, block_reasons)
     } else {
@@ -405,7 +972,15 @@ pub fn tag_request(
     };
     // logs.debug(|| format!("decision2 {:?}", decision));
 
-    (tags, decision, stats.mapped(globalfilters.len(), matched))
+    let globalfilters_micros = globalfilters_start.elapsed().as_micros() as u64;
+
+    (
+        tags,
+        decision,
+        stats
+            .durations(rinfo.durations, globalfilters_micros)
+            .mapped(globalfilters.len(), matched),
+    )
 }
 
 #[cfg(test)]
@@ -413,59 +988,50 @@ mod tests {
     use super::*;
     use crate::config::globalfilter::optimize_ipranges;
     use crate::config::globalfilter::GlobalFilterRelation;
-    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::hostmap::{parse_network, SecurityPolicy};
+    use crate::interface::{Initiator, SimpleAction};
     use crate::logs::Logs;
-    use crate::utils::map_request;
-    use crate::utils::RawRequest;
-    use crate::utils::RequestMeta;
+    use crate::utils::RequestBuilder;
     use regex::RegexBuilder;
     use std::collections::HashMap;
     use std::sync::Arc;
 
-    fn mk_rinfo() -> RequestInfo {
-        let raw_headers = [
-            ("content-type", "/sson"),
-            ("x-forwarded-for", "52.78.12.56"),
-            (":method", "GET"),
-            (":authority", "localhost:30081"),
-            (":path", "/adminl%20e?lol=boo&bar=bze&%20encoded=%20%20%20"),
-            ("x-forwarded-proto", "http"),
-            ("x-request-id", "af36dcec-524d-4d21-b90e-22d5798a6300"),
-            ("accept", "*/*"),
-            ("user-agent", "curl/7.58.0"),
-            ("x-envoy-internal", "true"),
-        ];
-        let mut headers = HashMap::<String, String>::new();
-        let mut attrs = HashMap::<String, String>::new();
+    fn tag_stats() -> StatsCollect<BStageSecpol> {
+        StatsCollect::new(std::time::Instant::now(), "test".to_string())
+            .secpol(crate::interface::stats::SecpolStats::default())
+    }
 
-        for (k, v) in raw_headers.iter() {
-            match k.strip_prefix(':') {
-                None => {
-                    headers.insert(k.to_string(), v.to_string());
-                }
-                Some(ak) => {
-                    attrs.insert(ak.to_string(), v.to_string());
-                }
-            }
-        }
-        let meta = RequestMeta::from_map(attrs).unwrap();
-        let mut logs = Logs::default();
-        let secpol = SecurityPolicy::default();
-        map_request(
-            &mut logs,
-            Arc::new(secpol),
-            None,
-            &RawRequest {
-                ipstr: "52.78.12.56".to_string(),
-                headers,
-                meta,
-                mbody: None,
-            },
-            None,
-            HashMap::new(),
+    /// runs tag_request without seeded or virtual tags
+    fn tag(
+        ri: &mut RequestInfo,
+        is_human: bool,
+        globalfilters: &[GlobalFilterSection],
+    ) -> (Tags, SimpleDecision, StatsCollect<BStageMapped>) {
+        tag_request(
+            tag_stats(),
+            is_human,
+            &[],
+            globalfilters,
+            ri,
+            &VirtualTags::default(),
+            &mut Logs::default(),
         )
     }
 
+    fn mk_rinfo() -> RequestInfo {
+        RequestBuilder::new("GET", "/adminl%20e?lol=boo&bar=bze&%20encoded=%20%20%20")
+            .ip("52.78.12.56")
+            .authority("localhost:30081")
+            .header("content-type", "/sson")
+            .header("x-forwarded-for", "52.78.12.56")
+            .header("x-forwarded-proto", "http")
+            .header("x-request-id", "af36dcec-524d-4d21-b90e-22d5798a6300")
+            .header("accept", "*/*")
+            .header("user-agent", "curl/7.58.0")
+            .header("x-envoy-internal", "true")
+            .build()
+    }
+
     fn t_check_entry(negated: bool, entry: GlobalFilterEntryE) -> MatchResult {
         check_entry(
             &mk_rinfo(),
@@ -505,6 +1071,16 @@ mod tests {
         assert!(!r.matching);
     }
 
+    #[test]
+    fn check_entry_session_source() {
+        // without session configuration, the session is computed from the ip
+        let r = t_check_entry(false, GlobalFilterEntryE::Session(single_re("^52\\.78\\.12\\.56$")));
+        assert!(r.matching);
+        let digest = mk_rinfo().session;
+        let r = t_check_entry(false, GlobalFilterEntryE::Session(single_re(&digest)));
+        assert!(!r.matching);
+    }
+
     #[test]
     fn check_path_in() {
         let r = t_check_entry(false, GlobalFilterEntryE::Path(single_re(".*adminl%20e.*")));
@@ -535,6 +1111,1549 @@ mod tests {
         assert!(r.matching);
     }
 
+    #[test]
+    fn check_client_cert_subject() {
+        let mut ri = mk_rinfo();
+        ri.client_cert = Some(crate::utils::parse_client_cert(
+            "Subject=\"CN=client,O=Acme\";DNS=client.acme.com",
+        ));
+        let tags = Tags::new(&VirtualTags::default());
+        let entry = |negated, entry| GlobalFilterEntry { negated, entry };
+        assert!(
+            check_entry(
+                &ri,
+                &tags,
+                &entry(false, GlobalFilterEntryE::ClientCertSubject(single_re("^CN=client,")))
+            )
+            .matching
+        );
+        assert!(
+            check_entry(
+                &ri,
+                &tags,
+                &entry(false, GlobalFilterEntryE::ClientCertSan(single_re("client.acme.com")))
+            )
+            .matching
+        );
+        assert!(
+            !check_entry(
+                &ri,
+                &tags,
+                &entry(true, GlobalFilterEntryE::ClientCertSubject(single_re("^CN=client,")))
+            )
+            .matching
+        );
+    }
+
+    #[test]
+    fn check_client_cert_missing() {
+        let r = t_check_entry(false, GlobalFilterEntryE::ClientCertSubject(single_re("CN=client")));
+        assert!(!r.matching);
+        let r = t_check_entry(true, GlobalFilterEntryE::ClientCertSubject(single_re("CN=client")));
+        assert!(r.matching);
+    }
+
+    fn check_origin(headers: &[(&str, &str)], entry: &str) -> bool {
+        let mut ri = mk_rinfo();
+        for (k, v) in headers {
+            ri.headers
+                .add(k.to_string(), Location::Header(k.to_string()), v.to_string());
+        }
+        ri.origin_host = crate::utils::origin_host(&ri.headers);
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::OriginHost(single_re(entry)),
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn check_origin_host() {
+        assert!(check_origin(
+            &[("origin", "https://www.example.com")],
+            "^www.example.com$"
+        ));
+        assert!(check_origin(
+            &[("referer", "https://shop.example.com:8443/cart?id=3")],
+            "^shop.example.com$"
+        ));
+        // origin has precedence over referer
+        assert!(check_origin(
+            &[
+                ("origin", "https://a.example.com"),
+                ("referer", "https://b.example.com/")
+            ],
+            "^a.example.com$"
+        ));
+        // a null origin falls back to the referer
+        assert!(check_origin(
+            &[("origin", "null"), ("referer", "https://b.example.com/")],
+            "^b.example.com$"
+        ));
+    }
+
+    #[test]
+    fn check_origin_host_mismatch() {
+        assert!(!check_origin(&[("origin", "https://evil.com")], "^www.example.com$"));
+    }
+
+    #[test]
+    fn check_origin_host_missing() {
+        assert!(!check_origin(&[], ".*"));
+        assert!(!check_origin(&[("origin", "not an url")], ".*"));
+    }
+
+    fn check_authority(host: &str, suffix: &str) -> bool {
+        let mut ri = mk_rinfo();
+        ri.rinfo.host = host.to_string();
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::AuthoritySuffix(suffix.to_string()),
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn authority_suffix_exact() {
+        assert!(check_authority("example.com", "example.com"));
+        assert!(check_authority("Example.COM:8443", "example.com"));
+        assert!(check_authority("example.com.", "example.com"));
+        assert!(!check_authority("example.com", ".example.com"));
+    }
+
+    #[test]
+    fn authority_suffix_subdomain() {
+        assert!(check_authority("www.example.com", "example.com"));
+        assert!(check_authority("a.b.Example.com:80", "example.com"));
+        assert!(check_authority("www.example.com", ".example.com"));
+    }
+
+    #[test]
+    fn authority_suffix_mismatch() {
+        assert!(!check_authority("badexample.com", "example.com"));
+        assert!(!check_authority("example.com.evil.net", "example.com"));
+        assert!(!check_authority("com", "example.com"));
+        assert!(!check_authority("", "example.com"));
+        assert!(!check_authority("[::1]:443", "example.com"));
+    }
+
+    fn check_time(hour: u32, minute: u32, start: u32, end: u32, tz: i32) -> bool {
+        let mut ri = mk_rinfo();
+        ri.timestamp = DateTime::parse_from_rfc3339(&format!("2022-03-14T{:02}:{:02}:00Z", hour, minute))
+            .unwrap()
+            .with_timezone(&Utc);
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::TimeWindow { start, end, tz },
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn time_window_inside() {
+        assert!(check_time(0, 0, 0, 6 * 60, 0));
+        assert!(check_time(3, 30, 0, 6 * 60, 0));
+        assert!(check_time(5, 59, 0, 6 * 60, 0));
+    }
+
+    #[test]
+    fn time_window_outside() {
+        assert!(!check_time(6, 0, 0, 6 * 60, 0));
+        assert!(!check_time(12, 0, 0, 6 * 60, 0));
+        assert!(!check_time(23, 59, 0, 6 * 60, 0));
+    }
+
+    #[test]
+    fn time_window_wrap_around() {
+        // 22:00 - 02:00
+        assert!(check_time(23, 0, 22 * 60, 2 * 60, 0));
+        assert!(check_time(1, 59, 22 * 60, 2 * 60, 0));
+        assert!(!check_time(2, 0, 22 * 60, 2 * 60, 0));
+        assert!(!check_time(21, 59, 22 * 60, 2 * 60, 0));
+    }
+
+    #[test]
+    fn time_window_offset() {
+        // 00:00 - 06:00 at UTC+02:00 is 22:00 - 04:00 UTC
+        assert!(check_time(22, 30, 0, 6 * 60, 120));
+        assert!(!check_time(4, 30, 0, 6 * 60, 120));
+        // 00:00 - 06:00 at UTC-05:00 is 05:00 - 11:00 UTC
+        assert!(check_time(10, 0, 0, 6 * 60, -300));
+        assert!(!check_time(2, 0, 0, 6 * 60, -300));
+    }
+
+    fn check_cookies_count(cookies: usize, negated: bool, above: bool, threshold: usize) -> bool {
+        let mut ri = mk_rinfo();
+        for i in 0..cookies {
+            ri.cookies
+                .add(format!("c{}", i), Location::Cookie(format!("c{}", i)), "v".to_string());
+        }
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated,
+                entry: GlobalFilterEntryE::CookiesCount { above, threshold },
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn cookies_count_above() {
+        assert!(!check_cookies_count(3, false, true, 3));
+        assert!(check_cookies_count(4, false, true, 3));
+        assert!(check_cookies_count(3, true, true, 3));
+        assert!(!check_cookies_count(4, true, true, 3));
+    }
+
+    #[test]
+    fn cookies_count_below() {
+        assert!(check_cookies_count(0, false, false, 1));
+        assert!(!check_cookies_count(1, false, false, 1));
+        assert!(!check_cookies_count(0, true, false, 1));
+        assert!(check_cookies_count(1, true, false, 1));
+    }
+
+    fn check_repeated_args(entry: GlobalFilterEntryE) -> bool {
+        let mut ri = mk_rinfo();
+        ri.rinfo.qinfo.query = "id=1&id=2&x=a&id=%33".to_string();
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry { negated: false, entry },
+        )
+        .matching
+    }
+
+    #[test]
+    fn check_args_count() {
+        assert!(check_repeated_args(GlobalFilterEntryE::ArgsCount("id".to_string(), 2)));
+        assert!(!check_repeated_args(GlobalFilterEntryE::ArgsCount("id".to_string(), 3)));
+        assert!(!check_repeated_args(GlobalFilterEntryE::ArgsCount("x".to_string(), 1)));
+        assert!(!check_repeated_args(GlobalFilterEntryE::ArgsCount(
+            "missing".to_string(),
+            0
+        )));
+    }
+
+    #[test]
+    fn check_args_any() {
+        assert!(check_repeated_args(GlobalFilterEntryE::ArgsAny(double_re("id", "^2$"))));
+        assert!(check_repeated_args(GlobalFilterEntryE::ArgsAny(double_re("id", "^3$"))));
+        assert!(!check_repeated_args(GlobalFilterEntryE::ArgsAny(double_re(
+            "id", "^5$"
+        ))));
+        assert!(!check_repeated_args(GlobalFilterEntryE::ArgsAny(double_re("x", "^1$"))));
+    }
+
+    fn check_jwt(auth: Option<&str>, key: &str, value: &str) -> bool {
+        let mut ri = mk_rinfo();
+        if let Some(a) = auth {
+            ri.headers.add(
+                "authorization".to_string(),
+                Location::Header("authorization".to_string()),
+                a.to_string(),
+            );
+        }
+        ri.jwt_claims = crate::utils::jwt::jwt_claims(&ri.headers, None);
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::JwtClaim(double_re(key, value)),
+            },
+        )
+        .matching
+    }
+
+    // {"alg":"HS256","typ":"JWT"} . {"sub":"1234567890","role":"admin"}
+    const JWT: &str = "Bearer eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiIxMjM0NTY3ODkwIiwicm9sZSI6ImFkbWluIn0.sig";
+
+    #[test]
+    fn check_jwt_claim() {
+        assert!(check_jwt(Some(JWT), "role", "^admin$"));
+        assert!(check_jwt(Some(JWT), "sub", "^123"));
+        assert!(!check_jwt(Some(JWT), "role", "^user$"));
+        assert!(!check_jwt(Some(JWT), "email", ".*"));
+    }
+
+    #[test]
+    fn check_jwt_claim_missing_token() {
+        assert!(!check_jwt(None, "role", ".*"));
+    }
+
+    #[test]
+    fn check_jwt_claim_malformed_token() {
+        assert!(!check_jwt(Some("Bearer not.a.jwt"), "role", ".*"));
+        assert!(!check_jwt(
+            Some("Bearer eyJzdWIiOiIxMjM0NTY3ODkwIiwicm9sZSI6ImFkbWluIn0"),
+            "role",
+            ".*"
+        ));
+    }
+
+    fn always_section(tag: &str, action: Option<SimpleAction>) -> GlobalFilterSection {
+        let mut gftags = crate::interface::RawTags::default();
+        gftags.insert(tag);
+        GlobalFilterSection {
+            id: "gf".to_string(),
+            name: "gf".to_string(),
+            tags: gftags,
+            rule: GlobalFilterRule::Entry(GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::Always(true),
+            }),
+            action,
+            log_matched: false,
+            log_rule_tree: false,
+            priority: 0,
+        }
+    }
+
+    fn tag_with_globalfilter(globalfilter_active: bool) -> Tags {
+        let mut ri = mk_rinfo();
+        let mut secpol = SecurityPolicy::default();
+        secpol.globalfilter_active = globalfilter_active;
+        ri.rinfo.secpolicy = Arc::new(secpol);
+        let section = always_section("gf-hit", None);
+        let (tags, _, _) = tag(&mut ri, true, &[section]);
+        tags
+    }
+
+    fn tag_with_seed(seed_tags: &[String], vtags: &VirtualTags) -> Tags {
+        let mut ri = mk_rinfo();
+        let mut gftags = crate::interface::RawTags::default();
+        gftags.insert("seed-matched");
+        let section = GlobalFilterSection {
+            id: "gf".to_string(),
+            name: "gf".to_string(),
+            tags: gftags,
+            rule: GlobalFilterRule::Entry(GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::Tag(SingleEntry {
+                    exact: "vseeded".to_string(),
+                    re: None,
+                }),
+            }),
+            action: None,
+            log_matched: false,
+            log_rule_tree: false,
+            priority: 0,
+        };
+        let (tags, _, _) = tag_request(
+            tag_stats(),
+            true,
+            seed_tags,
+            &[section],
+            &mut ri,
+            vtags,
+            &mut Logs::default(),
+        );
+        tags
+    }
+
+    #[test]
+    fn seeded_tags() {
+        let raw_vtags = vec![crate::config::raw::RawVirtualTag {
+            id: "vt".to_string(),
+            name: "vt".to_string(),
+            description: String::new(),
+            vmatch: vec![crate::config::raw::RawVirtualTagMatch {
+                vtag: "vseeded".to_string(),
+                tags: vec!["seeded".to_string()],
+            }],
+        }];
+        let vtags = crate::config::virtualtags::vtags_resolve(&mut Logs::default(), raw_vtags);
+
+        let tags = tag_with_seed(&["Seeded".to_string()], &vtags);
+        assert!(tags.contains("seeded"));
+        assert!(tags.contains("vseeded"));
+        assert!(tags.contains("seed-matched"));
+
+        let tags = tag_with_seed(&[], &vtags);
+        assert!(!tags.contains("seeded"));
+        assert!(!tags.contains("seed-matched"));
+    }
+
+    fn grpc_rinfo(content_type: &str) -> RequestInfo {
+        let mut ri = mk_rinfo();
+        ri.headers.add(
+            "content-type".to_string(),
+            Location::Header("content-type".to_string()),
+            content_type.to_string(),
+        );
+        ri.grpc = crate::utils::grpc::grpc_info(&ri.headers, "/helloworld.Greeter/SayHello");
+        ri
+    }
+
+    #[test]
+    fn grpc_tag_and_method() {
+        let mut ri = grpc_rinfo("application/grpc");
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        assert!(tags.contains("proto:grpc"));
+
+        let entry = |re: &str| GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::GrpcMethod(single_re(re)),
+        };
+        let vtags = VirtualTags::default();
+        assert!(check_entry(&ri, &Tags::new(&vtags), &entry("^helloworld\\.Greeter/SayHello$")).matching);
+        assert!(check_entry(&ri, &Tags::new(&vtags), &entry("/SayHello$")).matching);
+        assert!(!check_entry(&ri, &Tags::new(&vtags), &entry("/SayGoodbye$")).matching);
+    }
+
+    fn override_rinfo(headers: &[(&str, &str)]) -> (RequestInfo, Tags) {
+        let mut ri = mk_rinfo();
+        for (k, v) in headers {
+            ri.headers
+                .add(k.to_string(), Location::Header(k.to_string()), v.to_string());
+        }
+        let names = vec!["x-http-method-override".to_string(), "x-method-override".to_string()];
+        ri.method_override = crate::utils::method_override(&ri.headers, &names);
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        (ri, tags)
+    }
+
+    #[test]
+    fn method_override_present() {
+        let (ri, tags) = override_rinfo(&[("x-method-override", " "), ("x-http-method-override", "delete")]);
+        assert_eq!(
+            ri.method_override
+                .as_ref()
+                .map(|mo| (mo.header.as_str(), mo.method.as_str())),
+            Some(("x-http-method-override", "DELETE"))
+        );
+        assert!(tags.contains("method-override:delete"));
+        // the original method is kept for matching
+        assert_eq!(ri.rinfo.meta.method, "GET");
+
+        let entry = |re: &str| GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::MethodOverride(single_re(re)),
+        };
+        let vtags = VirtualTags::default();
+        assert!(check_entry(&ri, &Tags::new(&vtags), &entry("^(put|delete)$")).matching);
+        assert!(!check_entry(&ri, &Tags::new(&vtags), &entry("^post$")).matching);
+    }
+
+    #[test]
+    fn method_override_absent() {
+        let (ri, tags) = override_rinfo(&[("x-http-method", "DELETE")]);
+        assert!(ri.method_override.is_none());
+        assert!(!tags.tags.keys().any(|t| t.starts_with("method-override:")));
+        let entry = GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::MethodOverride(single_re(".*")),
+        };
+        assert!(!check_entry(&ri, &Tags::new(&VirtualTags::default()), &entry).matching);
+    }
+
+    #[test]
+    fn non_grpc_request() {
+        let mut ri = grpc_rinfo("application/json");
+        assert!(ri.grpc.is_none());
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        assert!(!tags.contains("proto:grpc"));
+        let entry = GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::GrpcMethod(single_re(".*")),
+        };
+        assert!(!check_entry(&ri, &Tags::new(&VirtualTags::default()), &entry).matching);
+    }
+
+    fn body_rinfo(content_type: &str, mbody: Option<&[u8]>) -> RequestInfo {
+        let builder = RequestBuilder::new("POST", "/api")
+            .ip("52.78.12.56")
+            .authority("localhost")
+            .header("content-type", content_type);
+        match mbody {
+            Some(body) => builder.body(body),
+            None => builder,
+        }
+        .build()
+    }
+
+    fn body_parse_failed_check(mbody: Option<&[u8]>) -> (bool, bool, bool) {
+        let mut ri = body_rinfo("application/json", mbody);
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        let entry = |failed: bool| GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::BodyParseFailed(failed),
+        };
+        let vtags = VirtualTags::default();
+        (
+            tags.contains("body-parse-failed"),
+            check_entry(&ri, &Tags::new(&vtags), &entry(true)).matching,
+            check_entry(&ri, &Tags::new(&vtags), &entry(false)).matching,
+        )
+    }
+
+    #[test]
+    fn body_parse_failed_valid_body() {
+        assert_eq!(body_parse_failed_check(Some(br#"{"a": 1}"#)), (false, false, true));
+    }
+
+    #[test]
+    fn body_parse_failed_malformed_json() {
+        assert_eq!(body_parse_failed_check(Some(br#"{"a": 1"#)), (true, true, false));
+    }
+
+    #[test]
+    fn body_parse_failed_no_body() {
+        assert_eq!(body_parse_failed_check(None), (false, false, true));
+    }
+
+    #[test]
+    fn multipart_form_field() {
+        let body = b"--xYzZY\r\nContent-Disposition: form-data; name=\"role\"\r\n\r\nadmin\r\n--xYzZY--\r\n";
+        let mut ri = body_rinfo("multipart/form-data; boundary=xYzZY", Some(body));
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        assert!(tags.contains("body:multipart"));
+
+        let entry = |value: &str| GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::FormField(double_re("role", value)),
+        };
+        let vtags = VirtualTags::default();
+        assert!(check_entry(&ri, &Tags::new(&vtags), &entry("^admin$")).matching);
+        assert!(!check_entry(&ri, &Tags::new(&vtags), &entry("^user$")).matching);
+
+        let ri = body_rinfo("application/json", Some(br#"{"role": "admin"}"#));
+        assert!(ri.rinfo.qinfo.form.is_none());
+        assert!(!check_entry(&ri, &Tags::new(&vtags), &entry("^admin$")).matching);
+    }
+
+    #[test]
+    fn mapping_timings() {
+        let mut ri = body_rinfo("application/json", Some(br#"{"role": "admin"}"#));
+        let (_, _, stats) = tag(&mut ri, true, &[]);
+        let timing = serde_json::to_value(&stats.mapped_stage_build().timing).unwrap();
+        let entries = timing.as_array().unwrap();
+        let value = |name: &str| {
+            entries
+                .iter()
+                .find(|e| e["name"] == name)
+                .and_then(|e| e["value"].as_u64())
+                .unwrap_or_else(|| panic!("missing timing entry {}", name))
+        };
+        for name in &["body_parse", "geo", "globalfilters"] {
+            value(name);
+        }
+        assert_eq!(value("body_bytes"), 17);
+    }
+
+    #[test]
+    fn identity_encoding() {
+        let encode = |enc: &IdentityEncoding, segments: &[&str]| {
+            let mut out = String::new();
+            for s in segments {
+                enc.push_segment(&mut out, s);
+            }
+            out
+        };
+        let default = IdentityEncoding::default();
+        assert_eq!(encode(&default, &["ip", "1.2.3.4"]), ".ip.1.2.3.4");
+        // segments containing the separator collide
+        assert_eq!(encode(&default, &["a.b", "c"]), encode(&default, &["a", "b.c"]));
+
+        let prefixed = IdentityEncoding {
+            separator: ".".to_string(),
+            length_prefixed: true,
+        };
+        assert_eq!(encode(&prefixed, &["ip", "1.2.3.4"]), ".2:ip.7:1.2.3.4");
+        assert_ne!(encode(&prefixed, &["a.b", "c"]), encode(&prefixed, &["a", "b.c"]));
+
+        let unseparated = IdentityEncoding {
+            separator: String::new(),
+            length_prefixed: false,
+        };
+        assert_eq!(encode(&unseparated, &["ab", "c"]), encode(&unseparated, &["a", "bc"]));
+        let unseparated_prefixed = IdentityEncoding {
+            separator: String::new(),
+            length_prefixed: true,
+        };
+        assert_ne!(
+            encode(&unseparated_prefixed, &["ab", "c"]),
+            encode(&unseparated_prefixed, &["a", "bc"])
+        );
+    }
+
+    #[test]
+    fn identity_signed() {
+        assert_eq!(identity_value("ip.1.2.3.4", None), identity_hash("ip.1.2.3.4"));
+        // RFC 4231, test case 2
+        assert_eq!(
+            identity_value("what do ya want for nothing?", Some(b"Jefe")),
+            "5BDCC146BF60754E6A042426089575C75A003F089D2739839DEC58B964EC3843"
+        );
+        assert_ne!(
+            identity_value("ip.1.2.3.4", Some(b"key1")),
+            identity_value("ip.1.2.3.4", Some(b"key2"))
+        );
+    }
+
+    fn check_fields(left: &str, right: &str, relation: FieldsRelation) -> bool {
+        let mut ri = mk_rinfo();
+        for (k, v) in &[("x-host", "boo"), ("x-agent", "curl")] {
+            ri.headers
+                .add(k.to_string(), Location::Header(k.to_string()), v.to_string());
+        }
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::FieldsMatch(
+                    RequestSelector::decode_dotted(left).unwrap(),
+                    RequestSelector::decode_dotted(right).unwrap(),
+                    relation,
+                ),
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn fields_equal() {
+        assert!(check_fields("headers.x-host", "args.lol", FieldsRelation::Equal));
+        assert!(check_fields("method", "method", FieldsRelation::Equal));
+    }
+
+    #[test]
+    fn fields_unequal() {
+        assert!(!check_fields("headers.x-host", "args.bar", FieldsRelation::Equal));
+        assert!(!check_fields(
+            "headers.user-agent",
+            "headers.x-agent",
+            FieldsRelation::Equal
+        ));
+    }
+
+    #[test]
+    fn fields_contains() {
+        assert!(check_fields(
+            "headers.user-agent",
+            "headers.x-agent",
+            FieldsRelation::Contains
+        ));
+        assert!(!check_fields(
+            "headers.x-agent",
+            "headers.user-agent",
+            FieldsRelation::Contains
+        ));
+    }
+
+    #[test]
+    fn fields_missing() {
+        assert!(!check_fields("headers.x-missing", "args.lol", FieldsRelation::Equal));
+        assert!(!check_fields("headers.x-host", "args.missing", FieldsRelation::Equal));
+        assert!(!check_fields("cookies.a", "cookies.b", FieldsRelation::Equal));
+    }
+
+    fn check_asn_range(asn: Option<u32>, negated: bool) -> bool {
+        let mut ri = mk_rinfo();
+        ri.rinfo.geoip.asn = asn;
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated,
+                entry: GlobalFilterEntryE::AsnRange { from: 64512, to: 65534 },
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn asn_range_in() {
+        assert!(check_asn_range(Some(65000), false));
+        assert!(!check_asn_range(Some(65000), true));
+    }
+
+    #[test]
+    fn asn_range_edges() {
+        assert!(check_asn_range(Some(64512), false));
+        assert!(check_asn_range(Some(65534), false));
+        assert!(!check_asn_range(Some(64511), false));
+        assert!(!check_asn_range(Some(65535), false));
+    }
+
+    #[test]
+    fn asn_range_out() {
+        assert!(!check_asn_range(Some(13335), false));
+        assert!(check_asn_range(Some(13335), true));
+        assert!(!check_asn_range(None, false));
+    }
+
+    #[test]
+    fn path_set_scale() {
+        let mut paths: Vec<String> = (0..5000).map(|i| format!("/api/v1/item/{}", i)).collect();
+        paths.extend((0..5000).map(|i| format!("/static/{}/*", i)));
+        let set = PathSet::new(paths.iter().map(|p| p.as_str()));
+
+        assert!(set.contains("/api/v1/item/0"));
+        assert!(set.contains("/api/v1/item/4999"));
+        assert!(!set.contains("/api/v1/item/5000"));
+        assert!(!set.contains("/api/v1/item/12/sub"));
+        assert!(set.contains("/static/42/"));
+        assert!(set.contains("/static/4999/js/app.js"));
+        assert!(!set.contains("/static/42"));
+        assert!(!set.contains("/static/5000/x"));
+        assert!(!set.contains("/"));
+    }
+
+    #[test]
+    fn path_set_entry() {
+        let check = |paths: &[&str]| {
+            t_check_entry(false, GlobalFilterEntryE::PathSet(PathSet::new(paths.iter().copied()))).matching
+        };
+        // the decoded path is `/adminl e`
+        assert!(check(&["/other", "/adminl e"]));
+        assert!(check(&["/adm*"]));
+        assert!(check(&["*"]));
+        assert!(!check(&["/adminl", "/admin*x"]));
+        assert!(!check(&[]));
+    }
+
+    #[test]
+    fn path_set_negated() {
+        assert!(raw_entry_matches(serde_json::json!(["pathset", ["/other", "/adm*"]])));
+        assert!(!raw_entry_matches(serde_json::json!(["pathset", ["!/other", "/adm*"]])));
+        assert!(raw_entry_matches(serde_json::json!([
+            "pathset",
+            ["!/other", "/static/*"]
+        ])));
+    }
+
+    fn check_header_base64(value: &str, pattern: &str) -> bool {
+        let mut ri = mk_rinfo();
+        ri.headers.add(
+            "x-payload".to_string(),
+            Location::Header("x-payload".to_string()),
+            value.to_string(),
+        );
+        check_entry(
+            &ri,
+            &Tags::new(&VirtualTags::default()),
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::HeaderBase64(double_re("x-payload", pattern)),
+            },
+        )
+        .matching
+    }
+
+    #[test]
+    fn header_base64_decoded() {
+        // {"role":"admin"}
+        assert!(check_header_base64("eyJyb2xlIjoiYWRtaW4ifQ==", r#""role":"admin""#));
+        assert!(!check_header_base64("eyJyb2xlIjoiYWRtaW4ifQ==", "^eyJ"));
+    }
+
+    #[test]
+    fn header_base64_raw_fallback() {
+        assert!(check_header_base64("not base64!", "^not base64"));
+        assert!(!check_header_base64("not base64!", "admin"));
+    }
+
+    #[test]
+    fn truncated_tags() {
+        let mut ri = mk_rinfo();
+        ri.rinfo.qinfo.args.truncated = true;
+        ri.cookies.truncated = true;
+        let (tags, _, _) = tag(&mut ri, true, &[]);
+        assert!(tags.contains("args-truncated"));
+        assert!(tags.contains("cookies-truncated"));
+        assert!(!tags.contains("headers-truncated"));
+    }
+
+    #[test]
+    fn count_only_monitor_has_no_headers() {
+        let mut ri = mk_rinfo();
+        let mut headers = HashMap::new();
+        headers.insert("x-monitored".to_string(), parse_request_template("yes"));
+        let action = SimpleAction {
+            atype: SimpleActionT::Count,
+            headers: Some(headers),
+            multi_headers: None,
+            status: 200,
+            extra_tags: None,
+        };
+        let (mut tags, decision, _) = tag(&mut ri, true, &[always_section("gf-count", Some(action))]);
+        assert!(tags.contains("gf-count"));
+        let (action, reasons) = match decision {
+            SimpleDecision::Action(action, reasons) => (action, reasons),
+            SimpleDecision::Pass => panic!("the global filter should have matched"),
+        };
+        assert_eq!(reasons.len(), 1);
+        let dec = action.to_decision(
+            true,
+            None::<&crate::grasshopper::DummyGrasshopper>,
+            &ri,
+            &mut tags,
+            reasons,
+        );
+        let maction = dec.maction.expect("count should produce an action");
+        assert_eq!(maction.atype, crate::interface::ActionType::Monitor);
+        assert!(maction.headers.is_none());
+        assert_eq!(dec.reasons.len(), 1);
+    }
+
+    #[test]
+    fn monitor_multi_headers_accumulated() {
+        let mut ri = mk_rinfo();
+        let monitor = |cookie: &str| {
+            let mut multi_headers = HashMap::new();
+            multi_headers.insert("set-cookie".to_string(), vec![parse_request_template(cookie)]);
+            SimpleAction {
+                atype: SimpleActionT::Monitor,
+                headers: None,
+                multi_headers: Some(multi_headers),
+                status: 200,
+                extra_tags: None,
+            }
+        };
+        let mut second = always_section("gf-second", Some(monitor("b=2")));
+        second.id = "gf2".to_string();
+        let (mut tags, decision, _) = tag(
+            &mut ri,
+            true,
+            &[always_section("gf-first", Some(monitor("a=1"))), second],
+        );
+        let (action, reasons) = match decision {
+            SimpleDecision::Action(action, reasons) => (action, reasons),
+            SimpleDecision::Pass => panic!("the global filters should have matched"),
+        };
+        let dec = action.to_decision(
+            true,
+            None::<&crate::grasshopper::DummyGrasshopper>,
+            &ri,
+            &mut tags,
+            reasons,
+        );
+        let mut cookies = dec
+            .maction
+            .unwrap()
+            .multi_headers
+            .unwrap()
+            .remove("set-cookie")
+            .unwrap();
+        cookies.sort();
+        assert_eq!(cookies, vec!["a=1".to_string(), "b=2".to_string()]);
+    }
+
+    #[test]
+    fn globalfilters_enabled() {
+        let tags = tag_with_globalfilter(true);
+        assert!(tags.contains("gf-hit"));
+    }
+
+    #[test]
+    fn globalfilters_disabled_acl_still_runs() {
+        let tags = tag_with_globalfilter(false);
+        assert!(!tags.contains("gf-hit"));
+        let mut acl = SecurityPolicy::default().acl_profile;
+        acl.deny.insert("human".to_string());
+        acl.deny.insert("gf-hit".to_string());
+        match crate::acl::check_acl(&tags, &acl) {
+            crate::acl::AclResult::Match { human, .. } => {
+                let (allowed, matched) = human.expect("acl should match");
+                assert!(!allowed);
+                assert!(matched.contains("human"));
+                assert!(!matched.contains("gf-hit"));
+            }
+            crate::acl::AclResult::Passthrough(_) => panic!("unexpected passthrough"),
+        }
+    }
+
+    #[test]
+    fn skipped_stages_stats() {
+        let mut secpol = SecurityPolicy::default();
+        secpol.globalfilter_active = false;
+        secpol.limit_active = false;
+        let stats = crate::interface::stats::SecpolStats::build(&secpol, 1);
+        assert_eq!(stats.skipped_stages(), vec!["globalfilter", "limit"]);
+    }
+
+    fn tag_allowlist_first(allowlist_first: bool) -> (Tags, SimpleDecision) {
+        let mut ri = mk_rinfo();
+        let mut secpol = SecurityPolicy::default();
+        secpol.allowlist_first = allowlist_first;
+        ri.rinfo.secpolicy = Arc::new(secpol);
+        let mut blocking = always_section("gf-block", Some(SimpleAction::default()));
+        blocking.id = "gf-block".to_string();
+        let mut allow = always_section(
+            "gf-allow",
+            Some(SimpleAction {
+                atype: SimpleActionT::Skip,
+                ..SimpleAction::default()
+            }),
+        );
+        allow.id = "gf-allow".to_string();
+        let (tags, decision, _) = tag(&mut ri, true, &[blocking, allow]);
+        (tags, decision)
+    }
+
+    #[test]
+    fn allowlist_first_early_skip() {
+        let (tags, decision) = tag_allowlist_first(true);
+        assert!(tags.contains("gf-allow"));
+        // the blocking filter was never evaluated
+        assert!(!tags.contains("gf-block"));
+        match decision {
+            SimpleDecision::Action(action, reasons) => {
+                assert_eq!(action.atype, SimpleActionT::Skip);
+                assert_eq!(reasons.len(), 1);
+                assert_eq!(reasons[0].decision, crate::interface::BDecision::Skip);
+                assert_eq!(reasons[0].extra, serde_json::json!({ "early_skip": true }));
+            }
+            SimpleDecision::Pass => panic!("the skip filter should match"),
+        }
+    }
+
+    #[test]
+    fn allowlist_first_disabled() {
+        let (tags, decision) = tag_allowlist_first(false);
+        assert!(tags.contains("gf-allow"));
+        assert!(tags.contains("gf-block"));
+        match decision {
+            SimpleDecision::Action(action, reasons) => {
+                assert_eq!(action.atype, SimpleActionT::Skip);
+                assert!(reasons.iter().all(|r| r.extra.is_null()));
+            }
+            SimpleDecision::Pass => panic!("both filters should match"),
+        }
+    }
+
+    #[test]
+    fn internal_networks() {
+        let nets: Vec<IpNet> = ["10.0.0.0/8", " 10.1.0.0/16", "192.168.1.1", "fd00::/8", "garbage", ""]
+            .iter()
+            .filter_map(|n| parse_network(n))
+            .collect();
+        assert_eq!(nets.len(), 4);
+        let check = |nets: &[IpNet], ip: &str| is_internal(nets, Some(ip.parse().unwrap()));
+        assert!(check(&nets, "10.1.2.3"));
+        assert!(check(&nets, "10.200.2.3"));
+        assert!(check(&nets, "192.168.1.1"));
+        assert!(check(&nets, "fd12::1"));
+        assert!(!check(&nets, "192.168.1.2"));
+        assert!(!check(&nets, "52.78.12.56"));
+        assert!(!is_internal(&nets, None));
+
+        // the order of overlapping networks does not matter
+        let reversed: Vec<IpNet> = nets.iter().rev().cloned().collect();
+        assert!(check(&reversed, "10.1.2.3"));
+        assert!(check(&reversed, "10.200.2.3"));
+        assert!(!check(&reversed, "11.0.0.1"));
+    }
+
+    fn tie_winner(priority1: i32, priority2: i32) -> Initiator {
+        let raw: Vec<crate::config::raw::RawGlobalFilterSection> = serde_json::from_value(serde_json::json!([
+            {"id": "gf1", "name": "first", "active": true, "tags": [], "action": "monitor", "priority": priority1,
+             "rule": {"relation": "OR", "entries": [["method", "GET"]]}},
+            {"id": "gf2", "name": "second", "active": true, "tags": [], "action": "monitor", "priority": priority2,
+             "rule": {"relation": "OR", "entries": [["method", "GET"]]}}
+        ]))
+        .unwrap();
+        let mut actions = HashMap::new();
+        actions.insert(
+            "monitor".to_string(),
+            SimpleAction {
+                atype: SimpleActionT::Monitor,
+                ..SimpleAction::default()
+            },
+        );
+        let mut logs = Logs::default();
+        let sections = GlobalFilterSection::resolve(&mut logs, &actions, raw);
+        let mut ri = mk_rinfo();
+        let (_, decision, _) = tag(&mut ri, true, &sections);
+        match decision {
+            SimpleDecision::Action(_, reasons) => {
+                assert_eq!(reasons.len(), 1);
+                reasons[0].initiator.clone()
+            }
+            SimpleDecision::Pass => panic!("both sections should match"),
+        }
+    }
+
+    fn raw_entry_matches(entry: serde_json::Value) -> bool {
+        raw_entry_matches_in(entry, mk_rinfo())
+    }
+
+    fn raw_entry_matches_in(entry: serde_json::Value, mut ri: RequestInfo) -> bool {
+        let raw: Vec<crate::config::raw::RawGlobalFilterSection> = serde_json::from_value(serde_json::json!([
+            {"id": "gf1", "name": "options", "active": true, "tags": ["matched"],
+             "rule": {"relation": "OR", "entries": [entry]}}
+        ]))
+        .unwrap();
+        let mut logs = Logs::default();
+        let sections = GlobalFilterSection::resolve(&mut logs, &HashMap::new(), raw);
+        let (tags, _, _) = tag(&mut ri, true, &sections);
+        tags.contains("matched")
+    }
+
+    #[test]
+    fn header_name_case() {
+        let with_headers = |raw: &[(&str, &str)]| {
+            let mut ri = mk_rinfo();
+            let raw: HashMap<String, String> = raw.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            ri.headers = crate::utils::map_headers(&SecurityPolicy::empty(), &raw).0;
+            ri
+        };
+        let entry = serde_json::json!(["headers", ["User-AGENT", "^curl/"]]);
+        assert!(raw_entry_matches_in(
+            entry.clone(),
+            with_headers(&[("USER-Agent", "curl/8.0")])
+        ));
+        assert!(!raw_entry_matches_in(entry, with_headers(&[("USER-Agent", "wget")])));
+
+        // headers that only differ by case are joined in a stable order
+        let ri = with_headers(&[("x-shard", "b"), ("X-Shard", "a")]);
+        assert_eq!(ri.headers.get_str("x-shard"), Some("a b"));
+        assert!(raw_entry_matches_in(
+            serde_json::json!(["headers", ["X-SHARD", "^a b$"]]),
+            ri
+        ));
+
+        // the separator is set in the content filter profile
+        let mut secpol = SecurityPolicy::empty();
+        secpol.content_filter_profile.header_join_separator = ", ".to_string();
+        let raw: HashMap<String, String> = [("x-shard", "b"), ("X-Shard", "a")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let headers = crate::utils::map_headers(&secpol, &raw).0;
+        assert_eq!(headers.get_str("x-shard"), Some("a, b"));
+    }
+
+    #[test]
+    fn randomness_metrics() {
+        let entropy = |v: &str| randomness(v, RandomnessMetric::Entropy);
+        assert_eq!(entropy("aaaaaaaaaaaa"), Some(0.0));
+        assert_eq!(entropy("abcdefghijklmnop"), Some(4.0));
+        // too short to tell
+        assert_eq!(entropy("q8Z!"), None);
+        assert_eq!(randomness("ABCDefgh", RandomnessMetric::Uppercase), Some(0.5));
+        assert_eq!(randomness("a-b_c.d/", RandomnessMetric::Special), Some(0.5));
+    }
+
+    #[test]
+    fn randomness_entry() {
+        let with_token = |token: &str| {
+            let mut ri = mk_rinfo();
+            ri.headers.add(
+                "x-token".to_string(),
+                Location::HeaderValue("x-token".to_string(), token.to_string()),
+                token.to_string(),
+            );
+            ri
+        };
+        let entry = || serde_json::json!(["randomness", ["headers.x-token", "entropy", 3.5]]);
+        assert!(raw_entry_matches_in(entry(), with_token("q8Z!xP3#vL9@mK2$")));
+        assert!(!raw_entry_matches_in(entry(), with_token("hellohellohello")));
+        assert!(!raw_entry_matches_in(entry(), with_token("q8Z!")));
+        // missing fields never match
+        assert!(!raw_entry_matches_in(entry(), mk_rinfo()));
+
+        let special = serde_json::json!(["randomness", ["headers.x-token", "special", 0.25]]);
+        assert!(raw_entry_matches_in(special.clone(), with_token("a%27%20OR%201=1")));
+        assert!(!raw_entry_matches_in(special, with_token("session42token")));
+    }
+
+    #[test]
+    fn args_presence() {
+        let with_query = |uri: &str| RequestBuilder::new("GET", uri).ip("52.78.12.56").build();
+        let entry = || serde_json::json!(["argspresent", "debug"]);
+        assert!(raw_entry_matches_in(entry(), with_query("/?debug&admin")));
+        assert!(raw_entry_matches_in(entry(), with_query("/?debug=1")));
+        assert!(raw_entry_matches_in(entry(), with_query("/?debug=")));
+        assert!(!raw_entry_matches_in(entry(), with_query("/?debugging=1&x=debug")));
+        assert!(!raw_entry_matches_in(
+            serde_json::json!(["argspresent", "!debug"]),
+            with_query("/?debug")
+        ));
+
+        let valueless = |uri: &str| {
+            let mut ri = with_query(uri);
+            let (tags, _, _) = tag(&mut ri, false, &[]);
+            tags.get("arg-valueless").cloned()
+        };
+        let locs = valueless("/?debug&a=b&admin").unwrap();
+        assert_eq!(
+            locs,
+            vec![
+                Location::UriArgument("debug".to_string()),
+                Location::UriArgument("admin".to_string())
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(valueless("/?debug=1&admin="), None);
+        assert_eq!(valueless("/"), None);
+    }
+
+    #[test]
+    fn geo_iso_codes() {
+        let mut ri = mk_rinfo();
+        ri.rinfo.geoip.region = Some("Quebec".to_string());
+        ri.rinfo.geoip.region_iso = Some("QC".to_string());
+
+        let mut tags = Tags::new(&VirtualTags::default());
+        tag_geo_iso(&mut tags, &ri.rinfo.geoip);
+        assert!(tags.contains("geo-region-iso:qc"));
+        assert!(tags.contains("geo-subregion-iso:nil"));
+
+        assert!(raw_entry_matches_in(serde_json::json!(["regioniso", "QC"]), ri.clone()));
+        assert!(!raw_entry_matches_in(
+            serde_json::json!(["regioniso", "ON"]),
+            ri.clone()
+        ));
+        assert!(raw_entry_matches_in(
+            serde_json::json!(["regioniso", "!ON"]),
+            ri.clone()
+        ));
+        // missing codes never match
+        assert!(!raw_entry_matches_in(serde_json::json!(["subregioniso", ".*"]), ri));
+    }
+
+    #[test]
+    fn uri_too_long_tag() {
+        let tagged = |too_long: bool| {
+            let mut ri = mk_rinfo();
+            ri.uri_too_long = too_long;
+            let (tags, _, _) = tag(&mut ri, false, &[]);
+            tags.contains("uri-too-long")
+        };
+        assert!(tagged(true));
+        assert!(!tagged(false));
+    }
+
+    #[test]
+    fn oversized_body_tags() {
+        let tagged = |policy: OversizedBodyPolicy| {
+            let mut secpol = SecurityPolicy::default();
+            secpol.content_filter_profile.max_body_size = 4;
+            secpol.content_filter_profile.oversized_body = policy;
+            let mut ri = RequestBuilder::new("POST", "/")
+                .body(b"a=1&b=2")
+                .security_policy(Arc::new(secpol))
+                .build();
+            let (tags, _, _) = tag(&mut ri, false, &[]);
+            ["body-too-large", "body-truncated", "body-uninspected"]
+                .iter()
+                .map(|t| tags.contains(t))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tagged(OversizedBodyPolicy::InspectTruncated), vec![true, true, false]);
+        assert_eq!(tagged(OversizedBodyPolicy::Pass), vec![true, false, true]);
+        assert_eq!(tagged(OversizedBodyPolicy::BlockUnverified), vec![true, false, false]);
+    }
+
+    #[test]
+    fn distinct_values() {
+        let request = |uri: &str, accept: &str| {
+            RequestBuilder::new("GET", uri)
+                .ip("52.78.12.56")
+                .header("accept", accept)
+                .build()
+        };
+        let args = || serde_json::json!(["distinctvalues", ["args.id", 1]]);
+        assert!(!raw_entry_matches_in(args(), request("/?id=1", "*/*")));
+        assert!(!raw_entry_matches_in(args(), request("/?id=1&id=1", "*/*")));
+        assert!(raw_entry_matches_in(args(), request("/?id=1&id=2&id=1", "*/*")));
+        assert!(!raw_entry_matches_in(args(), request("/?other=1&other=2", "*/*")));
+        assert!(raw_entry_matches_in(
+            serde_json::json!(["distinctvalues", ["!args.id", 1]]),
+            request("/?id=1", "*/*")
+        ));
+
+        let headers = || serde_json::json!(["distinctvalues", ["headers.accept", 2]]);
+        assert!(!raw_entry_matches_in(headers(), request("/", "text/html")));
+        assert!(!raw_entry_matches_in(
+            headers(),
+            request("/", "text/html, text/html, */*")
+        ));
+        assert!(raw_entry_matches_in(
+            headers(),
+            request("/", "text/html, application/json, */*")
+        ));
+    }
+
+    #[test]
+    fn cors_preflight() {
+        let options = |preflight: bool| {
+            let builder = RequestBuilder::new("OPTIONS", "/api").header("origin", "https://example.com");
+            if preflight {
+                builder.header("access-control-request-method", "PUT").build()
+            } else {
+                builder.build()
+            }
+        };
+        assert!(options(true).cors_preflight);
+        assert!(!options(false).cors_preflight);
+        assert!(
+            !RequestBuilder::new("GET", "/api")
+                .header("access-control-request-method", "PUT")
+                .build()
+                .cors_preflight
+        );
+
+        let tagged = |mut ri: RequestInfo| {
+            let (tags, _, _) = tag(&mut ri, false, &[]);
+            tags.contains("cors-preflight")
+        };
+        assert!(tagged(options(true)));
+        assert!(!tagged(options(false)));
+
+        let entry = |v: &str| serde_json::json!(["corspreflight", v]);
+        assert!(raw_entry_matches_in(entry("true"), options(true)));
+        assert!(!raw_entry_matches_in(entry("true"), options(false)));
+        assert!(raw_entry_matches_in(entry("false"), options(false)));
+        assert!(raw_entry_matches_in(entry("!true"), options(false)));
+    }
+
+    #[test]
+    fn named_captures() {
+        let mut headers = HashMap::new();
+        headers.insert(
+            "x-tenant".to_string(),
+            parse_request_template("${capture.tenant}/${capture.missing}"),
+        );
+        let action = SimpleAction {
+            atype: SimpleActionT::Custom {
+                content: "denied".to_string(),
+                json_content: None,
+                default_format: crate::config::raw::ContentFormat::Html,
+            },
+            headers: Some(headers),
+            multi_headers: None,
+            status: 403,
+            extra_tags: None,
+        };
+        let actions: HashMap<String, SimpleAction> = std::iter::once(("tenant-block".to_string(), action)).collect();
+        let raw: Vec<crate::config::raw::RawGlobalFilterSection> = serde_json::from_value(serde_json::json!([
+            {"id": "gf1", "name": "tenant", "active": true, "tags": ["tenant"], "action": "tenant-block",
+             "rule": {"relation": "AND", "entries": [
+                 ["headers", ["x-forwarded-host", "^(?P<tenant>[a-z]+)\\.example\\.com$"]],
+                 ["path", "^/(?P<section>[a-z]+)/"]
+             ]}}
+        ]))
+        .unwrap();
+        let mut logs = Logs::default();
+        let sections = GlobalFilterSection::resolve(&mut logs, &actions, raw);
+
+        let mut ri = RequestBuilder::new("GET", "/admin/users")
+            .header("x-forwarded-host", "acme.example.com")
+            .build();
+        let (mut tags, decision, _) = tag(&mut ri, true, &sections);
+        assert!(tags.contains("tenant"));
+        assert_eq!(ri.captures.get("tenant").map(|s| s.as_str()), Some("acme"));
+        assert_eq!(ri.captures.get("section").map(|s| s.as_str()), Some("admin"));
+
+        let (action, reasons) = match decision {
+            SimpleDecision::Action(action, reasons) => (action, reasons),
+            SimpleDecision::Pass => panic!("the global filter should have matched"),
+        };
+        let dec = action.to_decision(
+            true,
+            None::<&crate::grasshopper::DummyGrasshopper>,
+            &ri,
+            &mut tags,
+            reasons,
+        );
+        let headers = dec.maction.and_then(|a| a.headers).unwrap();
+        assert_eq!(headers.get("x-tenant").map(|s| s.as_str()), Some("acme/nil"));
+
+        // nothing is captured when the filter does not match
+        let mut ri = RequestBuilder::new("GET", "/admin/users")
+            .header("x-forwarded-host", "acme.example.org")
+            .build();
+        tag(&mut ri, true, &sections);
+        assert!(ri.captures.is_empty());
+    }
+
+    #[test]
+    fn raw_and_decoded_path() {
+        let traversal = || RequestBuilder::new("GET", "/static/%2e%2e/%2E%2E/etc/passwd").build();
+        assert_eq!(traversal().rinfo.qinfo.qpath, "/static/%2e%2e/%2E%2E/etc/passwd");
+        assert_eq!(traversal().rinfo.qinfo.decoded_path, "/static/../../etc/passwd");
+
+        // the encoded form is only seen in raw mode
+        assert!(raw_entry_matches_in(serde_json::json!(["path", "%2e%2e"]), traversal()));
+        assert!(!raw_entry_matches_in(
+            serde_json::json!(["decodedpath", "%2e%2e"]),
+            traversal()
+        ));
+        // and the decoded form only in decoded mode
+        assert!(!raw_entry_matches_in(
+            serde_json::json!(["path", "\\.\\./"]),
+            traversal()
+        ));
+        assert!(raw_entry_matches_in(
+            serde_json::json!(["decodedpath", "\\.\\./"]),
+            traversal()
+        ));
+
+        // both modes agree when there is nothing to decode
+        let plain = || RequestBuilder::new("GET", "/static/app.js").build();
+        assert!(raw_entry_matches_in(serde_json::json!(["path", "^/static/"]), plain()));
+        assert!(raw_entry_matches_in(
+            serde_json::json!(["decodedpath", "^/static/"]),
+            plain()
+        ));
+    }
+
+    #[test]
+    fn websocket_upgrade() {
+        let request = |upgrade: bool| {
+            let builder = RequestBuilder::new("GET", "/socket").header("connection", "Upgrade");
+            if upgrade {
+                builder.header("upgrade", "websocket").build()
+            } else {
+                builder.build()
+            }
+        };
+        let tagged = |mut ri: RequestInfo| {
+            let (tags, _, _) = tag(&mut ri, false, &[]);
+            tags.contains("websocket-upgrade")
+        };
+        assert!(tagged(request(true)));
+        assert!(!tagged(request(false)));
+
+        let entry = |v: &str| serde_json::json!(["websocketupgrade", v]);
+        assert!(raw_entry_matches_in(entry("true"), request(true)));
+        assert!(!raw_entry_matches_in(entry("true"), request(false)));
+        assert!(raw_entry_matches_in(entry("false"), request(false)));
+        assert!(!raw_entry_matches_in(entry("false"), request(true)));
+    }
+
+    #[test]
+    fn cookie_values_normalized() {
+        let with_cookies = |header: &str, dequote: bool| {
+            let mut ri = mk_rinfo();
+            crate::utils::cookie_map(&mut ri.cookies, header, dequote);
+            ri
+        };
+        let entry = || serde_json::json!(["cookies", ["session", "^abc123$"]]);
+        assert!(raw_entry_matches_in(entry(), with_cookies(r#"session="abc123""#, true)));
+        assert!(raw_entry_matches_in(
+            entry(),
+            with_cookies("theme=dark;  session=  abc123  ", true)
+        ));
+        assert!(!raw_entry_matches_in(
+            entry(),
+            with_cookies(r#"session="abc123""#, false)
+        ));
+        assert!(raw_entry_matches_in(entry(), with_cookies(" session=abc123 ", false)));
+    }
+
+    #[test]
+    fn regex_anchoring() {
+        // the path is `/adminl e`
+        assert!(raw_entry_matches(serde_json::json!(["path", "adminl"])));
+        assert!(!raw_entry_matches(
+            serde_json::json!(["path", "adminl", null, {"anchored": true}])
+        ));
+        assert!(raw_entry_matches(
+            serde_json::json!(["path", "/adminl.*", null, {"anchored": true}])
+        ));
+        assert!(raw_entry_matches(serde_json::json!([
+            "headers",
+            ["user-agent", "curl"]
+        ])));
+        assert!(!raw_entry_matches(
+            serde_json::json!(["headers", ["user-agent", "curl"], "ua", {"anchored": true}])
+        ));
+        // alternations are anchored as a whole
+        assert!(!raw_entry_matches(
+            serde_json::json!(["method", "POS|GE", null, {"anchored": true}])
+        ));
+        assert!(raw_entry_matches(
+            serde_json::json!(["method", "POST|GET", null, {"anchored": true}])
+        ));
+    }
+
+    #[test]
+    fn regex_case_sensitivity() {
+        assert!(raw_entry_matches(serde_json::json!(["path", "ADMINL"])));
+        assert!(!raw_entry_matches(
+            serde_json::json!(["path", "ADMINL", null, {"case_sensitive": true}])
+        ));
+        assert!(raw_entry_matches(
+            serde_json::json!(["path", "adminl", null, {"case_sensitive": true}])
+        ));
+    }
+
+    #[test]
+    fn malformed_regex_options() {
+        let raw: Vec<crate::config::raw::RawGlobalFilterSection> = serde_json::from_value(serde_json::json!([
+            {"id": "gf1", "name": "options", "active": true, "tags": ["matched"],
+             "rule": {"relation": "OR", "entries": [["path", "ADMINL", null, {"case_sensitive": "yes"}]]}}
+        ]))
+        .unwrap();
+        let mut logs = Logs::default();
+        let sections = GlobalFilterSection::resolve(&mut logs, &HashMap::new(), raw);
+        assert!(logs.logs.iter().any(|l| l.message.starts_with("Bad regex options")));
+        // the default options are used
+        let (tags, _, _) = tag(&mut mk_rinfo(), true, &sections);
+        assert!(tags.contains("matched"));
+    }
+
+    #[test]
+    fn priority_breaks_ties() {
+        let first = Initiator::GlobalFilter {
+            id: "gf1".to_string(),
+            name: "first".to_string(),
+        };
+        let second = Initiator::GlobalFilter {
+            id: "gf2".to_string(),
+            name: "second".to_string(),
+        };
+        // same priority, configuration order
+        assert_eq!(tie_winner(0, 0), first);
+        assert_eq!(tie_winner(1, 5), second);
+        assert_eq!(tie_winner(5, 1), first);
+        assert_eq!(tie_winner(-1, 0), second);
+    }
+
+    #[test]
+    fn internal_tag() {
+        // no internal networks are configured by default
+        let tags = tag_with_globalfilter(true);
+        assert!(!tags.contains("internal"));
+        assert!(tags.contains("gf-hit"));
+    }
+
+    fn tag_internal(networks: &[&str], skip_globalfilters: bool) -> Tags {
+        let mut ri = mk_rinfo();
+        let mut secpol = SecurityPolicy::default();
+        secpol.settings.internal_networks = networks.iter().filter_map(|n| parse_network(n)).collect();
+        secpol.settings.internal_skip_globalfilters = skip_globalfilters;
+        ri.rinfo.secpolicy = Arc::new(secpol);
+        let (tags, _, _) = tag(&mut ri, true, &[always_section("gf-hit", None)]);
+        tags
+    }
+
+    #[test]
+    fn internal_tag_configured() {
+        // the request comes from 52.78.12.56
+        let tags = tag_internal(&["10.0.0.0/8", "52.78.0.0/16"], false);
+        assert!(tags.contains("internal"));
+        assert!(tags.contains("gf-hit"));
+
+        let tags = tag_internal(&["52.78.12.56"], true);
+        assert!(tags.contains("internal"));
+        assert!(!tags.contains("gf-hit"));
+
+        let tags = tag_internal(&["52.79.0.0/16"], true);
+        assert!(!tags.contains("internal"));
+        assert!(tags.contains("gf-hit"));
+    }
+
+    fn reason_extra(rule: GlobalFilterRule, log_matched: bool, log_rule_tree: bool) -> serde_json::Value {
+        let mut ri = mk_rinfo();
+        let mut gftags = crate::interface::RawTags::default();
+        gftags.insert("gf-path");
+        let section = GlobalFilterSection {
+            id: "gf".to_string(),
+            name: "gf".to_string(),
+            tags: gftags,
+            rule,
+            action: Some(SimpleAction::default()),
+            log_matched,
+            log_rule_tree,
+            priority: 0,
+        };
+        let (_, decision, _) = tag(&mut ri, true, &[section]);
+        match decision {
+            SimpleDecision::Action(_, mut reasons) => {
+                assert_eq!(reasons.len(), 1);
+                reasons.pop().unwrap().extra
+            }
+            SimpleDecision::Pass => panic!("the global filter should have matched"),
+        }
+    }
+
+    fn matched_reason_extra(log_matched: bool) -> serde_json::Value {
+        let rule = GlobalFilterRule::Entry(GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::Path(single_re("adm[a-z]+")),
+        });
+        reason_extra(rule, log_matched, false)
+    }
+
+    #[test]
+    fn matched_value_logged() {
+        assert_eq!(matched_reason_extra(true), serde_json::json!({ "matched": ["adminl"] }));
+    }
+
+    #[test]
+    fn matched_value_not_logged_by_default() {
+        assert_eq!(matched_reason_extra(false), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn matched_rule_tree() {
+        let entry = |negated, entry| GlobalFilterRule::Entry(GlobalFilterEntry { negated, entry });
+        let rule = GlobalFilterRule::Rel(GlobalFilterRelation {
+            relation: Relation::And,
+            entries: vec![
+                entry(false, GlobalFilterEntryE::Path(single_re("adm[a-z]+"))),
+                GlobalFilterRule::Rel(GlobalFilterRelation {
+                    relation: Relation::Or,
+                    entries: vec![
+                        entry(false, GlobalFilterEntryE::Ip("1.1.1.1".parse().unwrap())),
+                        entry(false, GlobalFilterEntryE::Header(double_re("user-agent", "^curl"))),
+                        entry(true, GlobalFilterEntryE::Method(single_re("^POST$"))),
+                    ],
+                }),
+            ],
+        });
+        let loc = |l: Location| serde_json::to_value(l).unwrap();
+        assert_eq!(
+            reason_extra(rule.clone(), false, true),
+            serde_json::json!({
+                "rule_tree": {
+                    "relation": "and",
+                    "entries": [
+                        { "type": "path", "id": "adm[a-z]+", "negated": false, "locations": [loc(Location::Path)] },
+                        {
+                            "relation": "or",
+                            "entries": [
+                                {
+                                    "type": "headers",
+                                    "id": "user-agent:^curl",
+                                    "negated": false,
+                                    "locations": [loc(Location::HeaderValue(
+                                        "user-agent".to_string(),
+                                        "curl/7.58.0".to_string()
+                                    ))]
+                                },
+                                { "type": "method", "id": "^POST$", "negated": true, "locations": [loc(Location::Request)] }
+                            ]
+                        }
+                    ]
+                }
+            })
+        );
+        assert_eq!(reason_extra(rule, false, false), serde_json::Value::Null);
+    }
+
     fn mk_globalfilterentries(lst: &[&str]) -> Vec<GlobalFilterRule> {
         lst.iter()
             .map(|e| match e.strip_prefix('!') {
@@ -102,7 +102,7 @@ fn urldecode_bytes(input: &[u8]) -> DecodingResult<Vec<u8>> {
     }
 }
 
-fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
+pub fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
     const BAD_PADDING_MESSAGE: &str = "bad padding";
     if input.len() % 4 == 1 {
         return Err(BAD_PADDING_MESSAGE);
@@ -188,6 +188,9 @@ where
     F: Fn(String, String) -> Location,
 {
     for kv in query.split('&') {
+        if args.truncated {
+            break;
+        }
         let (k, v, rawvalue) = match kv.splitn(2, '=').collect_tuple() {
             Some((k, v)) => (urldecode_str_def(k), urldecode_str_def(v), v),
             None => (urldecode_str_def(kv), String::new(), ""),
@@ -197,6 +200,61 @@ where
     }
 }
 
+/// all the values of a key in query parameters, in order of appearance
+pub fn urlencoded_values(query: &str, key: &str) -> Vec<String> {
+    query
+        .split('&')
+        .map(|kv| match kv.splitn(2, '=').collect_tuple() {
+            Some((k, v)) => (urldecode_str_def(k), v),
+            None => (urldecode_str_def(kv), ""),
+        })
+        .filter(|(k, _)| k == key)
+        .map(|(_, v)| urldecode_str_def(v))
+        .collect()
+}
+
+/// keys of the query parameters that have no value, such as `debug` in `?debug&a=b`
+pub fn valueless_params(query: &str) -> Vec<String> {
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty() && !kv.contains('='))
+        .map(urldecode_str_def)
+        .collect()
+}
+
+/// checks that an url encoded string only contains well formed percent encodings, decoding to valid utf8
+///
+/// overlong utf8 encodings are rejected, as well as strings holding a REPLACEMENT CHARACTER, that
+/// is produced when non utf8 bytes are converted
+pub fn invalid_urlencoding(input: &str) -> bool {
+    if input.contains(char::REPLACEMENT_CHARACTER) {
+        return true;
+    }
+    let bytes = input.as_bytes();
+    let malformed = bytes.iter().enumerate().any(|(idx, c)| {
+        *c == b'%'
+            && !(bytes.get(idx + 1).copied().and_then(from_hex_digit).is_some()
+                && bytes.get(idx + 2).copied().and_then(from_hex_digit).is_some())
+    });
+    malformed
+        || match urldecode(input) {
+            DecodingResult::NoChange => false,
+            DecodingResult::Changed(decoded) => std::str::from_utf8(&decoded).is_err(),
+        }
+}
+
+/// the query parameters that are not properly encoded, as (decoded key, raw value) pairs
+pub fn invalid_urlencoded_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|kv| invalid_urlencoding(kv))
+        .map(|kv| match kv.splitn(2, '=').collect_tuple() {
+            Some((k, v)) => (urldecode_str_def(k), v.to_string()),
+            None => (urldecode_str_def(kv), String::new()),
+        })
+        .collect()
+}
+
 fn urldecode_bytes_str(input: &[u8]) -> String {
     match urldecode_bytes(input) {
         DecodingResult::NoChange => String::from_utf8_lossy(input).into_owned(),
@@ -210,6 +268,9 @@ where
     F: Fn(String, String) -> Location,
 {
     for kv in query.split(|x| *x == b'&') {
+        if args.truncated {
+            break;
+        }
         let (k, v) = match kv.splitn(2, |x| *x == b'=').collect_tuple() {
             Some((k, v)) => (urldecode_bytes_str(k), urldecode_bytes_str(v)),
             None => (urldecode_bytes_str(kv), String::new()),
@@ -403,6 +464,34 @@ mod test_lib {
         assert!(urldecode_str_def("%F0%9F%BE%20%21%") == "� !%");
     }
 
+    #[test]
+    fn test_invalid_urlencoding() {
+        assert!(!invalid_urlencoding("abcd"));
+        assert!(!invalid_urlencoding("a%20b+c"));
+        assert!(!invalid_urlencoding("%F0%9F%91%BE"));
+        assert!(invalid_urlencoding("100%"));
+        assert!(invalid_urlencoding("%p1"));
+        assert!(invalid_urlencoding("%a"));
+        // truncated utf8 sequence
+        assert!(invalid_urlencoding("%F0%9F%BE"));
+        // overlong encoding of '/'
+        assert!(invalid_urlencoding("%C0%AF"));
+        // non utf8 bytes, converted by the proxy
+        assert!(invalid_urlencoding(&String::from_utf8_lossy(b"ab\xff\xfe")));
+    }
+
+    #[test]
+    fn test_invalid_urlencoded_params() {
+        assert_eq!(
+            invalid_urlencoded_params("a=1&b=%C0%AF&c=%zz&d%20x=%41"),
+            vec![
+                ("b".to_string(), "%C0%AF".to_string()),
+                ("c".to_string(), "%zz".to_string())
+            ]
+        );
+        assert!(invalid_urlencoded_params("a=1&b=%2F").is_empty());
+    }
+
     #[test]
     fn test_ok_base64dec_all_str() {
         for (input, output) in [
@@ -0,0 +1,92 @@
+use crate::requestfields::RequestField;
+
+/// the method called by a gRPC request, parsed from a `/package.Service/Method` path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcInfo {
+    /// the fully qualified service name, `package.Service`
+    pub service: String,
+    /// the package of the service, empty when the service is not in a package
+    pub package: String,
+    pub method: String,
+}
+
+impl GrpcInfo {
+    /// the full method name, `package.Service/Method`
+    pub fn full_method(&self) -> String {
+        format!("{}/{}", self.service, self.method)
+    }
+}
+
+/// gRPC requests are identified by their content type, `application/grpc` or `application/grpc+proto` for example
+pub fn is_grpc(headers: &RequestField) -> bool {
+    headers
+        .get_str("content-type")
+        .map(|ct| {
+            let ct = ct.trim().to_ascii_lowercase();
+            ct == "application/grpc" || ct.starts_with("application/grpc+") || ct.starts_with("application/grpc;")
+        })
+        .unwrap_or(false)
+}
+
+/// parses the method of a gRPC request, returns None for other requests or malformed paths
+pub fn grpc_info(headers: &RequestField, qpath: &str) -> Option<GrpcInfo> {
+    if !is_grpc(headers) {
+        return None;
+    }
+    let (service, method) = qpath.strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    let package = service.rsplit_once('.').map(|(p, _)| p).unwrap_or_default();
+    Some(GrpcInfo {
+        service: service.to_string(),
+        package: package.to_string(),
+        method: method.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Location;
+
+    fn info(content_type: Option<&str>, path: &str) -> Option<GrpcInfo> {
+        let mut headers = RequestField::new(&[]);
+        if let Some(ct) = content_type {
+            headers.add(
+                "content-type".to_string(),
+                Location::Header("content-type".to_string()),
+                ct.to_string(),
+            );
+        }
+        grpc_info(&headers, path)
+    }
+
+    #[test]
+    fn grpc_method() {
+        let grpc = info(Some("application/grpc"), "/helloworld.v1.Greeter/SayHello").unwrap();
+        assert_eq!(grpc.service, "helloworld.v1.Greeter");
+        assert_eq!(grpc.package, "helloworld.v1");
+        assert_eq!(grpc.method, "SayHello");
+        assert_eq!(grpc.full_method(), "helloworld.v1.Greeter/SayHello");
+
+        let grpc = info(Some("application/grpc+proto"), "/Greeter/SayHello").unwrap();
+        assert_eq!(grpc.service, "Greeter");
+        assert_eq!(grpc.package, "");
+    }
+
+    #[test]
+    fn not_grpc() {
+        assert_eq!(info(None, "/helloworld.Greeter/SayHello"), None);
+        assert_eq!(info(Some("application/json"), "/helloworld.Greeter/SayHello"), None);
+        assert_eq!(info(Some("application/grpc-web"), "/helloworld.Greeter/SayHello"), None);
+    }
+
+    #[test]
+    fn malformed_grpc_path() {
+        assert_eq!(info(Some("application/grpc"), "/helloworld.Greeter"), None);
+        assert_eq!(info(Some("application/grpc"), "/helloworld.Greeter/"), None);
+        assert_eq!(info(Some("application/grpc"), "//SayHello"), None);
+        assert_eq!(info(Some("application/grpc"), "/a/b/c"), None);
+    }
+}
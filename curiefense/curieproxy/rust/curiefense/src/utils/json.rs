@@ -12,15 +12,12 @@ impl<'t, K: Eq + std::hash::Hash, V> NameValue<'t, K, V> {
     }
 }
 
-impl<'t, K: Eq + std::hash::Hash + std::fmt::Display, V: Serialize> Serialize for NameValue<'t, K, V> {
+impl<'t, K: Eq + std::hash::Hash + Serialize, V: Serialize> Serialize for NameValue<'t, K, V> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.inner.iter().map(|(k, v)| BigTableKV {
-            name: k.to_string(),
-            value: v,
-        }))
+        serializer.collect_seq(self.inner.iter().map(|(k, v)| BigTableKV { name: k, value: v }))
     }
 }
 
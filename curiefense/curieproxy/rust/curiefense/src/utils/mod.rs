@@ -1,70 +1,235 @@
 use chrono::{DateTime, Utc};
 use ipnet::IpNet;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use maxminddb::geoip2::country;
 use serde_json::json;
 use sha2::{Digest, Sha224};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use std::time::Instant;
 
 pub mod decoders;
+pub mod grpc;
 pub mod json;
+pub mod jwt;
+pub mod plugins;
 pub mod templating;
 pub mod url;
 
+use crate::body::form::{FormFields, FormLimits};
 use crate::body::parse_body;
 use crate::config::contentfilter::Transformation;
 use crate::config::hostmap::SecurityPolicy;
 use crate::config::matchers::{RequestSelector, RequestSelectorCondition};
-use crate::config::raw::ContentType;
+use crate::config::raw::{ContentType, OversizedBodyPolicy};
 use crate::config::virtualtags::VirtualTags;
 use crate::geo::{
     get_ipinfo_asn, get_ipinfo_carrier, get_ipinfo_company, get_ipinfo_location, get_ipinfo_privacy, get_maxmind_asn,
-    get_maxmind_city, get_maxmind_country, ipinfo_country_in_eu, ipinfo_resolve_continent, ipinfo_resolve_country_name,
-    USE_IPINFO,
+    get_maxmind_city, get_maxmind_country, ipinfo_country_in_eu, ipinfo_db, ipinfo_resolve_continent,
+    ipinfo_resolve_country_name, maxmind_db, USE_IPINFO,
 };
-use crate::interface::stats::Stats;
+use crate::interface::stats::{MappingDurations, Stats};
 use crate::interface::{AnalyzeResult, Decision, Location, Tags};
+use crate::learning::{Observations, LEARNING_MAX_FIELDS};
 use crate::logs::Logs;
 use crate::requestfields::RequestField;
-use crate::utils::decoders::{parse_urlencoded_params, urldecode_str, DecodingResult};
+use crate::utils::decoders::{
+    invalid_urlencoded_params, parse_urlencoded_params, urldecode_str, urlencoded_values, valueless_params,
+    DecodingResult,
+};
+
+lazy_static! {
+    /// name of the header holding the client certificate information, as forwarded by the proxy
+    pub static ref CLIENT_CERT_HEADER: String = std::env::var("CLIENT_CERT_HEADER")
+        .map(|h| h.to_ascii_lowercase())
+        .unwrap_or_else(|_| "x-forwarded-client-cert".to_string());
+    /// when set, cookie values enclosed in double quotes are unquoted
+    pub static ref COOKIE_DEQUOTE: bool =
+        std::env::var("COOKIE_DEQUOTE").map(|v| v == "true" || v == "1").unwrap_or(false);
+}
+
+/// client certificate information, as forwarded by the proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    pub subject: Option<String>,
+    pub san: Vec<String>,
+}
+
+/// a request method announced in a method override header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodOverride {
+    pub header: String,
+    /// the uppercased method
+    pub method: String,
+}
+
+/// splits a string on a separator, ignoring separators that are between double quotes
+fn split_unquoted(input: &str, sep: char) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            out.push(&input[start..i]);
+            start = i + 1;
+        }
+    }
+    out.push(&input[start..]);
+    out
+}
+
+/// parses the client certificate header
+///
+/// The Envoy x-forwarded-client-cert format is understood (Subject, URI and DNS keys, only the first
+/// element is used). Any other value is considered to be the raw subject DN.
+pub fn parse_client_cert(raw: &str) -> ClientCert {
+    let mut out = ClientCert::default();
+    let mut recognized = false;
+    let first = split_unquoted(raw, ',').into_iter().next().unwrap_or_default();
+    for kv in split_unquoted(first, ';') {
+        if let Some((k, v)) = kv.splitn(2, '=').collect_tuple() {
+            let v = v.trim().trim_matches('"').to_string();
+            match k.trim().to_ascii_lowercase().as_str() {
+                "subject" => out.subject = Some(v),
+                "uri" | "dns" => out.san.push(v),
+                "by" | "hash" | "cert" | "chain" => (),
+                _ => continue,
+            }
+            recognized = true;
+        }
+    }
+    if !recognized {
+        let subject = raw.trim();
+        if !subject.is_empty() {
+            out.subject = Some(subject.to_string());
+        }
+    }
+    out
+}
 
-pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
+/// Parse a cookie header into its cookies:
+/// * cookies are separated by `;`
+/// * names and values are trimmed
+/// * values enclosed in double quotes are unquoted when `dequote` is set
+///
+/// Names are kept as is, as cookie names are case sensitive.
+pub fn cookie_map(cookies: &mut RequestField, cookie: &str, dequote: bool) {
     // tries to split the cookie around "="
-    fn to_kv(cook: &str) -> (String, String) {
+    let to_kv = |cook: &str| -> (String, String) {
         match cook.splitn(2, '=').collect_tuple() {
-            Some((k, v)) => (k.to_string(), v.to_string()),
-            None => (cook.to_string(), String::new()),
+            Some((k, v)) => {
+                let v = v.trim();
+                let v = match v.strip_prefix('"').and_then(|q| q.strip_suffix('"')) {
+                    Some(unquoted) if dequote => unquoted,
+                    _ => v,
+                };
+                (k.trim().to_string(), v.to_string())
+            }
+            None => (cook.trim().to_string(), String::new()),
+        }
+    };
+    for (k, v) in cookie.split(';').filter(|c| !c.trim().is_empty()).map(to_kv) {
+        if cookies.truncated {
+            break;
         }
-    }
-    for (k, v) in cookie.split("; ").map(to_kv) {
         let loc = Location::CookieValue(k.clone(), v.clone());
         cookies.add(k, loc, v);
     }
 }
 
+/// headers that are kept first when max_parsed_headers is reached, along with the headers the session is computed
+/// from, the other headers are then kept in name order
+const PRIORITY_HEADERS: &[&str] = &[
+    "host",
+    "user-agent",
+    "content-type",
+    "content-length",
+    "referer",
+    "origin",
+    "authorization",
+    "x-forwarded-for",
+];
+
+fn is_priority_header(secpolicy: &SecurityPolicy, name: &str) -> bool {
+    PRIORITY_HEADERS.contains(&name)
+        || secpolicy
+            .session
+            .iter()
+            .chain(secpolicy.session_ids.iter())
+            .any(|s| matches!(s, RequestSelector::Header(h) if h == name))
+}
+
 /// Parse raw headers and:
 /// * lowercase the header name
 /// * extract cookies
 ///
 /// Returns (headers, cookies)
-pub fn map_headers(dec: &[Transformation], rawheaders: &HashMap<String, String>) -> (RequestField, RequestField) {
-    let mut cookies = RequestField::new(dec);
-    let mut headers = RequestField::new(dec);
-    for (k, v) in rawheaders {
-        let lk = k.to_lowercase();
+pub fn map_headers(secpolicy: &SecurityPolicy, rawheaders: &HashMap<String, String>) -> (RequestField, RequestField) {
+    let cf_profile = &secpolicy.content_filter_profile;
+    let dec = &cf_profile.decoding;
+    let mut cookies = RequestField::with_max_entries(dec, cf_profile.max_parsed_cookies);
+    let mut headers = RequestField::with_max_entries(dec, cf_profile.max_parsed_headers);
+    // sorted, so that headers sharing a normalized name are always joined in the same order
+    let mut sorted: Vec<(&String, &String)> = rawheaders.iter().collect();
+    sorted.sort();
+    let mut joined: BTreeMap<String, String> = BTreeMap::new();
+    for (k, v) in sorted {
+        let lk = normalize_header_name(k);
         if lk == "cookie" {
-            cookie_map(&mut cookies, v);
+            cookie_map(&mut cookies, v, *COOKIE_DEQUOTE);
         } else {
-            let loc = Location::HeaderValue(lk.clone(), v.clone());
-            headers.add(lk, loc, v.clone());
+            joined
+                .entry(lk)
+                .and_modify(|jv| {
+                    jv.push_str(&cf_profile.header_join_separator);
+                    jv.push_str(v);
+                })
+                .or_insert_with(|| v.clone());
+        }
+    }
+    let (priority, others): (Vec<_>, Vec<_>) = joined
+        .into_iter()
+        .partition(|(lk, _)| is_priority_header(secpolicy, lk));
+    for (lk, v) in priority.into_iter().chain(others) {
+        if headers.truncated {
+            break;
         }
+        let loc = Location::HeaderValue(lk.clone(), v.clone());
+        headers.add(lk, loc, v);
     }
 
     (headers, cookies)
 }
 
+/// header names are matched case insensitively, request headers and configured header names are normalized this way
+pub fn normalize_header_name(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// a websocket handshake, the connection header lists the upgrade token and the upgrade header the websocket protocol
+///
+/// a handshake is a GET request without a body, so that these headers can't be used to skip the body inspection of
+/// other requests
+pub fn is_websocket_upgrade(method: &str, headers: &RequestField, mbody: Option<&[u8]>) -> bool {
+    let lists = |name: &str, token: &str| {
+        headers
+            .get_str(name)
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    let no_body = mbody.map(|b| b.is_empty()).unwrap_or(true)
+        && headers
+            .get_str("content-length")
+            .map(|v| v.trim() == "0")
+            .unwrap_or(true);
+    method == "GET" && no_body && lists("connection", "upgrade") && lists("upgrade", "websocket")
+}
+
 #[derive(Debug, Clone, Copy)]
 enum ParseUriMode {
     Uri,
@@ -141,6 +306,7 @@ fn parse_uri(
 
 /// parses the request uri, storing the path and query parts (if possible)
 /// returns the hashmap of arguments
+#[allow(clippy::too_many_arguments)]
 fn map_args(
     logs: &mut Logs,
     dec: &[Transformation],
@@ -149,44 +315,67 @@ fn map_args(
     accepted_types: &[ContentType],
     mbody: Option<&[u8]>,
     max_depth: usize,
+    max_args: Option<usize>,
+    form_limits: FormLimits,
 ) -> QueryInfo {
     // this is necessary to do this in this convoluted way so at not to borrow attrs
     let uri = match urldecode_str(path) {
         DecodingResult::NoChange => path.to_string(),
         DecodingResult::Changed(nuri) => nuri,
     };
-    let mut args = RequestField::new(dec);
+    let mut args = RequestField::with_max_entries(dec, max_args);
     let mut path_as_map = RequestField::new(dec);
     let (qpath, query) = parse_uri(&mut args, &mut path_as_map, path, ParseUriMode::Uri);
+    let decoded_path = match urldecode_str(&qpath) {
+        DecodingResult::NoChange => qpath.clone(),
+        DecodingResult::Changed(npath) => npath,
+    };
     logs.debug("uri parsed");
 
-    let body_decoding = if let Some(body) = mbody {
+    let body_start = Instant::now();
+    let (body_decoding, form) = if let Some(body) = mbody {
         logs.debug("body parsing start");
-        if let Err(rr) = parse_body(logs, &mut args, max_depth, mcontent_type, accepted_types, body) {
-            // if the body could not be parsed, store it in an argument, as if it was text
-            args.add(
-                "RAW_BODY".to_string(),
-                Location::Body,
-                String::from_utf8_lossy(body).to_string(),
-            );
-            logs.debug(|| format!("body parsing failed: {}", rr));
-            BodyDecodingResult::DecodingFailed(rr)
-        } else {
-            logs.debug("body parsing succeeded");
-            BodyDecodingResult::ProperlyDecoded
+        match parse_body(
+            logs,
+            &mut args,
+            max_depth,
+            mcontent_type,
+            accepted_types,
+            form_limits,
+            body,
+        ) {
+            Err(rr) => {
+                // if the body could not be parsed, store it in an argument, as if it was text
+                args.add(
+                    "RAW_BODY".to_string(),
+                    Location::Body,
+                    String::from_utf8_lossy(body).to_string(),
+                );
+                logs.debug(|| format!("body parsing failed: {}", rr));
+                (BodyDecodingResult::DecodingFailed(rr), None)
+            }
+            Ok(form) => {
+                logs.debug("body parsing succeeded");
+                (BodyDecodingResult::ProperlyDecoded, form)
+            }
         }
     } else {
         logs.debug("no body to parse");
-        BodyDecodingResult::NoBody
+        (BodyDecodingResult::NoBody, None)
     };
 
+    let body_parse_micros = mbody.map(|_| body_start.elapsed().as_micros() as u64);
+
     QueryInfo {
         qpath,
+        decoded_path,
         query,
         uri,
         args,
         path_as_map,
         body_decoding,
+        body_parse_micros,
+        form,
     }
 }
 
@@ -195,6 +384,8 @@ fn map_args(
 pub struct QueryInfo {
     /// the "path" portion of the raw query path
     pub qpath: String,
+    /// URL decoded "path" portion, the same as qpath when decoding did not change it
+    pub decoded_path: String,
     /// the "query" portion of the raw query path
     pub query: String,
     /// URL decoded path, if decoding worked
@@ -202,6 +393,22 @@ pub struct QueryInfo {
     pub args: RequestField,
     pub path_as_map: RequestField,
     pub body_decoding: BodyDecodingResult,
+    /// time spent parsing the body, when there was one
+    pub body_parse_micros: Option<u64>,
+    /// fields of multipart/form-data bodies
+    pub form: Option<FormFields>,
+}
+
+impl QueryInfo {
+    /// all the values of a query argument, as `args` only keeps a single value per key
+    pub fn arg_values(&self, key: &str) -> Vec<String> {
+        urlencoded_values(&self.query, key)
+    }
+
+    /// query arguments written without a value, `a=` is not valueless
+    pub fn valueless_args(&self) -> Vec<String> {
+        valueless_params(&self.query)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -220,6 +427,9 @@ pub struct GeoIp {
     pub in_eu: Option<bool>,
     pub region: Option<String>,
     pub subregion: Option<String>,
+    // ISO 3166-2 subdivision codes, without the country prefix
+    pub region_iso: Option<String>,
+    pub subregion_iso: Option<String>,
     pub city_name: Option<String>,
 
     // Company informations
@@ -296,6 +506,8 @@ impl GeoIp {
         out.insert("company", json!(self.company));
         out.insert("region", json!(self.region));
         out.insert("subregion", json!(self.subregion));
+        out.insert("region_iso", json!(self.region_iso));
+        out.insert("subregion_iso", json!(self.subregion_iso));
         out.insert("is_anon", json!(self.is_proxy));
         out.insert("is_sat", json!(self.is_satellite));
 
@@ -348,12 +560,47 @@ pub struct RequestInfo {
     pub headers: RequestField,
     pub rinfo: RInfo,
     pub session: String,
+    /// the value the session is computed from, matched by the session global filter entries, it is never logged
+    pub session_source: String,
     pub session_ids: HashMap<String, String>,
     pub plugins: RequestField,
     pub identity: HashMap<String, String>,
+    pub client_cert: Option<ClientCert>,
+    pub content_length: Option<usize>,
+    /// host of the Origin header, or of the Referer header when there is no valid Origin
+    pub origin_host: Option<String>,
+    /// header values and query arguments that are not properly encoded
+    pub encoding_invalid: HashSet<Location>,
+    /// claims of the bearer JWT
+    pub jwt_claims: RequestField,
+    /// the called method, for gRPC requests
+    pub grpc: Option<grpc::GrpcInfo>,
+    /// random value, generated once per request, for the CSP nonces of the block pages
+    pub nonce: String,
+    /// the method announced in a method override header
+    pub method_override: Option<MethodOverride>,
+    /// durations of the mapping steps, for profiling
+    pub durations: MappingDurations,
+    /// shapes of the request values, only recorded in learning mode
+    pub observations: Option<Observations>,
+    /// the uri is longer than the max_uri_length of the content filter profile
+    pub uri_too_long: bool,
+    /// the body is larger than the max_body_size of the content filter profile
+    pub body_too_large: bool,
+    /// an OPTIONS request announcing the method of the actual request, as sent by browsers before CORS requests
+    pub cors_preflight: bool,
+    /// a websocket handshake, its body is never inspected
+    pub websocket_upgrade: bool,
+    /// named groups captured by the regexes of the matching global filters, the first capture of a name is kept
+    pub captures: HashMap<String, String>,
 }
 
 impl RequestInfo {
+    /// true when there was a body, but it could not be parsed
+    pub fn body_parse_failed(&self) -> bool {
+        matches!(self.rinfo.qinfo.body_decoding, BodyDecodingResult::DecodingFailed(_))
+    }
+
     pub fn into_json(self, tags: Tags) -> serde_json::Value {
         let mut v = self.into_json_notags();
         if let Some(m) = v.as_object_mut() {
@@ -439,8 +686,10 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
     let get_name = |mmap: &Option<std::collections::BTreeMap<&str, &str>>| {
         mmap.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase())
     };
+    // the same databases are used for all the lookups, even if they are reloaded meanwhile
+    let db = maxmind_db();
 
-    if let Ok((asninfo, _)) = get_maxmind_asn(ip) {
+    if let Ok((asninfo, _)) = get_maxmind_asn(&db, ip) {
         geoip.asn = asninfo.autonomous_system_number;
         geoip.company = asninfo.autonomous_system_organization.map(|s| s.to_string());
     }
@@ -468,14 +717,14 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         }
     };
 
-    if let Ok((cnty, network)) = get_maxmind_country(ip) {
+    if let Ok((cnty, network)) = get_maxmind_country(&db, ip) {
         extract_continent(geoip, cnty.continent);
         extract_country(geoip, cnty.country);
         extract_network(geoip, network);
         extract_mm_traits(geoip, cnty.traits);
     }
 
-    if let Ok((cty, network)) = get_maxmind_city(ip) {
+    if let Ok((cty, network)) = get_maxmind_city(&db, ip) {
         extract_continent(geoip, cty.continent);
         extract_country(geoip, cty.country);
         extract_network(geoip, network);
@@ -487,10 +736,15 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         if let Some(subs) = cty.subdivisions {
             match &subs[..] {
                 [] => (),
-                [region] => geoip.region = get_name(&region.names),
+                [region] => {
+                    geoip.region = get_name(&region.names);
+                    geoip.region_iso = region.iso_code.map(|s| s.to_string());
+                }
                 [region, subregion] => {
                     geoip.region = region.iso_code.map(|s| s.to_string());
                     geoip.subregion = subregion.iso_code.map(|s| s.to_string());
+                    geoip.region_iso = geoip.region.clone();
+                    geoip.subregion_iso = geoip.subregion.clone();
                 }
                 _ => logs.error(|| format!("Too many subdivisions were reported for {}", ip)),
             }
@@ -511,7 +765,9 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
 
     let extract_network = |g: &mut GeoIp, network: Option<IpNet>| g.network = network.map(|n| format!("{}", n.trunc()));
 
-    if let Ok((loc, network)) = get_ipinfo_location(ip) {
+    // the same databases are used for all the lookups, even if they are reloaded meanwhile
+    let db = ipinfo_db();
+    if let Ok((loc, network)) = get_ipinfo_location(&db, ip) {
         extract_network(geoip, network);
         geoip.city_name = Some(loc.city);
         geoip.country_name = ipinfo_resolve_country_name(loc.country.as_str());
@@ -522,13 +778,14 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         }
         geoip.country_iso = Some(loc.country);
         geoip.region = Some(loc.region);
+        geoip.region_iso = extract_string(loc.region_code);
         geoip.subregion = loc.postal_code; // TODO: this is not the exact same behaviour as maxmind
         if let (Ok(lat), Ok(lng)) = (loc.lat.parse(), loc.lng.parse()) {
             geoip.location = Some((lat, lng))
         };
     }
 
-    if let Ok((privacy, _)) = get_ipinfo_privacy(ip) {
+    if let Ok((privacy, _)) = get_ipinfo_privacy(&db, ip) {
         geoip.is_vpn = privacy.vpn.parse().ok();
         geoip.is_proxy = privacy.proxy.parse().ok();
         geoip.is_tor = privacy.tor.parse().ok();
@@ -543,7 +800,7 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         geoip.is_hosting = Some(false);
     }
 
-    if let Ok((company, network)) = get_ipinfo_company(ip) {
+    if let Ok((company, network)) = get_ipinfo_company(&db, ip) {
         extract_network(geoip, network);
         geoip.company = extract_string(company.name);
         geoip.company_country = extract_string(company.country);
@@ -556,7 +813,7 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         geoip.as_type = extract_string(company.as_type);
     }
 
-    if let Ok((carrier, _)) = get_ipinfo_carrier(ip) {
+    if let Ok((carrier, _)) = get_ipinfo_carrier(&db, ip) {
         geoip.is_mobile = Some(true);
         geoip.mobile_carrier_name = extract_string(carrier.carrier);
         geoip.mobile_country = extract_string(carrier.country_code);
@@ -567,7 +824,7 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         geoip.network = Some(carrier.network)
     }
 
-    if let Ok((asn, _)) = get_ipinfo_asn(ip) {
+    if let Ok((asn, _)) = get_ipinfo_asn(&db, ip) {
         // TODO: always get Err here, should be fixed
         geoip.network = Some(asn.route);
         geoip.asn = asn.asn.parse().ok();
@@ -577,6 +834,26 @@ pub fn find_geoip_ipinfo(_logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
     }
 }
 
+/// parses an x-forwarded-for entry, that can be an IPv4 or IPv6 address, with a port, IPv6 addresses being bracketed
+/// when there is a port
+pub fn parse_xff_entry(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    if let Ok(ip) = entry.parse() {
+        return Some(ip);
+    }
+    if let Some(bracketed) = entry.strip_prefix('[') {
+        let (ip, rest) = bracketed.split_once(']')?;
+        if !rest.is_empty() {
+            rest.strip_prefix(':')?.parse::<u16>().ok()?;
+        }
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    // only IPv4 addresses can have an unbracketed port
+    let (ip, port) = entry.split_once(':')?;
+    port.parse::<u16>().ok()?;
+    ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
 pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
     let pip = ipstr.trim().parse();
     let mut geoip = GeoIp {
@@ -591,6 +868,8 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
         continent_code: None,
         region: None,
         subregion: None,
+        region_iso: None,
+        subregion_iso: None,
         network: None,
         company: None,
         company_country: None,
@@ -661,9 +940,40 @@ pub fn map_request(
 
     logs.debug("map_request starts");
     logs.info(|| format!("decoding {:?}", &secpolicy.content_filter_profile.decoding));
-    let (headers, cookies) = map_headers(&secpolicy.content_filter_profile.decoding, &raw.headers);
+    let (headers, cookies) = map_headers(&secpolicy, &raw.headers);
+    if headers.truncated {
+        logs.warning(|| format!("too many headers, only the first {} were parsed", headers.len()));
+    }
+    if cookies.truncated {
+        logs.warning(|| format!("too many cookies, only the first {} were parsed", cookies.len()));
+    }
     logs.debug("headers mapped");
+    // checked on the raw method, a method override does not make a request a preflight
+    let cors_preflight = raw.meta.method == "OPTIONS" && headers.get("access-control-request-method").is_some();
+    let websocket_upgrade = is_websocket_upgrade(&raw.meta.method, &headers, raw.mbody);
+    let cf_profile = &secpolicy.content_filter_profile;
+    let body_too_large = !cf_profile.ignore_body && matches!(raw.mbody, Some(b) if b.len() > cf_profile.max_body_size);
+    let inspected_body = if cf_profile.ignore_body || websocket_upgrade {
+        None
+    } else if body_too_large {
+        logs.warning(|| {
+            format!(
+                "body is larger than the limit of {} bytes, applying the {:?} policy",
+                cf_profile.max_body_size, cf_profile.oversized_body
+            )
+        });
+        match cf_profile.oversized_body {
+            OversizedBodyPolicy::InspectTruncated => raw.mbody.map(|b| &b[..cf_profile.max_body_size]),
+            OversizedBodyPolicy::Pass => None,
+            // the request gets blocked anyway
+            OversizedBodyPolicy::BlockUnverified => raw.mbody,
+        }
+    } else {
+        raw.mbody
+    };
+    let geo_start = Instant::now();
     let geoip = find_geoip(logs, raw.ipstr.clone());
+    let geo_micros = geo_start.elapsed().as_micros() as u64;
     logs.debug("geoip computed");
     let mut qinfo = map_args(
         logs,
@@ -671,13 +981,18 @@ pub fn map_request(
         &raw.meta.path,
         headers.get_str("content-type"),
         &secpolicy.content_filter_profile.content_type,
-        if secpolicy.content_filter_profile.ignore_body {
-            None
-        } else {
-            raw.mbody
-        },
+        inspected_body,
         secpolicy.content_filter_profile.max_body_depth,
+        secpolicy.content_filter_profile.max_parsed_args,
+        secpolicy.content_filter_profile.form_limits,
     );
+    if qinfo.args.truncated {
+        logs.warning(|| format!("too many arguments, only the first {} were parsed", qinfo.args.len()));
+    }
+    // the fields of a truncated form are incomplete
+    if body_too_large {
+        qinfo.form = None;
+    }
     if secpolicy.content_filter_profile.referer_as_uri {
         if let Some(rf) = headers.get("referer") {
             parse_uri(
@@ -689,6 +1004,16 @@ pub fn map_request(
         }
     }
     logs.debug("args mapped");
+    let uri_too_long = qinfo.uri.len() > secpolicy.content_filter_profile.max_uri_length;
+    if uri_too_long {
+        logs.warning(|| {
+            format!(
+                "uri is {} bytes long, above the limit of {}",
+                qinfo.uri.len(),
+                secpolicy.content_filter_profile.max_uri_length
+            )
+        });
+    }
 
     let rinfo = RInfo {
         meta: raw.meta.clone(),
@@ -711,11 +1036,28 @@ pub fn map_request(
         headers,
         rinfo,
         session: String::new(),
+        session_source: String::new(),
         session_ids: HashMap::new(),
         plugins: plugins_field,
         identity: HashMap::new(),
+        client_cert: None,
+        content_length: None,
+        origin_host: None,
+        encoding_invalid: HashSet::new(),
+        jwt_claims: RequestField::new(&[]),
+        grpc: None,
+        nonce: String::new(),
+        method_override: None,
+        durations: MappingDurations::default(),
+        observations: None,
+        uri_too_long,
+        body_too_large,
+        cors_preflight,
+        websocket_upgrade,
+        captures: HashMap::new(),
     };
 
+    // session sources are tried in order, the first non empty one is used
     let raw_session = (if secpolicy.session.is_empty() {
         &[RequestSelector::Ip]
     } else {
@@ -723,8 +1065,7 @@ pub fn map_request(
     })
     .iter()
     .filter_map(|s| select_string(&dummy_reqinfo, s, None))
-    .next()
-    .unwrap_or_else(|| "???".to_string());
+    .find(|s| !s.is_empty());
 
     let session_string = |s: &str| {
         let mut hasher = Sha224::new();
@@ -734,24 +1075,271 @@ pub fn map_request(
         format!("{:x}", bytes)
     };
 
-    let session = session_string(&raw_session);
+    let session = session_string(raw_session.as_deref().unwrap_or("???"));
+    let session_source = raw_session.unwrap_or_default();
     let session_ids = secpolicy
         .session_ids
         .iter()
         .filter_map(|s| select_string(&dummy_reqinfo, s, None).map(|str| (s.to_string(), session_string(&str))))
         .collect();
 
+    let client_cert = dummy_reqinfo
+        .headers
+        .get_str(&CLIENT_CERT_HEADER)
+        .map(parse_client_cert);
+    let content_length = content_length(logs, &dummy_reqinfo.headers, raw.mbody);
+    let origin_host = origin_host(&dummy_reqinfo.headers);
+    let encoding_invalid = encoding_errors(raw);
+    let jwt_claims = jwt::jwt_claims(&dummy_reqinfo.headers, secpolicy.settings.jwt_hs256_secret.as_deref());
+    let grpc = grpc::grpc_info(&dummy_reqinfo.headers, &dummy_reqinfo.rinfo.qinfo.qpath);
+    let method_override = method_override(&dummy_reqinfo.headers, &secpolicy.settings.method_override_headers);
+    let durations = MappingDurations {
+        body_parse: dummy_reqinfo.rinfo.qinfo.body_parse_micros,
+        geo: geo_micros,
+        body_bytes: match raw.mbody {
+            Some(body) if !secpolicy.content_filter_profile.ignore_body => body.len(),
+            _ => 0,
+        },
+    };
+    let mut rinfo = dummy_reqinfo.rinfo;
+    if let Some(mo) = &method_override {
+        logs.info(|| format!("method overridden to {} by the {} header", mo.method, mo.header));
+        if secpolicy.settings.apply_method_override {
+            rinfo.meta.method = mo.method.clone();
+        }
+    }
+
     // logs.debug(|| format!("MAP headers {:?}", dummy_reqinfo.headers));
 
-    RequestInfo {
+    let mut reqinfo = RequestInfo {
         timestamp: dummy_reqinfo.timestamp,
         cookies: dummy_reqinfo.cookies,
         headers: dummy_reqinfo.headers,
-        rinfo: dummy_reqinfo.rinfo,
+        rinfo,
         session,
+        session_source,
         session_ids,
         plugins: dummy_reqinfo.plugins,
         identity: dummy_reqinfo.identity,
+        client_cert,
+        content_length,
+        origin_host,
+        encoding_invalid,
+        jwt_claims,
+        grpc,
+        nonce: request_nonce(),
+        method_override,
+        durations,
+        observations: None,
+        uri_too_long,
+        body_too_large,
+        cors_preflight,
+        websocket_upgrade,
+        captures: HashMap::new(),
+    };
+    if let Ok(hooks) = plugins::PLUGIN_HOOKS.read() {
+        plugins::apply_plugin_hooks(&hooks, &mut reqinfo);
+    }
+    // recorded before the values get masked
+    if reqinfo.rinfo.secpolicy.learning_mode {
+        reqinfo.observations = Some(Observations::new(
+            &reqinfo.rinfo.qinfo.qpath,
+            &reqinfo.rinfo.meta.method,
+            &[
+                ("args", &reqinfo.rinfo.qinfo.args),
+                ("headers", &reqinfo.headers),
+                ("cookies", &reqinfo.cookies),
+            ],
+            *LEARNING_MAX_FIELDS,
+        ));
+    }
+    reqinfo
+}
+
+/// builds a RequestInfo from a few values, mostly useful for testing filters
+///
+/// The request is mapped with `map_request`, as in production. It comes from 127.0.0.1 and has no body unless
+/// specified otherwise, and is evaluated against the default security policy.
+///
+/// ```
+/// use curiefense::config::globalfilter::{GlobalFilterEntry, GlobalFilterEntryE, PairEntry};
+/// use curiefense::config::virtualtags::VirtualTags;
+/// use curiefense::interface::Tags;
+/// use curiefense::tagging::check_entry;
+/// use curiefense::utils::RequestBuilder;
+///
+/// let rinfo = RequestBuilder::new("GET", "/login?next=/")
+///     .header("User-Agent", "curl/7.58.0")
+///     .build();
+/// let entry = GlobalFilterEntry {
+///     negated: false,
+///     entry: GlobalFilterEntryE::Header(PairEntry {
+///         key: "user-agent".to_string(),
+///         exact: "curl/7.58.0".to_string(),
+///         re: None,
+///     }),
+/// };
+/// assert!(check_entry(&rinfo, &Tags::new(&VirtualTags::default()), &entry).matching);
+/// assert_eq!(rinfo.rinfo.qinfo.qpath, "/login");
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    ip: String,
+    authority: Option<String>,
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Option<Vec<u8>>,
+    requestid: Option<String>,
+    secpolicy: Arc<SecurityPolicy>,
+    plugins: HashMap<String, String>,
+}
+
+impl RequestBuilder {
+    pub fn new(method: &str, path: &str) -> Self {
+        RequestBuilder {
+            ip: "127.0.0.1".to_string(),
+            authority: None,
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: HashMap::new(),
+            body: None,
+            requestid: None,
+            secpolicy: Arc::new(SecurityPolicy::default()),
+            plugins: HashMap::new(),
+        }
+    }
+
+    pub fn ip(mut self, ip: &str) -> Self {
+        self.ip = ip.to_string();
+        self
+    }
+
+    pub fn authority(mut self, authority: &str) -> Self {
+        self.authority = Some(authority.to_string());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = Some(body.to_vec());
+        self
+    }
+
+    pub fn requestid(mut self, requestid: &str) -> Self {
+        self.requestid = Some(requestid.to_string());
+        self
+    }
+
+    pub fn security_policy(mut self, secpolicy: Arc<SecurityPolicy>) -> Self {
+        self.secpolicy = secpolicy;
+        self
+    }
+
+    pub fn plugin(mut self, name: &str, value: &str) -> Self {
+        self.plugins.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn build(self) -> RequestInfo {
+        let mut logs = Logs::default();
+        let raw = RawRequest {
+            ipstr: self.ip,
+            headers: self.headers,
+            meta: RequestMeta {
+                authority: self.authority,
+                method: self.method,
+                path: self.path,
+                requestid: self.requestid,
+                extra: HashMap::new(),
+            },
+            mbody: self.body.as_deref(),
+        };
+        map_request(&mut logs, self.secpolicy, None, &raw, None, self.plugins)
+    }
+}
+
+/// 128 random bits, hex encoded
+pub fn request_nonce() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// locations of the header values and query arguments that are not properly encoded
+///
+/// header values are not url decoded, they are only checked for non utf8 bytes
+pub fn encoding_errors(raw: &RawRequest) -> HashSet<Location> {
+    let mut out = HashSet::new();
+    for (k, v) in raw.headers.iter() {
+        if v.contains(char::REPLACEMENT_CHARACTER) {
+            out.insert(Location::HeaderValue(k.to_ascii_lowercase(), v.clone()));
+        }
+    }
+    if let Some((_, query)) = raw.meta.path.splitn(2, '?').collect_tuple() {
+        for (k, v) in invalid_urlencoded_params(query) {
+            out.insert(Location::UriArgumentValue(k, v));
+        }
+    }
+    out
+}
+
+/// the method announced by the first non empty override header
+pub fn method_override(headers: &RequestField, names: &[String]) -> Option<MethodOverride> {
+    names.iter().find_map(|h| {
+        headers
+            .get_str(h)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| MethodOverride {
+                header: h.clone(),
+                method: v.to_ascii_uppercase(),
+            })
+    })
+}
+
+/// the lowercased host of the Origin header, falling back to the Referer header
+pub fn origin_host(headers: &RequestField) -> Option<String> {
+    ["origin", "referer"]
+        .iter()
+        .filter_map(|h| headers.get_str(h))
+        .find_map(url::url_host)
+        .map(|h| h.to_ascii_lowercase())
+}
+
+/// the content length of the request, the actual body length is preferred over the content-length header
+pub fn content_length(logs: &mut Logs, headers: &RequestField, mbody: Option<&[u8]>) -> Option<usize> {
+    let announced = headers
+        .get_str("content-length")
+        .and_then(|s| s.trim().parse::<usize>().ok());
+    match (mbody.map(|b| b.len()), announced) {
+        (Some(actual), Some(announced)) if actual != announced => {
+            logs.warning(|| {
+                format!(
+                    "content-length header ({}) does not match the body length ({})",
+                    announced, actual
+                )
+            });
+            Some(actual)
+        }
+        (Some(actual), _) => Some(actual),
+        (None, announced) => announced,
+    }
+}
+
+/// buckets the content length, to keep the tag cardinality low
+pub fn content_length_bucket(len: usize) -> &'static str {
+    match len {
+        0 => "0",
+        1..=1023 => "1-1k",
+        1024..=10239 => "1k-10k",
+        10240..=102399 => "10k-100k",
+        102400..=1048575 => "100k-1m",
+        1048576..=10485759 => "1m-10m",
+        _ => "10m-plus",
     }
 }
 
@@ -771,6 +1359,21 @@ pub fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: Optio
         RequestSelector::Header(k) => reqinfo.headers.get(k).map(Selected::Str),
         RequestSelector::Cookie(k) => reqinfo.cookies.get(k).map(Selected::Str),
         RequestSelector::Plugins(k) => reqinfo.plugins.get(k).map(Selected::Str),
+        RequestSelector::Jwt(k) => reqinfo.jwt_claims.get(k).map(Selected::Str),
+        RequestSelector::FormField(k) => reqinfo
+            .rinfo
+            .qinfo
+            .form
+            .as_ref()
+            .and_then(|f| f.values.get(k))
+            .map(Selected::Str),
+        RequestSelector::FormFilename(k) => reqinfo
+            .rinfo
+            .qinfo
+            .form
+            .as_ref()
+            .and_then(|f| f.filenames.get(k))
+            .map(Selected::Str),
         RequestSelector::Ip => Some(&reqinfo.rinfo.geoip.ipstr).map(Selected::Str),
         RequestSelector::Network => reqinfo.rinfo.geoip.network.as_ref().map(Selected::Str),
         RequestSelector::Uri => Some(&reqinfo.rinfo.qinfo.uri).map(Selected::Str),
@@ -795,6 +1398,12 @@ pub fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: Optio
         RequestSelector::Region => reqinfo.rinfo.geoip.region.as_ref().map(Selected::Str),
         RequestSelector::SubRegion => reqinfo.rinfo.geoip.subregion.as_ref().map(Selected::Str),
         RequestSelector::Session => Some(Selected::Str(&reqinfo.session)),
+        RequestSelector::ContentLength => reqinfo
+            .content_length
+            .map(|l| Selected::U32(u32::try_from(l).unwrap_or(u32::MAX))),
+        RequestSelector::GrpcService => reqinfo.grpc.as_ref().map(|g| Selected::Str(&g.service)),
+        RequestSelector::GrpcMethod => reqinfo.grpc.as_ref().map(|g| Selected::OStr(g.full_method())),
+        RequestSelector::Nonce => Some(Selected::Str(&reqinfo.nonce)),
     }
 }
 
@@ -806,6 +1415,22 @@ pub fn select_string(reqinfo: &RequestInfo, sel: &RequestSelector, tags: Option<
     })
 }
 
+/// all the values of a selector, for the fields that can be repeated
+///
+/// Query arguments are read from the raw query, as `args` only keeps a single value per key, and headers are split on
+/// commas, as proxies join repeated headers. Other selectors yield at most one value.
+pub fn select_values(reqinfo: &RequestInfo, sel: &RequestSelector, tags: Option<&Tags>) -> Vec<String> {
+    match sel {
+        RequestSelector::Args(k) => reqinfo.rinfo.qinfo.arg_values(k),
+        RequestSelector::Header(k) => reqinfo
+            .headers
+            .get(k)
+            .map(|v| v.split(',').map(|p| p.trim().to_string()).collect())
+            .unwrap_or_default(),
+        _ => select_string(reqinfo, sel, tags).into_iter().collect(),
+    }
+}
+
 pub fn check_selector_cond(reqinfo: &RequestInfo, tags: &Tags, sel: &RequestSelectorCondition) -> bool {
     match sel {
         RequestSelectorCondition::Tag(t) => tags.contains(t),
@@ -852,9 +1477,12 @@ mod tests {
             &[],
             None,
             500,
+            None,
+            FormLimits::default(),
         );
 
         assert_eq!(qinfo.qpath, "/a/b/%20c");
+        assert_eq!(qinfo.decoded_path, "/a/b/ c");
         assert_eq!(qinfo.uri, "/a/b/ c?xa =12&bbbb=12(&cccc&b64=YXJndW1lbnQ=");
         assert_eq!(qinfo.query, "xa%20=12&bbbb=12%28&cccc&b64=YXJndW1lbnQ%3D");
 
@@ -897,9 +1525,20 @@ mod tests {
     #[test]
     fn test_map_args_simple() {
         let mut logs = Logs::default();
-        let qinfo = map_args(&mut logs, &[], "/a/b", None, &[], None, 500);
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            "/a/b",
+            None,
+            &[],
+            None,
+            500,
+            None,
+            FormLimits::default(),
+        );
 
         assert_eq!(qinfo.qpath, "/a/b");
+        assert_eq!(qinfo.decoded_path, "/a/b");
         assert_eq!(qinfo.uri, "/a/b");
         assert_eq!(qinfo.query, "");
 
@@ -960,4 +1599,507 @@ mod tests {
         assert_eq!(expected_args, actual_args);
         assert_eq!(expected_path, actual_path);
     }
+
+    #[test]
+    fn client_cert_xfcc() {
+        let cert = parse_client_cert(
+            "By=spiffe://cluster.local/ns/foo/sa/bar;Hash=468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688;Subject=\"CN=client,O=Acme\";URI=spiffe://cluster.local/ns/foo/sa/client;DNS=client.acme.com",
+        );
+        assert_eq!(
+            cert,
+            ClientCert {
+                subject: Some("CN=client,O=Acme".to_string()),
+                san: vec![
+                    "spiffe://cluster.local/ns/foo/sa/client".to_string(),
+                    "client.acme.com".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn client_cert_raw_subject() {
+        let cert = parse_client_cert("CN=client,O=Acme");
+        assert_eq!(cert.subject.as_deref(), Some("CN=client,O=Acme"));
+        assert!(cert.san.is_empty());
+    }
+
+    fn session_request(headers: &[(&str, &str)]) -> RawRequest<'static> {
+        RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            meta: RequestMeta {
+                authority: Some("main.site".to_string()),
+                method: "GET".to_string(),
+                path: "/".to_string(),
+                requestid: None,
+                extra: HashMap::new(),
+            },
+            mbody: None,
+        }
+    }
+
+    fn session_secpol() -> SecurityPolicy {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.session = vec![
+            RequestSelector::Cookie("sid".to_string()),
+            RequestSelector::Header("x-session".to_string()),
+        ];
+        secpol
+    }
+
+    fn expected_session(secpol: &SecurityPolicy, raw: &str) -> String {
+        let mut hasher = Sha224::new();
+        hasher.update(&secpol.content_filter_profile.masking_seed);
+        hasher.update(raw.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    #[test]
+    fn session_from_cookie() {
+        let secpol = session_secpol();
+        let expected = expected_session(&secpol, "cookiesession");
+        let raw = session_request(&[("cookie", "sid=cookiesession"), ("x-session", "headersession")]);
+        let mut logs = Logs::default();
+        let ri = map_request(&mut logs, Arc::new(secpol), None, &raw, None, HashMap::new());
+        assert_eq!(ri.session, expected);
+        assert_eq!(ri.session_source, "cookiesession");
+    }
+
+    #[test]
+    fn session_header_fallback() {
+        let secpol = session_secpol();
+        let expected = expected_session(&secpol, "headersession");
+        let raw = session_request(&[("cookie", "other=value; sid="), ("x-session", "headersession")]);
+        let mut logs = Logs::default();
+        let ri = map_request(&mut logs, Arc::new(secpol), None, &raw, None, HashMap::new());
+        assert_eq!(ri.session, expected);
+        assert_eq!(ri.session_source, "headersession");
+    }
+
+    #[test]
+    fn grpc_request() {
+        let mut raw = session_request(&[("content-type", "application/grpc")]);
+        raw.meta.method = "POST".to_string();
+        raw.meta.path = "/helloworld.Greeter/SayHello".to_string();
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert_eq!(ri.grpc.as_ref().map(|g| g.method.as_str()), Some("SayHello"));
+        assert_eq!(
+            select_string(&ri, &RequestSelector::GrpcService, None),
+            Some("helloworld.Greeter".to_string())
+        );
+        assert_eq!(
+            select_string(&ri, &RequestSelector::GrpcMethod, None),
+            Some("helloworld.Greeter/SayHello".to_string())
+        );
+    }
+
+    #[test]
+    fn non_grpc_request() {
+        let mut raw = session_request(&[("content-type", "application/json")]);
+        raw.meta.path = "/helloworld.Greeter/SayHello".to_string();
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert!(ri.grpc.is_none());
+        assert_eq!(select_string(&ri, &RequestSelector::GrpcMethod, None), None);
+    }
+
+    #[test]
+    fn content_length_buckets() {
+        assert_eq!(content_length_bucket(0), "0");
+        assert_eq!(content_length_bucket(1), "1-1k");
+        assert_eq!(content_length_bucket(1023), "1-1k");
+        assert_eq!(content_length_bucket(1024), "1k-10k");
+        assert_eq!(content_length_bucket(50000), "10k-100k");
+        assert_eq!(content_length_bucket(102400), "100k-1m");
+        assert_eq!(content_length_bucket(2000000), "1m-10m");
+        assert_eq!(content_length_bucket(usize::MAX), "10m-plus");
+    }
+
+    #[test]
+    fn content_length_header_only() {
+        let raw = session_request(&[("content-length", "12")]);
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert_eq!(ri.content_length, Some(12));
+        assert_eq!(
+            select_string(&ri, &RequestSelector::ContentLength, None),
+            Some("12".to_string())
+        );
+    }
+
+    #[test]
+    fn content_length_body_mismatch() {
+        let mut raw = session_request(&[("content-length", "12")]);
+        raw.mbody = Some(b"abc");
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert_eq!(ri.content_length, Some(3));
+        assert!(
+            logs.logs
+                .iter()
+                .any(|l| l.level == crate::logs::LogLevel::Warning
+                    && l.message.contains("does not match the body length"))
+        );
+    }
+
+    #[test]
+    fn encoding_invalid() {
+        let lossy = String::from_utf8_lossy(b"curl\xc3\x28").into_owned();
+        let mut raw = session_request(&[("User-Agent", &lossy), ("accept", "*/*")]);
+        raw.meta.path = "/a?ok=%2F&bad=%C0%AF&trunc=%4".to_string();
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        let expected: HashSet<Location> = vec![
+            Location::HeaderValue("user-agent".to_string(), lossy.clone()),
+            Location::UriArgumentValue("bad".to_string(), "%C0%AF".to_string()),
+            Location::UriArgumentValue("trunc".to_string(), "%4".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(ri.encoding_invalid, expected);
+    }
+
+    #[test]
+    fn encoding_valid() {
+        let mut raw = session_request(&[("user-agent", "curl/7.58.0")]);
+        raw.meta.path = "/a?x=%F0%9F%91%BE&y=a+b".to_string();
+        let mut logs = Logs::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert!(ri.encoding_invalid.is_empty());
+    }
+
+    #[test]
+    fn args_cap() {
+        let mut logs = Logs::default();
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            "/a?a=1&b=2&c=3&d=4",
+            None,
+            &[],
+            None,
+            500,
+            Some(2),
+            FormLimits::default(),
+        );
+        assert!(qinfo.args.truncated);
+        assert_eq!(qinfo.args.len(), 2);
+        assert_eq!(qinfo.args.get_str("a"), Some("1"));
+        assert_eq!(qinfo.args.get_str("b"), Some("2"));
+        assert_eq!(qinfo.args.get_str("c"), None);
+    }
+
+    #[test]
+    fn args_cap_repeated() {
+        let mut logs = Logs::default();
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            "/a?a=1&a=2&b=3",
+            None,
+            &[],
+            None,
+            500,
+            Some(2),
+            FormLimits::default(),
+        );
+        assert!(qinfo.args.truncated);
+        assert_eq!(qinfo.args.get_str("a"), Some("1 2"));
+        assert_eq!(qinfo.args.get_str("b"), None);
+        // repeating a single key is capped as well
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            "/a?a=1&a=2&a=3&a=4",
+            None,
+            &[],
+            None,
+            500,
+            Some(2),
+            FormLimits::default(),
+        );
+        assert!(qinfo.args.truncated);
+        assert_eq!(qinfo.args.get_str("a"), Some("1 2"));
+    }
+
+    fn capped_policy(max_headers: Option<usize>, max_cookies: Option<usize>) -> SecurityPolicy {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.content_filter_profile.max_parsed_headers = max_headers;
+        secpol.content_filter_profile.max_parsed_cookies = max_cookies;
+        secpol
+    }
+
+    #[test]
+    fn headers_cap() {
+        let raw: HashMap<String, String> = (0..10).map(|i| (format!("X-H{}", i), i.to_string())).collect();
+        let (headers, cookies) = map_headers(&capped_policy(Some(3), None), &raw);
+        assert!(headers.truncated);
+        assert_eq!(headers.len(), 3);
+        assert!(!cookies.truncated);
+
+        let (headers, _) = map_headers(&capped_policy(Some(10), None), &raw);
+        assert!(!headers.truncated);
+        assert_eq!(headers.len(), 10);
+    }
+
+    #[test]
+    fn headers_cap_order() {
+        let mut raw: HashMap<String, String> = (0..10).map(|i| (format!("A-H{}", i), i.to_string())).collect();
+        raw.insert("User-Agent".to_string(), "agent".to_string());
+        raw.insert("Host".to_string(), "localhost".to_string());
+        for _ in 0..5 {
+            let (headers, _) = map_headers(&capped_policy(Some(3), None), &raw);
+            assert_eq!(headers.get_str("host"), Some("localhost"));
+            assert_eq!(headers.get_str("user-agent"), Some("agent"));
+            assert_eq!(headers.get_str("a-h0"), Some("0"));
+            assert_eq!(headers.len(), 3);
+        }
+    }
+
+    #[test]
+    fn headers_cap_keeps_identity_headers() {
+        let mut raw: HashMap<String, String> = (0..10).map(|i| (format!("A-H{}", i), i.to_string())).collect();
+        raw.insert("Authorization".to_string(), "Bearer abc".to_string());
+        raw.insert("X-Forwarded-For".to_string(), "1.2.3.4".to_string());
+        raw.insert("X-Session".to_string(), "s1".to_string());
+        raw.insert("X-Tenant".to_string(), "t1".to_string());
+        let mut secpol = capped_policy(Some(4), None);
+        secpol.session = vec![RequestSelector::Header("x-session".to_string())];
+        secpol.session_ids = vec![RequestSelector::Header("x-tenant".to_string())];
+        let (headers, _) = map_headers(&secpol, &raw);
+        assert!(headers.truncated);
+        assert_eq!(headers.get_str("authorization"), Some("Bearer abc"));
+        assert_eq!(headers.get_str("x-forwarded-for"), Some("1.2.3.4"));
+        assert_eq!(headers.get_str("x-session"), Some("s1"));
+        assert_eq!(headers.get_str("x-tenant"), Some("t1"));
+        assert_eq!(headers.get_str("a-h0"), None);
+    }
+
+    #[test]
+    fn cookies_cap() {
+        let mut raw = HashMap::new();
+        raw.insert("Cookie".to_string(), "a=1; b=2; c=3".to_string());
+        raw.insert("accept".to_string(), "*/*".to_string());
+        let (headers, cookies) = map_headers(&capped_policy(Some(1), Some(2)), &raw);
+        assert!(cookies.truncated);
+        assert_eq!(cookies.get_str("a"), Some("1"));
+        assert_eq!(cookies.get_str("b"), Some("2"));
+        assert_eq!(cookies.get_str("c"), None);
+        // the cookie header is not counted as a header
+        assert!(!headers.truncated);
+        assert_eq!(headers.get_str("accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn cookie_normalization() {
+        let parsed = |dequote: bool| {
+            let mut cookies = RequestField::new(&[]);
+            cookie_map(&mut cookies, r#" session = "abc123" ;theme=  dark ;; flag"#, dequote);
+            cookies
+        };
+        let cookies = parsed(true);
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies.get_str("session"), Some("abc123"));
+        assert_eq!(cookies.get_str("theme"), Some("dark"));
+        assert_eq!(cookies.get_str("flag"), Some(""));
+        assert_eq!(parsed(false).get_str("session"), Some("\"abc123\""));
+    }
+    #[test]
+    fn form_limits_from_profile() {
+        let body = b"--xYzZY\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n\
+            --xYzZY\r\nContent-Disposition: form-data; name=\"b\"\r\n\r\n22\r\n\
+            --xYzZY\r\nContent-Disposition: form-data; name=\"c\"\r\n\r\n3\r\n--xYzZY--\r\n";
+        let mut secpol = SecurityPolicy::empty();
+        secpol.content_filter_profile.form_limits = FormLimits {
+            max_fields: 2,
+            max_value_size: 1,
+        };
+        let ri = RequestBuilder::new("POST", "/upload")
+            .header("content-type", "multipart/form-data; boundary=xYzZY")
+            .body(body)
+            .security_policy(Arc::new(secpol))
+            .build();
+        let form = ri.rinfo.qinfo.form.unwrap();
+        assert_eq!(form.parts, 3);
+        assert_eq!(form.values.get_str("a"), Some("1"));
+        // too large
+        assert_eq!(form.values.get_str("b"), None);
+        // over the field limit
+        assert_eq!(form.values.get_str("c"), None);
+        assert_eq!(form.skipped, 2);
+    }
+
+    #[test]
+    fn method_override_from_host_map() {
+        let mk = |secpol: SecurityPolicy| {
+            RequestBuilder::new("POST", "/")
+                .header("x-http-method", "put")
+                .header("x-custom-method", "delete")
+                .security_policy(Arc::new(secpol))
+                .build()
+        };
+        let ri = mk(SecurityPolicy::empty());
+        assert_eq!(ri.method_override.unwrap().method, "PUT");
+        assert_eq!(ri.rinfo.meta.method, "POST");
+
+        let mut secpol = SecurityPolicy::empty();
+        secpol.settings.method_override_headers = vec!["x-custom-method".to_string()];
+        secpol.settings.apply_method_override = true;
+        let ri = mk(secpol);
+        assert_eq!(ri.method_override.unwrap().header, "x-custom-method");
+        assert_eq!(ri.rinfo.meta.method, "DELETE");
+    }
+
+    #[test]
+    fn uri_length_limit() {
+        let too_long = |path: &str| {
+            let mut secpol = SecurityPolicy::default();
+            secpol.content_filter_profile.max_uri_length = 10;
+            let rinfo = RequestBuilder::new("GET", path)
+                .security_policy(Arc::new(secpol))
+                .build();
+            assert_eq!(rinfo.rinfo.qinfo.uri, path);
+            rinfo.uri_too_long
+        };
+        assert!(!too_long("/abc?d=1"));
+        assert!(!too_long("/abcd?e=12"));
+        assert!(too_long("/abcd?e=123"));
+        // no limit by default
+        assert!(
+            !RequestBuilder::new("GET", &format!("/{}", "a".repeat(100_000)))
+                .build()
+                .uri_too_long
+        );
+    }
+
+    #[test]
+    fn websocket_upgrade() {
+        let request = |method: &str, headers: &[(&str, &str)], body: Option<&[u8]>| {
+            let builder = headers
+                .iter()
+                .fold(RequestBuilder::new(method, "/chat"), |b, (k, v)| b.header(k, v))
+                .header("content-type", "application/json");
+            match body {
+                Some(b) => builder.body(b),
+                None => builder,
+            }
+            .build()
+        };
+        let handshake = [("connection", "keep-alive, Upgrade"), ("upgrade", "WebSocket")];
+        let upgrade = request("GET", &handshake, None);
+        assert!(upgrade.websocket_upgrade);
+        assert!(matches!(upgrade.rinfo.qinfo.body_decoding, BodyDecodingResult::NoBody));
+        assert!(request("GET", &handshake, Some(&b""[..])).websocket_upgrade);
+
+        let normal = request("GET", &[("connection", "keep-alive")], None);
+        assert!(!normal.websocket_upgrade);
+
+        // both headers are needed
+        assert!(!request("GET", &[("upgrade", "websocket")], None).websocket_upgrade);
+        assert!(!request("GET", &[("connection", "upgrade"), ("upgrade", "h2c")], None).websocket_upgrade);
+    }
+
+    #[test]
+    fn websocket_headers_body_inspected() {
+        let body: &[u8] = br#"{"message": "hello"}"#;
+        for method in &["POST", "GET"] {
+            let rinfo = RequestBuilder::new(method, "/chat")
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("content-type", "application/json")
+                .body(body)
+                .build();
+            assert!(!rinfo.websocket_upgrade, "{}", method);
+            assert!(matches!(
+                rinfo.rinfo.qinfo.body_decoding,
+                BodyDecodingResult::ProperlyDecoded
+            ));
+            assert_eq!(rinfo.rinfo.qinfo.args.get_str("message"), Some("hello"));
+        }
+        // a content length announces a body
+        let announced = RequestBuilder::new("GET", "/chat")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("content-length", "20")
+            .build();
+        assert!(!announced.websocket_upgrade);
+    }
+
+    #[test]
+    fn oversized_body() {
+        let request = |policy: OversizedBodyPolicy, body: &[u8]| {
+            let mut secpol = SecurityPolicy::default();
+            secpol.content_filter_profile.max_body_size = 7;
+            secpol.content_filter_profile.oversized_body = policy;
+            RequestBuilder::new("POST", "/")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(body)
+                .security_policy(Arc::new(secpol))
+                .build()
+        };
+
+        let truncated = request(OversizedBodyPolicy::InspectTruncated, b"a=1&b=2&c=3");
+        assert!(truncated.body_too_large);
+        assert_eq!(truncated.rinfo.qinfo.args.get_str("a"), Some("1"));
+        assert_eq!(truncated.rinfo.qinfo.args.get_str("b"), Some("2"));
+        assert!(truncated.rinfo.qinfo.args.get("c").is_none());
+
+        let passed = request(OversizedBodyPolicy::Pass, b"a=1&b=2&c=3");
+        assert!(passed.body_too_large);
+        assert!(matches!(passed.rinfo.qinfo.body_decoding, BodyDecodingResult::NoBody));
+        assert!(passed.rinfo.qinfo.args.get("a").is_none());
+
+        let blocked = request(OversizedBodyPolicy::BlockUnverified, b"a=1&b=2&c=3");
+        assert!(blocked.body_too_large);
+        assert_eq!(blocked.rinfo.qinfo.args.get_str("c"), Some("3"));
+
+        // at the limit
+        let small = request(OversizedBodyPolicy::Pass, b"a=1&b=2");
+        assert!(!small.body_too_large);
+        assert_eq!(small.rinfo.qinfo.args.get_str("b"), Some("2"));
+    }
 }
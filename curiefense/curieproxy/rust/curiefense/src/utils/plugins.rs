@@ -0,0 +1,148 @@
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+
+use crate::interface::Location;
+use crate::utils::RequestInfo;
+
+/// a hook that computes plugin fields from a mapped request
+///
+/// The computed fields are added to the `plugins` field of the request, just like the values that are passed
+/// by the proxy, so that they can be used by global filters, templates and logs. Their keys are prefixed with
+/// the name of the hook, as `name.key`, so that hooks can't overwrite each other's fields.
+pub trait PluginHook: Send + Sync {
+    /// namespace of the computed fields
+    fn name(&self) -> &str;
+    fn compute(&self, reqinfo: &RequestInfo) -> Vec<(String, String)>;
+}
+
+lazy_static! {
+    /// hooks run at the end of map_request, in registration order
+    pub static ref PLUGIN_HOOKS: RwLock<Vec<Arc<dyn PluginHook>>> = RwLock::new(Vec::new());
+}
+
+pub fn register_plugin_hook(hook: Arc<dyn PluginHook>) {
+    if let Ok(mut hooks) = PLUGIN_HOOKS.write() {
+        hooks.push(hook);
+    }
+}
+
+/// runs the hooks in order, each hook can read the fields computed by the previous ones
+pub fn apply_plugin_hooks(hooks: &[Arc<dyn PluginHook>], reqinfo: &mut RequestInfo) {
+    for hook in hooks {
+        for (k, v) in hook.compute(reqinfo) {
+            let k = format!("{}.{}", hook.name(), k);
+            let l = Location::PluginValue(k.clone(), v.clone());
+            reqinfo.plugins.add(k, l, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::matchers::RequestSelector;
+    use crate::utils::{select_string, RequestBuilder};
+
+    /// the first path segment, when it looks like an api version
+    struct ApiVersion;
+
+    impl PluginHook for ApiVersion {
+        fn name(&self) -> &str {
+            "api"
+        }
+
+        fn compute(&self, reqinfo: &RequestInfo) -> Vec<(String, String)> {
+            reqinfo
+                .rinfo
+                .qinfo
+                .qpath
+                .trim_start_matches('/')
+                .split('/')
+                .next()
+                .filter(|s| s.len() > 1 && s.starts_with('v') && s[1..].chars().all(|c| c.is_ascii_digit()))
+                .map(|s| vec![("version".to_string(), s.to_string())])
+                .unwrap_or_default()
+        }
+    }
+
+    /// depends on the field computed by ApiVersion
+    struct Legacy;
+
+    impl PluginHook for Legacy {
+        fn name(&self) -> &str {
+            "compat"
+        }
+
+        fn compute(&self, reqinfo: &RequestInfo) -> Vec<(String, String)> {
+            let legacy = reqinfo.plugins.get_str("api.version") == Some("v1");
+            vec![("legacy".to_string(), legacy.to_string())]
+        }
+    }
+
+    fn mapped(path: &str) -> RequestInfo {
+        RequestBuilder::new("GET", path)
+            .ip("1.2.3.4")
+            .authority("main.site")
+            .security_policy(Arc::new(SecurityPolicy::empty()))
+            .plugin("static", "value")
+            .build()
+    }
+
+    #[test]
+    fn computed_field() {
+        let hooks: Vec<Arc<dyn PluginHook>> = vec![Arc::new(ApiVersion)];
+        let mut reqinfo = mapped("/v2/users?id=3");
+        apply_plugin_hooks(&hooks, &mut reqinfo);
+        assert_eq!(reqinfo.plugins.get_str("api.version"), Some("v2"));
+        assert_eq!(reqinfo.plugins.get_str("static"), Some("value"));
+        assert_eq!(
+            select_string(&reqinfo, &RequestSelector::Plugins("api.version".to_string()), None),
+            Some("v2".to_string())
+        );
+    }
+
+    #[test]
+    fn no_computed_field() {
+        let hooks: Vec<Arc<dyn PluginHook>> = vec![Arc::new(ApiVersion)];
+        let mut reqinfo = mapped("/users");
+        apply_plugin_hooks(&hooks, &mut reqinfo);
+        assert_eq!(reqinfo.plugins.get_str("api.version"), None);
+    }
+
+    #[test]
+    fn ordered_hooks() {
+        let hooks: Vec<Arc<dyn PluginHook>> = vec![Arc::new(ApiVersion), Arc::new(Legacy)];
+        let mut reqinfo = mapped("/v1/users");
+        apply_plugin_hooks(&hooks, &mut reqinfo);
+        assert_eq!(reqinfo.plugins.get_str("compat.legacy"), Some("true"));
+
+        let hooks: Vec<Arc<dyn PluginHook>> = vec![Arc::new(Legacy), Arc::new(ApiVersion)];
+        let mut reqinfo = mapped("/v1/users");
+        apply_plugin_hooks(&hooks, &mut reqinfo);
+        assert_eq!(reqinfo.plugins.get_str("compat.legacy"), Some("false"));
+    }
+
+    /// computes a field with the same key as ApiVersion
+    struct Version;
+
+    impl PluginHook for Version {
+        fn name(&self) -> &str {
+            "build"
+        }
+
+        fn compute(&self, _reqinfo: &RequestInfo) -> Vec<(String, String)> {
+            vec![("version".to_string(), "42".to_string())]
+        }
+    }
+
+    #[test]
+    fn namespaced_fields() {
+        let hooks: Vec<Arc<dyn PluginHook>> = vec![Arc::new(ApiVersion), Arc::new(Version)];
+        let mut reqinfo = mapped("/v2/users");
+        apply_plugin_hooks(&hooks, &mut reqinfo);
+        assert_eq!(reqinfo.plugins.get_str("api.version"), Some("v2"));
+        assert_eq!(reqinfo.plugins.get_str("build.version"), Some("42"));
+        assert_eq!(reqinfo.plugins.get_str("version"), None);
+    }
+}
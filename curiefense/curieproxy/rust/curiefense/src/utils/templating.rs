@@ -8,11 +8,30 @@ use nom::{
 };
 
 use crate::config::matchers::RequestSelector;
+use crate::interface::{InitiatorKind, Tags};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TVar {
     Selector(RequestSelector),
     Tag(String), // match for a specific tag
+    // match for any, or all, of a list of tags
+    Tags { all: bool, names: Vec<String> },
+    // number of triggers of a given kind, or only the blocking ones, known when rendering actions
+    Triggers { kind: InitiatorKind, active: bool },
+    // named group captured by a global filter regex
+    Capture(String),
+}
+
+impl TVar {
+    /// evaluates tag variables, selectors, trigger counts and captures are never matching
+    pub fn tags_match(&self, tags: &Tags) -> bool {
+        match self {
+            TVar::Selector(_) | TVar::Triggers { .. } | TVar::Capture(_) => false,
+            TVar::Tag(tagname) => tags.contains(tagname),
+            TVar::Tags { all: true, names } => names.iter().all(|t| tags.contains(t)),
+            TVar::Tags { all: false, names } => names.iter().any(|t| tags.contains(t)),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -84,6 +103,28 @@ fn parse_tvar(input: &str) -> IResult<&str, TVar> {
             }
         }
         ("tags", Some(tagname)) => Ok((input, TVar::Tag(tagname.to_string()))),
+        ("capture", Some(name)) => Ok((input, TVar::Capture(name.to_string()))),
+        ("triggers", Some(name)) => {
+            let (name, active) = match name.strip_suffix("_active") {
+                Some(n) => (n, true),
+                None => (name, false),
+            };
+            match InitiatorKind::decode(name) {
+                None => nom::combinator::fail(input),
+                Some(kind) => Ok((input, TVar::Triggers { kind, active })),
+            }
+        }
+        ("tagsany", Some(names)) | ("tagsall", Some(names)) => Ok((
+            input,
+            TVar::Tags {
+                all: selp1 == "tagsall",
+                names: names
+                    .split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
+            },
+        )),
         (_, Some(selp2)) => match RequestSelector::resolve_selector_raw(selp1, selp2) {
             Err(_) => nom::combinator::fail(input),
             Ok(t) => Ok((input, TVar::Selector(t))),
@@ -97,6 +138,19 @@ pub fn parse_request_template(i: &str) -> RequestTemplate {
     parse_template(parse_tvar, i).into_iter().map(|p| p.owned()).collect()
 }
 
+/// like parse_request_template, but fails on variables that could not be decoded, instead of keeping them as text
+pub fn parse_request_template_strict(i: &str) -> anyhow::Result<RequestTemplate> {
+    let template = parse_request_template(i);
+    for p in &template {
+        if let TemplatePart::Raw(s) = p {
+            if s.contains("${") {
+                anyhow::bail!("invalid template variable in {}", i);
+            }
+        }
+    }
+    Ok(template)
+}
+
 #[cfg(test)]
 mod test {
     use nom::bytes::complete::take_till1;
@@ -162,4 +216,70 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn tag_sets() {
+        use TemplatePart::*;
+        assert_eq!(
+            parse_request_template("${tagsany.bot, scanner}${tagsall.geo-fr,internal}"),
+            vec![
+                Var(TVar::Tags {
+                    all: false,
+                    names: vec!["bot".to_string(), "scanner".to_string()]
+                }),
+                Var(TVar::Tags {
+                    all: true,
+                    names: vec!["geo-fr".to_string(), "internal".to_string()]
+                })
+            ]
+        )
+    }
+
+    #[test]
+    fn capture() {
+        use TemplatePart::*;
+        assert_eq!(
+            parse_request_template("tenant=${capture.tenant}"),
+            vec![Raw("tenant=".to_string()), Var(TVar::Capture("tenant".to_string()))]
+        )
+    }
+
+    #[test]
+    fn triggers() {
+        use TemplatePart::*;
+        assert_eq!(
+            parse_request_template("${triggers.acl}/${triggers.rate_limit_active}"),
+            vec![
+                Var(TVar::Triggers {
+                    kind: InitiatorKind::Acl,
+                    active: false
+                }),
+                Raw("/".to_string()),
+                Var(TVar::Triggers {
+                    kind: InitiatorKind::RateLimit,
+                    active: true
+                })
+            ]
+        );
+        assert!(parse_request_template_strict("${triggers.unknown}").is_err());
+    }
+
+    #[test]
+    fn tag_sets_match() {
+        use crate::config::virtualtags::VirtualTags;
+        use crate::interface::Location;
+
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("bot", Location::Request);
+        tags.insert("geo-fr", Location::Ip);
+        let check = |template: &str| match parse_request_template(template).as_slice() {
+            [TemplatePart::Var(tv)] => tv.tags_match(&tags),
+            other => panic!("unexpected template {:?}", other),
+        };
+        assert!(check("${tagsany.scanner,bot}"));
+        assert!(!check("${tagsany.scanner,crawler}"));
+        assert!(check("${tagsall.bot,geo-fr}"));
+        assert!(!check("${tagsall.bot,geo-fr,internal}"));
+        assert!(check("${tags.bot}"));
+    }
 }
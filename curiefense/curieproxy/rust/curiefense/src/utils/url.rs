@@ -65,6 +65,30 @@ pub fn drop_scheme(uri: &str) -> &str {
     }
 }
 
+/// the host part of a host:port string, ipv6 literals keep their brackets
+pub fn strip_port(hostport: &str) -> Option<&str> {
+    if hostport.starts_with('[') {
+        // ipv6 literal
+        hostport.find(']').map(|end| &hostport[..=end])
+    } else {
+        match hostport.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => Some(host),
+            _ => Some(hostport),
+        }
+    }
+}
+
+/// extracts the host part of an url, without the port
+pub fn url_host(uri: &str) -> Option<&str> {
+    let (_, parsed) = parse_uri(uri).ok()?;
+    let host = strip_port(parsed._hostport)?;
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -103,4 +127,13 @@ mod test {
             "/c=GB?objectClass?one",
         );
     }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(url_host("https://www.example.com:123/forum"), Some("www.example.com"));
+        assert_eq!(url_host("https://john.doe@example.com"), Some("example.com"));
+        assert_eq!(url_host("ldap://[2001:db8::7]:389/c=GB"), Some("[2001:db8::7]"));
+        assert_eq!(url_host("null"), None);
+        assert_eq!(url_host("https:///path"), None);
+    }
 }
@@ -30,6 +30,7 @@ def make_request(st: Set[str], b64: bool = False) -> Tuple[Any, str]:
                 "cookies:0",
                 "headers:2",
                 "args:%d" % (len(st) * (2 if b64 else 1)),
+                "content-length:nil",
                 "geo-city:nil",
                 "geo-continent-name:north-america",
                 "geo-continent-code:na",
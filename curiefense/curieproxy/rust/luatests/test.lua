@@ -179,9 +179,16 @@ local function run_inspect_request_gen(raw_request_map, mode)
         for _, limit in pairs(limits) do
           local key = limit.key
           local curcount = 1
-          if not limit.zero_limits then
+          local expire
+          if limit.bucket_rate then
+            -- remaining tokens, -1 when the bucket is empty
+            curcount = -1
+            if not limit.zero_limits then
+              local now_ms = math.floor(socket.gettime() * 1000)
+              curcount = conn:eval(limit.bucket_script, 1, key, limit.bucket_rate, limit.bucket_burst, now_ms)
+            end
+          elseif not limit.zero_limits then
             local pw = limit.pairwith
-            local expire
             if pw then
               conn:sadd(key, pw)
               curcount = conn:scard(key)
@@ -197,7 +204,7 @@ local function run_inspect_request_gen(raw_request_map, mode)
               conn:expire(key, limit.timeframe)
             end
           end
-          table.insert(rlimits, limit:result(curcount))
+          table.insert(rlimits, limit:result(curcount, expire))
         end
 
         res = curiefense.inspect_request_process(r2, rlimits)